@@ -0,0 +1,88 @@
+use log::{info, warn};
+use std::{sync::OnceLock, thread::available_parallelism};
+
+use crate::state::worker_thread_name;
+
+/// Cores the worker threads run on, for consistent throughput on dedicated render boxes.
+#[derive(Clone, Copy)]
+pub struct CpuAffinity {
+    /// Pin every worker thread to a single core instead of letting them move between cores
+    pub pin_threads: bool,
+    /// Number of cores, starting from the first one, left to the OS, the network and the
+    /// logger/heartbeat threads
+    pub reserved_cores: usize,
+}
+
+static CPU_AFFINITY: OnceLock<CpuAffinity> = OnceLock::new();
+
+pub fn set_cpu_affinity(affinity: CpuAffinity) {
+    if !cfg!(target_os = "linux") && (affinity.pin_threads || affinity.reserved_cores > 0) {
+        warn!("CPU affinity is only supported on Linux, ignoring it");
+        return;
+    }
+
+    let _ = CPU_AFFINITY.set(affinity);
+}
+
+/// Restrict the calling worker thread, and the commands it spawns, to its cores.
+pub fn apply_worker_thread_affinity(thread_index: usize) {
+    let Some(affinity) = CPU_AFFINITY.get() else {
+        return;
+    };
+
+    if !affinity.pin_threads && affinity.reserved_cores == 0 {
+        return;
+    }
+
+    let cores = available_parallelism().map(|cores| cores.get()).unwrap_or(1);
+
+    if affinity.reserved_cores >= cores {
+        warn!(
+            "Cannot reserve {} cores out of {}, worker threads can run on any core",
+            affinity.reserved_cores, cores
+        );
+        return;
+    }
+
+    let usable_cores: Vec<usize> = (affinity.reserved_cores..cores).collect();
+
+    let thread_cores = if affinity.pin_threads {
+        vec![usable_cores[thread_index % usable_cores.len()]]
+    } else {
+        usable_cores
+    };
+
+    match set_current_thread_cores(&thread_cores) {
+        Ok(()) => info!(
+            "{} running on cores {:?}",
+            worker_thread_name(thread_index),
+            thread_cores
+        ),
+        Err(error) => warn!("Failed to set the CPU affinity: {}", error),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_current_thread_cores(cores: &[usize]) -> std::io::Result<()> {
+    // Safety: cpu_set_t is a plain bitmask, zeroed before use
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+
+        for &core in cores {
+            libc::CPU_SET(core, &mut set);
+        }
+
+        // With a pid of 0, only the calling thread is affected
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_current_thread_cores(_cores: &[usize]) -> std::io::Result<()> {
+    Ok(())
+}
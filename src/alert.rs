@@ -0,0 +1,119 @@
+use chrono::Utc;
+use log::{error, info, warn};
+use reqwest::blocking::Client;
+use serde::Serialize;
+use std::{sync::Arc, thread, time::Duration};
+
+use crate::{http::http_client, response::describe_error_response, state::WorkerState};
+
+const ALERT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// When to fire the alert webhook.
+pub struct AlertThresholds {
+    pub consecutive_failures: u64,
+    pub api_unreachable_for: Duration,
+}
+
+/// JSON body posted to the alert webhook, once when a condition starts ("firing") and once when
+/// it ends ("resolved").
+#[derive(Serialize)]
+struct AlertPayload<'a> {
+    worker_id: &'a str,
+    /// "consecutive_failures" or "api_unreachable"
+    condition: &'a str,
+    /// "firing" or "resolved"
+    status: &'a str,
+    message: String,
+    consecutive_failures: u64,
+    api_unreachable_for_seconds: u64,
+    last_error: Option<String>,
+    timestamp: String,
+}
+
+pub fn spawn_alert_monitor(
+    state: Arc<WorkerState>,
+    webhook_url: String,
+    worker_id: String,
+    thresholds: AlertThresholds,
+) -> Result<(), Box<dyn std::error::Error>> {
+    thread::Builder::new().name("alert".to_string()).spawn(move || {
+        let client = http_client();
+        let mut failures_firing = false;
+        let mut api_unreachable_firing = false;
+
+        loop {
+            let consecutive_failures = state.consecutive_failures();
+            let api_unreachable_since = state.api_unreachable_for();
+            let api_unreachable_for = api_unreachable_since.unwrap_or_default();
+
+            let failures_condition =
+                consecutive_failures > 0 && consecutive_failures >= thresholds.consecutive_failures;
+            let api_unreachable_condition =
+                api_unreachable_since.is_some() && api_unreachable_for >= thresholds.api_unreachable_for;
+
+            for (condition, is_met, firing, message) in [
+                (
+                    "consecutive_failures",
+                    failures_condition,
+                    &mut failures_firing,
+                    format!("{} consecutive jobs failed", consecutive_failures),
+                ),
+                (
+                    "api_unreachable",
+                    api_unreachable_condition,
+                    &mut api_unreachable_firing,
+                    format!("API unreachable for {:.0?}", api_unreachable_for),
+                ),
+            ] {
+                if is_met == *firing {
+                    continue;
+                }
+
+                *firing = is_met;
+
+                let payload = AlertPayload {
+                    worker_id: &worker_id,
+                    condition,
+                    status: if is_met { "firing" } else { "resolved" },
+                    message,
+                    consecutive_failures,
+                    api_unreachable_for_seconds: api_unreachable_for.as_secs(),
+                    last_error: state.last_error(),
+                    timestamp: Utc::now().to_rfc3339(),
+                };
+
+                if is_met {
+                    warn!("Alert {} firing: {}", condition, &payload.message);
+                } else {
+                    info!("Alert {} resolved", condition);
+                }
+
+                if let Err(error) = post_alert(&client, &webhook_url, &payload) {
+                    error!("Failed to call the alert webhook: {}", error);
+                }
+            }
+
+            thread::sleep(ALERT_CHECK_INTERVAL);
+        }
+    })?;
+
+    Ok(())
+}
+
+fn post_alert(
+    client: &Client,
+    webhook_url: &str,
+    payload: &AlertPayload,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client.post(webhook_url).json(payload).send()?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "Alert webhook rejected the alert. {}",
+            describe_error_response(response)
+        )
+        .into());
+    }
+
+    Ok(())
+}
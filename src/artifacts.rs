@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::utils::sha256_of_file;
+
+/// A file produced by a step, to be uploaded to the API or the storage.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Artifact {
+    /// File name, also the end of its storage key, eg: `rasters_1000_6000.tar.xz`
+    #[serde(alias = "file_name")]
+    pub name: String,
+    /// Form part the API expects the file in, eg: `rasters`
+    #[serde(alias = "form_part_name")]
+    pub role: String,
+    /// Not persisted, the outbox keeps its own copy of the files
+    #[serde(skip)]
+    pub path: PathBuf,
+    #[serde(alias = "mime_str")]
+    pub mime: String,
+    /// Hex encoded SHA-256 of the file, sent so that the API can check what it received
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// Artifacts declared by a step, uploaded together by `upload_artifacts` or `upload_files`.
+#[derive(Clone, Debug, Default)]
+pub struct UploadManifest {
+    pub artifacts: Vec<Artifact>,
+}
+
+impl UploadManifest {
+    pub fn new() -> Self {
+        UploadManifest::default()
+    }
+
+    /// Declare an artifact, computing its checksum.
+    pub fn add(
+        &mut self,
+        name: &str,
+        role: &str,
+        path: &Path,
+        mime: &str,
+    ) -> Result<&mut Self, Box<dyn std::error::Error>> {
+        self.artifacts.push(Artifact {
+            name: name.to_string(),
+            role: role.to_string(),
+            path: path.to_path_buf(),
+            mime: mime.to_string(),
+            sha256: Some(sha256_of_file(path)?),
+        });
+
+        Ok(self)
+    }
+
+    /// Manifest of a single artifact.
+    pub fn single(
+        name: &str,
+        role: &str,
+        path: &Path,
+        mime: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut manifest = UploadManifest::new();
+        manifest.add(name, role, path, mime)?;
+
+        Ok(manifest)
+    }
+
+    /// Names of the artifacts, for the logs.
+    pub fn names(&self) -> String {
+        self.artifacts
+            .iter()
+            .map(|artifact| artifact.name.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
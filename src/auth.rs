@@ -0,0 +1,165 @@
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use log::debug;
+use reqwest::blocking::{Request, RequestBuilder, Response};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    circuit::{check_circuit, record_api_result},
+    compression::{compress_json_body, record_accepted_encodings},
+    failover::{endpoint_count, is_api_url, mark_unhealthy, use_healthy_endpoint, RequestClass},
+    pinning::pinned_client,
+    state::current_correlation_id,
+    upload_parts::record_max_body_size,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Replaces the hash of the body in the HMAC signature of a multipart upload, so that the files
+/// are not read into memory to sign it, see `UploadPiece::content_hash_line`
+pub const CONTENT_HASH_HEADER: &str = "X-Mapant-Content-Sha256";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum AuthMode {
+    /// Send `Authorization: Bearer {worker_id}.{token}` with every request
+    Bearer,
+    /// Sign every request with an HMAC-SHA256 of its method, path, timestamp and body, keyed with
+    /// the token. The token itself never leaves the worker, recommended with pinned certificates.
+    Hmac,
+}
+
+/// Credentials of the worker on the mapant API.
+#[derive(Clone, Debug)]
+pub struct ApiAuth {
+    pub worker_id: String,
+    token: String,
+    mode: AuthMode,
+}
+
+impl ApiAuth {
+    pub fn new(worker_id: String, token: String, mode: AuthMode) -> Self {
+        ApiAuth {
+            worker_id,
+            token,
+            mode,
+        }
+    }
+
+    /// Authenticate the request with the configured mode and send it. Requests sent while a job
+    /// runs on the current thread carry its correlation id in the `X-Mapant-Correlation-Id` header.
+    ///
+    /// In HMAC mode, the `Authorization` header is
+    /// `Mapant-HMAC-SHA256 Credential={worker_id}, Timestamp={unix_seconds}, Signature={hex}`
+    /// where the signature covers `{METHOD}\n{path?query}\n{unix_seconds}\n{hex(sha256(body))}`.
+    /// The server is expected to reject timestamps too far from its clock to prevent replays. With
+    /// an `X-Mapant-Content-Sha256` header, its value replaces the hash of the body: the SHA-256 of
+    /// one `{part name} {file name} {file sha256} {offset} {length}\n` line per part of a
+    /// multipart upload, the server checks the received files against their checksums.
+    ///
+    /// Fails with `CircuitOpen` without sending anything while the API is considered down. Requests
+    /// to an unreachable base URL are sent again to the next one of their class, see `failover`.
+    /// With pinned fingerprints, the connections to API servers with another certificate are closed
+    /// during the TLS handshake, before the credentials are sent. JSON
+    /// bodies are gzipped once the API announced it accepts it, see `compression`.
+    pub fn send(&self, request: RequestBuilder) -> Result<Response, Box<dyn std::error::Error>> {
+        check_circuit()?;
+
+        let (client, request) = request.build_split();
+        let mut request = request?;
+        compress_json_body(&mut request)?;
+
+        let class = RequestClass::of(request.method());
+        // Every base URL of the class is tried once, streamed bodies can not be sent again though
+        let mut endpoints_left = endpoint_count(class);
+
+        loop {
+            let retry = request.try_clone();
+            let endpoint = use_healthy_endpoint(&mut request, class)?;
+
+            debug!("{} {}", request.method(), request.url().path());
+            self.authenticate(&mut request)?;
+
+            // Mirrors and storage have their own certificates, only the API is pinned
+            let result = match pinned_client() {
+                Some(pinned_client) if is_api_url(request.url()) => pinned_client.execute(request),
+                _ => client.execute(request),
+            };
+            record_api_result(&result);
+            endpoints_left -= 1;
+
+            match (result, endpoint) {
+                (Err(error), Some(endpoint)) if error.is_connect() || error.is_timeout() => {
+                    mark_unhealthy(class, endpoint);
+
+                    match retry {
+                        Some(retry) if endpoints_left > 0 => request = retry,
+                        _ => return Err(error.into()),
+                    }
+                }
+                (result, _) => {
+                    let response = result?;
+
+                    // Downloads can be served by mirrors, which say nothing of the API
+                    if matches!(class, RequestClass::Api) {
+                        record_accepted_encodings(&response);
+                        record_max_body_size(&response);
+                    }
+
+                    return Ok(response);
+                }
+            }
+        }
+    }
+
+    fn authenticate(&self, request: &mut Request) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(correlation_id) = current_correlation_id() {
+            request
+                .headers_mut()
+                .insert("X-Mapant-Correlation-Id", correlation_id.parse()?);
+        }
+
+        match self.mode {
+            AuthMode::Bearer => {
+                request.headers_mut().insert(
+                    "Authorization",
+                    format!("Bearer {}.{}", self.worker_id, self.token).parse()?,
+                );
+            }
+            AuthMode::Hmac => {
+                let body_hash = match (request.headers().get(CONTENT_HASH_HEADER), request.body()) {
+                    (Some(content_hash), _) => content_hash.to_str()?.to_string(),
+                    (None, Some(body)) => match body.as_bytes() {
+                        Some(body) => hex::encode(Sha256::digest(body)),
+                        None => return Err("Can not sign a streamed body without its content hash".into()),
+                    },
+                    (None, None) => hex::encode(Sha256::digest(b"")),
+                };
+
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+                let path = match request.url().query() {
+                    Some(query) => format!("{}?{}", request.url().path(), query),
+                    None => request.url().path().to_string(),
+                };
+
+                let string_to_sign = format!("{}\n{}\n{}\n{}", request.method(), path, timestamp, body_hash);
+
+                let mut mac = HmacSha256::new_from_slice(self.token.as_bytes())?;
+                mac.update(string_to_sign.as_bytes());
+                let signature = hex::encode(mac.finalize().into_bytes());
+
+                request.headers_mut().insert(
+                    "Authorization",
+                    format!(
+                        "Mapant-HMAC-SHA256 Credential={}, Timestamp={}, Signature={}",
+                        self.worker_id, timestamp, signature
+                    )
+                    .parse()?,
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use log::info;
+use std::sync::OnceLock;
+
+use crate::region::RegionProfile;
+
+/// min_x, min_y, max_x, max_y in the coordinate system of the region, eg: Lambert-93 for fr.
+pub type Bbox = (i64, i64, i64, i64);
+
+static JOB_BBOX: OnceLock<Bbox> = OnceLock::new();
+
+/// Only accept the jobs whose tiles intersect `bbox`, the others are declined.
+pub fn set_job_bbox(bbox: Bbox) {
+    info!(
+        "Only accepting the jobs of the tiles in {},{},{},{}",
+        bbox.0, bbox.1, bbox.2, bbox.3
+    );
+
+    let _ = JOB_BBOX.set(bbox);
+}
+
+/// Value of the `X-Mapant-Bbox` header, eg: "640000,6820000,680000,6870000". None without
+/// restriction.
+pub fn bbox_header_value() -> Option<String> {
+    JOB_BBOX
+        .get()
+        .map(|bbox| format!("{},{},{},{}", bbox.0, bbox.1, bbox.2, bbox.3))
+}
+
+pub fn job_bbox() -> Option<Bbox> {
+    JOB_BBOX.get().copied()
+}
+
+/// Tiles not intersecting the bbox, none without restriction. Tiles on its edges are in it, ids
+/// that are not `{min_x}_{min_y}` can not be placed and are considered in it too.
+pub fn tiles_outside_bbox<'a>(tiles_ids: &[&'a str], region: &RegionProfile) -> Vec<&'a str> {
+    let Some((min_x, min_y, max_x, max_y)) = job_bbox() else {
+        return vec![];
+    };
+
+    tiles_ids
+        .iter()
+        .copied()
+        .filter(|tile_id| {
+            let Some((tile_min_x, tile_min_y)) = tile_id
+                .trim()
+                .split_once('_')
+                .and_then(|(x, y)| Some((x.parse::<i64>().ok()?, y.parse::<i64>().ok()?)))
+            else {
+                return false;
+            };
+
+            tile_min_x >= max_x
+                || tile_min_y >= max_y
+                || tile_min_x + region.tile_size_meters <= min_x
+                || tile_min_y + region.tile_size_meters <= min_y
+        })
+        .collect()
+}
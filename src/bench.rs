@@ -0,0 +1,186 @@
+use cassini::{process_single_tile_lidar_step, process_single_tile_render_step};
+use log::info;
+use std::{
+    fs::{self, create_dir_all, remove_dir_all},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{available_parallelism, sleep, spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+use sysinfo::{get_current_pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+use crate::{
+    pyramid::generate_base_zoom_levels_tiles, sample_tile::write_sample_tile, utils::compress_directory,
+};
+
+const MEMORY_SAMPLING_INTERVAL: Duration = Duration::from_millis(200);
+/// Share of the total RAM the suggested number of threads is allowed to use
+const USABLE_MEMORY_RATIO: f64 = 0.8;
+
+/// Samples the resident memory of the process in the background to get its peak during a stage.
+struct PeakMemorySampler {
+    stop: Arc<AtomicBool>,
+    peak: Arc<AtomicU64>,
+    handle: JoinHandle<()>,
+}
+
+impl PeakMemorySampler {
+    fn start() -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let peak = Arc::new(AtomicU64::new(0));
+        let thread_stop = stop.clone();
+        let thread_peak = peak.clone();
+
+        let handle = spawn(move || {
+            let mut system = System::new();
+            let pid = match get_current_pid() {
+                Ok(pid) => pid,
+                Err(_) => return,
+            };
+
+            while !thread_stop.load(Ordering::SeqCst) {
+                system.refresh_processes_specifics(
+                    ProcessesToUpdate::Some(&[pid]),
+                    true,
+                    ProcessRefreshKind::nothing().with_memory(),
+                );
+
+                if let Some(process) = system.process(pid) {
+                    thread_peak.fetch_max(process.memory(), Ordering::SeqCst);
+                }
+
+                sleep(MEMORY_SAMPLING_INTERVAL);
+            }
+        });
+
+        PeakMemorySampler { stop, peak, handle }
+    }
+
+    fn stop(self) -> u64 {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+        self.peak.load(Ordering::SeqCst)
+    }
+}
+
+struct StageResult {
+    name: &'static str,
+    duration: Duration,
+    peak_memory: u64,
+}
+
+fn run_stage<F>(name: &'static str, stage: F) -> Result<StageResult, Box<dyn std::error::Error>>
+where
+    F: FnOnce() -> Result<(), Box<dyn std::error::Error>>,
+{
+    info!("Benchmarking {}", name);
+    let sampler = PeakMemorySampler::start();
+    let start = Instant::now();
+    stage()?;
+    let duration = start.elapsed();
+    let peak_memory = sampler.stop();
+
+    Ok(StageResult {
+        name,
+        duration,
+        peak_memory,
+    })
+}
+
+/// Run a LAZ file, the bundled sample tile by default, through the LiDAR, render and pyramid steps
+/// locally, print the timings and peak memory of every stage and suggest a number of threads for
+/// this machine.
+pub fn bench(laz_file_path: Option<&Path>, base_zoom_level: i32) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(laz_file_path) = laz_file_path.filter(|path| !path.exists()) {
+        return Err(format!("LAZ file {} not found", laz_file_path.display()).into());
+    }
+
+    let bench_dir_path = Path::new("bench-output");
+
+    if bench_dir_path.exists() {
+        remove_dir_all(bench_dir_path)?;
+    }
+
+    let lidar_step_path = bench_dir_path.join("lidar-step");
+    let render_step_path = bench_dir_path.join("render-step");
+    let tiles_path = bench_dir_path.join("tiles");
+    create_dir_all(&lidar_step_path)?;
+    create_dir_all(&tiles_path)?;
+
+    let laz_file_path = match laz_file_path {
+        Some(laz_file_path) => laz_file_path.to_path_buf(),
+        None => {
+            let sample_tile_path = bench_dir_path.join("sample-tile.laz");
+            write_sample_tile(&sample_tile_path)?;
+            sample_tile_path
+        }
+    };
+
+    let mut results: Vec<StageResult> = vec![];
+
+    results.push(run_stage("LiDAR step", || {
+        process_single_tile_lidar_step(&laz_file_path, &lidar_step_path);
+        Ok(())
+    })?);
+
+    results.push(run_stage("LiDAR step compression", || {
+        compress_directory(&lidar_step_path, &bench_dir_path.join("lidar-step.tar.xz"))
+    })?);
+
+    results.push(run_stage("Render step", || {
+        process_single_tile_render_step(&lidar_step_path, &render_step_path, vec![], false, true);
+        Ok(())
+    })?);
+
+    results.push(run_stage("Pyramid base zoom levels", || {
+        let base_tile_x_path = tiles_path.join(base_zoom_level.to_string()).join("0");
+        create_dir_all(&base_tile_x_path)?;
+        fs::copy(
+            render_step_path.join("full-map.png"),
+            base_tile_x_path.join("0.png"),
+        )?;
+        generate_base_zoom_levels_tiles(&tiles_path, 0, 0, base_zoom_level, None)?;
+        Ok(())
+    })?);
+
+    let mut system = System::new();
+    system.refresh_memory();
+    let total_memory = system.total_memory();
+    let cpus = available_parallelism().map(|cpus| cpus.get()).unwrap_or(1);
+    let peak_memory = results.iter().map(|result| result.peak_memory).max().unwrap_or(0);
+
+    let threads_fitting_in_memory = if peak_memory == 0 {
+        cpus
+    } else {
+        (total_memory as f64 * USABLE_MEMORY_RATIO / peak_memory as f64).floor() as usize
+    };
+
+    let suggested_threads = cpus.min(threads_fitting_in_memory).max(1);
+
+    println!();
+    println!("{:<28} {:>12} {:>16}", "Stage", "Duration", "Peak RAM (MB)");
+
+    for result in &results {
+        println!(
+            "{:<28} {:>12} {:>16}",
+            result.name,
+            format!("{:.1?}", result.duration),
+            result.peak_memory / 1_000_000
+        );
+    }
+
+    println!();
+    println!(
+        "{} CPUs, {} MB of RAM. Suggested value: --threads {}",
+        cpus,
+        total_memory / 1_000_000,
+        suggested_threads
+    );
+
+    remove_dir_all(bench_dir_path)?;
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use image::RgbaImage;
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Maximum number of released buffers kept by a thread
+static MAX_POOLED_BUFFERS: AtomicUsize = AtomicUsize::new(8);
+/// Capacity in bytes of the largest buffer kept, a 512x512 RGBA tile. The larger images, eg: the
+/// full maps of the render steps, are rare enough to be allocated every time
+const MAX_POOLED_BUFFER_CAPACITY: usize = 512 * 512 * 4;
+
+thread_local! {
+    /// Pixel buffers released by the image operations of the current thread, reused by the next
+    /// ones instead of allocating a new buffer for every tile of a pyramid batch.
+    static BUFFER_POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Keep at most `max_buffers` released buffers per thread, each of them holds at most a 512x512
+/// tile worth of pixels.
+pub fn set_max_pooled_buffers(max_buffers: usize) {
+    MAX_POOLED_BUFFERS.store(max_buffers, Ordering::Relaxed);
+}
+
+/// A zeroed buffer of `len` bytes, reusing the smallest released buffer large enough.
+pub fn take_buffer(len: usize) -> Vec<u8> {
+    let pooled_buffer = BUFFER_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+
+        pool.iter()
+            .enumerate()
+            .filter(|(_, buffer)| buffer.capacity() >= len)
+            .min_by_key(|(_, buffer)| buffer.capacity())
+            .map(|(index, _)| index)
+            .map(|index| pool.swap_remove(index))
+    });
+
+    match pooled_buffer {
+        Some(mut buffer) => {
+            buffer.clear();
+            buffer.resize(len, 0);
+            buffer
+        }
+        None => vec![0; len],
+    }
+}
+
+/// Give a buffer back to the pool of the current thread once its image is not needed anymore. Freed
+/// if larger than a tile.
+pub fn release_buffer(buffer: Vec<u8>) {
+    if buffer.capacity() > MAX_POOLED_BUFFER_CAPACITY {
+        return;
+    }
+
+    BUFFER_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+
+        if pool.len() < MAX_POOLED_BUFFERS.load(Ordering::Relaxed) {
+            pool.push(buffer);
+        }
+    });
+}
+
+/// A fully transparent image backed by a pooled buffer, see `release_buffer`.
+pub fn transparent_rgba_image(width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_raw(width, height, take_buffer(width as usize * height as usize * 4))
+        .expect("Buffer sized for the image")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn released_tile_buffer_reused() {
+        let buffer = take_buffer(256 * 256 * 4);
+        let pointer = buffer.as_ptr();
+        release_buffer(buffer);
+
+        let reused_buffer = take_buffer(256 * 256 * 4);
+
+        assert_eq!(reused_buffer.as_ptr(), pointer);
+    }
+
+    #[test]
+    fn buffer_larger_than_a_tile_not_pooled() {
+        release_buffer(vec![0; MAX_POOLED_BUFFER_CAPACITY + 1]);
+
+        assert_eq!(BUFFER_POOL.with(|pool| pool.borrow().len()), 0);
+    }
+}
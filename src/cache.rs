@@ -0,0 +1,352 @@
+use log::{error, info};
+use std::{
+    collections::HashMap,
+    fs::{read_dir, remove_dir_all, remove_file},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    journal::all_leased_jobs, scratch::configured_scratch_dir, state::WorkerState, stats::format_duration,
+    tile_lock::TileLock, utils::directory_size,
+};
+
+/// Directories of the work directory, and of the scratch directory, holding files reused across jobs
+pub const CACHE_DIRS: [&str; 4] = ["lidar-files", "lidar-step", "render-step", "tiles"];
+
+/// Limits applied by the cache garbage collection.
+#[derive(Clone, Default)]
+pub struct CachePolicy {
+    /// Maximum total size in bytes of the cache directories, the least recently modified entries
+    /// are removed first
+    pub budget: Option<u64>,
+    /// Maximum age of the entries of a cache directory, by directory name
+    pub ttls: HashMap<String, Duration>,
+}
+
+/// Parse TTLs like "lidar-files=24,tiles=6", in hours.
+pub fn parse_cache_ttls(value: &str) -> Result<HashMap<String, Duration>, String> {
+    value
+        .split(',')
+        .map(|ttl| {
+            let (cache_dir, hours) = ttl
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid cache TTL \"{}\", expected eg: tiles=6", ttl))?;
+
+            if !CACHE_DIRS.contains(&cache_dir) {
+                return Err(format!(
+                    "Unknown cache directory \"{}\", expected one of {}",
+                    cache_dir,
+                    CACHE_DIRS.join(", ")
+                ));
+            }
+
+            let hours: u64 = hours
+                .parse()
+                .map_err(|_| format!("Invalid number of hours \"{}\"", hours))?;
+
+            Ok((cache_dir.to_string(), Duration::from_secs(hours * 3600)))
+        })
+        .collect()
+}
+
+/// A tile (file or directory) in a cache directory.
+struct CacheEntry {
+    cache_dir: &'static str,
+    path: PathBuf,
+    /// Tile or area id, used to find the jobs and flag files referencing the entry
+    id: String,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl CacheEntry {
+    fn age(&self) -> Duration {
+        self.modified.elapsed().unwrap_or_default()
+    }
+}
+
+fn list_cache_entries(work_dir: &Path) -> Vec<CacheEntry> {
+    let mut entries = vec![];
+
+    let roots = [Some(work_dir), configured_scratch_dir()];
+
+    for (root, cache_dir) in roots
+        .into_iter()
+        .flatten()
+        .flat_map(|root| CACHE_DIRS.map(|cache_dir| (root, cache_dir)))
+    {
+        let Ok(dir_entries) = read_dir(root.join(cache_dir)) else {
+            continue;
+        };
+
+        for entry in dir_entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            // Lock files, ETags of the downloaded files, and tiles being downloaded
+            if name.ends_with(".lock") || name.ends_with(".etag") || name.contains(".partial-") {
+                continue;
+            }
+
+            let Ok(modified) = entry.metadata().and_then(|metadata| metadata.modified()) else {
+                continue;
+            };
+
+            let size = if path.is_dir() {
+                directory_size(&path)
+            } else {
+                entry.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+            };
+
+            entries.push(CacheEntry {
+                cache_dir,
+                id: name.split('.').next().unwrap_or(&name).to_string(),
+                path,
+                size,
+                modified,
+            });
+        }
+    }
+
+    entries
+}
+
+/// Whether the entry is used by a running job of this worker.
+fn is_in_use(entry: &CacheEntry, in_flight_jobs: &[String]) -> bool {
+    in_flight_jobs
+        .iter()
+        .any(|job_payload| job_payload.contains(entry.id.as_str()))
+}
+
+/// Lock the LiDAR step tiles before removing them, they can be used by other worker processes.
+/// None if the entry is locked by someone else.
+fn lock_entry(entry: &CacheEntry) -> Option<Option<TileLock>> {
+    if entry.cache_dir != "lidar-step" {
+        return Some(None);
+    }
+
+    let cache_dir = entry.path.parent()?;
+    TileLock::try_exclusive(cache_dir, &entry.id).ok()?.map(Some)
+}
+
+/// Size, number of entries and oldest entry of a cache directory.
+pub struct CacheUsage {
+    pub cache_dir: &'static str,
+    pub entries: usize,
+    /// In bytes
+    pub size: u64,
+    pub oldest: Option<Duration>,
+}
+
+/// Usage of every cache directory, walking them so it takes a while on a large cache.
+pub fn cache_usage(work_dir: &Path) -> Vec<CacheUsage> {
+    let entries = list_cache_entries(work_dir);
+
+    CACHE_DIRS
+        .into_iter()
+        .map(|cache_dir| {
+            let dir_entries: Vec<&CacheEntry> = entries
+                .iter()
+                .filter(|entry| entry.cache_dir == cache_dir)
+                .collect();
+
+            CacheUsage {
+                cache_dir,
+                entries: dir_entries.len(),
+                size: dir_entries.iter().map(|entry| entry.size).sum(),
+                oldest: dir_entries.iter().map(|entry| entry.age()).max(),
+            }
+        })
+        .collect()
+}
+
+/// Last `cache_usage` of the work directory, walked again once older than `max_age`.
+pub struct CacheUsageSnapshot {
+    max_age: Duration,
+    taken: Option<(Instant, Vec<CacheUsage>)>,
+}
+
+impl CacheUsageSnapshot {
+    pub fn new(max_age: Duration) -> Self {
+        CacheUsageSnapshot { max_age, taken: None }
+    }
+
+    pub fn get(&mut self) -> &[CacheUsage] {
+        let is_stale = self
+            .taken
+            .as_ref()
+            .is_none_or(|(taken_at, _)| taken_at.elapsed() > self.max_age);
+
+        if is_stale {
+            // The worker runs from its work directory
+            self.taken = Some((Instant::now(), cache_usage(Path::new(""))));
+        }
+
+        self.taken
+            .as_ref()
+            .map(|(_, usage)| usage.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// Print the size, number of entries and oldest entry of every cache directory.
+pub fn print_cache_report(work_dir: &Path) {
+    println!(
+        "{:<14}{:>10}{:>12}{:>14}",
+        "Cache", "Entries", "Size (MB)", "Oldest"
+    );
+
+    for usage in cache_usage(work_dir) {
+        println!(
+            "{:<14}{:>10}{:>12}{:>14}",
+            usage.cache_dir,
+            usage.entries,
+            usage.size / 1_000_000,
+            usage
+                .oldest
+                .map(format_duration)
+                .unwrap_or_else(|| "-".to_string())
+        );
+    }
+}
+
+/// Remove the entries older than their TTL, then the least recently modified ones until the cache
+/// fits in the budget. Entries used by `in_flight_jobs` or being downloaded are kept. Returns the
+/// number of bytes freed, or that would be freed with `dry_run`.
+pub fn collect_garbage(
+    work_dir: &Path,
+    policy: &CachePolicy,
+    in_flight_jobs: &[String],
+    dry_run: bool,
+) -> u64 {
+    let mut entries = list_cache_entries(work_dir);
+    entries.sort_by_key(|entry| entry.modified);
+
+    let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut freed: u64 = 0;
+
+    for entry in entries {
+        let expired = policy
+            .ttls
+            .get(entry.cache_dir)
+            .is_some_and(|ttl| entry.age() > *ttl);
+        let over_budget = policy.budget.is_some_and(|budget| total_size > budget);
+
+        if !(expired || over_budget) || is_in_use(&entry, in_flight_jobs) {
+            continue;
+        }
+
+        let Some(_lock) = lock_entry(&entry) else {
+            continue;
+        };
+
+        let reason = if expired { "expired" } else { "over budget" };
+
+        if dry_run {
+            info!(
+                "Would remove {} ({} MB, {})",
+                entry.path.display(),
+                entry.size / 1_000_000,
+                reason
+            );
+        } else {
+            if let Err(error) = remove_entry(&entry) {
+                error!(
+                    "Failed to remove {} from the cache: {}",
+                    entry.path.display(),
+                    error
+                );
+                continue;
+            }
+
+            info!(
+                "Removed {} from the cache ({} MB, {})",
+                entry.path.display(),
+                entry.size / 1_000_000,
+                reason
+            );
+        }
+
+        total_size -= entry.size;
+        freed += entry.size;
+    }
+
+    freed
+}
+
+fn remove_entry(entry: &CacheEntry) -> std::io::Result<()> {
+    if entry.path.is_dir() {
+        remove_dir_all(&entry.path)
+    } else {
+        let _ = remove_file(format!("{}.etag", entry.path.display()));
+        remove_file(&entry.path)
+    }
+}
+
+/// Remove the cached files of `tiles_ids` (LAZ files, LiDAR and render steps) and of `areas_ids`
+/// (pyramid tiles), eg: after the server reprocessed them. LiDAR step tiles used by other worker
+/// processes are waited for, entries used by `in_flight_jobs` make it fail so that the server
+/// retries later. Returns the number of bytes freed.
+pub fn invalidate_cache_entries(
+    work_dir: &Path,
+    tiles_ids: &[String],
+    areas_ids: &[String],
+    in_flight_jobs: &[String],
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut freed: u64 = 0;
+
+    let entries = list_cache_entries(work_dir)
+        .into_iter()
+        .filter(|entry| match entry.cache_dir {
+            "tiles" => areas_ids.contains(&entry.id),
+            _ => tiles_ids.contains(&entry.id),
+        });
+
+    for entry in entries {
+        if is_in_use(&entry, in_flight_jobs) {
+            return Err(format!("{} is used by a running job", entry.path.display()).into());
+        }
+
+        let _lock = match (entry.cache_dir, entry.path.parent()) {
+            ("lidar-step", Some(cache_dir)) => Some(TileLock::exclusive(cache_dir, &entry.id)?),
+            _ => None,
+        };
+
+        remove_entry(&entry)?;
+        info!("Removed {} from the cache, invalidated", entry.path.display());
+        freed += entry.size;
+    }
+
+    Ok(freed)
+}
+
+/// Periodically apply the cache policy, keeping the files of the in-flight jobs and of the jobs
+/// leased by the other worker processes sharing `job_journal`.
+pub fn spawn_cache_gc(
+    state: Arc<WorkerState>,
+    work_dir: PathBuf,
+    job_journal: PathBuf,
+    policy: CachePolicy,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    thread::Builder::new()
+        .name("cache-gc".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            let mut jobs = state.in_flight_jobs();
+            jobs.extend(all_leased_jobs(&job_journal));
+
+            let freed = collect_garbage(&work_dir, &policy, &jobs, false);
+
+            if freed > 0 {
+                info!("Cache garbage collection freed {} MB", freed / 1_000_000);
+            }
+        })?;
+
+    Ok(())
+}
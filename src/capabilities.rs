@@ -0,0 +1,125 @@
+use log::{info, warn};
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::OnceLock};
+
+use crate::subprocess::{run_subprocess, subprocess_command};
+
+/// Tools needed by a job type, with the drivers they must support
+type Requirements = &'static [(&'static str, &'static [&'static str])];
+
+/// GDAL tools and drivers needed by the job types, the other job types only need cassini
+const JOB_REQUIREMENTS: [(&str, Requirements); 5] = [
+    (
+        "Render",
+        &[("gdal_translate", &["GTiff"]), ("ogr2ogr", &["ESRI Shapefile"])],
+    ),
+    (
+        "RestyleRender",
+        &[("gdal_translate", &["GTiff"]), ("ogr2ogr", &["ESRI Shapefile"])],
+    ),
+    (
+        "Mosaic",
+        &[
+            ("gdalbuildvrt", &["VRT"]),
+            ("gdal_translate", &["COG", "GTiff", "PNG"]),
+        ],
+    ),
+    ("VectorPyramid", &[("ogr2ogr", &["GPKG", "PMTiles"])]),
+    ("Validate", &[("gdalinfo", &[]), ("ogrinfo", &[])]),
+];
+
+/// GDAL tools found on the machine, and the drivers they support.
+#[derive(Serialize, Debug, Default)]
+pub struct Capabilities {
+    /// Driver short names by tool, eg: "ogr2ogr" -> ["ESRI Shapefile", "GPKG", ...]. Missing tools
+    /// are not listed.
+    pub tools: BTreeMap<&'static str, Vec<String>>,
+}
+
+static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+
+/// Capabilities of the machine, probed on the first call.
+pub fn capabilities() -> &'static Capabilities {
+    CAPABILITIES.get_or_init(|| {
+        let mut tools = BTreeMap::new();
+
+        for (_, requirements) in JOB_REQUIREMENTS {
+            for (program, _) in requirements {
+                if !tools.contains_key(program) {
+                    if let Some(drivers) = probe_drivers(program) {
+                        tools.insert(*program, drivers);
+                    }
+                }
+            }
+        }
+
+        Capabilities { tools }
+    })
+}
+
+/// Probe the GDAL tools and log the job types they rule out, which are not requested from the
+/// API. There is no in-process fallback for them.
+pub fn log_capabilities() {
+    let capabilities = capabilities();
+
+    info!(
+        "GDAL tools found: {}",
+        capabilities.tools.keys().copied().collect::<Vec<_>>().join(", ")
+    );
+
+    for (job_type, missing) in unsupported_job_types() {
+        warn!("Not accepting {} jobs, missing {}", job_type, missing.join(", "));
+    }
+}
+
+/// Job types ruled out by the machine, with what they miss.
+pub fn unsupported_job_types() -> Vec<(&'static str, Vec<String>)> {
+    JOB_REQUIREMENTS
+        .iter()
+        .map(|(job_type, _)| (*job_type, missing_requirements(job_type)))
+        .filter(|(_, missing)| !missing.is_empty())
+        .collect()
+}
+
+/// Tools and drivers needed by a job type and missing on the machine, eg: "ogr2ogr (PMTiles)".
+pub fn missing_requirements(job_type: &str) -> Vec<String> {
+    let capabilities = capabilities();
+
+    JOB_REQUIREMENTS
+        .iter()
+        .filter(|(required_by, _)| *required_by == job_type)
+        .flat_map(|(_, requirements)| requirements.iter())
+        .filter_map(|(program, required_drivers)| {
+            let Some(drivers) = capabilities.tools.get(program) else {
+                return Some(program.to_string());
+            };
+
+            let missing_drivers: Vec<&str> = required_drivers
+                .iter()
+                .filter(|driver| !drivers.iter().any(|available| available == *driver))
+                .copied()
+                .collect();
+
+            (!missing_drivers.is_empty()).then(|| format!("{} ({})", program, missing_drivers.join(", ")))
+        })
+        .collect()
+}
+
+/// Driver short names listed by `--formats`, eg: "  GTiff -raster,multidimensional raster-
+/// (rw+vs): GeoTIFF". None if the tool can not be run.
+fn probe_drivers(program: &str) -> Option<Vec<String>> {
+    let output = run_subprocess(subprocess_command(program).arg("--formats"), program).ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let drivers = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with(' '))
+        .filter_map(|line| line.trim().split(" -").next())
+        .map(str::to_string)
+        .collect();
+
+    Some(drivers)
+}
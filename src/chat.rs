@@ -0,0 +1,177 @@
+use clap::ValueEnum;
+use log::{error, info};
+use reqwest::blocking::Client;
+use std::{sync::Arc, thread, time::Duration};
+
+use crate::{
+    http::http_client, response::describe_error_response, state::WorkerState, stats::format_duration,
+};
+
+const CHAT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Body expected by the chat webhook.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ChatFormat {
+    /// `{"content": ...}`, Discord webhooks
+    Discord,
+    /// `{"text": ...}`, Slack incoming webhooks and compatible ones, eg: Mattermost
+    Slack,
+    /// `{"text": ...}`, Matrix hookshot generic webhooks
+    Matrix,
+}
+
+impl ChatFormat {
+    /// Discord for the Discord webhook urls, Slack otherwise.
+    pub fn from_url(url: &str) -> Self {
+        if url.contains("discord.com/") || url.contains("discordapp.com/") {
+            ChatFormat::Discord
+        } else {
+            ChatFormat::Slack
+        }
+    }
+}
+
+/// Events posted to the chat webhook.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ChatEvent {
+    /// The worker started
+    Started,
+    /// Every `jobs_milestone` jobs completed
+    JobsCompleted,
+    /// `failure_streak` jobs failed in a row, and the next success
+    FailureStreak,
+    /// New jobs paused for low disk space, and resumed
+    DiskFull,
+}
+
+/// Where and what to post.
+pub struct ChatSettings {
+    pub webhook_url: String,
+    pub format: ChatFormat,
+    pub events: Vec<ChatEvent>,
+    pub jobs_milestone: u64,
+    pub failure_streak: u64,
+}
+
+/// Post short messages about the significant events of the worker to a chat channel, for the
+/// operators of small fleets without a monitoring stack.
+pub fn spawn_chat_notifier(
+    state: Arc<WorkerState>,
+    worker_id: String,
+    settings: ChatSettings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    thread::Builder::new().name("chat".to_string()).spawn(move || {
+        let client = http_client();
+        let notify = |event: ChatEvent, message: String| {
+            if !settings.events.contains(&event) {
+                return;
+            }
+
+            info!("Chat notification: {}", message);
+
+            let message = format!("[{}] {}", worker_id, message);
+
+            if let Err(error) = post_message(&client, &settings, &message) {
+                error!("Failed to call the chat webhook: {}", error);
+            }
+        };
+
+        notify(
+            ChatEvent::Started,
+            format!(
+                "Worker {} started with {} threads",
+                env!("CARGO_PKG_VERSION"),
+                state.thread_stats().len()
+            ),
+        );
+
+        let mut next_milestone = settings.jobs_milestone;
+        let mut failure_streak_notified = false;
+        let mut disk_full_notified = false;
+
+        loop {
+            thread::sleep(CHAT_CHECK_INTERVAL);
+
+            let run_stats = state.run_stats();
+            let jobs_done: u64 = run_stats
+                .by_job_type()
+                .values()
+                .map(|stats| stats.jobs_done)
+                .sum();
+
+            if settings.jobs_milestone > 0 && jobs_done >= next_milestone {
+                let failures: u64 = run_stats.by_job_type().values().map(|stats| stats.failures).sum();
+
+                notify(
+                    ChatEvent::JobsCompleted,
+                    format!(
+                        "{} jobs completed ({} failed) in {}",
+                        jobs_done,
+                        failures,
+                        format_duration(state.uptime())
+                    ),
+                );
+
+                next_milestone = (jobs_done / settings.jobs_milestone + 1) * settings.jobs_milestone;
+            }
+
+            let consecutive_failures = state.consecutive_failures();
+            let failing = settings.failure_streak > 0 && consecutive_failures >= settings.failure_streak;
+
+            if failing != failure_streak_notified {
+                failure_streak_notified = failing;
+
+                let message = if failing {
+                    format!(
+                        "{} jobs failed in a row, last error: {}",
+                        consecutive_failures,
+                        state.last_error().unwrap_or_default()
+                    )
+                } else {
+                    "Jobs succeed again".to_string()
+                };
+
+                notify(ChatEvent::FailureStreak, message);
+            }
+
+            let disk_full = state.is_disk_paused();
+
+            if disk_full != disk_full_notified {
+                disk_full_notified = disk_full;
+
+                let message = if disk_full {
+                    "Disk almost full, not accepting new jobs until space is freed"
+                } else {
+                    "Disk space freed, accepting new jobs again"
+                };
+
+                notify(ChatEvent::DiskFull, message.to_string());
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+fn post_message(
+    client: &Client,
+    settings: &ChatSettings,
+    message: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let body = match settings.format {
+        ChatFormat::Discord => serde_json::json!({ "content": message }),
+        ChatFormat::Slack | ChatFormat::Matrix => serde_json::json!({ "text": message }),
+    };
+
+    let response = client.post(&settings.webhook_url).json(&body).send()?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "Chat webhook rejected the message. {}",
+            describe_error_response(response)
+        )
+        .into());
+    }
+
+    Ok(())
+}
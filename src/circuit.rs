@@ -0,0 +1,139 @@
+use log::{debug, info, warn};
+use reqwest::blocking::Response;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+static FAILURE_THRESHOLD: AtomicU32 = AtomicU32::new(0);
+static COOLDOWN_SECONDS: AtomicU64 = AtomicU64::new(60);
+
+static CIRCUIT: Mutex<Circuit> = Mutex::new(Circuit {
+    consecutive_failures: 0,
+    opened_at: None,
+    probe_started_at: None,
+});
+
+/// Stop calling the API for `cooldown` after `failure_threshold` consecutive failures, 0 to never
+/// stop.
+pub fn configure_circuit_breaker(failure_threshold: u32, cooldown: Duration) {
+    FAILURE_THRESHOLD.store(failure_threshold, Ordering::SeqCst);
+    COOLDOWN_SECONDS.store(cooldown.as_secs(), Ordering::SeqCst);
+}
+
+fn cooldown() -> Duration {
+    Duration::from_secs(COOLDOWN_SECONDS.load(Ordering::SeqCst))
+}
+
+/// Consecutive failures of the API calls of all the threads. Once open, the calls fail right away
+/// until the cooldown is over, then a single call probes the API: the circuit closes if it
+/// succeeds, opens again for another cooldown otherwise.
+struct Circuit {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// The call probing the API after the cooldown. A probe older than the cooldown is assumed lost.
+    probe_started_at: Option<Instant>,
+}
+
+/// Error of the API calls not sent because the circuit is open.
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "API calls paused after consecutive failures")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// Whether API calls are currently failing right away. False while a probe is allowed.
+pub fn is_circuit_open() -> bool {
+    let circuit = CIRCUIT.lock().unwrap();
+
+    match circuit.opened_at {
+        Some(opened_at) => {
+            opened_at.elapsed() < cooldown()
+                || circuit
+                    .probe_started_at
+                    .is_some_and(|probe_started_at| probe_started_at.elapsed() < cooldown())
+        }
+        None => false,
+    }
+}
+
+/// Called before every API call, fails if the circuit is open.
+pub fn check_circuit() -> Result<(), CircuitOpen> {
+    let mut circuit = CIRCUIT.lock().unwrap();
+
+    let Some(opened_at) = circuit.opened_at else {
+        return Ok(());
+    };
+
+    let probing = circuit
+        .probe_started_at
+        .is_some_and(|probe_started_at| probe_started_at.elapsed() < cooldown());
+
+    if opened_at.elapsed() < cooldown() || probing {
+        return Err(CircuitOpen);
+    }
+
+    debug!("Probing the API");
+    circuit.probe_started_at = Some(Instant::now());
+
+    Ok(())
+}
+
+/// Called after every API call. Connection errors, timeouts and server errors are failures.
+pub fn record_api_result(result: &Result<Response, reqwest::Error>) {
+    let failed = match result {
+        Ok(response) => response.status().is_server_error(),
+        Err(error) => error.is_connect() || error.is_timeout(),
+    };
+
+    let mut circuit = CIRCUIT.lock().unwrap();
+
+    if !failed {
+        if circuit.opened_at.is_some() {
+            info!("API reachable again, resuming API calls");
+        }
+
+        *circuit = Circuit {
+            consecutive_failures: 0,
+            opened_at: None,
+            probe_started_at: None,
+        };
+
+        return;
+    }
+
+    circuit.consecutive_failures += 1;
+
+    if circuit.probe_started_at.take().is_some() {
+        debug!(
+            "API probe failed, pausing API calls for another {:.0?}",
+            cooldown()
+        );
+        circuit.opened_at = Some(Instant::now());
+        return;
+    }
+
+    let failure_threshold = FAILURE_THRESHOLD.load(Ordering::SeqCst);
+
+    if circuit.opened_at.is_none()
+        && failure_threshold > 0
+        && circuit.consecutive_failures >= failure_threshold
+    {
+        warn!(
+            "API unreachable after {} consecutive failures, pausing all API calls, probing it every {:.0?}",
+            circuit.consecutive_failures,
+            cooldown()
+        );
+
+        circuit.opened_at = Some(Instant::now());
+    }
+}
@@ -0,0 +1,145 @@
+use flate2::{write::GzEncoder, Compression};
+use reqwest::{
+    blocking::{multipart, Request, RequestBuilder, Response},
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE},
+};
+use std::{
+    io::Write,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Smaller bodies are not worth compressing
+const MIN_COMPRESSED_BODY_SIZE: usize = 1024;
+/// Larger multipart forms are streamed, they are mostly archives which are compressed already
+const MAX_COMPRESSED_FORM_SIZE: u64 = 8_000_000;
+
+/// Encoding of the request bodies sent to the API, see `RequestEncoding`
+static REQUEST_ENCODING: AtomicU8 = AtomicU8::new(RequestEncoding::Identity as u8);
+
+/// Content encodings of the request bodies, by order of preference.
+#[derive(Clone, Copy, PartialEq)]
+enum RequestEncoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl RequestEncoding {
+    fn current() -> Self {
+        match REQUEST_ENCODING.load(Ordering::SeqCst) {
+            2 => RequestEncoding::Zstd,
+            1 => RequestEncoding::Gzip,
+            _ => RequestEncoding::Identity,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RequestEncoding::Identity => "identity",
+            RequestEncoding::Gzip => "gzip",
+            RequestEncoding::Zstd => "zstd",
+        }
+    }
+
+    fn encode(self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            RequestEncoding::Identity => Ok(bytes.to_vec()),
+            RequestEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            // 0 for the default level of zstd
+            RequestEncoding::Zstd => zstd::encode_all(bytes, 0),
+        }
+    }
+}
+
+/// Remember the best encoding the API accepts for the request bodies, from the `Accept-Encoding`
+/// header of its responses (RFC 7694). The responses without the header, eg: from a proxy, keep
+/// the encoding negotiated before. Response bodies are decompressed by reqwest.
+pub fn record_accepted_encodings(response: &Response) {
+    let mut accepted_encodings = response
+        .headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|encoding| encoding.split(';').next().unwrap_or_default().trim().to_string())
+        .peekable();
+
+    if accepted_encodings.peek().is_none() {
+        return;
+    }
+
+    let encoding = accepted_encodings
+        .filter_map(|encoding| match encoding.as_str() {
+            "zstd" => Some(RequestEncoding::Zstd),
+            "gzip" => Some(RequestEncoding::Gzip),
+            _ => None,
+        })
+        .max_by_key(|encoding| *encoding as u8)
+        .unwrap_or(RequestEncoding::Identity);
+
+    REQUEST_ENCODING.store(encoding as u8, Ordering::SeqCst);
+}
+
+/// Compress the JSON body of a request to the API when it accepts it.
+pub fn compress_json_body(request: &mut Request) -> Result<(), Box<dyn std::error::Error>> {
+    let encoding = RequestEncoding::current();
+
+    if encoding == RequestEncoding::Identity || request.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(());
+    }
+
+    let is_json = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+
+    let Some(body) = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .filter(|body| is_json && body.len() >= MIN_COMPRESSED_BODY_SIZE)
+    else {
+        return Ok(());
+    };
+
+    let compressed = encoding.encode(body)?;
+    *request.body_mut() = Some(compressed.into());
+    request
+        .headers_mut()
+        .insert(CONTENT_ENCODING, encoding.name().parse()?);
+
+    Ok(())
+}
+
+/// Set a multipart form of `size` bytes as the body of the request, compressed when the API
+/// accepts it and the form is small enough to be buffered, eg: the pyramid tiles. Returns the
+/// request and the size of its body on the wire.
+pub fn multipart_body(
+    request: RequestBuilder,
+    form: multipart::Form,
+    size: u64,
+) -> Result<(RequestBuilder, u64), Box<dyn std::error::Error>> {
+    let encoding = RequestEncoding::current();
+
+    if encoding == RequestEncoding::Identity || size > MAX_COMPRESSED_FORM_SIZE {
+        return Ok((request.multipart(form), size));
+    }
+
+    let (client, request) = request.multipart(form).build_split();
+    let mut request = request?;
+    let mut wire_size = size;
+
+    if let Some(body) = request.body_mut() {
+        let compressed = encoding.encode(body.buffer()?)?;
+        wire_size = compressed.len() as u64;
+        *body = compressed.into();
+        request
+            .headers_mut()
+            .insert(CONTENT_ENCODING, encoding.name().parse()?);
+    }
+
+    Ok((RequestBuilder::from_parts(client, request), wire_size))
+}
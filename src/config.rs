@@ -0,0 +1,144 @@
+use reqwest::Url;
+use std::{
+    env,
+    fs::{remove_file, write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    auth::{ApiAuth, AuthMode},
+    capabilities::unsupported_job_types,
+    failover::parse_base_urls,
+    heartbeat::send_setup_heartbeat,
+    region::RegionProfile,
+};
+
+/// Environment variables read at startup, printed by `config validate`.
+const ENV_VARIABLES: [&str; 7] = [
+    "MAPANT_API_WORKER_ID",
+    "MAPANT_API_TOKEN",
+    "MAPANT_API_BASE_URL",
+    "MAPANT_API_DOWNLOAD_URLS",
+    "MAPANT_WORK_DIR",
+    "MAPANT_THREADS",
+    "MAPANT_WORKER_TAGS",
+];
+const SECRET_ENV_VARIABLES: [&str; 1] = ["MAPANT_API_TOKEN"];
+
+/// What `config validate` needs from the resolved command line, .env file and environment.
+pub struct ConfigToValidate<'a> {
+    /// Debug output of the parsed flags
+    pub flags: String,
+    /// Values to hide from the printed flags, eg: the Sentry DSN
+    pub secrets: Vec<&'a str>,
+    pub region: &'a RegionProfile,
+    pub auth_mode: AuthMode,
+    /// Directories the worker writes to, with what they are used for
+    pub directories: Vec<(&'static str, PathBuf)>,
+}
+
+/// Print the effective configuration with the secrets redacted, then check the credentials
+/// against the API and that the directories are writable. Fails if any check failed, missing GDAL
+/// tools are only warnings as the worker runs without them.
+pub fn validate_config(config: ConfigToValidate) -> Result<(), Box<dyn std::error::Error>> {
+    let mut flags = config.flags;
+
+    for secret in config.secrets.iter().filter(|secret| !secret.is_empty()) {
+        flags = flags.replace(secret, "<redacted>");
+    }
+
+    println!("Flags: {}", flags);
+    println!("Environment:");
+
+    for name in ENV_VARIABLES {
+        let value = match env::var(name) {
+            Ok(_) if SECRET_ENV_VARIABLES.contains(&name) => "<redacted>".to_string(),
+            Ok(value) => value,
+            Err(_) => "(not set)".to_string(),
+        };
+
+        println!("  {}={}", name, value);
+    }
+
+    println!(
+        "Region profile: {} (EPSG:{}, {}m tiles, base zoom {})",
+        config.region.name, config.region.epsg, config.region.tile_size_meters, config.region.base_zoom_level
+    );
+    println!("Checks:");
+
+    let mut problems = 0;
+    let mut report = |check: &str, result: Result<(), String>| match result {
+        Ok(()) => println!("  ok    {}", check),
+        Err(error) => {
+            problems += 1;
+            println!("  FAIL  {}: {}", check, error);
+        }
+    };
+
+    report("Credentials", check_credentials(config.auth_mode));
+
+    for (usage, directory) in &config.directories {
+        report(
+            &format!("{} {} writable", usage, directory.display()),
+            check_writable_dir(directory),
+        );
+    }
+
+    for (job_type, missing) in unsupported_job_types() {
+        println!(
+            "  warn  Not accepting {} jobs, missing {}",
+            job_type,
+            missing.join(", ")
+        );
+    }
+
+    if problems > 0 {
+        return Err(format!("{} problems found in the configuration", problems).into());
+    }
+
+    println!("Configuration valid");
+
+    Ok(())
+}
+
+fn check_credentials(auth_mode: AuthMode) -> Result<(), String> {
+    let worker_id = env::var("MAPANT_API_WORKER_ID").map_err(|_| "MAPANT_API_WORKER_ID not set")?;
+    let token = env::var("MAPANT_API_TOKEN").map_err(|_| "MAPANT_API_TOKEN not set")?;
+    let base_urls =
+        parse_base_urls(&env::var("MAPANT_API_BASE_URL").unwrap_or_else(|_| "https://mapant.fr".to_string()));
+
+    for base_url in &base_urls {
+        Url::parse(base_url).map_err(|error| format!("Invalid base URL {}: {}", base_url, error))?;
+    }
+
+    let base_url = base_urls.first().ok_or("MAPANT_API_BASE_URL is empty")?;
+
+    send_setup_heartbeat(
+        &ApiAuth::new(worker_id, token, auth_mode),
+        base_url,
+        Path::new("."),
+    )
+    .map_err(|error| error.to_string())
+}
+
+/// Check that files can be created in a directory, or in its closest existing parent if it is
+/// created by the worker later on.
+pub fn check_writable_dir(directory: &Path) -> Result<(), String> {
+    let existing = directory
+        .ancestors()
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.exists())
+        .ok_or("No existing parent directory")?;
+
+    let probe_path = existing.join(".mapant-write-probe");
+
+    write(&probe_path, "")
+        .and_then(|_| remove_file(&probe_path))
+        .map_err(|error| format!("Can not write in {}: {}", existing.display(), error))
+}
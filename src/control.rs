@@ -0,0 +1,330 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    cache::CACHE_DIRS, scratch::configured_scratch_dir, state::WorkerState, stats::format_duration,
+    utils::directory_size,
+};
+
+/// Time a control client has to send its command and read the answer
+#[cfg(unix)]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State of a running worker, sent on its control socket.
+#[derive(Serialize, Deserialize)]
+struct WorkerStatus {
+    /// One line summary of the current jobs, see `WorkerState::summary`
+    summary: String,
+    uptime_seconds: u64,
+    threads: Vec<ThreadStatus>,
+    /// Size in bytes of every cache directory
+    cache: Vec<(String, u64)>,
+    last_api_contact_seconds_ago: Option<u64>,
+    api_unreachable_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ThreadStatus {
+    name: String,
+    current_job: Option<String>,
+    stage: Option<String>,
+    job_elapsed_seconds: Option<u64>,
+    jobs_done: u64,
+}
+
+fn worker_status(state: &WorkerState, work_dir: &Path) -> WorkerStatus {
+    WorkerStatus {
+        summary: state.summary(),
+        uptime_seconds: state.uptime().as_secs(),
+        threads: state
+            .thread_stats()
+            .into_iter()
+            .map(|thread| ThreadStatus {
+                name: thread.name,
+                current_job: thread.current_job,
+                stage: thread.stage,
+                job_elapsed_seconds: thread.job_elapsed.map(|elapsed| elapsed.as_secs()),
+                jobs_done: thread.jobs_done,
+            })
+            .collect(),
+        cache: CACHE_DIRS
+            .iter()
+            .map(|cache_dir| {
+                let scratch_size = configured_scratch_dir()
+                    .map(|scratch_dir| directory_size(&scratch_dir.join(cache_dir)))
+                    .unwrap_or(0);
+
+                (
+                    cache_dir.to_string(),
+                    directory_size(&work_dir.join(cache_dir)) + scratch_size,
+                )
+            })
+            .collect(),
+        last_api_contact_seconds_ago: state.last_api_contact().map(|elapsed| elapsed.as_secs()),
+        api_unreachable_seconds: state.api_unreachable_for().map(|elapsed| elapsed.as_secs()),
+    }
+}
+
+/// Run a control command, returning its JSON answer.
+fn run_command(
+    command: &str,
+    state: &WorkerState,
+    work_dir: &Path,
+    shutdown_requested: &AtomicBool,
+) -> String {
+    let mut words = command.split_whitespace();
+
+    let result: Result<(), String> = match (words.next(), words.next()) {
+        (Some("status"), None) => {
+            return serde_json::to_string(&worker_status(state, work_dir))
+                .unwrap_or_else(|error| json!({ "error": error.to_string() }).to_string());
+        }
+        (Some("pause"), None) => {
+            info!("Paused through the control socket, in-flight jobs keep running");
+            state.set_paused(true);
+            Ok(())
+        }
+        (Some("resume"), None) => {
+            info!("Resumed through the control socket");
+            state.set_paused(false);
+            Ok(())
+        }
+        (Some("drain"), None) => {
+            // Same as SIGTERM: in-flight jobs get the drain timeout to finish, then the worker exits
+            shutdown_requested.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+        (Some("cancel"), Some(_)) => {
+            // Job descriptions contain spaces, eg: "Render 1000_6000"
+            let job = command.trim_start_matches("cancel").trim();
+
+            if state.cancel_job(job) {
+                info!("Cancelling job {} through the control socket", job);
+                Ok(())
+            } else {
+                Err(format!("No running job {}", job))
+            }
+        }
+        (Some("set-threads"), Some(threads)) => match threads.parse::<usize>() {
+            Ok(threads) if threads >= 1 && threads <= state.thread_count() => {
+                info!("Running {} threads, set through the control socket", threads);
+                state.set_active_threads(threads);
+                Ok(())
+            }
+            _ => Err(format!(
+                "Invalid number of threads {}, expected 1 to {}",
+                threads,
+                state.thread_count()
+            )),
+        },
+        _ => Err(format!(
+            "Unknown command \"{}\", expected status, pause, resume, drain, cancel JOB or set-threads N",
+            command
+        )),
+    };
+
+    match result {
+        Ok(()) => json!({ "ok": true }).to_string(),
+        Err(error) => json!({ "error": error }).to_string(),
+    }
+}
+
+/// Answer the commands sent on a local Unix socket, one line per connection, for the `status` and
+/// `control` subcommands and the operators' scripts:
+/// - `status`: the `WorkerStatus`
+/// - `pause` / `resume`: stop / start fetching new jobs, in-flight jobs keep running
+/// - `drain`: finish the in-flight jobs and exit, as on SIGTERM
+/// - `set-threads N`: change the number of active worker threads
+/// - `cancel JOB`: stop a running job, eg: `cancel Render 1000_6000`
+///
+/// Answers are JSON, `{"ok":true}` or `{"error":"..."}` for the commands without output.
+#[cfg(unix)]
+pub fn spawn_control_socket(
+    socket_path: &Path,
+    state: Arc<WorkerState>,
+    work_dir: std::path::PathBuf,
+    shutdown_requested: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use log::error;
+    use std::{
+        io::{BufRead, BufReader, ErrorKind, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        thread,
+    };
+
+    if socket_path.exists() {
+        match UnixStream::connect(socket_path) {
+            Ok(_) => {
+                return Err(format!(
+                    "Another worker listens on the control socket {}",
+                    socket_path.display()
+                )
+                .into())
+            }
+            // Left behind by a previous instance that did not exit cleanly
+            Err(error) if error.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(socket_path)?,
+            Err(error) => {
+                return Err(format!(
+                    "Can't use the control socket {}: {}",
+                    socket_path.display(),
+                    error
+                )
+                .into())
+            }
+        }
+    }
+
+    let listener = UnixListener::bind(socket_path)?;
+    info!("Control socket listening on {}", socket_path.display());
+
+    thread::Builder::new()
+        .name("control-socket".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        error!("Failed to accept a control connection: {}", error);
+                        continue;
+                    }
+                };
+
+                // A client that never sends its command must not block the others
+                if let Err(error) = stream
+                    .set_read_timeout(Some(CLIENT_TIMEOUT))
+                    .and_then(|()| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+                {
+                    error!("Failed to set the timeouts of a control connection: {}", error);
+                    continue;
+                }
+
+                let mut command = String::new();
+
+                match BufReader::new(&stream).read_line(&mut command) {
+                    // eg: another worker checking whether the socket is in use
+                    Ok(0) => continue,
+                    Ok(_) => {}
+                    Err(error) => {
+                        error!("Failed to read the control command: {}", error);
+                        continue;
+                    }
+                }
+
+                let response = run_command(command.trim(), &state, &work_dir, &shutdown_requested);
+
+                if let Err(error) = writeln!(stream, "{}", response) {
+                    error!("Failed to answer the control command: {}", error);
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn_control_socket(
+    _socket_path: &Path,
+    _state: Arc<WorkerState>,
+    _work_dir: std::path::PathBuf,
+    _shutdown_requested: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+/// Send a command to the worker listening on `socket_path` and return its JSON answer.
+#[cfg(unix)]
+pub fn send_control_command(socket_path: &Path, command: &str) -> Result<String, Box<dyn std::error::Error>> {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let mut stream = UnixStream::connect(socket_path)
+        .map_err(|error| format!("No worker listening on {}: {}", socket_path.display(), error))?;
+
+    writeln!(stream, "{}", command)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    Ok(response)
+}
+
+#[cfg(not(unix))]
+pub fn send_control_command(
+    _socket_path: &Path,
+    _command: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    Err("The control socket is only supported on Unix".into())
+}
+
+/// Print the status of the worker listening on `socket_path`.
+pub fn print_status(socket_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let response = send_control_command(socket_path, "status")?;
+    let status: WorkerStatus = serde_json::from_str(&response)?;
+
+    println!("{}", status.summary);
+    println!(
+        "Uptime: {}",
+        format_duration(Duration::from_secs(status.uptime_seconds))
+    );
+
+    match (
+        status.last_api_contact_seconds_ago,
+        status.api_unreachable_seconds,
+    ) {
+        (_, Some(unreachable)) => println!("API unreachable for {}s", unreachable),
+        (Some(last_contact), None) => println!("Last API contact: {}s ago", last_contact),
+        (None, None) => println!("Last API contact: never"),
+    }
+
+    println!();
+    println!(
+        "{:<14}{:<28}{:<24}{:>10}{:>11}",
+        "Thread", "Job", "Stage", "Elapsed", "Jobs done"
+    );
+
+    for thread in status.threads {
+        println!(
+            "{:<14}{:<28}{:<24}{:>10}{:>11}",
+            thread.name,
+            thread.current_job.unwrap_or_else(|| "idle".to_string()),
+            thread.stage.unwrap_or_default(),
+            thread
+                .job_elapsed_seconds
+                .map(|elapsed| format!("{}s", elapsed))
+                .unwrap_or_default(),
+            thread.jobs_done
+        );
+    }
+
+    println!();
+
+    for (cache_dir, size) in status.cache {
+        println!("Cache {:<14}{:>8} MB", cache_dir, size / 1_000_000);
+    }
+
+    Ok(())
+}
+
+/// Send a command to the running worker and print its answer, failing if it was rejected.
+pub fn print_command_result(socket_path: &Path, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let response: serde_json::Value = serde_json::from_str(&send_control_command(socket_path, command)?)?;
+
+    if let Some(error) = response.get("error").and_then(|error| error.as_str()) {
+        return Err(error.into());
+    }
+
+    println!("{}", response);
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use log::{error, info, warn};
+use std::{
+    fs::{read_dir, remove_dir_all},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+use sysinfo::Disks;
+
+use crate::{
+    scratch::configured_scratch_dir, state::WorkerState, tile_lock::TileLock, utils::directory_size,
+};
+
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Free space in bytes of the disk holding `path`, None if it could not be found.
+pub fn available_space(path: &Path) -> Option<u64> {
+    disk_usage(path).map(|(available_space, _)| available_space)
+}
+
+/// Free and total space in bytes of the disk holding `path`, None if it could not be found.
+pub fn disk_usage(path: &Path) -> Option<(u64, u64)> {
+    let path = path.canonicalize().ok()?;
+    let disks = Disks::new_with_refreshed_list();
+
+    disks
+        .list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| (disk.available_space(), disk.total_space()))
+}
+
+/// Watch the free space of the work directory. Below `low_water_mark` bytes, the worker stops
+/// accepting new jobs and evicts the LiDAR step cache. It resumes once the free space is back
+/// above `high_water_mark` bytes. In-flight jobs are not interrupted. A `low_water_mark` of 0
+/// disables this check.
+///
+/// The scratch directory, when set, must have as much free space, and use less than
+/// `scratch_budget` bytes.
+pub fn spawn_disk_monitor(
+    state: Arc<WorkerState>,
+    work_dir: PathBuf,
+    low_water_mark: u64,
+    high_water_mark: u64,
+    scratch_budget: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    thread::Builder::new()
+        .name("disk-monitor".to_string())
+        .spawn(move || loop {
+            let mut free_space = match available_space(&work_dir) {
+                Some(free_space) => free_space,
+                None => {
+                    error!("Could not find the disk of {}", work_dir.display());
+                    thread::sleep(DISK_CHECK_INTERVAL);
+                    continue;
+                }
+            };
+
+            let scratch_free_space = configured_scratch_dir().and_then(available_space);
+            let scratch_usage = configured_scratch_dir().map(directory_size).unwrap_or(0);
+            let scratch_over_budget = scratch_budget.is_some_and(|budget| scratch_usage > budget);
+
+            let low_space = low_water_mark > 0
+                && (free_space < low_water_mark
+                    || scratch_free_space.is_some_and(|free_space| free_space < low_water_mark));
+
+            if low_space || scratch_over_budget {
+                if !state.is_disk_paused() {
+                    if scratch_over_budget {
+                        warn!(
+                            "Scratch directory uses {} MB, above its budget, pausing",
+                            scratch_usage / 1_000_000
+                        );
+                    } else {
+                        warn!(
+                            "Only {} MB free on disk, pausing until {} MB are available",
+                            free_space.min(scratch_free_space.unwrap_or(free_space)) / 1_000_000,
+                            high_water_mark / 1_000_000
+                        );
+                    }
+
+                    state.set_disk_paused(true);
+                }
+
+                if free_space < low_water_mark {
+                    evict_lidar_step_cache(
+                        &work_dir.join("lidar-step"),
+                        &state,
+                        high_water_mark.saturating_sub(free_space),
+                    );
+                    free_space = available_space(&work_dir).unwrap_or(free_space);
+                }
+            }
+
+            let enough_space = low_water_mark == 0
+                || (free_space > high_water_mark
+                    && scratch_free_space.is_none_or(|free_space| free_space > high_water_mark));
+
+            if enough_space && !scratch_over_budget && state.is_disk_paused() {
+                info!(
+                    "{} MB free on disk, accepting new jobs again",
+                    free_space / 1_000_000
+                );
+
+                state.set_disk_paused(false);
+            }
+
+            thread::sleep(DISK_CHECK_INTERVAL);
+        })?;
+
+    Ok(())
+}
+
+/// Remove the least recently modified tiles of the LiDAR step cache until `bytes_to_free` are freed.
+/// Tiles locked by a thread or another worker process, or referenced by an in-flight job, are kept.
+fn evict_lidar_step_cache(lidar_step_path: &Path, state: &WorkerState, bytes_to_free: u64) {
+    let entries = match read_dir(lidar_step_path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    let in_flight_jobs = state.in_flight_jobs();
+
+    let mut tiles: Vec<(SystemTime, String, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter(|entry| !entry.file_name().to_string_lossy().contains(".partial-"))
+        .filter_map(|entry| {
+            let tile_id = entry.file_name().to_string_lossy().to_string();
+            let modified = entry.metadata().and_then(|metadata| metadata.modified()).ok()?;
+            Some((modified, tile_id, entry.path()))
+        })
+        .filter(|(_, tile_id, _)| {
+            !in_flight_jobs
+                .iter()
+                .any(|job_payload| job_payload.contains(tile_id.as_str()))
+        })
+        .collect();
+
+    tiles.sort();
+
+    let mut freed: u64 = 0;
+
+    for (_, tile_id, path) in tiles {
+        if freed >= bytes_to_free {
+            break;
+        }
+
+        let _lock = match TileLock::try_exclusive(lidar_step_path, &tile_id) {
+            Ok(Some(lock)) => lock,
+            _ => continue,
+        };
+
+        let size = directory_size(&path);
+
+        match remove_dir_all(&path) {
+            Ok(_) => {
+                info!("Evicted LiDAR step files of tile {} from the cache", &tile_id);
+                freed += size;
+            }
+            Err(error) => error!("Failed to evict tile {} from the cache: {}", &tile_id, error),
+        }
+    }
+
+    if freed > 0 {
+        info!("Freed {} MB from the LiDAR step cache", freed / 1_000_000);
+    }
+}
@@ -0,0 +1,50 @@
+use chrono::{SecondsFormat, Utc};
+use log::warn;
+use serde_json::{json, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    thread,
+};
+
+use crate::state::current_correlation_id;
+
+/// File the job events are appended to, one JSON object per line.
+static JOB_EVENTS: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Append the lifecycle events of the jobs to `path`, see `record_job_event`. Disabled unless
+/// called.
+pub fn enable_job_events(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = JOB_EVENTS.set(Mutex::new(file));
+
+    Ok(())
+}
+
+/// Append an event of `job` (eg: "Render 1000_6000", as in the heartbeats), like "accepted",
+/// "stage", "completed", "failed" or "cancelled", with the `details` fields, eg:
+/// `{"time":"2024-05-01T12:00:00.000Z","event":"stage","job":"Render 1000_6000","stage":"rendering",...}`
+pub fn record_job_event(job: &str, event: &str, details: Value) {
+    let Some(file) = JOB_EVENTS.get() else {
+        return;
+    };
+
+    let mut line = json!({
+        "time": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "event": event,
+        "job": job,
+        "thread": thread::current().name(),
+        "correlation_id": current_correlation_id(),
+    });
+
+    if let (Some(line), Value::Object(details)) = (line.as_object_mut(), details) {
+        line.extend(details);
+    }
+
+    // A single write per line, the lines of the threads are not interleaved
+    if let Err(error) = file.lock().unwrap().write_all(format!("{}\n", line).as_bytes()) {
+        warn!("Failed to record the job event: {}", error);
+    }
+}
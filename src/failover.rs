@@ -0,0 +1,131 @@
+use log::warn;
+use reqwest::{blocking::Request, Method, Url};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    OnceLock,
+};
+
+/// Requests failing over independently from each other, artifact mirrors can be up while the API
+/// is down and the other way round.
+#[derive(Clone, Copy)]
+pub enum RequestClass {
+    /// Job polling, results uploads and other calls to the API
+    Api,
+    /// Artifacts and tiles downloads
+    Download,
+}
+
+impl RequestClass {
+    pub fn of(method: &Method) -> Self {
+        if method == Method::GET {
+            RequestClass::Download
+        } else {
+            RequestClass::Api
+        }
+    }
+}
+
+/// Base URLs of a request class, primary first, and the index of the one that last worked.
+struct Endpoints {
+    base_urls: Vec<String>,
+    healthy: AtomicUsize,
+}
+
+static API_ENDPOINTS: OnceLock<Endpoints> = OnceLock::new();
+static DOWNLOAD_ENDPOINTS: OnceLock<Endpoints> = OnceLock::new();
+
+/// Parse a comma separated list of base URLs, eg: "https://mapant.fr,https://mirror.mapant.fr".
+pub fn parse_base_urls(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|base_url| base_url.trim().trim_end_matches('/').to_string())
+        .filter(|base_url| !base_url.is_empty())
+        .collect()
+}
+
+/// Requests to one of these base URLs are sent to the healthy base URL of their class instead.
+pub fn set_base_urls(api_base_urls: Vec<String>, download_base_urls: Vec<String>) {
+    let _ = API_ENDPOINTS.set(Endpoints {
+        base_urls: api_base_urls,
+        healthy: AtomicUsize::new(0),
+    });
+
+    let _ = DOWNLOAD_ENDPOINTS.set(Endpoints {
+        base_urls: download_base_urls,
+        healthy: AtomicUsize::new(0),
+    });
+}
+
+fn endpoints(class: RequestClass) -> Option<&'static Endpoints> {
+    match class {
+        RequestClass::Api => API_ENDPOINTS.get(),
+        RequestClass::Download => DOWNLOAD_ENDPOINTS.get(),
+    }
+}
+
+/// Number of base URLs a request of `class` can be sent to.
+pub fn endpoint_count(class: RequestClass) -> usize {
+    endpoints(class).map_or(1, |endpoints| endpoints.base_urls.len().max(1))
+}
+
+/// Point the request to the healthy base URL of its class, returning its index. None if the
+/// request is not for a known base URL, eg: a presigned storage URL.
+pub fn use_healthy_endpoint(
+    request: &mut Request,
+    class: RequestClass,
+) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+    let Some(endpoints) = endpoints(class) else {
+        return Ok(None);
+    };
+
+    let url = request.url().to_string();
+
+    let Some(base_url) = [API_ENDPOINTS.get(), DOWNLOAD_ENDPOINTS.get()]
+        .into_iter()
+        .flatten()
+        .flat_map(|endpoints| endpoints.base_urls.iter())
+        .find(|base_url| url.starts_with(base_url.as_str()))
+    else {
+        return Ok(None);
+    };
+
+    let healthy = endpoints.healthy.load(Ordering::SeqCst);
+    let healthy_base_url = &endpoints.base_urls[healthy];
+
+    if healthy_base_url != base_url {
+        *request.url_mut() = Url::parse(&format!("{}{}", healthy_base_url, &url[base_url.len()..]))?;
+    }
+
+    Ok(Some(healthy))
+}
+
+/// Whether `url` is on one of the API base URLs, eg: not a download mirror.
+pub fn is_api_url(url: &Url) -> bool {
+    API_ENDPOINTS.get().is_some_and(|endpoints| {
+        endpoints
+            .base_urls
+            .iter()
+            .any(|base_url| url.as_str().starts_with(base_url.as_str()))
+    })
+}
+
+/// Switch the requests of `class` to the next base URL, unless another thread already did.
+pub fn mark_unhealthy(class: RequestClass, index: usize) {
+    let Some(endpoints) = endpoints(class) else {
+        return;
+    };
+
+    let next = (index + 1) % endpoints.base_urls.len();
+
+    let switched = endpoints
+        .healthy
+        .compare_exchange(index, next, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok();
+
+    if switched && next != index {
+        warn!(
+            "{} unreachable, failing over to {}",
+            endpoints.base_urls[index], endpoints.base_urls[next]
+        );
+    }
+}
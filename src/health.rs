@@ -0,0 +1,105 @@
+use log::{error, info};
+use std::{net::SocketAddr, sync::Arc, thread, time::Duration};
+use tiny_http::{Header, Response, Server};
+
+use crate::{
+    history::JobHistory,
+    state::WorkerState,
+    web_ui::{spawn_status_refresher, WEB_UI_PAGE},
+};
+
+/// Serve the probes used by Kubernetes (or any supervisor), and a page to check the worker from
+/// a browser:
+/// - `/livez`: 200 unless a job has been running for more than `max_job_duration`
+/// - `/readyz`: 200 unless the worker is draining or paused and does not accept new jobs
+/// - `/stats`: jobs done and busy time of every worker thread, one per line
+/// - `/`: live status, recent jobs, throughput and cache usage, from `/status.json`, only with
+///   `web_ui` as they show the jobs and their errors to anyone reaching the port
+pub fn spawn_health_server(
+    address: SocketAddr,
+    state: Arc<WorkerState>,
+    history: Option<Arc<JobHistory>>,
+    max_job_duration: Duration,
+    web_ui: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = Server::http(address).map_err(|error| error.to_string())?;
+
+    info!("Health server listening on {}", address);
+
+    let cached_status = if web_ui {
+        Some(spawn_status_refresher(state.clone(), history)?)
+    } else {
+        None
+    };
+
+    thread::Builder::new().name("health".to_string()).spawn(move || {
+        for request in server.incoming_requests() {
+            let mut content_type = "text/plain; charset=utf-8";
+
+            let (status, body) = match request.url() {
+                "/livez" => {
+                    let stalled_threads = state.stalled_threads(max_job_duration);
+
+                    if stalled_threads.is_empty() {
+                        (200, "ok".to_string())
+                    } else {
+                        (503, format!("stalled threads: {:?}", stalled_threads))
+                    }
+                }
+                "/readyz" => {
+                    if state.is_draining() {
+                        (503, "draining".to_string())
+                    } else if state.is_paused() {
+                        (503, "paused".to_string())
+                    } else if state.is_disk_paused() {
+                        (503, "paused: low disk space".to_string())
+                    } else if state.is_quota_paused() {
+                        (503, "paused: quota".to_string())
+                    } else {
+                        (200, "ok".to_string())
+                    }
+                }
+                "/stats" => {
+                    let lines: Vec<String> = state
+                        .thread_stats()
+                        .iter()
+                        .map(|stats| {
+                            format!(
+                                "{} jobs_done={} busy_seconds={} current_job={}",
+                                stats.name,
+                                stats.jobs_done,
+                                stats.busy_time.as_secs(),
+                                stats.current_job.as_deref().unwrap_or("none")
+                            )
+                        })
+                        .collect();
+
+                    (200, lines.join("\n"))
+                }
+                "/" if cached_status.is_some() => {
+                    content_type = "text/html; charset=utf-8";
+                    (200, WEB_UI_PAGE.to_string())
+                }
+                "/status.json" => match cached_status.as_ref().map(|status| status.get()) {
+                    Some(Some(status)) => {
+                        content_type = "application/json";
+                        (200, status)
+                    }
+                    Some(None) => (503, "status not computed yet".to_string()),
+                    None => (404, "not found".to_string()),
+                },
+                _ => (404, "not found".to_string()),
+            };
+
+            let response = Response::from_string(body)
+                .with_status_code(status)
+                .with_header(Header::from_bytes("Content-Type", content_type).unwrap());
+
+            if let Err(error) = request.respond(response) {
+                error!("Failed to respond to health request: {}", error);
+            }
+        }
+    })?;
+
+    Ok(())
+}
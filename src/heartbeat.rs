@@ -0,0 +1,223 @@
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use sysinfo::{Networks, System};
+
+use crate::{
+    auth::ApiAuth,
+    bbox::{job_bbox, Bbox},
+    capabilities::{capabilities, Capabilities},
+    disk::disk_usage,
+    http::http_client,
+    response::describe_error_response,
+    state::WorkerState,
+    tags::{worker_tags, WorkerTags},
+    worker::{supported_job_types, PROTOCOL_VERSION},
+};
+
+/// Resources of the machine, so that the server dashboard can spot the ones that are thrashing.
+#[derive(Serialize)]
+struct SystemTelemetry {
+    cpu_count: usize,
+    /// Average usage of all the CPUs since the previous heartbeat, in percent
+    cpu_usage_percent: f32,
+    load_average_one_minute: f64,
+    memory_used_bytes: u64,
+    memory_total_bytes: u64,
+    /// Free and total space of the disk holding the work directory
+    disk_free_bytes: Option<u64>,
+    disk_total_bytes: Option<u64>,
+    /// Average throughput of all the network interfaces since the previous heartbeat
+    network_received_bytes_per_second: u64,
+    network_transmitted_bytes_per_second: u64,
+}
+
+#[derive(Serialize)]
+struct Heartbeat {
+    protocol_version: u32,
+    /// "running", "paused: manual" (by an operator), "paused" (low disk space), "paused: quota"
+    /// (monthly bandwidth budget reached), "draining" or "setup" (sent once by `init` and
+    /// `config validate`)
+    status: &'static str,
+    current_jobs: Vec<String>,
+    system: SystemTelemetry,
+    /// GDAL tools and drivers of the machine
+    capabilities: &'static Capabilities,
+    /// Job types the capabilities allow, as sent when fetching jobs
+    supported_jobs: Vec<&'static str>,
+    /// Tags declared by the operator, as sent when fetching jobs
+    tags: &'static WorkerTags,
+    /// Area the worker accepts jobs in, see `--bbox`
+    bbox: Option<Bbox>,
+}
+
+/// Answer of the API to a heartbeat.
+#[derive(Deserialize, Default)]
+struct HeartbeatResponse {
+    /// Jobs to stop, as described in `current_jobs`
+    #[serde(default)]
+    cancelled_jobs: Vec<String>,
+}
+
+struct TelemetrySampler {
+    system: System,
+    networks: Networks,
+    sampled_at: Instant,
+}
+
+impl TelemetrySampler {
+    fn new() -> Self {
+        let mut system = System::new();
+        system.refresh_cpu_usage();
+
+        TelemetrySampler {
+            system,
+            networks: Networks::new_with_refreshed_list(),
+            sampled_at: Instant::now(),
+        }
+    }
+
+    fn sample(&mut self, work_dir: &Path) -> SystemTelemetry {
+        self.system.refresh_cpu_usage();
+        self.system.refresh_memory();
+        self.networks.refresh(true);
+
+        let elapsed = self.sampled_at.elapsed().as_secs_f64().max(1.0);
+        self.sampled_at = Instant::now();
+
+        let (received, transmitted) =
+            self.networks
+                .values()
+                .fold((0, 0), |(received, transmitted), network| {
+                    (received + network.received(), transmitted + network.transmitted())
+                });
+
+        let disk_usage = disk_usage(work_dir);
+
+        SystemTelemetry {
+            cpu_count: self.system.cpus().len(),
+            cpu_usage_percent: self.system.global_cpu_usage(),
+            load_average_one_minute: System::load_average().one,
+            memory_used_bytes: self.system.used_memory(),
+            memory_total_bytes: self.system.total_memory(),
+            disk_free_bytes: disk_usage.map(|(free, _)| free),
+            disk_total_bytes: disk_usage.map(|(_, total)| total),
+            network_received_bytes_per_second: (received as f64 / elapsed) as u64,
+            network_transmitted_bytes_per_second: (transmitted as f64 / elapsed) as u64,
+        }
+    }
+}
+
+/// Send a single heartbeat with the "setup" status, to check the credentials of the worker.
+pub fn send_setup_heartbeat(
+    auth: &ApiAuth,
+    base_url: &str,
+    work_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let heartbeat = Heartbeat {
+        protocol_version: PROTOCOL_VERSION,
+        status: "setup",
+        current_jobs: vec![],
+        system: TelemetrySampler::new().sample(work_dir),
+        capabilities: capabilities(),
+        supported_jobs: supported_job_types(),
+        tags: worker_tags(),
+        bbox: job_bbox(),
+    };
+
+    let url = format!("{}/api/map-generation/heartbeat", base_url);
+    let response = auth.send(
+        http_client()
+            .post(&url)
+            .header("Origin", base_url)
+            .json(&heartbeat),
+    )?;
+
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            Err("The API rejected the worker id or the token".into())
+        }
+        _ => Err(format!(
+            "Unexpected answer from the API. {}",
+            describe_error_response(response)
+        )
+        .into()),
+    }
+}
+
+/// Periodically send the status of the worker and the resources of the machine to the API.
+pub fn spawn_heartbeat(
+    state: Arc<WorkerState>,
+    auth: ApiAuth,
+    base_url: String,
+    work_dir: PathBuf,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    thread::Builder::new()
+        .name("heartbeat".to_string())
+        .spawn(move || {
+            let client = http_client();
+            let mut sampler = TelemetrySampler::new();
+            let url = format!("{}/api/map-generation/heartbeat", base_url);
+
+            loop {
+                thread::sleep(interval);
+
+                let status = if state.is_draining() {
+                    "draining"
+                } else if state.is_paused() {
+                    "paused: manual"
+                } else if state.is_disk_paused() {
+                    "paused"
+                } else if state.is_quota_paused() {
+                    "paused: quota"
+                } else {
+                    "running"
+                };
+
+                let heartbeat = Heartbeat {
+                    protocol_version: PROTOCOL_VERSION,
+                    status,
+                    current_jobs: state
+                        .thread_stats()
+                        .into_iter()
+                        .filter_map(|thread| thread.current_job)
+                        .collect(),
+                    system: sampler.sample(&work_dir),
+                    capabilities: capabilities(),
+                    supported_jobs: supported_job_types(),
+                    tags: worker_tags(),
+                    bbox: job_bbox(),
+                };
+
+                match auth.send(client.post(&url).header("Origin", &base_url).json(&heartbeat)) {
+                    Ok(response) if response.status().is_success() => {
+                        debug!("Heartbeat sent");
+
+                        // Older APIs answer without a body
+                        let response: HeartbeatResponse = response.json().unwrap_or_default();
+
+                        for job in response.cancelled_jobs {
+                            if state.cancel_job(&job) {
+                                info!("Cancelling job {} at the request of the server", job);
+                            }
+                        }
+                    }
+                    Ok(response) => warn!(
+                        "Heartbeat rejected by the API. {}",
+                        describe_error_response(response)
+                    ),
+                    Err(error) => warn!("Failed to send heartbeat: {}", error),
+                }
+            }
+        })?;
+
+    Ok(())
+}
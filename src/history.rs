@@ -0,0 +1,192 @@
+use chrono::{Local, TimeZone};
+use rusqlite::{params, params_from_iter, Connection};
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A job processed by this worker, as stored in the history database.
+pub struct JobRecord {
+    pub job_type: String,
+    /// Tile id for the Lidar, Render, RestyleRender and Validate jobs, `{z}/{x}/{y}` for the
+    /// Pyramid jobs, area id for the Mosaic and VectorPyramid jobs, tile and area ids for the
+    /// Cleanup jobs
+    pub tile: String,
+    pub thread: String,
+    /// Unix timestamps in seconds
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+}
+
+/// Filters of the `history` subcommand.
+pub struct HistoryQuery {
+    pub tile: Option<String>,
+    pub since_hours: Option<u64>,
+    pub failed_only: bool,
+    pub limit: u32,
+}
+
+/// Local SQLite database recording every job processed by this worker.
+pub struct JobHistory {
+    connection: Mutex<Connection>,
+}
+
+impl JobHistory {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let connection = Connection::open(path)?;
+
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                id INTEGER PRIMARY KEY,
+                job_type TEXT NOT NULL,
+                tile TEXT NOT NULL,
+                thread TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                ended_at INTEGER NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                outcome TEXT NOT NULL,
+                error TEXT,
+                bytes_downloaded INTEGER NOT NULL,
+                bytes_uploaded INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS jobs_tile ON jobs (tile);
+            CREATE INDEX IF NOT EXISTS jobs_started_at ON jobs (started_at);",
+        )?;
+
+        Ok(JobHistory {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn record(&self, record: &JobRecord) -> Result<(), Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().unwrap();
+
+        connection.execute(
+            "INSERT INTO jobs (job_type, tile, thread, started_at, ended_at, duration_ms, outcome, error,
+                bytes_downloaded, bytes_uploaded)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                record.job_type,
+                record.tile,
+                record.thread,
+                record.started_at as i64,
+                record.ended_at as i64,
+                record.duration_ms as i64,
+                if record.error.is_none() {
+                    "success"
+                } else {
+                    "failure"
+                },
+                record.error,
+                record.bytes_downloaded as i64,
+                record.bytes_uploaded as i64,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Most recent jobs matching the query, newest first.
+    pub fn query(&self, query: &HistoryQuery) -> Result<Vec<JobRecord>, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut conditions: Vec<&str> = vec![];
+        let mut values: Vec<String> = vec![];
+
+        if let Some(tile) = &query.tile {
+            conditions.push("tile = ?");
+            values.push(tile.clone());
+        }
+
+        if let Some(since_hours) = query.since_hours {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            conditions.push("started_at >= CAST(? AS INTEGER)");
+            values.push(now.saturating_sub(since_hours * 3600).to_string());
+        }
+
+        if query.failed_only {
+            conditions.push("outcome = 'failure'");
+        }
+
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        let mut statement = connection.prepare(&format!(
+            "SELECT job_type, tile, thread, started_at, ended_at, duration_ms, error, bytes_downloaded,
+                bytes_uploaded
+            FROM jobs {} ORDER BY started_at DESC, id DESC LIMIT {}",
+            where_clause, query.limit
+        ))?;
+
+        let records = statement
+            .query_map(params_from_iter(values.iter()), |row| {
+                Ok(JobRecord {
+                    job_type: row.get(0)?,
+                    tile: row.get(1)?,
+                    thread: row.get(2)?,
+                    started_at: row.get::<_, i64>(3)? as u64,
+                    ended_at: row.get::<_, i64>(4)? as u64,
+                    duration_ms: row.get::<_, i64>(5)? as u64,
+                    error: row.get(6)?,
+                    bytes_downloaded: row.get::<_, i64>(7)? as u64,
+                    bytes_uploaded: row.get::<_, i64>(8)? as u64,
+                })
+            })?
+            .collect::<Result<Vec<JobRecord>, rusqlite::Error>>()?;
+
+        Ok(records)
+    }
+}
+
+/// Print the jobs matching the query, for the `history` subcommand.
+pub fn print_history(path: &Path, query: &HistoryQuery) -> Result<(), Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Err(format!("No job history found at {}", path.display()).into());
+    }
+
+    let history = JobHistory::open(path)?;
+    let records = history.query(query)?;
+
+    if records.is_empty() {
+        println!("No job found");
+        return Ok(());
+    }
+
+    println!(
+        "{:<20} {:<8} {:<16} {:<12} {:>10} {:>10} {:>10}  Outcome",
+        "Started at", "Type", "Tile", "Thread", "Duration", "Down (MB)", "Up (MB)"
+    );
+
+    for record in records {
+        let started_at = Local
+            .timestamp_opt(record.started_at as i64, 0)
+            .single()
+            .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+
+        println!(
+            "{:<20} {:<8} {:<16} {:<12} {:>9.1}s {:>10.1} {:>10.1}  {}",
+            started_at,
+            record.job_type,
+            record.tile,
+            record.thread,
+            record.duration_ms as f64 / 1000.0,
+            record.bytes_downloaded as f64 / 1_000_000.0,
+            record.bytes_uploaded as f64 / 1_000_000.0,
+            match &record.error {
+                Some(error) => format!("failure: {}", error),
+                None => "success".to_string(),
+            }
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use clap::ValueEnum;
+use hickory_resolver::{
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
+use reqwest::{
+    blocking::{Client, ClientBuilder},
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::{HeaderMap, HeaderValue},
+};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{Arc, OnceLock},
+};
+
+use crate::toolchain::CASSINI_VERSION;
+
+/// Address family used to connect to the servers.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum IpVersion {
+    /// Every address the resolver returns
+    #[default]
+    Any,
+    /// Only IPv4 addresses, eg: with a broken IPv6 connectivity making every connection hang
+    Ipv4,
+    /// Only IPv6 addresses
+    Ipv6,
+}
+
+impl IpVersion {
+    fn accepts(&self, ip: IpAddr) -> bool {
+        match self {
+            IpVersion::Any => true,
+            IpVersion::Ipv4 => ip.is_ipv4(),
+            IpVersion::Ipv6 => ip.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for IpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpVersion::Any => write!(f, "IP"),
+            IpVersion::Ipv4 => write!(f, "IPv4"),
+            IpVersion::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Network options of the HTTP clients.
+#[derive(Default)]
+pub struct HttpOptions {
+    pub ip_version: IpVersion,
+    /// Addresses used for these hosts instead of resolving them, like curl's `--resolve`
+    pub resolve: HostOverrides,
+    /// DNS servers queried instead of the system resolver, if not empty
+    pub dns_servers: DnsServers,
+}
+
+/// Addresses by host name.
+pub type HostOverrides = Vec<(String, IpAddr)>;
+
+/// Addresses of DNS servers.
+pub type DnsServers = Vec<SocketAddr>;
+
+/// Port of the DNS servers given without one
+const DNS_PORT: u16 = 53;
+
+static HTTP_OPTIONS: OnceLock<HttpOptions> = OnceLock::new();
+static WORKER_ID: OnceLock<String> = OnceLock::new();
+
+/// eg: "mapant-fr-worker/0.1.0 cassini/0.12.5 (linux; x86_64)"
+pub fn user_agent() -> String {
+    format!(
+        "{}/{} cassini/{} ({}; {})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        CASSINI_VERSION,
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )
+}
+
+/// Sent in the `X-Mapant-Worker-Id` header of every request, so that the servers can attribute
+/// the traffic to the workers.
+pub fn set_worker_id(worker_id: &str) {
+    let _ = WORKER_ID.set(worker_id.to_string());
+}
+
+pub fn set_http_options(options: HttpOptions) {
+    let _ = HTTP_OPTIONS.set(options);
+}
+
+/// Parse host overrides like "mapant.fr=203.0.113.7,lidar.example.org=2001:db8::1".
+pub fn parse_host_overrides(value: &str) -> Result<HostOverrides, String> {
+    value
+        .split(',')
+        .map(|host_override| {
+            let (host, ip) = host_override.trim().split_once('=').ok_or_else(|| {
+                format!(
+                    "Invalid host override \"{}\", expected eg: mapant.fr=203.0.113.7",
+                    host_override
+                )
+            })?;
+
+            let ip: IpAddr = ip.parse().map_err(|_| format!("Invalid IP address \"{}\"", ip))?;
+
+            Ok((host.to_string(), ip))
+        })
+        .collect()
+}
+
+/// Parse DNS servers like "1.1.1.1,9.9.9.9:53,[2606:4700:4700::1111]:53", on port 53 if not set.
+pub fn parse_dns_servers(value: &str) -> Result<DnsServers, String> {
+    value
+        .split(',')
+        .map(|dns_server| {
+            let dns_server = dns_server.trim();
+
+            dns_server
+                .parse::<SocketAddr>()
+                .or_else(|_| {
+                    dns_server
+                        .parse::<IpAddr>()
+                        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+                })
+                .map_err(|_| format!("Invalid DNS server \"{}\", expected eg: 1.1.1.1", dns_server))
+        })
+        .collect()
+}
+
+/// Client for the HTTP requests of the worker, with the options set by `set_http_options`. Every
+/// request carries the `user_agent` and the worker id.
+pub fn http_client() -> Client {
+    http_client_builder()
+        .build()
+        .expect("Failed to build the HTTP client")
+}
+
+pub fn http_client_builder() -> ClientBuilder {
+    let mut headers = HeaderMap::new();
+
+    if let Some(worker_id) = WORKER_ID
+        .get()
+        .and_then(|worker_id| HeaderValue::from_str(worker_id).ok())
+    {
+        headers.insert("X-Mapant-Worker-Id", worker_id);
+    }
+
+    let mut builder = Client::builder()
+        .user_agent(user_agent())
+        .default_headers(headers);
+
+    if let Some(options) = HTTP_OPTIONS.get() {
+        for (host, ip) in &options.resolve {
+            // Port 0 is replaced by the port of the URL
+            builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+        }
+
+        if options.ip_version != IpVersion::Any || !options.dns_servers.is_empty() {
+            builder = builder.dns_resolver(Arc::new(HttpResolver {
+                ip_version: options.ip_version,
+                dns_servers: (!options.dns_servers.is_empty())
+                    .then(|| dns_servers_resolver(&options.dns_servers)),
+            }));
+        }
+    }
+
+    builder
+}
+
+/// Resolver querying `dns_servers` over UDP, and over TCP for the truncated answers.
+fn dns_servers_resolver(dns_servers: &[SocketAddr]) -> TokioAsyncResolver {
+    let name_servers: Vec<NameServerConfig> = dns_servers
+        .iter()
+        .flat_map(|dns_server| {
+            [
+                NameServerConfig::new(*dns_server, Protocol::Udp),
+                NameServerConfig::new(*dns_server, Protocol::Tcp),
+            ]
+        })
+        .collect();
+
+    TokioAsyncResolver::tokio(
+        ResolverConfig::from_parts(None, vec![], name_servers),
+        ResolverOpts::default(),
+    )
+}
+
+/// Resolver of the HTTP clients, keeping the addresses of one family only.
+struct HttpResolver {
+    ip_version: IpVersion,
+    /// Queried instead of the system resolver if set
+    dns_servers: Option<TokioAsyncResolver>,
+}
+
+impl Resolve for HttpResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let ip_version = self.ip_version;
+        let dns_servers = self.dns_servers.clone();
+        let host = name.as_str().to_string();
+
+        Box::pin(async move {
+            let ips: Vec<IpAddr> = match dns_servers {
+                Some(dns_servers) => dns_servers.lookup_ip(host.as_str()).await?.iter().collect(),
+                None => {
+                    let lookup_host = host.clone();
+
+                    tokio::task::spawn_blocking(move || (lookup_host.as_str(), 0).to_socket_addrs())
+                        .await??
+                        .map(|addr| addr.ip())
+                        .collect()
+                }
+            };
+
+            let addrs: Vec<SocketAddr> = ips
+                .into_iter()
+                .filter(|ip| ip_version.accepts(*ip))
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(format!("No {} address for {}", ip_version, host).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
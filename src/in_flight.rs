@@ -0,0 +1,43 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+/// Jobs running on the threads of this worker, by `Job::label`, so that a job handed twice by
+/// the API, eg: after a lease race, is not processed by two threads writing to the same
+/// directories.
+pub struct InFlightJobs {
+    labels: Mutex<HashSet<String>>,
+}
+
+impl InFlightJobs {
+    pub fn new() -> Self {
+        InFlightJobs {
+            labels: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Register the job, None if another thread is already running it.
+    pub fn claim(self: &Arc<Self>, label: &str) -> Option<InFlightGuard> {
+        if !self.labels.lock().unwrap().insert(label.to_string()) {
+            return None;
+        }
+
+        Some(InFlightGuard {
+            jobs: self.clone(),
+            label: label.to_string(),
+        })
+    }
+}
+
+/// Unregisters a job when dropped, including when its processing panics.
+pub struct InFlightGuard {
+    jobs: Arc<InFlightJobs>,
+    label: String,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.jobs.labels.lock().unwrap().remove(&self.label);
+    }
+}
@@ -0,0 +1,130 @@
+use log::{info, warn};
+use reqwest::{blocking::Client, StatusCode};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{read_to_string, rename, write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{auth::ApiAuth, response::describe_error_response};
+
+/// Name of the file holding the SHA-256 of the archive a LiDAR step directory comes from.
+pub const ARCHIVE_HASH_FILE_NAME: &str = "archive.sha256";
+
+/// Hashes of the inputs of the outputs uploaded by this worker, by storage key of the output, eg:
+/// `lidar-steps/1000_6000`. Persisted as JSON so that reruns after a restart are skipped too.
+struct InputHashes {
+    path: PathBuf,
+    hashes: Mutex<HashMap<String, String>>,
+}
+
+static INPUT_HASHES: OnceLock<InputHashes> = OnceLock::new();
+
+/// Skip the jobs whose inputs did not change since this worker uploaded their outputs, recording
+/// the input hashes in `path`. Disabled unless called.
+pub fn enable_incremental_reprocessing(path: &Path) {
+    let hashes = match read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|error| {
+            warn!(
+                "Ignoring the invalid input hashes file {}: {}",
+                path.display(),
+                error
+            );
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    };
+
+    let _ = INPUT_HASHES.set(InputHashes {
+        path: path.to_path_buf(),
+        hashes: Mutex::new(hashes),
+    });
+}
+
+/// Hash of the inputs of an output, with the worker version as the processing may change with it.
+pub fn inputs_hash(inputs: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+
+    for input in inputs {
+        hasher.update([0]);
+        hasher.update(input);
+    }
+
+    hex::encode(hasher.finalize())
+}
+
+/// Whether the output was uploaded by this worker from the same inputs.
+pub fn is_output_unchanged(output_key: &str, inputs_hash: &str) -> bool {
+    INPUT_HASHES.get().is_some_and(|input_hashes| {
+        input_hashes
+            .hashes
+            .lock()
+            .unwrap()
+            .get(output_key)
+            .is_some_and(|hash| hash == inputs_hash)
+    })
+}
+
+/// Record the inputs of an output just uploaded. Failing to persist them is only logged, the
+/// next rerun of the job is then processed again.
+pub fn record_output_inputs(output_key: &str, inputs_hash: &str) {
+    let Some(input_hashes) = INPUT_HASHES.get() else {
+        return;
+    };
+
+    let mut hashes = input_hashes.hashes.lock().unwrap();
+    hashes.insert(output_key.to_string(), inputs_hash.to_string());
+
+    if let Err(error) = save_input_hashes(&input_hashes.path, &hashes) {
+        warn!(
+            "Failed to record the input hashes in {}: {}",
+            input_hashes.path.display(),
+            error
+        );
+    }
+}
+
+/// Written next to the file then renamed, a crash while writing leaves the previous hashes.
+fn save_input_hashes(
+    path: &Path,
+    hashes: &HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let partial_path = path.with_extension("partial");
+    write(&partial_path, serde_json::to_string(hashes)?)?;
+    rename(&partial_path, path)?;
+
+    Ok(())
+}
+
+/// Tell the API that the output uploaded to `url` is still up to date, instead of uploading it
+/// again. False if the API does not have it anymore, the job has to be processed then.
+pub fn acknowledge_unchanged_output(
+    client: &Client,
+    auth: &ApiAuth,
+    url: &str,
+    origin: &str,
+    inputs_hash: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let response = auth.send(
+        client
+            .post(format!("{}/unchanged", url))
+            .header("Origin", origin)
+            .json(&serde_json::json!({ "inputs_sha256": inputs_hash })),
+    )?;
+
+    match response.status() {
+        status if status.is_success() => Ok(true),
+        StatusCode::NOT_FOUND | StatusCode::CONFLICT | StatusCode::GONE => {
+            info!("The API does not have the output uploaded to {} anymore", url);
+            Ok(false)
+        }
+        _ => Err(format!(
+            "Failed to acknowledge the unchanged output. {}",
+            describe_error_response(response)
+        )
+        .into()),
+    }
+}
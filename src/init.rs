@@ -0,0 +1,179 @@
+use reqwest::Url;
+use std::{
+    env,
+    fs::{create_dir_all, read_to_string, write},
+    io::{stdin, stdout, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    auth::{ApiAuth, AuthMode},
+    config::check_writable_dir,
+    heartbeat::send_setup_heartbeat,
+    thread_count::auto_threads,
+};
+
+/// Ask the settings of the worker, check them against the API and write them to the .env file
+/// read at startup. The other variables of an existing file are kept.
+pub fn run_init(env_file_path: &Path, auth_mode: AuthMode) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Setting up the worker, press Enter to keep the value in brackets.");
+
+    let worker_id = prompt(
+        "Worker id",
+        env::var("MAPANT_API_WORKER_ID").ok(),
+        false,
+        parse_non_empty,
+    )?;
+    let token = prompt("Token", env::var("MAPANT_API_TOKEN").ok(), true, parse_non_empty)?;
+    let base_url = prompt(
+        "API base URL",
+        Some(env::var("MAPANT_API_BASE_URL").unwrap_or_else(|_| "https://mapant.fr".to_string())),
+        false,
+        parse_base_url,
+    )?;
+    let work_dir = prompt(
+        "Work directory",
+        Some(match env::var("MAPANT_WORK_DIR") {
+            Ok(work_dir) => work_dir,
+            Err(_) => env::current_dir()?.display().to_string(),
+        }),
+        false,
+        parse_work_dir,
+    )?;
+    let threads = prompt(
+        "Number of threads",
+        Some(env::var("MAPANT_THREADS").unwrap_or_else(|_| auto_threads(0, &work_dir).to_string())),
+        false,
+        parse_threads,
+    )?;
+
+    println!("Checking the credentials against {}...", base_url);
+    let auth = ApiAuth::new(worker_id.clone(), token.clone(), auth_mode);
+    send_setup_heartbeat(&auth, &base_url, &work_dir)
+        .map_err(|error| format!("Could not check the credentials: {}", error))?;
+
+    write_env_file(
+        env_file_path,
+        &[
+            ("MAPANT_API_WORKER_ID", worker_id),
+            ("MAPANT_API_TOKEN", token),
+            ("MAPANT_API_BASE_URL", base_url),
+            ("MAPANT_WORK_DIR", work_dir.display().to_string()),
+            ("MAPANT_THREADS", threads.to_string()),
+        ],
+    )?;
+
+    println!(
+        "Settings written to {}, the worker can be started from this directory.",
+        env_file_path.display()
+    );
+
+    Ok(())
+}
+
+/// Ask a value until it parses. Secrets are not echoed back as the default.
+fn prompt<T>(
+    label: &str,
+    default: Option<String>,
+    secret: bool,
+    parse: fn(&str) -> Result<T, String>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    loop {
+        match (&default, secret) {
+            (Some(_), true) => print!("{} [unchanged]: ", label),
+            (Some(default), false) => print!("{} [{}]: ", label, default),
+            (None, _) => print!("{}: ", label),
+        }
+        stdout().flush()?;
+
+        let mut line = String::new();
+
+        if stdin().read_line(&mut line)? == 0 {
+            return Err("Setup cancelled".into());
+        }
+
+        let value = match (line.trim(), &default) {
+            ("", Some(default)) => default.as_str(),
+            (value, _) => value,
+        };
+
+        match parse(value) {
+            Ok(value) => return Ok(value),
+            Err(error) => println!("{}", error),
+        }
+    }
+}
+
+fn parse_non_empty(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Err("A value is required".to_string());
+    }
+
+    Ok(value.to_string())
+}
+
+fn parse_base_url(value: &str) -> Result<String, String> {
+    let url = Url::parse(value).map_err(|error| format!("Invalid URL: {}", error))?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("The URL must start with https:// or http://".to_string());
+    }
+
+    Ok(value.trim_end_matches('/').to_string())
+}
+
+/// Create the directory if needed and check that the worker can write in it.
+fn parse_work_dir(value: &str) -> Result<PathBuf, String> {
+    let work_dir = PathBuf::from(value);
+
+    create_dir_all(&work_dir).map_err(|error| format!("Can not create {}: {}", work_dir.display(), error))?;
+    check_writable_dir(&work_dir)?;
+
+    Ok(work_dir)
+}
+
+fn parse_threads(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(threads) if threads > 0 => Ok(threads),
+        _ => Err("Expected a number of threads of at least 1".to_string()),
+    }
+}
+
+/// Replace the variables in the file, or append them. Values are single quoted so that dotenv
+/// reads them as is, the file is only readable by its owner as it holds the token.
+fn write_env_file(path: &Path, variables: &[(&str, String)]) -> Result<(), Box<dyn std::error::Error>> {
+    let existing = if path.exists() {
+        read_to_string(path)?
+    } else {
+        String::new()
+    };
+
+    let mut lines: Vec<String> = existing.lines().map(str::to_string).collect();
+
+    for (name, value) in variables {
+        if value.contains('\'') {
+            return Err(format!("{} can not contain a single quote", name).into());
+        }
+
+        let line = format!("{}='{}'", name, value);
+        let prefix = format!("{}=", name);
+
+        match lines
+            .iter_mut()
+            .find(|line| line.trim_start().starts_with(&prefix))
+        {
+            Some(existing_line) => *existing_line = line,
+            None => lines.push(line),
+        }
+    }
+
+    write(path, lines.join("\n") + "\n")?;
+
+    #[cfg(unix)]
+    {
+        use std::{fs::set_permissions, os::unix::fs::PermissionsExt};
+        set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,245 @@
+use log::{error, info, warn};
+use std::{
+    fs::{read_to_string, remove_file, rename, write, File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{auth::ApiAuth, http::http_client, utils::notify_job_abandoned};
+
+/// Maximum number of worker processes sharing a job journal path, eg: in the same work directory
+const MAX_INSTANCES: usize = 64;
+
+/// Jobs leased from the API and not finished yet, persisted so that the jobs of a worker that
+/// crashed or was killed can be handed back to the API on the next start, instead of waiting for
+/// their lease to expire.
+///
+/// Every process running with the same journal path gets its own instance of the journal, eg:
+/// `leased-jobs.json`, `leased-jobs.1.json`..., locked through a `.lock` file next to it for as
+/// long as the process runs. The instances left unlocked belong to processes that stopped.
+pub struct JobJournal {
+    path: PathBuf,
+    leased_jobs: Mutex<Vec<String>>,
+    /// Held until the process stops
+    _lock: File,
+}
+
+/// Path of the journal of an instance, the journal path itself for the first one.
+fn instance_path(path: &Path, instance: usize) -> PathBuf {
+    if instance == 0 {
+        return path.to_path_buf();
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    match path.extension() {
+        Some(extension) => {
+            path.with_file_name(format!("{}.{}.{}", stem, instance, extension.to_string_lossy()))
+        }
+        None => path.with_file_name(format!("{}.{}", stem, instance)),
+    }
+}
+
+/// Lock an instance of the journal, None if a running process holds it.
+fn try_lock_instance(instance_path: &Path) -> Result<Option<File>, Box<dyn std::error::Error>> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(instance_path.with_extension("lock"))?;
+
+    match lock.try_lock() {
+        Ok(()) => Ok(Some(lock)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(error)) => Err(error.into()),
+    }
+}
+
+fn read_leased_jobs(path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    Ok(serde_json::from_str(&read_to_string(path)?)?)
+}
+
+/// Jobs leased by every instance of the journal, the ones of running processes included, eg: to
+/// keep their files in the cache.
+pub fn all_leased_jobs(path: &Path) -> Vec<String> {
+    let mut leased_jobs = vec![];
+
+    for instance in 0..MAX_INSTANCES {
+        let path = instance_path(path, instance);
+
+        match read_leased_jobs(&path) {
+            Ok(jobs) => leased_jobs.extend(jobs),
+            Err(error) => warn!("Failed to read the job journal {}: {}", path.display(), error),
+        }
+    }
+
+    leased_jobs
+}
+
+impl JobJournal {
+    /// Lock the first instance of the journal no running process holds. The jobs of the other
+    /// unlocked instances, left by stopped processes, are moved to it to be recovered.
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut journal: Option<JobJournal> = None;
+
+        for instance in 0..MAX_INSTANCES {
+            let path = instance_path(path, instance);
+
+            if journal.is_some() && !path.exists() {
+                continue;
+            }
+
+            let Some(lock) = try_lock_instance(&path)? else {
+                continue;
+            };
+
+            let leased_jobs = read_leased_jobs(&path)?;
+
+            match &journal {
+                None => {
+                    journal = Some(JobJournal {
+                        path,
+                        leased_jobs: Mutex::new(leased_jobs),
+                        _lock: lock,
+                    })
+                }
+                Some(journal) => {
+                    let mut journal_jobs = journal.leased_jobs.lock().unwrap();
+                    journal_jobs.extend(leased_jobs);
+                    journal.persist(&journal_jobs);
+                    remove_file(&path)?;
+                }
+            }
+        }
+
+        let journal = journal.ok_or_else(|| {
+            format!(
+                "{} worker processes already use the job journal {}",
+                MAX_INSTANCES,
+                path.display()
+            )
+        })?;
+
+        info!("Using the job journal {}", journal.path.display());
+
+        Ok(journal)
+    }
+
+    /// Record a job payload received from the API. "No job left" answers are not jobs.
+    pub fn lease(&self, job_payload: &str) {
+        let is_job = serde_json::from_str::<serde_json::Value>(job_payload)
+            .ok()
+            .and_then(|value| {
+                value
+                    .get("type")
+                    .and_then(|job_type| job_type.as_str())
+                    .map(str::to_string)
+            })
+            .is_some_and(|job_type| job_type != "NoJobLeft");
+
+        if !is_job {
+            return;
+        }
+
+        let mut leased_jobs = self.leased_jobs.lock().unwrap();
+        leased_jobs.push(job_payload.to_string());
+        self.persist(&leased_jobs);
+    }
+
+    /// Forget a job once done, failed or handed back to the API.
+    pub fn release(&self, job_payload: &str) {
+        let mut leased_jobs = self.leased_jobs.lock().unwrap();
+
+        if let Some(index) = leased_jobs
+            .iter()
+            .position(|leased_job| leased_job == job_payload)
+        {
+            leased_jobs.remove(index);
+            self.persist(&leased_jobs);
+        }
+    }
+
+    /// Tell the API that the jobs still leased by previous runs of the worker are abandoned.
+    pub fn recover(&self, auth: &ApiAuth, base_url: &str) {
+        let leased_jobs = self.leased_jobs.lock().unwrap().len();
+
+        if leased_jobs > 0 {
+            warn!(
+                "{} jobs leased by previous runs were not finished, abandoning them",
+                leased_jobs
+            );
+
+            self.abandon_all(auth, base_url);
+        }
+    }
+
+    /// Hand every leased job back to the API, in-flight and queued ones alike. Jobs the API could
+    /// not be told about are kept for the next start.
+    pub fn abandon_all(&self, auth: &ApiAuth, base_url: &str) {
+        let leased_jobs = self.leased_jobs.lock().unwrap().clone();
+        abandon_jobs(self, leased_jobs, auth, base_url);
+    }
+
+    fn persist(&self, leased_jobs: &[String]) {
+        if let Err(error) = write_atomically(&self.path, leased_jobs) {
+            error!("Failed to write the job journal: {}", error);
+        }
+    }
+}
+
+/// Write aside then rename, a crash while writing must not lose the journal.
+fn write_atomically(path: &Path, leased_jobs: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let temporary_path = path.with_extension("tmp");
+    write(&temporary_path, serde_json::to_string(leased_jobs)?)?;
+    rename(&temporary_path, path)?;
+
+    Ok(())
+}
+
+/// Releases a leased job when dropped, including when its processing panics.
+pub struct LeaseGuard {
+    journal: Arc<JobJournal>,
+    job_payload: String,
+}
+
+impl LeaseGuard {
+    pub fn new(journal: Arc<JobJournal>, job_payload: &str) -> Self {
+        LeaseGuard {
+            journal,
+            job_payload: job_payload.to_string(),
+        }
+    }
+}
+
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        self.journal.release(&self.job_payload);
+    }
+}
+
+/// Best effort "job abandoned" calls so that the API can requeue the jobs right away. The jobs
+/// the API was told about are released from the journal.
+pub fn abandon_jobs(
+    journal: &JobJournal,
+    job_payloads: impl IntoIterator<Item = String>,
+    auth: &ApiAuth,
+    base_url: &str,
+) {
+    let client = http_client();
+
+    for job_payload in job_payloads {
+        warn!("Abandoning job {}", &job_payload);
+
+        match notify_job_abandoned(&client, auth, base_url, &job_payload) {
+            Ok(()) => {
+                info!("Job handed back to the API");
+                journal.release(&job_payload);
+            }
+            Err(error) => error!("Failed to notify the API of the abandoned job: {}", error),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use log::warn;
+use reqwest::{blocking::Client, Url};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::segmented_download::download_file_segmented;
+
+/// Hosts a download failed from are tried last during that time
+const FAILED_HOST_PENALTY: Duration = Duration::from_secs(300);
+
+fn failed_hosts() -> &'static Mutex<HashMap<String, Instant>> {
+    static FAILED_HOSTS: OnceLock<Mutex<HashMap<String, Instant>>> = OnceLock::new();
+    FAILED_HOSTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn host_of(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn failed_recently(host: &str) -> bool {
+    failed_hosts()
+        .lock()
+        .unwrap()
+        .get(host)
+        .is_some_and(|failed_at| failed_at.elapsed() < FAILED_HOST_PENALTY)
+}
+
+/// Download a LAZ file from the first of `urls` that works, eg: IGN then community mirrors. Hosts
+/// a download recently failed from are tried last, so that the other threads move to the mirrors
+/// too. The requests to every host are limited by `--host-limits`, see `HostPermit`.
+pub fn download_laz_file(
+    client: &Client,
+    urls: &[String],
+    file_path: &PathBuf,
+    expected_size: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut candidates: Vec<(&String, String)> = urls.iter().map(|url| (url, host_of(url))).collect();
+    candidates.sort_by_key(|(_, host)| failed_recently(host));
+
+    let mut last_error: Option<Box<dyn std::error::Error>> = None;
+
+    for (url, host) in candidates {
+        match download_file_segmented(client, url, file_path, expected_size) {
+            Ok(()) => {
+                failed_hosts().lock().unwrap().remove(&host);
+                return Ok(());
+            }
+            Err(error) => {
+                warn!(
+                    "Failed to download {} from {}: {}",
+                    file_path.display(),
+                    host,
+                    error
+                );
+
+                failed_hosts().lock().unwrap().insert(host, Instant::now());
+
+                last_error = Some(error);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| "No URL to download the LAZ file from".into()))
+}
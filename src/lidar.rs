@@ -1,33 +1,70 @@
 use cassini::process_single_tile_lidar_step;
 use log::{error, info};
-use reqwest::blocking::Client;
 use std::time::Instant;
-use std::{fs::create_dir_all, path::Path};
-
-use crate::utils::{compress_directory, download_file, upload_file};
+use std::{
+    fs::{create_dir_all, remove_dir_all, rename, write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    artifacts::UploadManifest,
+    auth::ApiAuth,
+    http::http_client,
+    incremental::{
+        acknowledge_unchanged_output, inputs_hash, is_output_unchanged, record_output_inputs,
+        ARCHIVE_HASH_FILE_NAME,
+    },
+    laz_mirrors::download_laz_file,
+    panics::catch_cassini_panic,
+    region::RegionProfile,
+    render::{partial_tile_dir_path, remove_partial_tile_dirs},
+    scratch::scratch_dir,
+    state::report_stage,
+    tile_lock::TileLock,
+    tile_metadata::write_tile_metadata,
+    utils::{compress_directory, sha256_of_file, upload_artifacts, StorageHints},
+};
 
 pub fn lidar_step(
     tile_id: &str,
-    laz_file_url: &str,
-    worker_id: &str,
-    token: &str,
+    laz_file_urls: &[String],
+    expected_size: Option<u64>,
+    auth: &ApiAuth,
     base_api_url: &str,
+    region: &RegionProfile,
+    storage: Option<&StorageHints>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let lidar_files_path = Path::new("lidar-files");
+    let lidar_files_path = scratch_dir().join("lidar-files");
     let lidar_file_path = lidar_files_path.join(format!("{}.laz", &tile_id));
 
     if !lidar_files_path.exists() {
-        create_dir_all(lidar_files_path)?;
+        create_dir_all(&lidar_files_path)?;
     }
 
+    report_stage("downloading LAZ");
     info!("Downloading laz file for tile {}", &tile_id);
     let start = Instant::now();
-    let client = Client::new();
-    download_file(&client, &laz_file_url, &lidar_file_path, None)?;
+    let client = http_client();
+    download_laz_file(&client, laz_file_urls, &lidar_file_path, expected_size)?;
     let duration = start.elapsed();
 
     info!("Laz file for tile {} downloaded in {:.1?}", &tile_id, duration);
 
+    let url = format!("{}/api/map-generation/lidar-steps/{}", base_api_url, &tile_id);
+    let output_key = format!("lidar-steps/{}", tile_id);
+    let inputs_hash = inputs_hash(&[&sha256_of_file(&lidar_file_path)?, &region.name]);
+
+    if is_output_unchanged(&output_key, &inputs_hash)
+        && acknowledge_unchanged_output(&client, auth, &url, base_api_url, &inputs_hash)?
+    {
+        info!(
+            "LAZ file of tile {} unchanged since its LiDAR step was uploaded, skipping it",
+            &tile_id
+        );
+
+        return Ok(());
+    }
+
     let lidar_step_path = Path::new("lidar-step");
 
     if !lidar_step_path.exists() {
@@ -36,10 +73,60 @@ pub fn lidar_step(
 
     let output_dir_path = lidar_step_path.join(&tile_id);
 
+    // Renders of other threads or processes sharing the cache only see the files once complete
+    let tile_lock = TileLock::exclusive(lidar_step_path, tile_id)?;
+    remove_partial_tile_dirs(lidar_step_path, tile_id)?;
+    let partial_dir_path = partial_tile_dir_path(lidar_step_path, tile_id);
+
+    let manifest = match process_lidar_files(tile_id, &lidar_file_path, &partial_dir_path, region) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            if partial_dir_path.exists() {
+                remove_dir_all(&partial_dir_path)?;
+            }
+
+            return Err(error);
+        }
+    };
+
+    if output_dir_path.exists() {
+        remove_dir_all(&output_dir_path)?;
+    }
+
+    rename(&partial_dir_path, &output_dir_path)?;
+    drop(tile_lock);
+
+    report_stage("uploading");
+
+    upload_artifacts(
+        &client,
+        auth,
+        url,
+        base_api_url,
+        &manifest,
+        storage,
+        "lidar-steps",
+    )?;
+    record_output_inputs(&output_key, &inputs_hash);
+
+    Ok(())
+}
+
+/// Run the LiDAR step of a tile into `output_dir_path` and compress its files, the archive hash
+/// is written last.
+fn process_lidar_files(
+    tile_id: &str,
+    lidar_file_path: &PathBuf,
+    output_dir_path: &PathBuf,
+    region: &RegionProfile,
+) -> Result<UploadManifest, Box<dyn std::error::Error>> {
+    report_stage("LiDAR processing");
     info!("Processing LiDAR step for tile {}", &tile_id);
     let start = Instant::now();
 
-    process_single_tile_lidar_step(&lidar_file_path, &output_dir_path);
+    catch_cassini_panic("LiDAR step", || {
+        process_single_tile_lidar_step(lidar_file_path, output_dir_path)
+    })?;
 
     let duration = start.elapsed();
 
@@ -57,12 +144,17 @@ pub fn lidar_step(
         return Err(format!("LiDAR step for tile {} failed", &tile_id).into());
     }
 
+    write_tile_metadata(output_dir_path, tile_id, region)?;
+
+    report_stage("compressing");
     info!("Compressing resulting files for tile {}", &tile_id);
     let start = Instant::now();
 
     let archive_file_name = format!("{}.tar.xz", &tile_id);
-    let archive_path = lidar_step_path.join(&archive_file_name);
-    compress_directory(&output_dir_path, &archive_path)?;
+    let archives_path = scratch_dir().join("lidar-step");
+    create_dir_all(&archives_path)?;
+    let archive_path = archives_path.join(&archive_file_name);
+    compress_directory(output_dir_path, &archive_path)?;
 
     let duration = start.elapsed();
 
@@ -71,18 +163,12 @@ pub fn lidar_step(
         &tile_id, duration
     );
 
-    let url = format!("{}/api/map-generation/lidar-steps/{}", base_api_url, &tile_id);
+    let manifest = UploadManifest::single(&archive_file_name, "file", &archive_path, "application/x-bzip2")?;
 
-    upload_file(
-        &client,
-        worker_id,
-        token,
-        url,
-        base_api_url,
-        archive_file_name,
-        archive_path,
-        "application/x-bzip2",
-    )?;
+    // Part of the inputs of the renders using these files, see `render_step`
+    if let Some(archive_hash) = &manifest.artifacts[0].sha256 {
+        write(output_dir_path.join(ARCHIVE_HASH_FILE_NAME), archive_hash)?;
+    }
 
-    Ok(())
+    Ok(manifest)
 }
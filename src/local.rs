@@ -0,0 +1,296 @@
+use cassini::{process_single_tile_lidar_step, process_single_tile_render_step};
+use log::{info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, create_dir_all, read_dir},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    http::http_client,
+    pyramid::{generate_base_zoom_levels_tiles, merge_children_tiles},
+    region::RegionProfile,
+    render::resize_png_to_high_quality_square,
+    segmented_download::download_file_segmented,
+    tile_metadata::{tile_extent, write_tile_metadata},
+};
+
+/// Where the LAZ files of a local generation come from.
+pub enum LocalLazSource {
+    Directory(PathBuf),
+    /// Download every tile of the bbox (min_x, min_y, max_x, max_y) with an url template.
+    /// Supported placeholders: {min_x}, {min_y}, {max_x}, {max_y} in meters and
+    /// {min_x_km}, {max_y_km} in kilometers padded to 4 digits (IGN file names style).
+    Bbox((i64, i64, i64, i64), String),
+}
+
+/// Run the LiDAR, render and pyramid steps on a local area without any API.
+/// The resulting tiles are written in `{output_dir}/tiles/{z}/{x}/{y}.png`, with (x, y) = (0, 0)
+/// being the top left tile of the area at the base zoom level.
+pub fn generate_local(
+    source: LocalLazSource,
+    output_dir: &Path,
+    region: &RegionProfile,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let start = Instant::now();
+
+    let lidar_files_path = output_dir.join("lidar-files");
+    let lidar_step_path = output_dir.join("lidar-step");
+    let render_step_path = output_dir.join("render-step");
+    let tiles_path = output_dir.join("tiles");
+    create_dir_all(&lidar_files_path)?;
+    create_dir_all(&lidar_step_path)?;
+    create_dir_all(&render_step_path)?;
+    create_dir_all(&tiles_path)?;
+
+    let laz_files = match source {
+        LocalLazSource::Directory(laz_dir) => list_laz_files(&laz_dir)?,
+        LocalLazSource::Bbox(bbox, url_template) => {
+            download_bbox_laz_files(bbox, &url_template, &lidar_files_path, region)?
+        }
+    };
+
+    if laz_files.is_empty() {
+        return Err("No LAZ file to process".into());
+    }
+
+    // LiDAR step, tiles are indexed by their bottom left corner on the region grid
+    let mut lidar_step_dirs: HashMap<(i64, i64), PathBuf> = HashMap::new();
+
+    for laz_file in &laz_files {
+        let file_stem = laz_file.file_stem().unwrap().to_string_lossy().to_string();
+        let lidar_step_dir = lidar_step_path.join(&file_stem);
+
+        if lidar_step_dir.join("extent.txt").exists() {
+            info!("LiDAR step for {} already done", &file_stem);
+        } else {
+            info!("Processing LiDAR step for {}", &file_stem);
+            let step_start = Instant::now();
+            process_single_tile_lidar_step(laz_file, &lidar_step_dir);
+
+            if lidar_step_dir.join("extent.txt").exists() {
+                write_tile_metadata(&lidar_step_dir, &file_stem, region)?;
+            }
+
+            info!(
+                "LiDAR step for {} processed in {:.1?}",
+                &file_stem,
+                step_start.elapsed()
+            );
+        }
+
+        if !lidar_step_dir.join("extent.txt").exists() {
+            warn!("LiDAR step for {} failed, skipping it", &file_stem);
+            continue;
+        }
+
+        let (min_x, min_y, _, _) = tile_extent(&lidar_step_dir)?;
+
+        let corner = (
+            min_x.div_euclid(region.tile_size_meters) * region.tile_size_meters,
+            min_y.div_euclid(region.tile_size_meters) * region.tile_size_meters,
+        );
+
+        lidar_step_dirs.insert(corner, lidar_step_dir);
+    }
+
+    // Render step
+    let mut full_maps: HashMap<(i64, i64), PathBuf> = HashMap::new();
+
+    for (&(min_x, min_y), lidar_step_dir) in &lidar_step_dirs {
+        let tile_id = format!("{}_{}", min_x, min_y);
+        let render_dir = render_step_path.join(&tile_id);
+        let full_map_path = render_dir.join("full-map.png");
+
+        if full_map_path.exists() {
+            info!("Render step for tile {} already done", &tile_id);
+            full_maps.insert((min_x, min_y), full_map_path);
+            continue;
+        }
+
+        let neighbor_dirs: Vec<PathBuf> = lidar_step_dirs
+            .iter()
+            .filter(|(&(x, y), _)| {
+                (x, y) != (min_x, min_y)
+                    && (x - min_x).abs() <= region.tile_size_meters
+                    && (y - min_y).abs() <= region.tile_size_meters
+            })
+            .map(|(_, dir)| dir.clone())
+            .collect();
+
+        info!("Processing render step for tile {}", &tile_id);
+        let step_start = Instant::now();
+
+        process_single_tile_render_step(lidar_step_dir, &render_dir, neighbor_dirs, false, true);
+
+        if !full_map_path.exists() {
+            warn!("Render step for tile {} failed, skipping it", &tile_id);
+            continue;
+        }
+
+        let (real_min_x, real_min_y, real_max_x, real_max_y) = tile_extent(lidar_step_dir)?;
+        let extent = region.get_extent_from_tile_id(&tile_id);
+
+        if (real_min_x, real_min_y, real_max_x, real_max_y) != extent {
+            resize_png_to_high_quality_square(
+                &full_map_path,
+                &full_map_path,
+                extent,
+                real_min_x,
+                real_max_y,
+                region.high_quality_tile_pixel_size,
+            )?;
+        }
+
+        info!(
+            "Render step for tile {} processed in {:.1?}",
+            &tile_id,
+            step_start.elapsed()
+        );
+        full_maps.insert((min_x, min_y), full_map_path);
+    }
+
+    // Pyramid step
+    let area_min_x = full_maps
+        .keys()
+        .map(|(x, _)| *x)
+        .min()
+        .ok_or("No tile rendered")?;
+    let area_max_y = full_maps
+        .keys()
+        .map(|(_, y)| *y)
+        .max()
+        .ok_or("No tile rendered")?
+        + region.tile_size_meters;
+
+    info!("Generating base zoom levels tiles");
+    let mut current_level_tiles: HashSet<(i32, i32)> = HashSet::new();
+
+    for (&(min_x, min_y), full_map_path) in &full_maps {
+        let x = ((min_x - area_min_x) / region.tile_size_meters) as i32;
+        let y = ((area_max_y - min_y - region.tile_size_meters) / region.tile_size_meters) as i32;
+
+        let base_tile_x_path = tiles_path
+            .join(region.base_zoom_level.to_string())
+            .join(x.to_string());
+
+        create_dir_all(&base_tile_x_path)?;
+        fs::copy(full_map_path, base_tile_x_path.join(format!("{}.png", y)))?;
+        generate_base_zoom_levels_tiles(&tiles_path, x, y, region.base_zoom_level, None)?;
+        current_level_tiles.insert((x, y));
+    }
+
+    let mut z = region.base_zoom_level - 1;
+
+    while z >= 0 && current_level_tiles.len() > 1 {
+        info!("Generating tiles for zoom {}", z);
+
+        let parent_tiles: HashSet<(i32, i32)> =
+            current_level_tiles.iter().map(|(x, y)| (x / 2, y / 2)).collect();
+
+        for &(x, y) in &parent_tiles {
+            let children_tiles = [
+                [x * 2, y * 2],
+                [x * 2 + 1, y * 2],
+                [x * 2, y * 2 + 1],
+                [x * 2 + 1, y * 2 + 1],
+            ];
+
+            let child_images = children_tiles.map(|[x_child, y_child]| {
+                image::open(
+                    tiles_path
+                        .join((z + 1).to_string())
+                        .join(x_child.to_string())
+                        .join(format!("{}.png", y_child)),
+                )
+                .ok()
+            });
+
+            merge_children_tiles(&tiles_path, x, y, z, &child_images, None)?;
+        }
+
+        current_level_tiles = parent_tiles;
+        z -= 1;
+    }
+
+    info!(
+        "Local area generated in {:.1?}, tiles available in {}",
+        start.elapsed(),
+        tiles_path.display()
+    );
+
+    Ok(())
+}
+
+fn list_laz_files(laz_dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut laz_files: Vec<PathBuf> = vec![];
+
+    for entry in read_dir(laz_dir)? {
+        let path = entry?.path();
+
+        let is_laz = path
+            .extension()
+            .map(|extension| {
+                let extension = extension.to_string_lossy().to_lowercase();
+                extension == "laz" || extension == "las"
+            })
+            .unwrap_or(false);
+
+        if path.is_file() && is_laz {
+            laz_files.push(path);
+        }
+    }
+
+    laz_files.sort();
+
+    Ok(laz_files)
+}
+
+fn download_bbox_laz_files(
+    (min_x, min_y, max_x, max_y): (i64, i64, i64, i64),
+    url_template: &str,
+    lidar_files_path: &Path,
+    region: &RegionProfile,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let client = http_client();
+    let tile_size = region.tile_size_meters;
+    let mut laz_files: Vec<PathBuf> = vec![];
+
+    let mut tile_min_y = min_y.div_euclid(tile_size) * tile_size;
+
+    while tile_min_y < max_y {
+        let mut tile_min_x = min_x.div_euclid(tile_size) * tile_size;
+
+        while tile_min_x < max_x {
+            let tile_id = format!("{}_{}", tile_min_x, tile_min_y);
+            let laz_file_path = lidar_files_path.join(format!("{}.laz", &tile_id));
+
+            if !laz_file_path.exists() {
+                let url = url_template
+                    .replace("{min_x}", &tile_min_x.to_string())
+                    .replace("{min_y}", &tile_min_y.to_string())
+                    .replace("{max_x}", &(tile_min_x + tile_size).to_string())
+                    .replace("{max_y}", &(tile_min_y + tile_size).to_string())
+                    .replace("{min_x_km}", &format!("{:04}", tile_min_x / 1000))
+                    .replace("{max_y_km}", &format!("{:04}", (tile_min_y + tile_size) / 1000));
+
+                info!("Downloading laz file for tile {}", &tile_id);
+
+                if let Err(error) = download_file_segmented(&client, &url, &laz_file_path, None) {
+                    warn!("Could not download laz file for tile {}: {}", &tile_id, error);
+                    let _ = fs::remove_file(&laz_file_path);
+                    tile_min_x += tile_size;
+                    continue;
+                }
+            }
+
+            laz_files.push(laz_file_path);
+            tile_min_x += tile_size;
+        }
+
+        tile_min_y += tile_size;
+    }
+
+    Ok(laz_files)
+}
@@ -0,0 +1,48 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static LOG_FILE: OnceLock<Mutex<BufWriter<File>>> = OnceLock::new();
+
+/// Flushes the log file when dropped, it must live as long as the worker.
+pub struct LogFileGuard;
+
+impl Drop for LogFileGuard {
+    fn drop(&mut self) {
+        flush_log_file();
+    }
+}
+
+/// Start copying the logs to a `logs-{timestamp}.csv` file in the work directory.
+pub fn open_log_file() -> io::Result<LogFileGuard> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    let mut log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("logs-{}.csv", timestamp))?;
+
+    log_file.write_all("Timestamp,Thread,Correlation ID,Log Level,Message\n".as_bytes())?;
+
+    // Wrap the file in a Mutex to allow safe concurrent access
+    let _ = LOG_FILE.set(Mutex::new(BufWriter::new(log_file)));
+
+    Ok(LogFileGuard)
+}
+
+/// Append a line to the log file, if it is open.
+pub fn write_log_line(line: &str) {
+    if let Some(log_file) = LOG_FILE.get() {
+        let _ = log_file.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+/// Write the buffered lines, for the exits that skip the drop of the guard.
+pub fn flush_log_file() {
+    if let Some(log_file) = LOG_FILE.get() {
+        let _ = log_file.lock().unwrap().flush();
+    }
+}
@@ -1,248 +1,1452 @@
+mod affinity;
+mod alert;
+mod artifacts;
+mod auth;
+mod bbox;
+mod bench;
+mod buffer_pool;
+mod cache;
+mod capabilities;
+mod chat;
+mod circuit;
+mod compression;
+mod config;
+mod control;
+mod disk;
+mod events;
+mod failover;
+mod health;
+mod heartbeat;
+mod history;
+mod http;
+mod in_flight;
+mod incremental;
+mod init;
+mod journal;
+mod laz_mirrors;
 mod lidar;
+mod local;
+mod log_file;
+mod memory;
+mod metrics_push;
+mod mosaic;
+mod outbox;
+mod panics;
+mod partial_download;
+mod pinning;
+mod poison;
+mod priority;
+mod profile;
+mod progress;
 mod pyramid;
+mod quota;
+mod raster;
+mod rate_limit;
+mod region;
 mod render;
+mod render_checkpoint;
+mod reporting;
+mod response;
+mod s3;
+mod sample_tile;
+mod scaling;
+mod schedule;
+mod scheduler;
+mod scratch;
+mod segmented_download;
+mod self_test;
+mod shutdown;
+mod stage_budget;
+mod state;
+mod stats;
+mod status_file;
+mod subprocess;
+mod systemd;
+mod tags;
+mod textfile_metrics;
+mod thread_count;
+mod tile_encoder;
+mod tile_lock;
+mod tile_metadata;
+mod toolchain;
+mod tui;
+mod upload_parts;
 mod utils;
+mod validate;
+mod vector_pyramid;
+mod web_ui;
+mod worker;
 
-use clap::Parser;
+use affinity::CpuAffinity;
+use alert::AlertThresholds;
+use auth::{ApiAuth, AuthMode};
+use bbox::Bbox;
+use cache::CachePolicy;
+use chat::{ChatEvent, ChatFormat, ChatSettings};
+use clap::{Parser, Subcommand};
+use config::ConfigToValidate;
 use dotenv::dotenv;
-use lidar::lidar_step;
-use log::{error, info, warn};
-use pyramid::pyramid_step;
-use render::render_step;
-use reqwest::{self};
-use serde::{Deserialize, Serialize};
+use history::{HistoryQuery, JobHistory};
+use http::{DnsServers, HostOverrides, HttpOptions, IpVersion};
+use in_flight::InFlightJobs;
+use journal::JobJournal;
+use local::LocalLazSource;
+use log::{info, warn};
+use memory::MemoryLimits;
+use metrics_push::MetricsQueue;
+use pinning::Fingerprints;
+use poison::PoisonedJobs;
+use priority::{IoniceClass, ProcessingPriority};
+use profile::Profile;
+use quota::BandwidthQuota;
+use raster::CreationOptions;
+use rate_limit::HostLimits;
+use region::{parse_bbox, RegionProfile};
+use schedule::QuietHours;
+use scheduler::JobQueue;
+use stage_budget::StageBudgets;
+use state::{current_correlation_id, worker_thread_name, WorkerState};
 use std::{
+    collections::{HashMap, VecDeque},
     env,
-    fs::OpenOptions,
-    io::{BufWriter, Write},
-    sync::Mutex,
-    thread::{self, sleep, spawn, JoinHandle},
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, sleep, JoinHandle},
+    time::Duration,
 };
+use tags::WorkerTags;
+use tile_encoder::PngInterlacing;
+use tui::LogLines;
+use worker::{run_single_job, supervise_worker_thread, WorkerContext};
 
 // Update the docs when modifying
 #[derive(Parser, Debug)]
 #[command(version, about = "A worker node for the mapant.fr map generation")]
 pub struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     #[arg(
         long,
         short,
-        help = "Number of threads to parallelize the work",
-        default_value = "3"
+        env = "MAPANT_THREADS",
+        help = "Number of threads to parallelize the work. Defaults to what the cores, RAM and free disk space of the machine allow"
     )]
     threads: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Start even with a number of threads needing much more RAM than the machine has"
+    )]
+    force: bool,
+
+    #[arg(
+        long,
+        help = "Maximum number of threads in a stage at once, within --threads: job types in lower case, download (LAZ files and storage) and upload, eg: lidar=1,render=2,pyramid=4,download=4,upload=2",
+        value_parser = stage_budget::parse_stage_budgets,
+    )]
+    stage_threads: Option<StageBudgets>,
+
+    #[arg(
+        long,
+        env = "MAPANT_WORK_DIR",
+        help = "Directory where the jobs are processed, the caches and the logs are kept. Defaults to the current directory"
+    )]
+    work_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "MAPANT_WORKER_TAGS",
+        help = "Tags sent with the next-job requests and the heartbeats so that the server can target jobs to this worker, eg: region=alps,disk=fast",
+        value_parser = tags::parse_tags,
+    )]
+    tags: Option<WorkerTags>,
+
+    #[arg(
+        long,
+        help = "Only accept the jobs of the tiles in this area, in the coordinate system of the region (Lambert-93 for fr): min_x,min_y,max_x,max_y. Sent to the server so that it hands out jobs of this area first",
+        value_parser = parse_bbox,
+    )]
+    bbox: Option<Bbox>,
+
+    #[arg(
+        long,
+        help = "Maximum number of threads that can be activated at runtime with SIGUSR1 (one more) and SIGUSR2 (one less). Defaults to --threads"
+    )]
+    max_threads: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Region profile defining the tiles grid and projection (fr). Other grids are described with --region-file",
+        default_value = "fr",
+        global = true
+    )]
+    region: String,
+
+    #[arg(
+        long,
+        help = "Path to a json file describing a custom region profile. Takes precedence over --region",
+        global = true
+    )]
+    region_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Maximum duration of a job in minutes before the worker is considered hung and the systemd watchdog stops being pinged",
+        default_value = "180"
+    )]
+    max_job_duration: u64,
+
+    #[arg(
+        long,
+        help = "Port of the HTTP server exposing the /livez and /readyz probes, and the status page with --web-ui. Disabled if not set"
+    )]
+    health_port: Option<u16>,
+
+    #[arg(
+        long,
+        help = "Address the health server listens on, eg: 0.0.0.0 for the probes of Kubernetes. The /stats and status pages are then reachable from other machines",
+        default_value = "127.0.0.1"
+    )]
+    health_bind: IpAddr,
+
+    #[arg(
+        long,
+        help = "Serve the status page on / of the health server. It shows the current and recent jobs and their errors to anyone who can reach the health port"
+    )]
+    web_ui: bool,
+
+    #[arg(
+        long,
+        help = "Seconds to wait for in-flight jobs after SIGTERM before abandoning them",
+        default_value = "25"
+    )]
+    drain_timeout: u64,
+
+    #[arg(
+        long,
+        help = "Niceness of the processing and of the spawned gdal/ogr2ogr commands, from 0 (normal priority) to 19 (lowest). Unchanged if not set",
+        value_parser = clap::value_parser!(i32).range(0..=19)
+    )]
+    nice: Option<i32>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "I/O scheduling class of the processing and of the spawned commands (Linux only). Unchanged if not set"
+    )]
+    ionice_class: Option<IoniceClass>,
+
+    #[arg(long, help = "Pin every worker thread to its own core (Linux only)")]
+    pin_threads: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Preset of the memory hungry settings, overriding the matching flags",
+        default_value = "default"
+    )]
+    profile: Profile,
+
+    #[arg(
+        long,
+        help = "Fetch and process a single job then exit, with a non-zero status if it failed. Results kept in the outbox are sent by the next run"
+    )]
+    once: bool,
+
+    #[arg(
+        long,
+        help = "Exit with a zero status once the API has no job left and the in-flight jobs are done, instead of polling every 30 seconds. For autoscaling groups and batch schedulers"
+    )]
+    exit_when_idle: bool,
+
+    #[arg(
+        long,
+        help = "Number of cores, starting from the first one, on which worker threads do not run, left to the OS and the network (Linux only)",
+        default_value = "0"
+    )]
+    reserved_cores: usize,
+
+    #[arg(
+        long,
+        help = "Virtual memory limit in MB (RLIMIT_AS) of every spawned gdal/ogr2ogr command, exceeding it fails the job. Mapped files and reserved memory count too, so it must be well above the memory actually used. No limit if not set (Unix only)"
+    )]
+    subprocess_memory_limit: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Resident memory in MB of the worker above which the newest job is aborted, one at a time, 0 for no limit. The job stops at its next stage or transfer, not during the in-process LiDAR and render steps of cassini",
+        default_value = "0"
+    )]
+    memory_limit: u64,
+
+    #[arg(
+        long,
+        help = "Growth in MB of the worker memory during a job before it is aborted, 0 for no limit. With several jobs running, only the newest job over the budget is aborted, one at a time. Like --memory-limit, it takes effect at the next stage or transfer of the job",
+        default_value = "0"
+    )]
+    job_memory_budget: u64,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "How requests to the API are authenticated",
+        default_value = "bearer"
+    )]
+    auth_mode: AuthMode,
+
+    #[arg(
+        long,
+        help = "Fetch the next job and download its inputs while the current job is processed"
+    )]
+    prefetch: bool,
+
+    #[arg(
+        long,
+        help = "Size in MB of the lidar-step cache above which prefetching is paused",
+        default_value = "20000"
+    )]
+    prefetch_disk_budget: u64,
+
+    #[arg(
+        long,
+        help = "SQLite database where every processed job is recorded",
+        default_value = "job-history.sqlite",
+        global = true
+    )]
+    history_db: PathBuf,
+
+    #[arg(long, help = "Do not record the processed jobs in the history database")]
+    no_history: bool,
+
+    #[arg(
+        long,
+        help = "File where the hashes of the inputs of the uploaded outputs are recorded",
+        default_value = "input-hashes.json"
+    )]
+    input_hashes_file: PathBuf,
+
+    #[arg(
+        long,
+        help = "Process the jobs again even if their inputs did not change since their outputs were uploaded"
+    )]
+    no_incremental: bool,
+
+    #[arg(
+        long,
+        help = "Unix socket on which the worker answers the status and control subcommands, eg: mapant-worker.sock. Anyone allowed to connect to it can pause or drain the worker. Disabled by default",
+        global = true
+    )]
+    control_socket: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Directory of the heavy temporary files (downloaded LAZ files and archives, render intermediates), eg: on a fast NVMe disk. Defaults to the work directory"
+    )]
+    scratch_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Maximum size in MB of the scratch directory above which new jobs are not accepted"
+    )]
+    scratch_budget: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Maximum size in MB of a downloaded file, bigger responses are rejected. 0 for no limit",
+        default_value = "10000"
+    )]
+    max_download_size: u64,
+
+    #[arg(
+        long,
+        help = "Number of parallel range requests used to download the LAZ files",
+        default_value = "4"
+    )]
+    download_connections: usize,
+
+    #[arg(
+        long,
+        help = "Requests per minute / concurrent connections allowed by external host, * for the other hosts, 0 for no limit, eg: geoservices.ign.fr=60/4,*=300/16",
+        value_parser = rate_limit::parse_host_limits,
+        default_value = "geoservices.ign.fr=60/4"
+    )]
+    host_limits: HostLimits,
+
+    #[arg(
+        long,
+        help = "Maximum next-job requests per second of the worker, all threads together",
+        default_value = "5",
+        value_parser = rate_limit::parse_poll_rate
+    )]
+    max_poll_rate: f64,
+
+    #[arg(
+        long,
+        help = "Maximum random delay in seconds before each thread polls its first job, up to 3600, 0 to start them at once",
+        default_value = "10",
+        value_parser = rate_limit::parse_startup_jitter
+    )]
+    startup_jitter: f64,
+
+    #[arg(
+        long,
+        help = "GDAL creation options of the GeoTIFF rasters, comma separated, empty for none",
+        value_parser = raster::parse_creation_options,
+        default_value = "COMPRESS=DEFLATE,TILED=YES,PREDICTOR=2"
+    )]
+    geotiff_creation_options: CreationOptions,
+
+    #[arg(
+        long,
+        help = "Add overviews to the GeoTIFF rasters, for clients displaying them zoomed out"
+    )]
+    raster_overviews: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Interlacing of the PNG web tiles of the pyramid jobs for which the server has no preference",
+        default_value = "none"
+    )]
+    png_interlacing: PngInterlacing,
+
+    #[arg(
+        long,
+        help = "Quantize the line-art pyramid tiles and layer PNGs to a 256 colors palette to make them smaller. Lossy, the antialiased edges can lose colors"
+    )]
+    palette_quantization: bool,
+
+    #[arg(
+        long,
+        help = "Maximum size in MB of the cache directories (lidar-files, lidar-step, render-step, tiles), the least recently modified entries are removed first. No limit if not set",
+        global = true
+    )]
+    cache_budget: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Maximum age in hours of the entries of the cache directories, eg: lidar-files=24,tiles=6",
+        value_parser = cache::parse_cache_ttls,
+        global = true
+    )]
+    cache_ttl: Option<HashMap<String, Duration>>,
+
+    #[arg(
+        long,
+        help = "Interval in minutes of the cache garbage collection, when a cache budget or TTL is set. 0 to disable",
+        default_value = "60"
+    )]
+    cache_gc_interval: u64,
+
+    #[arg(
+        long,
+        help = "Minutes between two summaries of the processed jobs in the logs, 0 to disable",
+        default_value = "60"
+    )]
+    summary_interval: u64,
+
+    #[arg(
+        long,
+        help = "Interval in seconds between two writes of --status-file, eg: 30. 0 to disable",
+        default_value = "0"
+    )]
+    status_file_interval: u64,
+
+    #[arg(
+        long,
+        help = "File where a JSON snapshot of the current jobs, errors and cache sizes is written",
+        default_value = "status.json"
+    )]
+    status_file: PathBuf,
+
+    #[arg(
+        long,
+        help = "Free disk space in MB below which new jobs are not accepted and the cache is evicted, eg: 5000. 0 to disable",
+        default_value = "0"
+    )]
+    disk_low_water_mark: u64,
+
+    #[arg(
+        long,
+        help = "Free disk space in MB above which new jobs are accepted again after a pause caused by --disk-low-water-mark",
+        default_value = "10000"
+    )]
+    disk_high_water_mark: u64,
+
+    #[arg(
+        long,
+        help = "Local time windows during which the worker is throttled, eg: 07:00-22:00 or 08:00-12:00,14:00-19:00. Full speed the rest of the time"
+    )]
+    quiet_hours: Option<String>,
+
+    #[arg(
+        long,
+        help = "Number of threads processing jobs during the quiet hours",
+        default_value = "1"
+    )]
+    quiet_hours_threads: usize,
+
+    #[arg(
+        long,
+        help = "Bandwidth in MB/s shared by all the downloads and uploads during the quiet hours, 0 for no limit",
+        default_value = "0"
+    )]
+    quiet_hours_bandwidth: f64,
+
+    #[arg(
+        long,
+        help = "Maximum GB downloaded and uploaded per calendar month, new jobs are not accepted once reached. No limit if not set"
+    )]
+    monthly_bandwidth_budget: Option<u64>,
+
+    #[arg(
+        long,
+        help = "File where the bandwidth used during the current month is persisted",
+        default_value = "bandwidth-usage.json"
+    )]
+    bandwidth_usage_file: PathBuf,
+
+    #[arg(
+        long,
+        help = "Attempts at a job failing with an error before reporting it as failed to the API, 1 to never retry it",
+        default_value = "3"
+    )]
+    job_attempts: u32,
+
+    #[arg(
+        long,
+        help = "Consecutive failures of the same job on this worker after which it is refused for --poison-cooldown hours. 0 to never refuse a job",
+        default_value = "3"
+    )]
+    poison_threshold: u32,
+
+    #[arg(
+        long,
+        help = "Hours during which a job that failed --poison-threshold times in a row is refused",
+        default_value = "24"
+    )]
+    poison_cooldown: u64,
+
+    #[arg(
+        long,
+        help = "File where the repeatedly failing jobs are persisted",
+        default_value = "poisoned-jobs.json"
+    )]
+    poisoned_jobs_file: PathBuf,
+
+    #[arg(
+        long,
+        help = "File where the jobs leased from the API are recorded, to hand them back after a crash",
+        default_value = "leased-jobs.json"
+    )]
+    job_journal: PathBuf,
+
+    #[arg(
+        long,
+        help = "Directory where the results of the jobs finished while the API is unreachable are kept until it is back",
+        default_value = "outbox"
+    )]
+    outbox_dir: PathBuf,
+
+    #[arg(
+        long,
+        help = "Fail the jobs finished while the API is unreachable instead of keeping their results"
+    )]
+    no_outbox: bool,
+
+    #[arg(
+        long,
+        help = "Seconds between two attempts to send the results kept in the outbox",
+        default_value = "60"
+    )]
+    outbox_replay_interval: u64,
+
+    #[arg(
+        long,
+        help = "Url called with a JSON POST when the worker looks sick (consecutive failures, API unreachable)"
+    )]
+    alert_webhook_url: Option<String>,
+
+    #[arg(
+        long,
+        help = "Number of consecutive failed jobs firing the alert webhook",
+        default_value = "5"
+    )]
+    alert_consecutive_failures: u64,
+
+    #[arg(
+        long,
+        help = "Minutes of unreachable API firing the alert webhook",
+        default_value = "10"
+    )]
+    alert_api_unreachable_minutes: u64,
+
+    #[arg(
+        long,
+        help = "Discord, Slack or Matrix (hookshot) webhook url receiving short messages about the significant events of the worker. Disabled if not set"
+    )]
+    chat_webhook_url: Option<String>,
+
+    #[arg(
+        long,
+        help = "Body expected by --chat-webhook-url, guessed from the url if not set"
+    )]
+    chat_format: Option<ChatFormat>,
+
+    #[arg(
+        long,
+        help = "Events posted to --chat-webhook-url, comma separated",
+        value_delimiter = ',',
+        default_value = "started,jobs-completed,failure-streak,disk-full"
+    )]
+    chat_events: Vec<ChatEvent>,
+
+    #[arg(
+        long,
+        help = "Number of completed jobs between two jobs-completed chat messages",
+        default_value = "100"
+    )]
+    chat_jobs_milestone: u64,
+
+    #[arg(
+        long,
+        help = "Number of consecutive failed jobs posting a failure-streak chat message",
+        default_value = "5"
+    )]
+    chat_failure_streak: u64,
+
+    #[arg(
+        long,
+        env = "MAPANT_LOG_FILTER",
+        help = "Log levels per module applied on top of RUST_LOG, eg: utils=debug,render=info"
+    )]
+    log_filter: Option<String>,
+
+    #[arg(
+        long,
+        help = "Show a live dashboard of the worker threads instead of the logs in the terminal"
+    )]
+    tui: bool,
+
+    #[arg(
+        long,
+        help = "Size in MB above which the progress of downloads and uploads is logged",
+        default_value = "100"
+    )]
+    progress_log_threshold: u64,
+
+    #[arg(
+        long,
+        help = "Seconds between two heartbeats sending the worker status and machine resources to the API, 0 to disable",
+        default_value = "60"
+    )]
+    heartbeat_interval: u64,
+
+    #[arg(
+        long,
+        help = "Consecutive API failures after which all the API calls are paused, 0 to never pause them",
+        default_value = "5"
+    )]
+    circuit_failure_threshold: u32,
+
+    #[arg(
+        long,
+        help = "Seconds during which the API calls are paused before probing the API again",
+        default_value = "60"
+    )]
+    circuit_cooldown: u64,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Address family used to connect to the API and the storage",
+        default_value = "any"
+    )]
+    ip_version: IpVersion,
+
+    #[arg(
+        long,
+        help = "Addresses used instead of resolving these hosts, eg: mapant.fr=203.0.113.7",
+        value_parser = http::parse_host_overrides,
+    )]
+    resolve: Option<HostOverrides>,
+
+    #[arg(
+        long,
+        help = "DNS servers queried instead of the system resolver, comma separated, eg: 1.1.1.1,9.9.9.9:53",
+        value_parser = http::parse_dns_servers,
+    )]
+    dns_servers: Option<DnsServers>,
+
+    #[arg(
+        long,
+        help = "SHA-256 fingerprints of the certificates or public keys accepted for the API, comma separated, checked during the TLS handshake before anything is sent. They replace the certificate authorities for the API, not for the download mirrors",
+        value_parser = pinning::parse_fingerprints,
+    )]
+    pinned_fingerprints: Option<Fingerprints>,
+
+    #[arg(
+        long,
+        env = "SENTRY_DSN",
+        help = "DSN of a Sentry compatible server receiving panics and job failures. Disabled if not set"
+    )]
+    sentry_dsn: Option<String>,
+
+    #[arg(
+        long,
+        help = "Seconds between two pushes of the job metrics to the API, for deployments without Prometheus. Disabled if not set"
+    )]
+    metrics_push_interval: Option<u64>,
+
+    #[arg(
+        long,
+        help = "File where the metrics are written in the Prometheus text format, for the textfile collector of node_exporter, eg: /var/lib/node_exporter/mapant-worker.prom. Disabled if not set"
+    )]
+    textfile_metrics: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Seconds between two writes of --textfile-metrics",
+        default_value = "30"
+    )]
+    textfile_metrics_interval: u64,
+
+    #[arg(
+        long,
+        help = "File where a JSON line is appended for every job accepted, stage started and job completed, failed or cancelled. Disabled if not set"
+    )]
+    job_events_file: Option<PathBuf>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type", content = "data")]
-enum Job {
-    Lidar {
-        tile_id: String,
-        tile_url: String,
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Ask the worker id, token, API URL, work directory and number of threads, check them against
+    /// the API and write them to the .env file
+    Init {
+        #[arg(long, help = "File the settings are written to", default_value = ".env")]
+        env_file: PathBuf,
+    },
+    /// Generate a tile pyramid for a local area without any API, to preview it before it is scheduled
+    GenerateLocal {
+        #[arg(
+            long,
+            help = "Directory containing the LAZ files of the area",
+            conflicts_with = "bbox"
+        )]
+        laz_dir: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Area to download and generate: min_x,min_y,max_x,max_y",
+            value_parser = parse_bbox,
+            requires = "laz_url_template"
+        )]
+        bbox: Option<(i64, i64, i64, i64)>,
+
+        #[arg(
+            long,
+            help = "Url template of the LAZ files for --bbox, eg: https://example.com/LHD_FXX_{min_x_km}_{max_y_km}.laz"
+        )]
+        laz_url_template: Option<String>,
+
+        #[arg(
+            long,
+            help = "Directory where the files are generated",
+            default_value = "local-area"
+        )]
+        output_dir: PathBuf,
+    },
+    /// Run a LAZ file through the LiDAR, render and pyramid steps and suggest a number of threads
+    Bench {
+        #[arg(
+            long,
+            help = "LAZ file of a single tile to benchmark with. The bundled sample tile by default, 25 times smaller than a 1 km tile: the durations are shorter, the suggested threads higher"
+        )]
+        laz_file: Option<PathBuf>,
+    },
+    /// Print the current jobs, uptime, cache usage and last API contact of the running worker
+    Status,
+    /// Send a command to the running worker: pause, resume, drain or set-threads N
+    Control {
+        #[arg(required = true, num_args = 1..)]
+        command: Vec<String>,
+    },
+    /// Check the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
     },
-    Render {
-        tile_id: String,
-        neigbhoring_tiles_ids: Vec<String>,
+    /// Manage the cache directories
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
     },
-    Pyramid {
-        x: i32,
-        y: i32,
-        z: i32,
-        base_zoom_level_tile_id: Option<String>,
-        area_id: String,
+    /// List the jobs recorded in the local history database, newest first
+    History {
+        #[arg(long, help = "Only the jobs of this tile id, or {z}/{x}/{y} for pyramid jobs")]
+        tile: Option<String>,
+
+        #[arg(long, help = "Only the jobs started in the last N hours")]
+        since_hours: Option<u64>,
+
+        #[arg(long, help = "Only the failed jobs")]
+        failed: bool,
+
+        #[arg(long, help = "Maximum number of jobs to list", default_value = "50")]
+        limit: u32,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommands {
+    /// Print the effective configuration with the secrets redacted, check the credentials against
+    /// the API and that the directories are writable. Exits with a non-zero status on problems
+    Validate,
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommands {
+    /// Report the size and age of the cache directories and prune them according to --cache-budget
+    /// and --cache-ttl
+    Gc {
+        #[arg(long, help = "Only list the entries that would be removed")]
+        dry_run: bool,
     },
-    NoJobLeft,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let timestamp = format!(
-        "{}",
-        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-    );
+    dotenv().ok();
 
-    let log_file_name = format!("logs-{}.csv", timestamp);
+    let args = Args::parse();
 
-    let mut log_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(&log_file_name)
-        .expect("Unable to open log file");
+    // `init` may be fixing a work directory that does not exist anymore
+    if let (Some(work_dir), false) = (
+        &args.work_dir,
+        matches!(args.command, Some(Commands::Init { .. })),
+    ) {
+        env::set_current_dir(work_dir)
+            .map_err(|error| format!("Can not use {} as work directory: {}", work_dir.display(), error))?;
+    }
 
-    log_file
-        .write_all("Timestamp,Thread ID,Log Level,Message\n".as_bytes())
-        .unwrap();
+    // Only the runs processing jobs keep their logs in a file
+    let _log_file_guard = match args.command {
+        None | Some(Commands::GenerateLocal { .. }) => Some(log_file::open_log_file()?),
+        _ => None,
+    };
 
-    let log_file = BufWriter::new(log_file);
+    let log_lines: LogLines = Arc::new(Mutex::new(VecDeque::with_capacity(tui::LOG_LINES_CAPACITY)));
+    let logger_log_lines = log_lines.clone();
+    let tui_enabled = args.tui;
 
-    // Wrap the file in a Mutex to allow safe concurrent access
-    let log_file = Mutex::new(log_file);
+    let mut logger_builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+    if let Some(log_filter) = &args.log_filter {
+        logger_builder.parse_filters(&expand_log_filter(log_filter));
+    }
+
+    logger_builder
         .format(move |buf, record| {
             use std::io::Write;
             let ts = buf.timestamp_seconds();
             let level_style = buf.default_level_style(record.level());
+            let thread_name = thread::current().name().unwrap_or("unnamed").to_string();
+            let correlation_id = current_correlation_id();
+
+            let thread_label = match &correlation_id {
+                Some(correlation_id) => format!("{} {}", thread_name, correlation_id),
+                None => thread_name.clone(),
+            };
 
-            // Write to console
-            buf.write_all(
-                format!(
-                    "[{} {:?} {level_style}{}{level_style:#}] {}\n",
-                    ts,
-                    thread::current().id(),
-                    record.level(),
-                    record.args()
+            // The dashboard takes over the terminal, logs are displayed in it instead
+            if tui_enabled {
+                tui::push_log_line(
+                    &logger_log_lines,
+                    format!("[{} {} {}] {}", ts, thread_label, record.level(), record.args()),
+                );
+            } else {
+                // Write to console
+                buf.write_all(
+                    format!(
+                        "[{} {} {level_style}{}{level_style:#}] {}\n",
+                        ts,
+                        thread_label,
+                        record.level(),
+                        record.args()
+                    )
+                    .as_bytes(),
                 )
-                .as_bytes(),
-            )
-            .unwrap();
+                .unwrap();
+            }
 
             // Write to the file
-            let mut file = log_file.lock().unwrap();
-
-            file.write_all(
-                format!(
-                    "{},{:?},{},\"{}\"\n",
-                    ts,
-                    thread::current().id(),
-                    record.level(),
-                    record.args()
-                )
-                .as_bytes(),
-            )
-            .unwrap();
+            log_file::write_log_line(&format!(
+                "{},{},{},{},\"{}\"\n",
+                ts,
+                thread_name,
+                correlation_id.unwrap_or_default(),
+                record.level(),
+                record.args()
+            ));
 
             Ok(())
         })
         .init();
 
-    dotenv().ok();
+    let _error_reporting_guard = reporting::init_error_reporting(args.sentry_dsn.as_deref());
+
+    progress::set_progress_log_threshold(args.progress_log_threshold * 1_000_000);
+    priority::set_processing_priority(ProcessingPriority {
+        nice: args.nice,
+        ionice_class: args.ionice_class,
+    });
+    subprocess::set_subprocess_memory_limit(
+        args.subprocess_memory_limit
+            .map_or(0, |memory_limit| memory_limit * 1_000_000),
+    );
+    utils::set_max_download_size(args.max_download_size * 1_000_000);
+    segmented_download::set_download_connections(args.download_connections);
+    rate_limit::set_host_limits(args.host_limits.clone());
+    rate_limit::set_max_poll_rate(args.max_poll_rate);
+    raster::set_geotiff_creation_options(args.geotiff_creation_options.clone());
+    raster::set_raster_overviews(args.raster_overviews);
+    tile_encoder::set_default_png_interlacing(args.png_interlacing);
+    tile_encoder::set_palette_quantization(args.palette_quantization);
+    for (stage, threads) in args.stage_threads.iter().flatten() {
+        stage_budget::set_stage_budget(stage, *threads);
+    }
+    profile::apply_profile(args.profile);
+    http::set_http_options(HttpOptions {
+        ip_version: args.ip_version,
+        resolve: args.resolve.clone().unwrap_or_default(),
+        dns_servers: args.dns_servers.clone().unwrap_or_default(),
+    });
+    if let Some(pinned_fingerprints) = &args.pinned_fingerprints {
+        pinning::set_pinned_fingerprints(pinned_fingerprints.clone());
+    }
+
+    circuit::configure_circuit_breaker(
+        args.circuit_failure_threshold,
+        Duration::from_secs(args.circuit_cooldown),
+    );
+    affinity::set_cpu_affinity(CpuAffinity {
+        pin_threads: args.pin_threads,
+        reserved_cores: args.reserved_cores,
+    });
+
+    let region = match &args.region_file {
+        Some(path) => RegionProfile::from_file(path)?,
+        None => RegionProfile::from_name(&args.region)?,
+    };
+
+    info!(
+        "Using region profile {} (EPSG:{}, {}m tiles, base zoom {})",
+        region.name, region.epsg, region.tile_size_meters, region.base_zoom_level
+    );
+
+    let cache_policy = CachePolicy {
+        budget: args.cache_budget.map(|budget| budget * 1_000_000),
+        ttls: args.cache_ttl.clone().unwrap_or_default(),
+    };
+
+    if let Some(Commands::Config {
+        command: ConfigCommands::Validate,
+    }) = &args.command
+    {
+        let mut directories = vec![
+            ("Work directory", env::current_dir()?),
+            ("Job journal directory", parent_dir(&args.job_journal)),
+        ];
+
+        if let Some(control_socket) = &args.control_socket {
+            directories.push(("Control socket directory", parent_dir(control_socket)));
+        }
+
+        if let Some(scratch_dir) = &args.scratch_dir {
+            directories.push(("Scratch directory", scratch_dir.clone()));
+        }
+
+        if !args.no_outbox {
+            directories.push(("Outbox directory", args.outbox_dir.clone()));
+        }
+
+        if !args.no_history {
+            directories.push(("History database directory", parent_dir(&args.history_db)));
+        }
+
+        if args.monthly_bandwidth_budget.is_some() {
+            directories.push((
+                "Bandwidth usage directory",
+                parent_dir(&args.bandwidth_usage_file),
+            ));
+        }
+
+        if args.poison_threshold > 0 {
+            directories.push(("Poisoned jobs directory", parent_dir(&args.poisoned_jobs_file)));
+        }
+
+        if args.status_file_interval > 0 {
+            directories.push(("Status file directory", parent_dir(&args.status_file)));
+        }
+
+        if let Some(textfile_metrics) = &args.textfile_metrics {
+            directories.push(("Textfile metrics directory", parent_dir(textfile_metrics)));
+        }
+
+        if let Some(job_events_file) = &args.job_events_file {
+            directories.push(("Job events directory", parent_dir(job_events_file)));
+        }
+
+        return config::validate_config(ConfigToValidate {
+            flags: format!("{:#?}", args),
+            secrets: [&args.sentry_dsn, &args.alert_webhook_url, &args.chat_webhook_url]
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect(),
+            region: &region,
+            auth_mode: args.auth_mode,
+            directories,
+        });
+    }
+
+    match args.command {
+        Some(Commands::Init { env_file }) => {
+            return init::run_init(&env_file, args.auth_mode);
+        }
+        Some(Commands::GenerateLocal {
+            laz_dir,
+            bbox,
+            laz_url_template,
+            output_dir,
+        }) => {
+            let source = match (laz_dir, bbox, laz_url_template) {
+                (Some(laz_dir), _, _) => LocalLazSource::Directory(laz_dir),
+                (None, Some(bbox), Some(laz_url_template)) => LocalLazSource::Bbox(bbox, laz_url_template),
+                _ => return Err("Either --laz-dir or --bbox and --laz-url-template must be provided".into()),
+            };
+
+            priority::lower_current_thread_priority();
+            return local::generate_local(source, &output_dir, &region);
+        }
+        Some(Commands::Bench { laz_file }) => {
+            return bench::bench(laz_file.as_deref(), region.base_zoom_level);
+        }
+        Some(Commands::History {
+            tile,
+            since_hours,
+            failed,
+            limit,
+        }) => {
+            let query = HistoryQuery {
+                tile,
+                since_hours,
+                failed_only: failed,
+                limit,
+            };
+
+            return history::print_history(&args.history_db, &query);
+        }
+        Some(Commands::Config { .. }) => {}
+        Some(Commands::Status) => {
+            return control::print_status(required_control_socket(&args.control_socket)?);
+        }
+        Some(Commands::Control { command }) => {
+            return control::print_command_result(
+                required_control_socket(&args.control_socket)?,
+                &command.join(" "),
+            );
+        }
+        Some(Commands::Cache {
+            command: CacheCommands::Gc { dry_run },
+        }) => {
+            let work_dir = env::current_dir()?;
+            cache::print_cache_report(&work_dir);
+
+            // The files of the jobs of running workers are kept
+            let leased_jobs = journal::all_leased_jobs(&args.job_journal);
+            let freed = cache::collect_garbage(&work_dir, &cache_policy, &leased_jobs, dry_run);
+            println!(
+                "{} MB {}",
+                freed / 1_000_000,
+                if dry_run { "to free" } else { "freed" }
+            );
+
+            return Ok(());
+        }
+        None => {}
+    }
 
     let mapant_api_worker_id =
         env::var("MAPANT_API_WORKER_ID").expect("MAPANT_API_WORKER_ID environment variable not set.");
     let mapant_api_token =
         env::var("MAPANT_API_TOKEN").expect("MAPANT_API_TOKEN environment variable not set.");
-    let mapant_api_base_url =
-        env::var("MAPANT_API_BASE_URL").unwrap_or_else(|_| "https://mapant.fr".to_string());
+    // Primary first, then mirrors, eg: "https://mapant.fr,https://mirror.mapant.fr"
+    let mapant_api_base_urls = failover::parse_base_urls(
+        &env::var("MAPANT_API_BASE_URL").unwrap_or_else(|_| "https://mapant.fr".to_string()),
+    );
+    let mapant_api_base_url = mapant_api_base_urls
+        .first()
+        .cloned()
+        .expect("MAPANT_API_BASE_URL environment variable is empty.");
 
-    let args = Args::parse();
-    let threads = args.threads.unwrap_or(3);
-
-    let mut handles: Vec<JoinHandle<()>> = Vec::with_capacity(threads);
-
-    for _ in 0..threads {
-        let worker_id = mapant_api_worker_id.clone();
-        let token = mapant_api_token.clone();
-        let base_url = mapant_api_base_url.clone();
-
-        let spawned_thread = spawn(move || loop {
-            match get_and_handle_next_job(&worker_id, &token, &base_url) {
-                Ok(_) => {
-                    sleep(Duration::from_millis(1));
-                }
-                Err(error) => {
-                    error!("Error: {}. Restarting the thread...", error);
-                    sleep(Duration::from_secs(1));
-                }
-            }
-        });
+    // Artifacts can be served by other mirrors than the API, eg: a CDN
+    let mapant_download_base_urls = match env::var("MAPANT_API_DOWNLOAD_URLS") {
+        Ok(download_urls) => [
+            vec![mapant_api_base_url.clone()],
+            failover::parse_base_urls(&download_urls),
+        ]
+        .concat(),
+        Err(_) => mapant_api_base_urls.clone(),
+    };
+
+    failover::set_base_urls(mapant_api_base_urls, mapant_download_base_urls);
+
+    toolchain::log_toolchain();
+    capabilities::log_capabilities();
+    reporting::set_toolchain(toolchain::toolchain());
+    reporting::set_worker_id(&mapant_api_worker_id);
+    http::set_worker_id(&mapant_api_worker_id);
+    tags::set_worker_tags(args.tags.clone().unwrap_or_default());
+    if let Some(bbox) = args.bbox {
+        bbox::set_job_bbox(bbox);
+    }
+    let auth = ApiAuth::new(mapant_api_worker_id, mapant_api_token, args.auth_mode);
+
+    if !args.no_incremental {
+        incremental::enable_incremental_reprocessing(&args.input_hashes_file);
+    }
+
+    if let Some(job_events_file) = &args.job_events_file {
+        events::enable_job_events(job_events_file)?;
+    }
+
+    let history = if args.no_history {
+        None
+    } else {
+        Some(Arc::new(JobHistory::open(&args.history_db)?))
+    };
+
+    let metrics = args.metrics_push_interval.map(|_| Arc::new(MetricsQueue::new()));
+
+    let poisoned_jobs = if args.poison_threshold == 0 {
+        None
+    } else {
+        Some(Arc::new(PoisonedJobs::open(
+            &args.poisoned_jobs_file,
+            args.poison_threshold,
+            Duration::from_secs(args.poison_cooldown * 3600),
+        )))
+    };
+
+    let quota = match args.monthly_bandwidth_budget {
+        Some(budget) => Some(Arc::new(BandwidthQuota::open(
+            &args.bandwidth_usage_file,
+            budget * 1_000_000_000,
+        )?)),
+        None => None,
+    };
+
+    if !args.no_outbox {
+        outbox::set_outbox_dir(&args.outbox_dir)?;
+        outbox::spawn_outbox_replay(auth.clone(), Duration::from_secs(args.outbox_replay_interval))?;
+    }
+
+    // Config file replaced by a style render of a previous run that was killed
+    if render::restore_render_config()? {
+        warn!("Restored the render config replaced by an interrupted style render");
+    }
+
+    // Jobs left by a previous run that crashed or was killed, the API can requeue them right away
+    let journal = Arc::new(JobJournal::open(&args.job_journal)?);
+    journal.recover(&auth, &mapant_api_base_url);
+
+    let threads = match args.threads {
+        Some(threads) if args.force => threads,
+        Some(threads) => {
+            thread_count::check_threads(threads, args.reserved_cores, &env::current_dir()?)?;
+            threads
+        }
+        None => thread_count::auto_threads(args.reserved_cores, &env::current_dir()?),
+    };
+
+    // Threads above `threads` are spawned idle, to be activated at runtime
+    let max_threads = args.max_threads.unwrap_or(threads).max(threads);
+    let state = Arc::new(WorkerState::new(max_threads));
+    state.set_active_threads(threads);
+
+    if let Some(scratch_dir) = &args.scratch_dir {
+        scratch::set_scratch_dir(scratch_dir)?;
+    }
+
+    if args.disk_low_water_mark > 0 || args.scratch_budget.is_some() {
+        disk::spawn_disk_monitor(
+            state.clone(),
+            env::current_dir()?,
+            args.disk_low_water_mark * 1_000_000,
+            args.disk_high_water_mark.max(args.disk_low_water_mark) * 1_000_000,
+            args.scratch_budget
+                .map(|scratch_budget| scratch_budget * 1_000_000),
+        )?;
+    }
+
+    if args.memory_limit > 0 || args.job_memory_budget > 0 {
+        memory::spawn_memory_watchdog(
+            state.clone(),
+            MemoryLimits {
+                process: args.memory_limit * 1_000_000,
+                job: args.job_memory_budget * 1_000_000,
+            },
+        )?;
+    }
+
+    let cache_policy_set = cache_policy.budget.is_some() || !cache_policy.ttls.is_empty();
+
+    if cache_policy_set && args.cache_gc_interval > 0 {
+        cache::spawn_cache_gc(
+            state.clone(),
+            env::current_dir()?,
+            args.job_journal.clone(),
+            cache_policy,
+            Duration::from_secs(args.cache_gc_interval * 60),
+        )?;
+    }
+
+    if let Some(quiet_hours) = &args.quiet_hours {
+        schedule::spawn_scheduler(
+            QuietHours {
+                windows: schedule::parse_time_windows(quiet_hours)?,
+                threads: args.quiet_hours_threads,
+                bandwidth_limit: (args.quiet_hours_bandwidth * 1_000_000.0) as u64,
+            },
+            state.clone(),
+            threads,
+        )?;
+    }
+
+    if let Some(alert_webhook_url) = &args.alert_webhook_url {
+        alert::spawn_alert_monitor(
+            state.clone(),
+            alert_webhook_url.clone(),
+            auth.worker_id.clone(),
+            AlertThresholds {
+                consecutive_failures: args.alert_consecutive_failures,
+                api_unreachable_for: Duration::from_secs(args.alert_api_unreachable_minutes * 60),
+            },
+        )?;
+    }
+
+    if let Some(chat_webhook_url) = &args.chat_webhook_url {
+        chat::spawn_chat_notifier(
+            state.clone(),
+            auth.worker_id.clone(),
+            ChatSettings {
+                webhook_url: chat_webhook_url.clone(),
+                format: args
+                    .chat_format
+                    .unwrap_or_else(|| ChatFormat::from_url(chat_webhook_url)),
+                events: args.chat_events.clone(),
+                jobs_milestone: args.chat_jobs_milestone,
+                failure_streak: args.chat_failure_streak,
+            },
+        )?;
+    }
+
+    let context = WorkerContext {
+        auth: auth.clone(),
+        base_url: mapant_api_base_url.clone(),
+        region: region.clone(),
+        state: state.clone(),
+        // A prefetched job would be leased and never processed
+        prefetch_disk_budget: (args.prefetch && !args.once).then_some(args.prefetch_disk_budget * 1_000_000),
+        history: history.clone(),
+        metrics: metrics.clone(),
+        quota: quota.clone(),
+        poisoned_jobs,
+        job_attempts: args.job_attempts.max(1),
+        queue: Arc::new(JobQueue::new()),
+        in_flight: Arc::new(InFlightJobs::new()),
+        journal: journal.clone(),
+        once: args.once,
+        exit_when_idle: args.exit_when_idle,
+    };
+
+    if args.once {
+        return run_single_job(context);
+    }
+
+    let mut handles: Vec<JoinHandle<()>> = Vec::with_capacity(max_threads);
+
+    for thread_index in 0..max_threads {
+        let context = context.clone();
+        // Spread the first requests of the threads, and of the workers restarted at the same time
+        let startup_delay = Duration::from_secs_f64(args.startup_jitter * rate_limit::random_fraction());
+
+        let spawned_thread = thread::Builder::new()
+            .name(worker_thread_name(thread_index))
+            .spawn(move || {
+                context.state.sleep_unless_draining(startup_delay);
+                supervise_worker_thread(context, thread_index)
+            })?;
 
         handles.push(spawned_thread);
+    }
 
-        sleep(Duration::from_millis(200));
+    let max_job_duration = Duration::from_secs(args.max_job_duration * 60);
+
+    if let Some(health_port) = args.health_port {
+        health::spawn_health_server(
+            SocketAddr::new(args.health_bind, health_port),
+            state.clone(),
+            history.clone(),
+            max_job_duration,
+            args.web_ui,
+        )?;
     }
 
-    for handle in handles {
-        handle.join().unwrap();
+    if args.summary_interval > 0 {
+        let state = state.clone();
+        let summary_interval = Duration::from_secs(args.summary_interval * 60);
+
+        thread::Builder::new()
+            .name("summary".to_string())
+            .spawn(move || loop {
+                sleep(summary_interval);
+                info!("{}", state.run_summary());
+            })?;
     }
 
-    return Ok(());
-}
+    if args.status_file_interval > 0 {
+        status_file::spawn_status_file_writer(
+            state.clone(),
+            history.clone(),
+            args.status_file.clone(),
+            Duration::from_secs(args.status_file_interval),
+        )?;
+    }
 
-fn get_and_handle_next_job(
-    worker_id: &str,
-    token: &str,
-    base_url: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::blocking::Client::new();
-    let url = format!("{}/api/map-generation/next-job", base_url);
-
-    let res = client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}.{}", worker_id, token))
-        .send()?;
-
-    if !res.status().is_success() {
-        error!(
-            "Failed to call mapant generation 'next-job' endpoint. Status: {}",
-            res.status()
-        );
+    if let Some(textfile_metrics) = &args.textfile_metrics {
+        textfile_metrics::spawn_textfile_metrics_writer(
+            state.clone(),
+            textfile_metrics.clone(),
+            Duration::from_secs(args.textfile_metrics_interval.max(1)),
+        )?;
+    }
 
-        return Err("Failed to call endpoint".into());
+    if let (Some(metrics), Some(metrics_push_interval)) = (&metrics, args.metrics_push_interval) {
+        metrics_push::spawn_metrics_push(
+            metrics.clone(),
+            state.clone(),
+            auth.clone(),
+            mapant_api_base_url.clone(),
+            Duration::from_secs(metrics_push_interval),
+        )?;
     }
 
-    let text = res.text()?;
-    let job: Job = serde_json::from_str(&text)?;
+    if args.heartbeat_interval > 0 {
+        heartbeat::spawn_heartbeat(
+            state.clone(),
+            auth.clone(),
+            mapant_api_base_url.clone(),
+            env::current_dir()?,
+            Duration::from_secs(args.heartbeat_interval),
+        )?;
+    }
 
-    match job {
-        Job::Lidar { tile_id, tile_url } => {
-            info!("Handle Lidar job for tile {}", tile_id);
-            let start = Instant::now();
+    systemd::spawn_notifier(state.clone(), max_job_duration);
+    systemd::notify_ready();
 
-            lidar_step(&tile_id, &tile_url, worker_id, token, base_url)?;
+    scaling::spawn_thread_scaling(state.clone(), max_threads)?;
+    let shutdown_requested = shutdown::register_shutdown_signals()?;
+    if let Some(control_socket) = &args.control_socket {
+        control::spawn_control_socket(
+            control_socket,
+            state.clone(),
+            env::current_dir()?,
+            shutdown_requested.clone(),
+        )?;
+    }
 
-            let duration = start.elapsed();
-            info!("Lidar job for tile {} done in {:.1?}", &tile_id, duration);
+    if args.tui {
+        tui::spawn_tui(state.clone(), log_lines, shutdown_requested.clone())?;
+    }
 
-            get_and_handle_next_job(worker_id, token, base_url)?;
-        }
-        Job::Render {
-            tile_id,
-            neigbhoring_tiles_ids,
-        } => {
-            info!("Handle Render job for tile {}", tile_id);
-            let start = Instant::now();
+    shutdown::wait_for_threads(
+        handles,
+        &state,
+        &journal,
+        &shutdown_requested,
+        Duration::from_secs(args.drain_timeout),
+        &auth,
+        &mapant_api_base_url,
+    );
+
+    // Transfers of the prefetch threads since the last job
+    if let Some(quota) = &quota {
+        quota.record_transfers();
+    }
 
-            render_step(&tile_id, &neigbhoring_tiles_ids, worker_id, token, base_url)?;
+    tui::restore_terminal();
 
-            let duration = start.elapsed();
-            info!("Render job for tile {} done in {:.1?}", &tile_id, duration);
+    return Ok(());
+}
 
-            get_and_handle_next_job(worker_id, token, base_url)?;
-        }
-        Job::Pyramid {
-            x,
-            y,
-            z,
-            base_zoom_level_tile_id,
-            area_id,
-        } => {
-            info!("Handle Pyramid job x={}, y={}, z={}", x, y, z);
-            let start = Instant::now();
+/// Control socket of the running worker, for the status and control subcommands.
+fn required_control_socket(control_socket: &Option<PathBuf>) -> Result<&Path, Box<dyn std::error::Error>> {
+    control_socket
+        .as_deref()
+        .ok_or_else(|| "Pass the --control-socket the worker was started with".into())
+}
 
-            pyramid_step(
-                x,
-                y,
-                z,
-                base_zoom_level_tile_id,
-                area_id,
-                worker_id,
-                token,
-                base_url,
-            )?;
+/// Directory of a file, "." for a file name alone.
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
 
-            let duration = start.elapsed();
+/// Allow the modules of the worker to be referenced by their short name in the log filter
+/// (`utils=debug` instead of `mapant_fr_worker::utils=debug`). Short names are kept as is too, so
+/// that external crates (`reqwest=debug`) still work.
+fn expand_log_filter(log_filter: &str) -> String {
+    log_filter
+        .split(',')
+        .flat_map(|directive| match directive.split_once('=') {
+            Some((module, level)) if !module.contains("::") => vec![
+                directive.to_string(),
+                format!("{}::{}={}", module_path!(), module, level),
+            ],
+            _ => vec![directive.to_string()],
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
 
-            info!("Pyramid job x={}, y={}, z={} done in {:.1?}", x, y, z, duration);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            get_and_handle_next_job(worker_id, token, base_url)?;
-        }
-        Job::NoJobLeft => {
-            warn!("No job left, retrying in 30 seconds");
-            std::thread::sleep(std::time::Duration::from_secs(30));
-            get_and_handle_next_job(worker_id, token, base_url)?;
-        }
+    #[test]
+    fn expand_log_filter_adds_the_worker_modules() {
+        assert_eq!(
+            expand_log_filter("utils=debug,render=trace"),
+            "utils=debug,mapant_fr_worker::utils=debug,render=trace,mapant_fr_worker::render=trace"
+        );
     }
 
-    Ok(())
+    #[test]
+    fn expand_log_filter_keeps_the_other_directives() {
+        assert_eq!(expand_log_filter("info"), "info");
+        assert_eq!(
+            expand_log_filter("reqwest::connect=debug"),
+            "reqwest::connect=debug"
+        );
+        assert_eq!(
+            expand_log_filter("warn,reqwest=debug"),
+            "warn,reqwest=debug,mapant_fr_worker::reqwest=debug"
+        );
+    }
 }
@@ -0,0 +1,114 @@
+use log::{error, warn};
+use std::{sync::Arc, thread, time::Duration};
+use sysinfo::{get_current_pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+use crate::state::{worker_thread_name, WorkerState};
+
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Memory thresholds in bytes above which jobs are aborted, 0 to disable.
+pub struct MemoryLimits {
+    /// Resident memory of the whole process
+    pub process: u64,
+    /// Growth of the process memory since a job started
+    pub job: u64,
+}
+
+/// Periodically sample the memory of the process. The memory of a job can't be told apart from the
+/// one of the jobs running alongside, so a single job is aborted at a time, the newest one, at its
+/// next stage or transfer: the newest job exceeding its budget, or the newest job when the process
+/// exceeds its limit. Its memory is released before the next one is considered. A job in an
+/// in-process cassini step is only stopped once the step returns.
+pub fn spawn_memory_watchdog(
+    state: Arc<WorkerState>,
+    limits: MemoryLimits,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pid = get_current_pid()?;
+
+    thread::Builder::new()
+        .name("memory-watchdog".to_string())
+        .spawn(move || {
+            let mut system = System::new();
+
+            loop {
+                system.refresh_processes_specifics(
+                    ProcessesToUpdate::Some(&[pid]),
+                    false,
+                    ProcessRefreshKind::nothing().with_memory(),
+                );
+
+                let Some(process_memory) = system.process(pid).map(|process| process.memory()) else {
+                    error!("Could not read the memory of the worker process");
+                    thread::sleep(MEMORY_CHECK_INTERVAL);
+                    continue;
+                };
+
+                state.set_process_memory(process_memory);
+                let threads = state.thread_stats();
+
+                if threads.iter().any(|thread| thread.aborting) {
+                    thread::sleep(MEMORY_CHECK_INTERVAL);
+                    continue;
+                }
+
+                // The newest job has the smallest estimate, the growth since it started
+                let newest_job = |over_job_budget: bool| {
+                    threads
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, thread)| {
+                            thread
+                                .memory_estimate
+                                .is_some_and(|estimate| !over_job_budget || estimate > limits.job)
+                        })
+                        .min_by_key(|(_, thread)| thread.job_elapsed)
+                        .map(|(thread_index, thread)| (thread_index, thread.memory_estimate.unwrap_or(0)))
+                };
+
+                if limits.job > 0 {
+                    if let Some((thread_index, memory_estimate)) = newest_job(true) {
+                        abort_job(
+                            &state,
+                            thread_index,
+                            format!(
+                                "the worker grew by {} MB since it started, above the job budget of {} MB",
+                                memory_estimate / 1_000_000,
+                                limits.job / 1_000_000
+                            ),
+                        );
+
+                        thread::sleep(MEMORY_CHECK_INTERVAL);
+                        continue;
+                    }
+                }
+
+                if limits.process > 0 && process_memory > limits.process {
+                    if let Some((thread_index, _)) = newest_job(false) {
+                        abort_job(
+                            &state,
+                            thread_index,
+                            format!(
+                                "the worker uses {} MB, above the limit of {} MB",
+                                process_memory / 1_000_000,
+                                limits.process / 1_000_000
+                            ),
+                        );
+                    }
+                }
+
+                thread::sleep(MEMORY_CHECK_INTERVAL);
+            }
+        })?;
+
+    Ok(())
+}
+
+fn abort_job(state: &WorkerState, thread_index: usize, reason: String) {
+    if state.request_abort(thread_index, reason.clone()) {
+        warn!(
+            "Aborting the job of {}: {}",
+            worker_thread_name(thread_index),
+            reason
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use log::{debug, warn};
+use reqwest::blocking::Client;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{auth::ApiAuth, http::http_client, response::describe_error_response, state::WorkerState};
+
+/// Maximum number of job metrics kept while the API is unreachable, the oldest are dropped first
+const MAX_PENDING_JOB_METRICS: usize = 10_000;
+const PUSH_ATTEMPTS: u32 = 3;
+
+/// Metrics of a processed job, pushed with the next batch.
+#[derive(Serialize, Clone)]
+pub struct JobMetric {
+    pub job_type: String,
+    pub tile: String,
+    pub correlation_id: Option<String>,
+    /// Unix timestamp in seconds
+    pub ended_at: u64,
+    pub duration_ms: u64,
+    pub succeeded: bool,
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    /// Effective throughputs of the job transfers, none if nothing was transferred
+    pub download_bytes_per_second: Option<u64>,
+    pub upload_bytes_per_second: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct MetricsBatch<'a> {
+    jobs: &'a [JobMetric],
+    busy_threads: usize,
+    threads: usize,
+    consecutive_failures: u64,
+    disk_paused: bool,
+}
+
+/// Job metrics waiting to be pushed to the API.
+pub struct MetricsQueue {
+    pending: Mutex<VecDeque<JobMetric>>,
+}
+
+impl MetricsQueue {
+    pub fn new() -> Self {
+        MetricsQueue {
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn push(&self, metric: JobMetric) {
+        let mut pending = self.pending.lock().unwrap();
+
+        if pending.len() >= MAX_PENDING_JOB_METRICS {
+            pending.pop_front();
+        }
+
+        pending.push_back(metric);
+    }
+}
+
+/// Periodically push the metrics of the processed jobs to the API, for deployments without
+/// Prometheus. A batch that could not be sent is kept and retried with the next one.
+pub fn spawn_metrics_push(
+    queue: Arc<MetricsQueue>,
+    state: Arc<WorkerState>,
+    auth: ApiAuth,
+    base_url: String,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    thread::Builder::new()
+        .name("metrics-push".to_string())
+        .spawn(move || {
+            let client = http_client();
+            let url = format!("{}/api/map-generation/worker-metrics", base_url);
+
+            loop {
+                thread::sleep(interval);
+
+                let jobs: Vec<JobMetric> = queue.pending.lock().unwrap().iter().cloned().collect();
+                let threads = state.thread_stats();
+
+                let batch = MetricsBatch {
+                    jobs: &jobs,
+                    busy_threads: threads
+                        .iter()
+                        .filter(|thread| thread.current_job.is_some())
+                        .count(),
+                    threads: threads.len(),
+                    consecutive_failures: state.consecutive_failures(),
+                    disk_paused: state.is_disk_paused(),
+                };
+
+                if push_batch(&client, &auth, &url, &base_url, &batch) {
+                    debug!("Pushed metrics of {} jobs", jobs.len());
+
+                    // Jobs ended during the push stay in the queue for the next batch
+                    let mut pending = queue.pending.lock().unwrap();
+                    let pushed = jobs.len().min(pending.len());
+                    pending.drain(..pushed);
+                } else {
+                    warn!(
+                        "Failed to push worker metrics, {} jobs kept for the next attempt",
+                        jobs.len()
+                    );
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Send a batch, retrying with an increasing delay. Returns whether it was accepted.
+fn push_batch(client: &Client, auth: &ApiAuth, url: &str, base_url: &str, batch: &MetricsBatch) -> bool {
+    for attempt in 1..=PUSH_ATTEMPTS {
+        match auth.send(client.post(url).header("Origin", base_url).json(batch)) {
+            Ok(response) if response.status().is_success() => return true,
+            Ok(response) => debug!(
+                "Metrics push attempt {} rejected. {}",
+                attempt,
+                describe_error_response(response)
+            ),
+            Err(error) => debug!("Metrics push attempt {} failed: {}", attempt, error),
+        }
+
+        if attempt < PUSH_ATTEMPTS {
+            thread::sleep(Duration::from_secs(2u64.pow(attempt)));
+        }
+    }
+
+    false
+}
@@ -0,0 +1,317 @@
+use log::info;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{create_dir_all, remove_dir_all, write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    artifacts::UploadManifest,
+    auth::ApiAuth,
+    disk::available_space,
+    http::http_client,
+    raster::{check_raster, creation_option_args, finish_raster},
+    region::RegionProfile,
+    scratch::scratch_dir,
+    state::report_stage,
+    subprocess::{run_subprocess_checked, subprocess_command},
+    utils::{decompress_archive, directory_size, download_artifact, upload_artifacts, StorageHints},
+};
+
+/// Raster assembled by a mosaic job.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum MosaicLayer {
+    /// Digital elevation model, from the rasters of the render steps
+    Dem,
+    /// Rendered map, from the full map pngs of the render steps
+    FullMap,
+}
+
+impl MosaicLayer {
+    fn name(&self) -> &'static str {
+        match self {
+            MosaicLayer::Dem => "dem",
+            MosaicLayer::FullMap => "full-map",
+        }
+    }
+}
+
+/// Assemble the render step outputs of `tiles_ids` into a single Cloud Optimized GeoTIFF, and
+/// upload it as the `{layer}.tif` artifact of the area.
+pub fn mosaic_step(
+    area_id: &str,
+    tiles_ids: &Vec<String>,
+    layer: MosaicLayer,
+    auth: &ApiAuth,
+    base_api_url: &str,
+    region: &RegionProfile,
+    storage: Option<&StorageHints>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mosaic_dir_path = scratch_dir()
+        .join("mosaic")
+        .join(format!("{}-{}", area_id, layer.name()));
+
+    // Left behind by an interrupted job
+    if mosaic_dir_path.exists() {
+        remove_dir_all(&mosaic_dir_path)?;
+    }
+
+    create_dir_all(&mosaic_dir_path)?;
+
+    let mosaic = Mosaic {
+        dir_path: &mosaic_dir_path,
+        area_id,
+        tiles_ids,
+        layer,
+    };
+    let result = build_and_upload_mosaic(&mosaic, auth, base_api_url, region, storage);
+
+    // Mosaics are only built once, nothing worth keeping in the cache
+    remove_dir_all(&mosaic_dir_path)?;
+
+    result
+}
+
+/// Mosaic being built by a mosaic job.
+struct Mosaic<'a> {
+    /// Scratch directory of the rasters and of the mosaic
+    dir_path: &'a Path,
+    area_id: &'a str,
+    tiles_ids: &'a [String],
+    layer: MosaicLayer,
+}
+
+fn build_and_upload_mosaic(
+    mosaic: &Mosaic,
+    auth: &ApiAuth,
+    base_api_url: &str,
+    region: &RegionProfile,
+    storage: Option<&StorageHints>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Mosaic {
+        dir_path: mosaic_dir_path,
+        area_id,
+        tiles_ids,
+        layer,
+    } = *mosaic;
+    let client = http_client();
+
+    report_stage("downloading rasters");
+    info!(
+        "Downloading the {} rasters of {} tiles for area {}",
+        layer.name(),
+        tiles_ids.len(),
+        area_id
+    );
+    let start = Instant::now();
+
+    let mut rasters_paths: Vec<PathBuf> = vec![];
+
+    for (index, tile_id) in tiles_ids.iter().enumerate() {
+        let tile_dir_path = mosaic_dir_path.join(tile_id);
+        create_dir_all(&tile_dir_path)?;
+
+        let raster_path = match layer {
+            MosaicLayer::Dem => download_dem(&client, tile_id, &tile_dir_path, auth, base_api_url, storage)?,
+            MosaicLayer::FullMap => download_full_map(
+                &client,
+                tile_id,
+                &tile_dir_path,
+                auth,
+                base_api_url,
+                region,
+                storage,
+            )?,
+        };
+
+        rasters_paths.push(raster_path);
+
+        // The sizes of the rasters are only known once downloaded
+        if index == 0 {
+            check_scratch_space(mosaic_dir_path, directory_size(&tile_dir_path), tiles_ids.len())?;
+        }
+    }
+
+    info!(
+        "Rasters for the {} mosaic of area {} downloaded in {:.1?}",
+        layer.name(),
+        area_id,
+        start.elapsed()
+    );
+
+    report_stage("assembling mosaic");
+    info!("Assembling the {} mosaic of area {}", layer.name(), area_id);
+    let start = Instant::now();
+
+    let vrt_path = mosaic_dir_path.join("mosaic.vrt");
+
+    // In a file, the paths of thousands of tiles go beyond the maximum size of the arguments
+    let rasters_list_path = mosaic_dir_path.join("rasters.txt");
+    write(
+        &rasters_list_path,
+        rasters_paths
+            .iter()
+            .map(|raster_path| format!("{}\n", raster_path.display()))
+            .collect::<String>(),
+    )?;
+
+    run_subprocess_checked(
+        subprocess_command("gdalbuildvrt")
+            .arg("-input_file_list")
+            .arg(&rasters_list_path)
+            .arg(&vrt_path)
+            .arg("-q"),
+        "gdalbuildvrt",
+    )?;
+
+    let mosaic_file_name = format!("{}.tif", layer.name());
+    let mosaic_path = mosaic_dir_path.join(&mosaic_file_name);
+
+    run_subprocess_checked(
+        subprocess_command("gdal_translate")
+            .args(["-of", "COG"])
+            .args(["-co", "COMPRESS=DEFLATE"])
+            .args(["-co", "BIGTIFF=IF_SAFER"])
+            .arg(&vrt_path)
+            .arg(&mosaic_path)
+            .arg("--quiet"),
+        "gdal_translate",
+    )?;
+    check_raster(&mosaic_path, region.epsg)?;
+
+    info!(
+        "Mosaic {} of area {} assembled in {:.1?}",
+        layer.name(),
+        area_id,
+        start.elapsed()
+    );
+
+    report_stage("uploading");
+    let url = format!(
+        "{}/api/map-generation/mosaics/{}/{}",
+        base_api_url,
+        area_id,
+        layer.name()
+    );
+
+    upload_artifacts(
+        &client,
+        auth,
+        url,
+        base_api_url,
+        &UploadManifest::single(&mosaic_file_name, "mosaic", &mosaic_path, "image/tiff")?,
+        storage,
+        &format!("mosaics/{}", area_id),
+    )?;
+
+    Ok(())
+}
+
+/// Fail before downloading the rasters of every tile if the scratch directory can not hold them,
+/// estimated from the size of the files of the first tile. The mosaic is at most as large as the
+/// rasters.
+fn check_scratch_space(
+    mosaic_dir_path: &Path,
+    first_tile_size: u64,
+    tiles_count: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The other tiles, and the mosaic
+    let needed_space = first_tile_size.saturating_mul(2 * tiles_count as u64 - 1);
+
+    match available_space(mosaic_dir_path) {
+        Some(free_space) if free_space < needed_space => Err(format!(
+            "The mosaic needs about {} MB of scratch space, {} MB free",
+            needed_space / 1_000_000,
+            free_space / 1_000_000
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Download and decompress the rasters of a render step, returning the path of its DEM.
+fn download_dem(
+    client: &Client,
+    tile_id: &str,
+    tile_dir_path: &Path,
+    auth: &ApiAuth,
+    base_api_url: &str,
+    storage: Option<&StorageHints>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let archive_path = tile_dir_path.join(format!("rasters_{}.tar.xz", tile_id));
+
+    download_artifact(
+        client,
+        &format!(
+            "{}/api/map-generation/render-steps/{}/rasters",
+            base_api_url, tile_id
+        ),
+        &archive_path,
+        Some(auth),
+        storage,
+        &format!("render-steps/{}/rasters_{}.tar.xz", tile_id, tile_id),
+    )?;
+
+    let rasters_dir_path = tile_dir_path.join("rasters");
+    create_dir_all(&rasters_dir_path)?;
+    decompress_archive(&archive_path, &rasters_dir_path)?;
+
+    let dem_path = rasters_dir_path.join("dem.tif");
+
+    if !dem_path.exists() {
+        return Err(format!("No DEM in the rasters of tile {}", tile_id).into());
+    }
+
+    Ok(dem_path)
+}
+
+/// Download the full map png of a render step and georeference it from its tile id, since pngs
+/// carry no coordinates.
+fn download_full_map(
+    client: &Client,
+    tile_id: &str,
+    tile_dir_path: &Path,
+    auth: &ApiAuth,
+    base_api_url: &str,
+    region: &RegionProfile,
+    storage: Option<&StorageHints>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let png_path = tile_dir_path.join("full-map.png");
+
+    download_artifact(
+        client,
+        &format!(
+            "{}/api/map-generation/render-steps/{}/full-map",
+            base_api_url, tile_id
+        ),
+        &png_path,
+        Some(auth),
+        storage,
+        &format!("render-steps/{}/full-map.png", tile_id),
+    )?;
+
+    let (min_x, min_y, max_x, max_y) = region.get_extent_from_tile_id(tile_id);
+    let tif_path = tile_dir_path.join("full-map.tif");
+
+    run_subprocess_checked(
+        subprocess_command("gdal_translate")
+            .args(["-a_srs", &format!("EPSG:{}", region.epsg)])
+            .args([
+                "-a_ullr",
+                &min_x.to_string(),
+                &max_y.to_string(),
+                &max_x.to_string(),
+                &min_y.to_string(),
+            ])
+            .args(creation_option_args())
+            .arg(&png_path)
+            .arg(&tif_path)
+            .arg("--quiet"),
+        "gdal_translate",
+    )?;
+    finish_raster(&tif_path, region.epsg)?;
+
+    Ok(tif_path)
+}
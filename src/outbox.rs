@@ -0,0 +1,326 @@
+use log::{debug, error, info, warn};
+use reqwest::{blocking::Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{copy, create_dir_all, hard_link, read_dir, read_to_string, remove_dir_all, rename, write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+use crate::{
+    artifacts::Artifact,
+    auth::ApiAuth,
+    circuit::CircuitOpen,
+    http::http_client,
+    utils::{send_artifacts, send_json, StorageHints},
+};
+
+static OUTBOX_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Replays failing for another reason than the API being unreachable are given up after that
+const MAX_REPLAY_ATTEMPTS: u32 = 10;
+
+const REQUEST_FILE_NAME: &str = "request.json";
+const FILES_DIR_NAME: &str = "files";
+
+/// Keep the results of the jobs finished while the API is unreachable in `path`, to send them
+/// once it is back instead of failing the jobs.
+pub fn set_outbox_dir(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    create_dir_all(path)?;
+    let _ = OUTBOX_DIR.set(path.to_path_buf());
+
+    Ok(())
+}
+
+fn outbox_dir() -> Option<&'static Path> {
+    OUTBOX_DIR.get().map(|path| path.as_path())
+}
+
+/// Status answered by the reverse proxy of the API while its backend is down.
+#[derive(Debug)]
+pub struct ApiUnavailable(pub StatusCode);
+
+impl fmt::Display for ApiUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "API unavailable, status: {}", self.0)
+    }
+}
+
+impl std::error::Error for ApiUnavailable {}
+
+pub fn is_gateway_error(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Whether a request failed because the API could not be reached, rather than because it was
+/// refused.
+pub fn is_api_unreachable(error: &(dyn std::error::Error + 'static)) -> bool {
+    error.is::<ApiUnavailable>()
+        || error.is::<CircuitOpen>()
+        || error
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|error| error.is_connect() || error.is_timeout())
+}
+
+/// A request that could not reach the API, as persisted in the outbox.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+enum PendingRequest {
+    /// Files sent with `upload_artifacts`, copied to the `files` directory of the entry
+    Upload {
+        url: String,
+        origin: String,
+        files: Vec<Artifact>,
+        storage: Option<StorageHints>,
+        key_prefix: String,
+    },
+    /// JSON body posted to the API
+    Json {
+        url: String,
+        origin: String,
+        body: serde_json::Value,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutboxEntry {
+    request: PendingRequest,
+    #[serde(default)]
+    attempts: u32,
+}
+
+/// Keep an upload in the outbox when it failed because the API is unreachable. Other errors are
+/// returned as is, as well as every error when there is no outbox.
+pub fn keep_upload_if_unreachable(
+    result: Result<(), Box<dyn std::error::Error>>,
+    url: &str,
+    origin: &str,
+    artifacts: &[Artifact],
+    storage: Option<&StorageHints>,
+    key_prefix: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match result {
+        Err(error) if outbox_dir().is_some() && is_api_unreachable(error.as_ref()) => {
+            warn!("{}, keeping the upload to {} in the outbox", error, url);
+
+            let request = PendingRequest::Upload {
+                url: url.to_string(),
+                origin: origin.to_string(),
+                files: artifacts.to_vec(),
+                storage: storage.cloned(),
+                key_prefix: key_prefix.to_string(),
+            };
+
+            store_entry(request, artifacts)
+        }
+        result => result,
+    }
+}
+
+/// Same as `keep_upload_if_unreachable`, for a JSON body.
+pub fn keep_json_if_unreachable(
+    result: Result<(), Box<dyn std::error::Error>>,
+    url: &str,
+    origin: &str,
+    body: &serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match result {
+        Err(error) if outbox_dir().is_some() && is_api_unreachable(error.as_ref()) => {
+            warn!("{}, keeping the request to {} in the outbox", error, url);
+
+            let request = PendingRequest::Json {
+                url: url.to_string(),
+                origin: origin.to_string(),
+                body: body.clone(),
+            };
+
+            store_entry(request, &[])
+        }
+        result => result,
+    }
+}
+
+/// Entries are directories named after their creation time, so that they are replayed in order.
+/// They are written with a `.partial` suffix then renamed, a crash while writing leaves no
+/// incomplete entry to replay.
+fn store_entry(request: PendingRequest, artifacts: &[Artifact]) -> Result<(), Box<dyn std::error::Error>> {
+    let outbox_dir = outbox_dir().ok_or("No outbox directory")?;
+
+    let entry_name = format!(
+        "{}-{}",
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis(),
+        Uuid::new_v4()
+    );
+
+    let partial_entry_dir = outbox_dir.join(format!("{}.partial", entry_name));
+    let files_dir = partial_entry_dir.join(FILES_DIR_NAME);
+    create_dir_all(&files_dir)?;
+
+    for artifact in artifacts {
+        // The job's directories are removed once it is done, the outputs are kept with the entry
+        if hard_link(&artifact.path, files_dir.join(&artifact.name)).is_err() {
+            copy(&artifact.path, files_dir.join(&artifact.name))?;
+        }
+    }
+
+    write_entry(&partial_entry_dir, &OutboxEntry { request, attempts: 0 })?;
+    rename(&partial_entry_dir, outbox_dir.join(entry_name))?;
+
+    Ok(())
+}
+
+fn read_entry(entry_dir: &Path) -> Result<OutboxEntry, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_str(&read_to_string(
+        entry_dir.join(REQUEST_FILE_NAME),
+    )?)?)
+}
+
+fn write_entry(entry_dir: &Path, entry: &OutboxEntry) -> Result<(), Box<dyn std::error::Error>> {
+    let temporary_path = entry_dir.join(format!("{}.tmp", REQUEST_FILE_NAME));
+    write(&temporary_path, serde_json::to_string(entry)?)?;
+    rename(&temporary_path, entry_dir.join(REQUEST_FILE_NAME))?;
+
+    Ok(())
+}
+
+/// Periodically replay the outbox entries, oldest first, until the API is unreachable again.
+pub fn spawn_outbox_replay(auth: ApiAuth, interval: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(outbox_dir) = outbox_dir() else {
+        return Ok(());
+    };
+
+    thread::Builder::new()
+        .name("outbox".to_string())
+        .spawn(move || loop {
+            replay_outbox(outbox_dir, &auth);
+            thread::sleep(interval);
+        })?;
+
+    Ok(())
+}
+
+fn replay_outbox(outbox_dir: &Path, auth: &ApiAuth) {
+    let Ok(dir_entries) = read_dir(outbox_dir) else {
+        return;
+    };
+
+    let mut entry_dirs: Vec<PathBuf> = dir_entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.join(REQUEST_FILE_NAME).exists() && path.extension().is_none())
+        .collect();
+
+    if entry_dirs.is_empty() {
+        return;
+    }
+
+    entry_dirs.sort();
+    info!("Replaying {} requests from the outbox", entry_dirs.len());
+
+    let client = http_client();
+
+    for entry_dir in entry_dirs {
+        let mut entry = match read_entry(&entry_dir) {
+            Ok(entry) => entry,
+            Err(error) => {
+                error!("Failed to read outbox entry {}: {}", entry_dir.display(), error);
+                continue;
+            }
+        };
+
+        match replay_entry(&client, auth, &entry_dir, &entry.request) {
+            Ok(()) => {
+                info!("Outbox entry {} replayed", entry_dir.display());
+
+                if let Err(error) = remove_dir_all(&entry_dir) {
+                    error!("Failed to remove outbox entry {}: {}", entry_dir.display(), error);
+                }
+            }
+            Err(error) if is_api_unreachable(error.as_ref()) => {
+                debug!("API still unreachable, replaying the outbox later: {}", error);
+                return;
+            }
+            Err(error) => {
+                entry.attempts += 1;
+
+                if entry.attempts >= MAX_REPLAY_ATTEMPTS {
+                    error!(
+                        "Giving up outbox entry {} after {} attempts: {}",
+                        entry_dir.display(),
+                        entry.attempts,
+                        error
+                    );
+
+                    let _ = remove_dir_all(&entry_dir);
+                    continue;
+                }
+
+                warn!(
+                    "Failed to replay outbox entry {}, attempt {}: {}",
+                    entry_dir.display(),
+                    entry.attempts,
+                    error
+                );
+
+                if let Err(error) = write_entry(&entry_dir, &entry) {
+                    error!("Failed to update outbox entry {}: {}", entry_dir.display(), error);
+                }
+            }
+        }
+    }
+}
+
+fn replay_entry(
+    client: &Client,
+    auth: &ApiAuth,
+    entry_dir: &Path,
+    request: &PendingRequest,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match request {
+        PendingRequest::Upload {
+            url,
+            origin,
+            files,
+            storage,
+            key_prefix,
+        } => {
+            let files = files
+                .iter()
+                .map(|file| Artifact {
+                    path: entry_dir.join(FILES_DIR_NAME).join(&file.name),
+                    ..file.clone()
+                })
+                .collect::<Vec<_>>();
+
+            let result = send_artifacts(
+                client,
+                auth,
+                url.clone(),
+                origin,
+                &files,
+                storage.as_ref(),
+                key_prefix,
+            );
+
+            match result {
+                // Presigned urls expire during long outages, the API accepts the files too
+                Err(error) if storage.is_some() && !is_api_unreachable(error.as_ref()) => {
+                    warn!(
+                        "Failed to upload to the storage ({}), uploading through the API",
+                        error
+                    );
+                    send_artifacts(client, auth, url.clone(), origin, &files, None, key_prefix)
+                }
+                result => result,
+            }
+        }
+        PendingRequest::Json { url, origin, body } => send_json(client, auth, url, origin, body),
+    }
+}
@@ -0,0 +1,37 @@
+use log::error;
+use std::{
+    any::Any,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+};
+
+use crate::state::JobAborted;
+
+/// Message of a panic payload, as given to `panic!`.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Call cassini, turning a panic into an error failing the job only, eg: on a corrupted LAZ
+/// file. Aborts requested by the worker keep unwinding.
+pub fn catch_cassini_panic<T, F: FnOnce() -> T>(
+    step: &str,
+    call: F,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let payload = match catch_unwind(AssertUnwindSafe(call)) {
+        Ok(value) => return Ok(value),
+        Err(payload) => payload,
+    };
+
+    if payload.is::<JobAborted>() {
+        resume_unwind(payload);
+    }
+
+    let message = panic_message(payload.as_ref());
+    error!("Cassini panicked during the {}: {}", step, message);
+
+    Err(format!("Cassini panicked during the {}: {}", step, message).into())
+}
@@ -0,0 +1,160 @@
+use log::{info, warn};
+use reqwest::{
+    blocking::RequestBuilder,
+    header::{IF_RANGE, RANGE},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_to_string, remove_file, rename, write, File, OpenOptions},
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// Where a download left in a `.part` file stopped, in a `.part.json` file next to it.
+#[derive(Serialize, Deserialize)]
+struct PartialDownloadState {
+    /// Without the query string, as the presigned urls change from one request to the next
+    url: String,
+    etag: Option<String>,
+    /// Bytes of the `.part` file known to be written
+    offset: u64,
+}
+
+/// A download written to `<file>.part`, kept when interrupted so that the next download of the
+/// same url, eg: after a restart, resumes it with a Range request instead of starting over.
+pub struct PartialDownload {
+    part_path: PathBuf,
+    state_path: PathBuf,
+    url: String,
+    etag: Option<String>,
+    offset: u64,
+}
+
+impl PartialDownload {
+    /// The interrupted download of `url` into `file_path` if any, truncated to its recorded
+    /// offset, an empty one otherwise.
+    pub fn open(file_path: &Path, url: &str) -> Self {
+        let part_path = PathBuf::from(format!("{}.part", file_path.display()));
+        let state_path = PathBuf::from(format!("{}.part.json", file_path.display()));
+        let url = url.split('?').next().unwrap_or(url).to_string();
+
+        let state = read_to_string(&state_path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<PartialDownloadState>(&content).ok())
+            .filter(|state| state.url == url && state.offset > 0)
+            .filter(|state| {
+                part_path
+                    .metadata()
+                    .is_ok_and(|metadata| metadata.len() >= state.offset)
+            });
+
+        let mut partial_download = PartialDownload {
+            part_path,
+            state_path,
+            url,
+            etag: None,
+            offset: 0,
+        };
+
+        match state {
+            Some(state) => {
+                partial_download.etag = state.etag;
+                partial_download.offset = state.offset;
+            }
+            None => partial_download.discard(),
+        }
+
+        partial_download
+    }
+
+    /// Bytes already downloaded.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Ask for the rest of the file only, all of it if it changed since the first bytes were
+    /// received.
+    pub fn resume_request(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request.header(RANGE, format!("bytes={}-", self.offset));
+
+        match &self.etag {
+            Some(etag) => request.header(IF_RANGE, etag),
+            None => request,
+        }
+    }
+
+    /// The `.part` file to write the response to, after the bytes already downloaded if the
+    /// response resumes them.
+    pub fn file(&mut self, resumed: bool, etag: Option<String>) -> std::io::Result<File> {
+        if resumed {
+            info!(
+                "Resuming the download of {} after {} MB",
+                self.url,
+                self.offset / 1_000_000
+            );
+        } else {
+            self.offset = 0;
+        }
+
+        self.etag = etag;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&self.part_path)?;
+        file.set_len(self.offset)?;
+        file.seek(SeekFrom::End(0))?;
+
+        // A crash from now on resumes from the current offset
+        self.save_state();
+
+        Ok(file)
+    }
+
+    /// Record how far the download went, to resume it later.
+    pub fn keep(&mut self, file: &File) {
+        match file.sync_all().and_then(|_| file.metadata()) {
+            Ok(metadata) => {
+                self.offset = metadata.len();
+                self.save_state();
+            }
+            Err(error) => warn!("Failed to flush {}: {}", self.part_path.display(), error),
+        }
+    }
+
+    /// Move the complete download to `file_path`.
+    pub fn finish(self, file_path: &Path) -> std::io::Result<()> {
+        rename(&self.part_path, file_path)?;
+        let _ = remove_file(&self.state_path);
+
+        Ok(())
+    }
+
+    /// Remove the `.part` file, the next download starts over.
+    pub fn discard(&mut self) {
+        let _ = remove_file(&self.part_path);
+        let _ = remove_file(&self.state_path);
+        self.offset = 0;
+    }
+
+    fn save_state(&self) {
+        let state = PartialDownloadState {
+            url: self.url.clone(),
+            etag: self.etag.clone(),
+            offset: self.offset,
+        };
+
+        let result = serde_json::to_string(&state)
+            .map_err(std::io::Error::other)
+            .and_then(|content| write(&self.state_path, content));
+
+        if let Err(error) = result {
+            warn!(
+                "Failed to record the progress of {}: {}",
+                self.part_path.display(),
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use log::error;
+use reqwest::blocking::Client;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, OnceLock};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::http::http_client_builder;
+
+/// Hex encoded SHA-256 fingerprints.
+pub type Fingerprints = Vec<String>;
+
+static PINNED_FINGERPRINTS: OnceLock<Fingerprints> = OnceLock::new();
+static PINNED_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Only connect to the API servers whose certificate or public key has one of these hex encoded
+/// SHA-256 fingerprints.
+pub fn set_pinned_fingerprints(fingerprints: Fingerprints) {
+    let _ = PINNED_FINGERPRINTS.set(fingerprints);
+}
+
+/// Parse comma separated SHA-256 fingerprints, with or without colons, eg: as printed by
+/// `openssl x509 -noout -fingerprint -sha256`.
+pub fn parse_fingerprints(value: &str) -> Result<Fingerprints, String> {
+    value
+        .split(',')
+        .map(|fingerprint| {
+            let normalized = fingerprint.trim().replace(':', "").to_lowercase();
+
+            if normalized.len() != 64 || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!(
+                    "Invalid fingerprint \"{}\", expected a hex encoded SHA-256",
+                    fingerprint
+                ));
+            }
+
+            Ok(normalized)
+        })
+        .collect()
+}
+
+/// Client checking the certificate of the server during the TLS handshake, to send the API
+/// requests when fingerprints are pinned. None otherwise.
+pub fn pinned_client() -> Option<&'static Client> {
+    let fingerprints = PINNED_FINGERPRINTS.get()?;
+
+    Some(PINNED_CLIENT.get_or_init(|| {
+        let provider = Arc::new(ring::default_provider());
+        let verifier = PinnedCertificateVerifier {
+            fingerprints: fingerprints.clone(),
+            provider: provider.clone(),
+        };
+
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("Failed to configure TLS")
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+
+        http_client_builder()
+            .use_preconfigured_tls(config)
+            .build()
+            .expect("Failed to build the HTTP client")
+    }))
+}
+
+/// Accept only the servers whose certificate or public key has one of the pinned fingerprints,
+/// before anything is sent over the connection. The pin replaces the validation by the certificate
+/// authorities, the handshake signatures are still checked so that the server proves it holds the
+/// pinned key.
+#[derive(Debug)]
+struct PinnedCertificateVerifier {
+    fingerprints: Fingerprints,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let certificate_fingerprint = hex::encode(Sha256::digest(end_entity));
+        let public_key_fingerprint = public_key_fingerprint(end_entity);
+
+        let pinned = self.fingerprints.iter().any(|fingerprint| {
+            *fingerprint == certificate_fingerprint || Some(fingerprint) == public_key_fingerprint.as_ref()
+        });
+
+        if !pinned {
+            error!(
+                "Certificate of {} matches none of the pinned fingerprints: certificate {}, public key {}",
+                server_name.to_str(),
+                certificate_fingerprint,
+                public_key_fingerprint.as_deref().unwrap_or("unknown")
+            );
+
+            return Err(rustls::Error::General(format!(
+                "Certificate of {} is not pinned",
+                server_name.to_str()
+            )));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Hex encoded SHA-256 of the DER encoded SubjectPublicKeyInfo of a DER certificate, what public
+/// key pins are computed on: `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der |
+/// sha256sum`. None if the certificate can not be parsed.
+fn public_key_fingerprint(certificate: &[u8]) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+
+    Some(hex::encode(Sha256::digest(certificate.public_key().raw)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed P-256 certificate for api.mapant.test
+    const CERTIFICATE_DER_HEX: &str = concat!(
+        "3082018a30820131a00302010202147c8ef34428128e422c2c25905f6f2c148b17ffb0300a06082a8648ce3d04030230",
+        "1a3118301606035504030c0f6170692e6d6170616e742e746573743020170d3236313031383032333733375a180f3231",
+        "3236303932343032333733375a301a3118301606035504030c0f6170692e6d6170616e742e746573743059301306072a",
+        "8648ce3d020106082a8648ce3d0301070342000435f6590d9da89f16ead486370e36eebbbe76d18aa6b71f275b5e2471",
+        "d8f0d33650b0098f3e8c1aab60649aaf0b60839adaaf20959eeb592998fb0a440b87bf08a3533051301d0603551d0e04",
+        "1604144a09c158ddf594c006e463203825f83a56d438bd301f0603551d230418301680144a09c158ddf594c006e46320",
+        "3825f83a56d438bd300f0603551d130101ff040530030101ff300a06082a8648ce3d04030203470030440220712e7d42",
+        "0671bc26ae5bc178f849dc2371b665a14668ab679daa53fb2a03ace1022064234040a16c1d9579da4708c4f6e9bc99d8",
+        "ffaa4b032bae6bcc676ca964d66f",
+    );
+
+    #[test]
+    fn parse_fingerprints_as_printed_by_openssl() {
+        assert_eq!(
+            parse_fingerprints(
+                "89:DB:79:6C:D8:29:8F:AD:B2:5D:1E:5C:71:1E:09:57:4E:49:21:9D:55:B3:1D:61:D4:8A:3D:DA:D7:23:30:1C, \
+                 20e46b690d28de5d947748091a03153d2f22cc910ae7a6cc1d870bc9f818f31b"
+            ),
+            Ok(vec![
+                "89db796cd8298fadb25d1e5c711e09574e49219d55b31d61d48a3ddad723301c".to_string(),
+                "20e46b690d28de5d947748091a03153d2f22cc910ae7a6cc1d870bc9f818f31b".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_fingerprints_rejects_other_hashes() {
+        for value in [
+            "",
+            "89db796c",
+            "sha256:89db796cd8298fadb25d1e5c711e09574e49219d55b31d61d48a3ddad723301c",
+        ] {
+            assert!(parse_fingerprints(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn certificate_fingerprint_matches_openssl() {
+        let certificate = hex::decode(CERTIFICATE_DER_HEX).unwrap();
+
+        // openssl x509 -noout -fingerprint -sha256
+        assert_eq!(
+            hex::encode(Sha256::digest(&certificate)),
+            "89db796cd8298fadb25d1e5c711e09574e49219d55b31d61d48a3ddad723301c"
+        );
+    }
+
+    #[test]
+    fn public_key_fingerprint_matches_openssl() {
+        let certificate = hex::decode(CERTIFICATE_DER_HEX).unwrap();
+
+        // openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | sha256sum
+        assert_eq!(
+            public_key_fingerprint(&certificate).as_deref(),
+            Some("20e46b690d28de5d947748091a03153d2f22cc910ae7a6cc1d870bc9f818f31b")
+        );
+    }
+
+    #[test]
+    fn public_key_fingerprint_of_a_truncated_certificate() {
+        let certificate = hex::decode(CERTIFICATE_DER_HEX).unwrap();
+
+        assert_eq!(public_key_fingerprint(&certificate[..200]), None);
+    }
+}
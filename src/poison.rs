@@ -0,0 +1,145 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{read_to_string, write},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::utils::write_atomically;
+
+/// Failures of a job on this worker, as persisted in the poisoned jobs file.
+#[derive(Serialize, Deserialize)]
+struct JobFailures {
+    consecutive_failures: u32,
+    last_error: String,
+    /// Unix timestamp in seconds until which the job is refused
+    poisoned_until: Option<u64>,
+}
+
+/// Negative cache of the jobs failing again and again on this worker, eg: a tile crashing cassini
+/// deterministically. After `threshold` consecutive failures a job is refused for `cooldown`,
+/// then tried once more. Persisted so that it survives restarts.
+pub struct PoisonedJobs {
+    path: PathBuf,
+    threshold: u32,
+    cooldown: Duration,
+    /// By job, see `Job::label`
+    jobs: Mutex<HashMap<String, JobFailures>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl PoisonedJobs {
+    /// Start empty when the file is missing or unreadable, eg: truncated by a crash.
+    pub fn open(path: &Path, threshold: u32, cooldown: Duration) -> Self {
+        let jobs: HashMap<String, JobFailures> = match read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|error| {
+                warn!(
+                    "Ignoring the invalid poisoned jobs file {}: {}",
+                    path.display(),
+                    error
+                );
+                HashMap::new()
+            }),
+            Err(error) if error.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(error) => {
+                warn!(
+                    "Could not read the poisoned jobs file {}: {}",
+                    path.display(),
+                    error
+                );
+                HashMap::new()
+            }
+        };
+
+        let poisoned = jobs
+            .values()
+            .filter(|failures| failures.poisoned_until.is_some_and(|until| until > now()))
+            .count();
+
+        if poisoned > 0 {
+            info!("{} poisoned jobs refused until their cooldown ends", poisoned);
+        }
+
+        PoisonedJobs {
+            path: path.to_path_buf(),
+            threshold,
+            cooldown,
+            jobs: Mutex::new(jobs),
+        }
+    }
+
+    /// Why the job is refused, None if it can run.
+    pub fn refusal_reason(&self, job: &str) -> Option<String> {
+        let jobs = self.jobs.lock().unwrap();
+        let failures = jobs.get(job)?;
+        let remaining = failures
+            .poisoned_until?
+            .checked_sub(now())
+            .filter(|remaining| *remaining > 0)?;
+
+        Some(format!(
+            "refusing poisoned job, failed {} times in a row on this worker, retried in {} min. Last error: {}",
+            failures.consecutive_failures,
+            remaining.div_ceil(60),
+            failures.last_error
+        ))
+    }
+
+    /// Count a failure of the job, or forget its failures when it succeeded, and persist them.
+    pub fn record(&self, job: &str, error: Option<&str>) {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        match error {
+            None => {
+                if jobs.remove(job).is_none() {
+                    return;
+                }
+            }
+            Some(error) => {
+                let failures = jobs.entry(job.to_string()).or_insert(JobFailures {
+                    consecutive_failures: 0,
+                    last_error: String::new(),
+                    poisoned_until: None,
+                });
+
+                failures.consecutive_failures += 1;
+                failures.last_error = error.to_string();
+
+                if failures.consecutive_failures >= self.threshold {
+                    warn!(
+                        "{} failed {} times in a row, refusing it for {} h",
+                        job,
+                        failures.consecutive_failures,
+                        self.cooldown.as_secs() / 3600
+                    );
+                    failures.poisoned_until = Some(now() + self.cooldown.as_secs());
+                }
+            }
+        }
+
+        if let Err(error) = self.save(&jobs) {
+            error!(
+                "Failed to save the poisoned jobs to {}: {}",
+                self.path.display(),
+                error
+            );
+        }
+    }
+
+    fn save(&self, jobs: &HashMap<String, JobFailures>) -> Result<(), Box<dyn std::error::Error>> {
+        write_atomically(&self.path, |partial_path| {
+            write(partial_path, serde_json::to_string_pretty(jobs)?)?;
+            Ok(())
+        })
+    }
+}
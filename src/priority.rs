@@ -0,0 +1,75 @@
+use clap::ValueEnum;
+use log::warn;
+use std::sync::OnceLock;
+
+/// I/O scheduling class of the processing, Linux only.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum IoniceClass {
+    /// Lowest priority of the default class
+    BestEffort,
+    /// Only gets disk time when no other program needs it
+    Idle,
+}
+
+/// Priority of the processing, so that the worker can run on a desktop without making it unusable.
+#[derive(Clone, Copy)]
+pub struct ProcessingPriority {
+    /// Niceness of the processing, from 0 (normal priority) to 19 (lowest), unchanged if None
+    pub nice: Option<i32>,
+    pub ionice_class: Option<IoniceClass>,
+}
+
+static PROCESSING_PRIORITY: OnceLock<ProcessingPriority> = OnceLock::new();
+
+pub fn set_processing_priority(priority: ProcessingPriority) {
+    let _ = PROCESSING_PRIORITY.set(priority);
+}
+
+/// Lower the priority of the calling thread, to be called by the threads running the cassini
+/// processing. On macOS the niceness applies to the whole process.
+pub fn lower_current_thread_priority() {
+    if let Err(error) = apply_processing_priority() {
+        warn!("Failed to lower the processing priority: {}", error);
+    }
+}
+
+/// Apply the processing priority to the calling thread. Only does syscalls, so that it can be
+/// called between fork and exec.
+pub fn apply_processing_priority() -> std::io::Result<()> {
+    match PROCESSING_PRIORITY.get() {
+        Some(priority) => apply_priority(priority),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn apply_priority(priority: &ProcessingPriority) -> std::io::Result<()> {
+    if let Some(nice) = priority.nice {
+        // With `who` set to 0, Linux only changes the niceness of the calling thread
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(ionice_class) = priority.ionice_class {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+        let ioprio = match ionice_class {
+            IoniceClass::BestEffort => (2 << IOPRIO_CLASS_SHIFT) | 7,
+            IoniceClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+        };
+
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_priority(_priority: &ProcessingPriority) -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use clap::ValueEnum;
+use log::info;
+
+use crate::{
+    buffer_pool::set_max_pooled_buffers, segmented_download::set_download_connections,
+    stage_budget::set_stage_budget, utils::set_archive_compression_level,
+};
+
+/// Presets of the memory hungry settings, applied on top of the flags.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum Profile {
+    /// The settings given by the flags
+    #[default]
+    Default,
+    /// For Raspberry Pi class machines and 4 GB VPS: a single LiDAR job at once, downloads in a
+    /// single stream, xz preset 1 for the archives and a single pooled image buffer per thread
+    LowMemory,
+}
+
+pub fn apply_profile(profile: Profile) {
+    if profile == Profile::LowMemory {
+        info!("Using the low memory profile");
+
+        set_stage_budget("lidar", 1);
+        set_download_connections(1);
+        set_archive_compression_level(1);
+        set_max_pooled_buffers(1);
+    }
+}
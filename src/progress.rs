@@ -0,0 +1,155 @@
+use log::info;
+use std::{
+    io::Read,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    quota::count_transferred_bytes,
+    state::{current_abort_reason, report_transfer_progress, report_transferred_bytes},
+};
+
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Size in bytes above which the progress of a transfer is logged, see `set_progress_log_threshold`
+static PROGRESS_LOG_THRESHOLD: AtomicU64 = AtomicU64::new(100_000_000);
+
+pub fn set_progress_log_threshold(bytes: u64) {
+    PROGRESS_LOG_THRESHOLD.store(bytes, Ordering::SeqCst);
+}
+
+/// Bandwidth in bytes per second shared by all the transfers of the worker, 0 for no limit
+static BANDWIDTH_LIMIT: AtomicU64 = AtomicU64::new(0);
+/// Start of the current throttling window and bytes transferred since
+static THROTTLE_WINDOW: Mutex<Option<(Instant, u64)>> = Mutex::new(None);
+const THROTTLE_WINDOW_DURATION: Duration = Duration::from_secs(1);
+
+pub fn set_bandwidth_limit(bytes_per_second: u64) {
+    BANDWIDTH_LIMIT.store(bytes_per_second, Ordering::SeqCst);
+}
+
+/// Sleep as long as needed to keep the transfers of all the threads under the bandwidth limit.
+fn throttle(bytes: u64) {
+    let limit = BANDWIDTH_LIMIT.load(Ordering::SeqCst);
+
+    if limit == 0 {
+        return;
+    }
+
+    let wait = {
+        let mut window = THROTTLE_WINDOW.lock().unwrap();
+        let (started_at, transferred) = window.get_or_insert((Instant::now(), 0));
+        let allowed_duration = Duration::from_secs_f64(*transferred as f64 / limit as f64);
+
+        if started_at.elapsed() >= THROTTLE_WINDOW_DURATION.max(allowed_duration) {
+            *started_at = Instant::now();
+            *transferred = 0;
+        }
+
+        *transferred += bytes;
+
+        Duration::from_secs_f64(*transferred as f64 / limit as f64).saturating_sub(started_at.elapsed())
+    };
+
+    sleep(wait);
+}
+
+/// Wrap a transfer stream (response body, file being uploaded) to report its progress to the
+/// worker state, and to log it every few seconds for transfers larger than the threshold.
+pub struct ProgressReader<R> {
+    inner: R,
+    label: String,
+    total: Option<u64>,
+    done: u64,
+    started_at: Instant,
+    logged_at: Instant,
+}
+
+impl<R: Read> ProgressReader<R> {
+    /// `label` is used in the logs, eg: "Download of 1000_6000.laz"
+    pub fn new(inner: R, label: String, total: Option<u64>) -> Self {
+        ProgressReader {
+            inner,
+            label,
+            total,
+            done: 0,
+            started_at: Instant::now(),
+            logged_at: Instant::now(),
+        }
+    }
+
+    fn log_progress(&self) {
+        let elapsed = self.started_at.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 {
+            self.done as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        match self.total {
+            Some(total) if total > 0 => {
+                let eta = if speed > 0.0 {
+                    Duration::from_secs_f64(total.saturating_sub(self.done) as f64 / speed)
+                } else {
+                    Duration::ZERO
+                };
+
+                info!(
+                    "{}: {:.0}% ({} / {} MB), {:.1} MB/s, ETA {:.0?}",
+                    self.label,
+                    self.done as f64 * 100.0 / total as f64,
+                    self.done / 1_000_000,
+                    total / 1_000_000,
+                    speed / 1_000_000.0,
+                    eta
+                );
+            }
+            _ => {
+                info!(
+                    "{}: {} MB, {:.1} MB/s",
+                    self.label,
+                    self.done / 1_000_000,
+                    speed / 1_000_000.0
+                );
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(reason) = current_abort_reason() {
+            return Err(std::io::Error::other(format!("Job aborted: {}", reason)));
+        }
+
+        let read = self.inner.read(buf)?;
+
+        if read == 0 {
+            report_transfer_progress(None);
+            return Ok(0);
+        }
+
+        self.done += read as u64;
+        report_transferred_bytes(read as u64);
+        count_transferred_bytes(read as u64);
+        throttle(read as u64);
+
+        if let Some(total) = self.total {
+            report_transfer_progress(Some(self.done as f64 / total.max(1) as f64));
+        }
+
+        let large_transfer = self.total.unwrap_or(self.done) >= PROGRESS_LOG_THRESHOLD.load(Ordering::SeqCst);
+
+        if large_transfer && self.logged_at.elapsed() >= PROGRESS_LOG_INTERVAL {
+            self.log_progress();
+            self.logged_at = Instant::now();
+        }
+
+        Ok(read)
+    }
+}
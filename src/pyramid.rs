@@ -1,17 +1,29 @@
-use image::{imageops::FilterType, GenericImage, GenericImageView, Rgba, RgbaImage};
-use log::{error, info};
-use reqwest::{
-    blocking::{multipart, Client},
-    header::{HeaderMap, HeaderValue},
-};
+use fast_image_resize::{images::Image, FilterType, IntoImageView, ResizeAlg, ResizeOptions, Resizer};
+use image::{GenericImage, GenericImageView};
+use log::{debug, error, info};
+use reqwest::{blocking::Client, StatusCode};
 use std::{
-    fs::{create_dir_all, read, File},
+    fs::{create_dir_all, File},
     io::copy,
     path::{Path, PathBuf},
     time::Instant,
 };
 
-use crate::utils::download_file;
+use crate::{
+    artifacts::UploadManifest,
+    auth::ApiAuth,
+    buffer_pool::{release_buffer, take_buffer, transparent_rgba_image},
+    http::http_client,
+    progress::ProgressReader,
+    region::RegionProfile,
+    response::describe_error_response,
+    state::report_stage,
+    tile_encoder::{save_tile, write_avif_rendition, TileEncoding},
+    utils::{
+        add_download, download_file, store_etag, take_etag, upload_files, with_if_none_match,
+        write_atomically,
+    },
+};
 
 const TILE_PIXEL_SIZE: u32 = 256;
 
@@ -21,9 +33,10 @@ pub fn pyramid_step(
     z: i32,
     base_zoom_level_tile_id: Option<String>,
     area_id: String,
-    worker_id: &str,
-    token: &str,
+    tile_encoding: Option<TileEncoding>,
+    auth: &ApiAuth,
     base_api_url: &str,
+    region: &RegionProfile,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let tiles_dir_path = Path::new("tiles");
 
@@ -37,7 +50,7 @@ pub fn pyramid_step(
         create_dir_all(&area_tiles_dir_path)?;
     }
 
-    let client = Client::new();
+    let client = http_client();
 
     match base_zoom_level_tile_id {
         Some(tile_id) => {
@@ -46,11 +59,12 @@ pub fn pyramid_step(
                 x,
                 y,
                 area_id,
-                worker_id,
-                token,
+                auth,
                 base_api_url,
                 &area_tiles_dir_path,
                 tile_id,
+                region.base_zoom_level,
+                tile_encoding,
             )?;
         }
         None => {
@@ -60,10 +74,10 @@ pub fn pyramid_step(
                 y,
                 z,
                 area_id,
-                worker_id,
-                token,
+                auth,
                 base_api_url,
                 &area_tiles_dir_path,
+                tile_encoding,
             )?;
         }
     }
@@ -76,37 +90,40 @@ pub fn pyramid_step_base_zoom_level(
     x: i32,
     y: i32,
     area_id: String,
-    worker_id: &str,
-    token: &str,
+    auth: &ApiAuth,
     base_api_url: &str,
     area_tiles_dir_path: &PathBuf,
     tile_id: String,
+    base_zoom_level: i32,
+    tile_encoding: Option<TileEncoding>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    report_stage("downloading base tile");
     info!("Downloading the base high quality tile for tile {}", &tile_id);
 
     let start = Instant::now();
 
-    let zoom_11_x_path = area_tiles_dir_path.join("11").join(x.to_string());
+    let zoom_base_x_path = area_tiles_dir_path
+        .join(base_zoom_level.to_string())
+        .join(x.to_string());
 
-    if !zoom_11_x_path.exists() {
-        create_dir_all(&zoom_11_x_path)?;
+    if !zoom_base_x_path.exists() {
+        create_dir_all(&zoom_base_x_path)?;
     }
 
-    let zoom_11_tile_path = zoom_11_x_path.join(format!("{}.png", y));
+    let zoom_base_tile_path = zoom_base_x_path.join(format!("{}.png", y));
 
-    let zoom_11_tile_url = format!(
+    let zoom_base_tile_url = format!(
         "{}/api/map-generation/render-steps/{}/full-map",
         base_api_url, tile_id
     );
 
-    let mut headers = HeaderMap::new();
-
-    headers.append(
-        "Authorization",
-        HeaderValue::from_str(&format!("Bearer {}.{}", worker_id, token))?,
-    );
-
-    download_file(&client, &zoom_11_tile_url, &zoom_11_tile_path, Some(headers))?;
+    download_file(
+        &client,
+        &zoom_base_tile_url,
+        &zoom_base_tile_path,
+        Some(auth),
+        None,
+    )?;
 
     let duration = start.elapsed();
 
@@ -116,135 +133,188 @@ pub fn pyramid_step_base_zoom_level(
     );
 
     info!(
-        "Generating tiles for zoom 11, 12 and 13 for high quality tile {}",
+        "Generating tiles for zoom {}, {} and {} for high quality tile {}",
+        base_zoom_level,
+        base_zoom_level + 1,
+        base_zoom_level + 2,
         &tile_id
     );
 
     let start = Instant::now();
 
-    let zoom_12_path = &area_tiles_dir_path.join("12");
-    let zoom_12_x_path = &zoom_12_path.join((x * 2).to_string());
-    let zoom_12_x_plus_1_path = &zoom_12_path.join((x * 2 + 1).to_string());
+    report_stage("generating tiles");
+    let mut tiles_for_upload =
+        generate_base_zoom_levels_tiles(area_tiles_dir_path, x, y, base_zoom_level, tile_encoding)?;
+    add_avif_renditions(&mut tiles_for_upload, tile_encoding)?;
+
+    report_stage("uploading");
+    upload_base_zoom_tiles(
+        &client,
+        base_api_url,
+        &area_id,
+        auth,
+        base_zoom_level,
+        x,
+        y,
+        tiles_for_upload,
+    )?;
+
+    let duration = start.elapsed();
+
+    info!(
+        "Tiles for zoom {}, {} and {} for high quality tile {} generated in {:.1?}",
+        base_zoom_level,
+        base_zoom_level + 1,
+        base_zoom_level + 2,
+        &tile_id,
+        duration
+    );
 
-    if !zoom_12_x_path.exists() {
-        create_dir_all(zoom_12_x_path)?;
+    Ok(())
+}
+
+/// Web tile generated from a high quality tile.
+pub struct GeneratedTile {
+    pub path: PathBuf,
+    /// eg: "1234.png"
+    pub file_name: String,
+    /// Part of the upload form, eg: "12_2048_1234"
+    pub form_part_name: String,
+}
+
+/// Split the high quality tile at `{area_tiles_dir_path}/{base_zoom_level}/{x}/{y}.png` into the
+/// tiles of the two next zoom levels, and resize them all to the web tile size, encoded with
+/// `tile_encoding`.
+pub fn generate_base_zoom_levels_tiles(
+    area_tiles_dir_path: &Path,
+    x: i32,
+    y: i32,
+    base_zoom_level: i32,
+    tile_encoding: Option<TileEncoding>,
+) -> Result<Vec<GeneratedTile>, Box<dyn std::error::Error>> {
+    let zoom_base_tile_path = area_tiles_dir_path
+        .join(base_zoom_level.to_string())
+        .join(x.to_string())
+        .join(format!("{}.png", y));
+
+    let zoom_plus_1_path = &area_tiles_dir_path.join((base_zoom_level + 1).to_string());
+    let zoom_plus_1_x_path = &zoom_plus_1_path.join((x * 2).to_string());
+    let zoom_plus_1_x_plus_1_path = &zoom_plus_1_path.join((x * 2 + 1).to_string());
+
+    if !zoom_plus_1_x_path.exists() {
+        create_dir_all(zoom_plus_1_x_path)?;
     }
 
-    if !zoom_12_x_plus_1_path.exists() {
-        create_dir_all(zoom_12_x_plus_1_path)?;
+    if !zoom_plus_1_x_plus_1_path.exists() {
+        create_dir_all(zoom_plus_1_x_plus_1_path)?;
     }
 
-    let zoom_12_tiles_paths = [
-        &zoom_12_x_path.join(format!("{}.png", (y * 2).to_string())),
-        &zoom_12_x_plus_1_path.join(format!("{}.png", (y * 2).to_string())),
-        &zoom_12_x_path.join(format!("{}.png", (y * 2 + 1).to_string())),
-        &zoom_12_x_plus_1_path.join(format!("{}.png", (y * 2 + 1).to_string())),
+    let zoom_plus_1_tiles_paths = [
+        &zoom_plus_1_x_path.join(format!("{}.png", (y * 2).to_string())),
+        &zoom_plus_1_x_plus_1_path.join(format!("{}.png", (y * 2).to_string())),
+        &zoom_plus_1_x_path.join(format!("{}.png", (y * 2 + 1).to_string())),
+        &zoom_plus_1_x_plus_1_path.join(format!("{}.png", (y * 2 + 1).to_string())),
     ];
 
-    split_image_in_four(&zoom_11_tile_path, &zoom_12_tiles_paths)?;
+    split_image_in_four(&zoom_base_tile_path, &zoom_plus_1_tiles_paths)?;
 
-    // (tile_path, file_name, form_part_name)
-    let mut tiles_for_upload: Vec<(PathBuf, String, String)> = vec![];
+    let mut tiles_for_upload: Vec<GeneratedTile> = vec![];
 
-    // Generate tiles for zoom 13
-    let zoom_12_tiles = [
+    // Generate tiles for base zoom + 2
+    let zoom_plus_1_tiles = [
         [x * 2, y * 2],
         [x * 2 + 1, y * 2],
         [x * 2, y * 2 + 1],
         [x * 2 + 1, y * 2 + 1],
     ];
 
-    for (i_12, [x_12, y_12]) in zoom_12_tiles.iter().enumerate() {
-        let zoom_13_path = &area_tiles_dir_path.join("13");
-        let zoom_13_x_path = &zoom_13_path.join((x_12 * 2).to_string());
-        let zoom_13_x_plus_1_path = &zoom_13_path.join((x_12 * 2 + 1).to_string());
+    for (i_plus_1, [x_plus_1, y_plus_1]) in zoom_plus_1_tiles.iter().enumerate() {
+        let zoom_plus_2_path = &area_tiles_dir_path.join((base_zoom_level + 2).to_string());
+        let zoom_plus_2_x_path = &zoom_plus_2_path.join((x_plus_1 * 2).to_string());
+        let zoom_plus_2_x_plus_1_path = &zoom_plus_2_path.join((x_plus_1 * 2 + 1).to_string());
 
-        if !zoom_13_x_path.exists() {
-            create_dir_all(zoom_13_x_path)?;
+        if !zoom_plus_2_x_path.exists() {
+            create_dir_all(zoom_plus_2_x_path)?;
         }
 
-        if !zoom_13_x_plus_1_path.exists() {
-            create_dir_all(zoom_13_x_plus_1_path)?;
+        if !zoom_plus_2_x_plus_1_path.exists() {
+            create_dir_all(zoom_plus_2_x_plus_1_path)?;
         }
 
-        let zoom_13_tiles_paths = [
-            &zoom_13_x_path.join(format!("{}.png", (y_12 * 2).to_string())),
-            &zoom_13_x_plus_1_path.join(format!("{}.png", (y_12 * 2).to_string())),
-            &zoom_13_x_path.join(format!("{}.png", (y_12 * 2 + 1).to_string())),
-            &zoom_13_x_plus_1_path.join(format!("{}.png", (y_12 * 2 + 1).to_string())),
+        let zoom_plus_2_tiles_paths = [
+            &zoom_plus_2_x_path.join(format!("{}.png", (y_plus_1 * 2).to_string())),
+            &zoom_plus_2_x_plus_1_path.join(format!("{}.png", (y_plus_1 * 2).to_string())),
+            &zoom_plus_2_x_path.join(format!("{}.png", (y_plus_1 * 2 + 1).to_string())),
+            &zoom_plus_2_x_plus_1_path.join(format!("{}.png", (y_plus_1 * 2 + 1).to_string())),
         ];
 
-        split_image_in_four(&zoom_12_tiles_paths[i_12], &zoom_13_tiles_paths)?;
+        split_image_in_four(&zoom_plus_1_tiles_paths[i_plus_1], &zoom_plus_2_tiles_paths)?;
 
-        // Resize and upload zoom 13 tiles
-        let mut i_13 = 0;
+        // Resize and upload base zoom + 2 tiles
+        let mut i_plus_2 = 0;
 
-        let zoom_13_tiles = [
-            [x_12 * 2, y_12 * 2],
-            [x_12 * 2 + 1, y_12 * 2],
-            [x_12 * 2, y_12 * 2 + 1],
-            [x_12 * 2 + 1, y_12 * 2 + 1],
+        let zoom_plus_2_tiles = [
+            [x_plus_1 * 2, y_plus_1 * 2],
+            [x_plus_1 * 2 + 1, y_plus_1 * 2],
+            [x_plus_1 * 2, y_plus_1 * 2 + 1],
+            [x_plus_1 * 2 + 1, y_plus_1 * 2 + 1],
         ];
 
-        for zoom_13_tile_path in zoom_13_tiles_paths {
-            resize_image_in_place(zoom_13_tile_path, TILE_PIXEL_SIZE, TILE_PIXEL_SIZE)?;
-            let [x_13, y_13] = zoom_13_tiles[i_13];
+        for zoom_plus_2_tile_path in zoom_plus_2_tiles_paths {
+            resize_image_in_place(
+                zoom_plus_2_tile_path,
+                TILE_PIXEL_SIZE,
+                TILE_PIXEL_SIZE,
+                tile_encoding,
+            )?;
+            let [x_plus_2, y_plus_2] = zoom_plus_2_tiles[i_plus_2];
 
-            tiles_for_upload.push((
-                zoom_13_tile_path.clone(),
-                format!("{}.png", y_13),
-                format!("{}_{}_{}", 13, x_13, y_13),
-            ));
+            tiles_for_upload.push(GeneratedTile {
+                path: zoom_plus_2_tile_path.clone(),
+                file_name: format!("{}.png", y_plus_2),
+                form_part_name: format!("{}_{}_{}", base_zoom_level + 2, x_plus_2, y_plus_2),
+            });
 
-            i_13 += 1;
+            i_plus_2 += 1;
         }
     }
 
-    // Resize and upload zoom 12 tiles
-    let mut i_12 = 0;
-
-    for zoom_12_tile_path in zoom_12_tiles_paths {
-        resize_image_in_place(zoom_12_tile_path, TILE_PIXEL_SIZE, TILE_PIXEL_SIZE)?;
-        let [x_12, y_12] = zoom_12_tiles[i_12];
-
-        tiles_for_upload.push((
-            zoom_12_tile_path.clone(),
-            format!("{}.png", y_12),
-            format!("{}_{}_{}", 12, x_12, y_12),
-        ));
-
-        i_12 += 1;
+    // Resize and upload base zoom + 1 tiles
+    let mut i_plus_1 = 0;
+
+    for zoom_plus_1_tile_path in zoom_plus_1_tiles_paths {
+        resize_image_in_place(
+            zoom_plus_1_tile_path,
+            TILE_PIXEL_SIZE,
+            TILE_PIXEL_SIZE,
+            tile_encoding,
+        )?;
+        let [x_plus_1, y_plus_1] = zoom_plus_1_tiles[i_plus_1];
+
+        tiles_for_upload.push(GeneratedTile {
+            path: zoom_plus_1_tile_path.clone(),
+            file_name: format!("{}.png", y_plus_1),
+            form_part_name: format!("{}_{}_{}", base_zoom_level + 1, x_plus_1, y_plus_1),
+        });
+
+        i_plus_1 += 1;
     }
 
-    // Resize and upload zoom 11 tile
-    resize_image_in_place(&zoom_11_tile_path, TILE_PIXEL_SIZE, TILE_PIXEL_SIZE)?;
-
-    tiles_for_upload.push((
-        zoom_11_tile_path,
-        format!("{}.png", y),
-        format!("{}_{}_{}", 11, x, y),
-    ));
-
-    upload_base_zoom_tiles(
-        &client,
-        base_api_url,
-        &area_id,
-        worker_id,
-        token,
-        11,
-        x,
-        y,
-        tiles_for_upload,
+    // Resize and upload base zoom tile
+    resize_image_in_place(
+        &zoom_base_tile_path,
+        TILE_PIXEL_SIZE,
+        TILE_PIXEL_SIZE,
+        tile_encoding,
     )?;
 
-    let duration = start.elapsed();
-
-    info!(
-        "Tiles for zoom 11, 12 and 13 for high quality tile {} generated in {:.1?}",
-        &tile_id, duration
-    );
+    tiles_for_upload.push(GeneratedTile {
+        path: zoom_base_tile_path,
+        file_name: format!("{}.png", y),
+        form_part_name: format!("{}_{}_{}", base_zoom_level, x, y),
+    });
 
-    Ok(())
+    Ok(tiles_for_upload)
 }
 
 pub fn pyramid_step_lower_zoom_level(
@@ -253,11 +323,12 @@ pub fn pyramid_step_lower_zoom_level(
     y: i32,
     z: i32,
     area_id: String,
-    worker_id: &str,
-    token: &str,
+    auth: &ApiAuth,
     base_api_url: &str,
     area_tiles_dir_path: &PathBuf,
+    tile_encoding: Option<TileEncoding>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    report_stage("downloading children tiles");
     info!("Zoom={} x={} y={}, Trying to download children tiles", z, x, y);
 
     let start = Instant::now();
@@ -271,13 +342,6 @@ pub fn pyramid_step_lower_zoom_level(
 
     let mut child_images: [Option<image::DynamicImage>; 4] = [None, None, None, None];
 
-    let mut headers = HeaderMap::new();
-
-    headers.append(
-        "Authorization",
-        HeaderValue::from_str(&format!("Bearer {}.{}", worker_id, token))?,
-    );
-
     for (i, [x_child, y_child]) in children_tiles.iter().enumerate() {
         let child_tile_url = format!(
             "{}/api/map-generation/pyramid-steps/{}/{}/{}/{}",
@@ -298,14 +362,28 @@ pub fn pyramid_step_lower_zoom_level(
 
         let child_tile_path = child_tile_x_path.join(format!("{}.png", y_child));
 
-        let mut response = client.get(&child_tile_url).headers(headers.clone()).send()?;
+        let download_start = Instant::now();
+        let response = auth.send(with_if_none_match(client.get(&child_tile_url), &child_tile_path))?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            debug!(
+                "Zoom={} x={} y={}, tile {}/{}/{} unchanged",
+                z,
+                x,
+                y,
+                z + 1,
+                x_child,
+                y_child
+            );
+            child_images[i] = image::open(&child_tile_path).ok();
+            continue;
+        }
 
         if !response.status().is_success() && response.status().as_str() != "404" {
             error!(
-                "Failed to download pyramide tile with url {}. Status: {}. Response: {:?}",
-                response.status(),
+                "Failed to download pyramide tile with url {}. {}",
                 &child_tile_url,
-                response.text()
+                describe_error_response(response)
             );
 
             return Err(Box::new(std::io::Error::new(
@@ -314,8 +392,19 @@ pub fn pyramid_step_lower_zoom_level(
             )));
         }
 
-        let mut file = File::create(&child_tile_path)?;
-        copy(&mut response, &mut file)?;
+        let total = response.content_length();
+        let etag = take_etag(&response, &child_tile_path);
+        let mut reader = ProgressReader::new(
+            response,
+            format!("Download of tile {}/{}/{}", z + 1, x_child, y_child),
+            total,
+        );
+
+        let size = write_atomically(&child_tile_path, |partial_path| {
+            Ok(copy(&mut reader, &mut File::create(partial_path)?)?)
+        })?;
+        add_download(size, download_start.elapsed());
+        store_etag(&child_tile_path, etag);
 
         let child_image = image::open(&child_tile_path).ok();
         child_images[i] = child_image;
@@ -328,10 +417,51 @@ pub fn pyramid_step_lower_zoom_level(
         z, x, y, duration
     );
 
+    report_stage("merging");
     info!("Zoom={} x={} y={}, merging and resizing children tiles", z, x, y);
 
     let start = Instant::now();
 
+    let tile_path = merge_children_tiles(area_tiles_dir_path, x, y, z, &child_images, tile_encoding)?;
+
+    let duration = start.elapsed();
+
+    info!(
+        "Zoom={} x={} y={}, children tiles merged and resized in {:.1?}",
+        z, x, y, duration
+    );
+
+    let avif_path = write_avif_rendition(&tile_path, tile_encoding)?;
+
+    report_stage("uploading");
+    // Uploading tile
+    upload_tile(
+        &client,
+        base_api_url,
+        &tile_path,
+        avif_path.as_deref(),
+        format!("{}.png", y),
+        &area_id,
+        z,
+        x,
+        y,
+        auth,
+    )?;
+
+    Ok(())
+}
+
+/// Merge the four (maybe missing) children tiles into the tile `{z}/{x}/{y}.png` and resize it to
+/// the web tile size, encoded with `tile_encoding`. Children are ordered: [Top-left, Top-right,
+/// Bottom-left, Bottom-right]
+pub fn merge_children_tiles(
+    area_tiles_dir_path: &Path,
+    x: i32,
+    y: i32,
+    z: i32,
+    child_images: &[Option<image::DynamicImage>; 4],
+    tile_encoding: Option<TileEncoding>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
     // Merging children tiles
     let tile_x_path = area_tiles_dir_path.join(&z.to_string()).join(&x.to_string());
 
@@ -339,7 +469,7 @@ pub fn pyramid_step_lower_zoom_level(
         create_dir_all(&tile_x_path)?;
     }
 
-    let mut tile_image = RgbaImage::from_pixel(TILE_PIXEL_SIZE * 2, TILE_PIXEL_SIZE * 2, Rgba([0, 0, 0, 0]));
+    let mut tile_image = transparent_rgba_image(TILE_PIXEL_SIZE * 2, TILE_PIXEL_SIZE * 2);
 
     if let Some(image) = &child_images[0] {
         tile_image.copy_from(&image.to_rgba8(), 0, 0)?;
@@ -359,31 +489,18 @@ pub fn pyramid_step_lower_zoom_level(
 
     // Saving on disk and resizing
     let tile_path = tile_x_path.join(format!("{}.png", y));
-    tile_image.save(&tile_path)?;
-    resize_image_in_place(&tile_path, TILE_PIXEL_SIZE, TILE_PIXEL_SIZE)?;
-
-    let duration = start.elapsed();
-
-    info!(
-        "Zoom={} x={} y={}, children tiles merged and resized in {:.1?}",
-        z, x, y, duration
-    );
-
-    // Uploading tile
-    upload_tile(
-        &client,
-        base_api_url,
-        &tile_path,
-        format!("{}.png", y),
-        &area_id,
-        z,
-        x,
-        y,
-        worker_id,
-        token,
-    )?;
-
-    Ok(())
+    write_atomically(&tile_path, |partial_path| {
+        tile_image.save(partial_path)?;
+        resize_image_in_place(
+            &partial_path.to_path_buf(),
+            TILE_PIXEL_SIZE,
+            TILE_PIXEL_SIZE,
+            tile_encoding,
+        )
+    })?;
+    release_buffer(tile_image.into_raw());
+
+    Ok(tile_path)
 }
 
 /// Split an image in four parts: Top-left, Top-right, Bottom-left and Bottom-right
@@ -414,136 +531,142 @@ fn split_image_in_four(
     ];
 
     for (i, &(x, y, w, h)) in regions.iter().enumerate() {
-        let sub_image = img.view(x, y, w, h).to_image(); // Extract sub-image
-        sub_image
-            .save(&output_paths[i])
-            .expect("Failed to save output image");
+        // Extract sub-image
+        let mut sub_image = transparent_rgba_image(w, h);
+        sub_image.copy_from(&*img.view(x, y, w, h), 0, 0)?;
+        write_atomically(output_paths[i], |partial_path| Ok(sub_image.save(partial_path)?))?;
+        release_buffer(sub_image.into_raw());
     }
 
     Ok(())
 }
 
+/// Resize the web tile at `image_path`, written with the tile encoder.
 fn resize_image_in_place(
     image_path: &PathBuf,
     width: u32,
     height: u32,
+    tile_encoding: Option<TileEncoding>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let img = image::open(&Path::new(image_path))?;
-    let resized_img = img.resize(width, height, FilterType::Lanczos3);
-    resized_img.save(image_path)?;
+    let pixel_type = img.pixel_type().ok_or("Unsupported pixel type for resizing")?;
+    let buffer = take_buffer(width as usize * height as usize * pixel_type.size());
+    let mut resized_img = Image::from_vec_u8(width, height, buffer, pixel_type)?;
+
+    // SIMD accelerated, several times faster than image's resize for the same Lanczos3 filter
+    Resizer::new().resize(
+        &img,
+        &mut resized_img,
+        &ResizeOptions::new().resize_alg(ResizeAlg::Convolution(FilterType::Lanczos3)),
+    )?;
+
+    // Other worker processes sharing the tiles directory never read a partially written tile
+    write_atomically(image_path, |partial_path| {
+        save_tile(
+            partial_path,
+            resized_img.buffer(),
+            width,
+            height,
+            img.color(),
+            tile_encoding,
+        )
+    })?;
+    release_buffer(resized_img.into_vec());
+
+    Ok(())
+}
+
+/// Add the AVIF renditions of the tiles if the server asked for them, uploaded in the
+/// `{form_part_name}_avif` parts.
+fn add_avif_renditions(
+    tiles: &mut Vec<GeneratedTile>,
+    tile_encoding: Option<TileEncoding>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut renditions = vec![];
+
+    for tile in tiles.iter() {
+        if let Some(avif_path) = write_avif_rendition(&tile.path, tile_encoding)? {
+            renditions.push(GeneratedTile {
+                path: avif_path,
+                file_name: tile.file_name.replace(".png", ".avif"),
+                form_part_name: format!("{}_avif", tile.form_part_name),
+            });
+        }
+    }
+
+    tiles.extend(renditions);
 
     Ok(())
 }
 
+fn tile_content_type(file_name: &str) -> &'static str {
+    if file_name.ends_with(".avif") {
+        "image/avif"
+    } else {
+        "image/png"
+    }
+}
+
 fn upload_tile(
     client: &Client,
     base_api_url: &str,
-    file_path: &PathBuf,
+    file_path: &Path,
+    avif_path: Option<&Path>,
     file_name: String,
     area_id: &str,
     zoom: i32,
     x: i32,
     y: i32,
-    worker_id: &str,
-    token: &str,
+    auth: &ApiAuth,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Uploading tile zoom={} x={} y={}", zoom, x, y);
-    let start = Instant::now();
-
-    let file = read(file_path)?;
-
-    let part = multipart::Part::bytes(file)
-        .file_name(file_name)
-        .mime_str("image/png")?;
-
-    let form = multipart::Form::new().part("file", part);
 
     let url = format!(
         "{}/api/map-generation/pyramid-steps/{}/{}/{}/{}",
         base_api_url, area_id, zoom, x, y
     );
 
-    let response = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}.{}", worker_id, token))
-        .header("Origin", base_api_url)
-        .multipart(form)
-        .send()?;
-
-    if response.status().is_success() {
-        let duration = start.elapsed();
+    let mut manifest = UploadManifest::single(&file_name, "file", file_path, "image/png")?;
 
-        info!("Tile zoom={} x={} y={} uploaded in {:.1?}", zoom, x, y, duration);
-    } else {
-        error!(
-            "Failed to upload tile zoom={} x={} y={}: {} {}",
-            zoom,
-            x,
-            y,
-            response.status(),
-            response.text()?
-        );
+    if let Some(avif_path) = avif_path {
+        manifest.add(
+            &file_name.replace(".png", ".avif"),
+            "file_avif",
+            avif_path,
+            "image/avif",
+        )?;
     }
 
-    Ok(())
+    upload_files(client, auth, url, base_api_url, &manifest)
 }
 
 fn upload_base_zoom_tiles(
     client: &Client,
     base_api_url: &str,
     area_id: &str,
-    worker_id: &str,
-    token: &str,
+    auth: &ApiAuth,
     zoom: i32,
     x: i32,
     y: i32,
-    tiles: Vec<(PathBuf, String, String)>,
+    tiles: Vec<GeneratedTile>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Uploading tiles for base level zoom={} x={} y={}", zoom, x, y);
 
-    let start = Instant::now();
-
-    let mut form = multipart::Form::new();
-
-    for (tile_path, tile_file_name, tile_form_part_name) in tiles {
-        let file = read(tile_path)?;
-
-        let part = multipart::Part::bytes(file)
-            .file_name(tile_file_name)
-            .mime_str("image/png")?;
-
-        form = form.part(tile_form_part_name, part);
-    }
-
     let url = format!(
         "{}/api/map-generation/pyramid-steps/{}/base-level/{}/{}",
         base_api_url, area_id, x, y
     );
 
-    let response = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}.{}", worker_id, token))
-        .header("Origin", base_api_url)
-        .multipart(form)
-        .send()?;
-
-    if response.status().is_success() {
-        let duration = start.elapsed();
+    let mut manifest = UploadManifest::new();
 
-        info!(
-            "Tiles for base level zoom={} x={} y={} uploaded in {:.1?}",
-            zoom, x, y, duration
-        );
-    } else {
-        error!(
-            "Failed to upload tiles for base level zoom={} x={} y={}: {} {}",
-            zoom,
-            x,
-            y,
-            response.status(),
-            response.text()?
-        );
+    for tile in tiles {
+        manifest.add(
+            &tile.file_name,
+            &tile.form_part_name,
+            &tile.path,
+            tile_content_type(&tile.file_name),
+        )?;
     }
 
-    Ok(())
+    upload_files(client, auth, url, base_api_url, &manifest)
 }
@@ -0,0 +1,173 @@
+use chrono::Local;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_to_string, write, File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::utils::write_atomically;
+
+/// Bytes transferred by all the threads of the worker since the usage was last updated
+static UNRECORDED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Count bytes sent or received by a transfer of any thread, eg: a job prefetch. Called as the
+/// bytes go through, so that failed and interrupted transfers count too.
+pub fn count_transferred_bytes(bytes: u64) {
+    UNRECORDED_BYTES.fetch_add(bytes, Ordering::SeqCst);
+}
+
+/// Bytes transferred during a calendar month, as persisted in the usage file.
+#[derive(Serialize, Deserialize)]
+struct MonthlyUsage {
+    /// eg: "2024-03"
+    month: String,
+    bytes: u64,
+}
+
+/// Monthly bandwidth budget, for volunteers on metered connections. The usage is persisted so
+/// that it survives restarts, and is reset at the start of every month (local time). Worker
+/// processes sharing the usage file add their transfers to it under a lock on a `.lock` file next
+/// to it.
+pub struct BandwidthQuota {
+    path: PathBuf,
+    /// Budget in bytes, downloads and uploads included
+    budget: u64,
+    /// Usage of every process, as of the last update of the file
+    usage: Mutex<MonthlyUsage>,
+    lock: File,
+}
+
+fn current_month() -> String {
+    Local::now().format("%Y-%m").to_string()
+}
+
+/// Usage persisted in the file, none if missing or unreadable, eg: truncated by a crash.
+fn read_usage(path: &Path) -> MonthlyUsage {
+    let empty_usage = MonthlyUsage {
+        month: current_month(),
+        bytes: 0,
+    };
+
+    if !path.exists() {
+        return empty_usage;
+    }
+
+    match read_to_string(path)
+        .map_err(|error| error.to_string())
+        .and_then(|content| serde_json::from_str(&content).map_err(|error| error.to_string()))
+    {
+        Ok(usage) => usage,
+        Err(error) => {
+            warn!(
+                "Failed to read the bandwidth usage from {}, starting from 0: {}",
+                path.display(),
+                error
+            );
+
+            empty_usage
+        }
+    }
+}
+
+impl BandwidthQuota {
+    pub fn open(path: &Path, budget: u64) -> Result<Self, Box<dyn std::error::Error>> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))?;
+
+        let quota = BandwidthQuota {
+            path: path.to_path_buf(),
+            budget,
+            usage: Mutex::new(read_usage(path)),
+            lock,
+        };
+
+        info!(
+            "Monthly bandwidth budget: {} / {} MB used",
+            quota.used() / 1_000_000,
+            budget / 1_000_000
+        );
+
+        Ok(quota)
+    }
+
+    /// Add the bytes transferred by the worker since the previous call to the usage of the month
+    /// and persist it, along with the transfers of the other processes sharing the usage file.
+    pub fn record_transfers(&self) {
+        let mut usage = self.usage.lock().unwrap();
+        let was_exhausted = usage.bytes >= self.budget;
+        let bytes = UNRECORDED_BYTES.swap(0, Ordering::SeqCst);
+
+        match self.add_to_file(bytes) {
+            Ok(file_usage) => *usage = file_usage,
+            Err(error) => {
+                error!(
+                    "Failed to save the bandwidth usage to {}: {}",
+                    self.path.display(),
+                    error
+                );
+
+                roll_over(&mut usage);
+                usage.bytes += bytes;
+            }
+        }
+
+        if !was_exhausted && usage.bytes >= self.budget {
+            warn!(
+                "Monthly bandwidth budget of {} MB reached, not accepting new jobs until next month",
+                self.budget / 1_000_000
+            );
+        }
+    }
+
+    /// Read the usage file again and add `bytes` to it, under the lock of the file.
+    fn add_to_file(&self, bytes: u64) -> Result<MonthlyUsage, Box<dyn std::error::Error>> {
+        self.lock.lock()?;
+
+        let mut usage = read_usage(&self.path);
+        roll_over(&mut usage);
+        usage.bytes += bytes;
+
+        let saved = write_atomically(&self.path, |partial_path| {
+            write(partial_path, serde_json::to_string(&usage)?)?;
+            Ok(())
+        });
+
+        self.lock.unlock()?;
+        saved?;
+
+        Ok(usage)
+    }
+
+    /// Bytes transferred since the start of the month.
+    pub fn used(&self) -> u64 {
+        let mut usage = self.usage.lock().unwrap();
+        roll_over(&mut usage);
+
+        usage.bytes
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.record_transfers();
+        self.used() >= self.budget
+    }
+}
+
+/// Reset the usage when a new month started.
+fn roll_over(usage: &mut MonthlyUsage) {
+    let month = current_month();
+
+    if usage.month != month {
+        info!("New month, resetting the bandwidth usage of {}", usage.month);
+
+        usage.month = month;
+        usage.bytes = 0;
+    }
+}
@@ -0,0 +1,228 @@
+use log::warn;
+use std::{
+    cell::Cell,
+    fs::metadata,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use crate::subprocess::{run_subprocess, run_subprocess_checked, subprocess_command};
+
+/// GDAL creation options of a GeoTIFF, eg: "COMPRESS=DEFLATE".
+pub type CreationOptions = Vec<String>;
+
+static GEOTIFF_CREATION_OPTIONS: OnceLock<CreationOptions> = OnceLock::new();
+static RASTER_OVERVIEWS: AtomicBool = AtomicBool::new(false);
+
+/// Size of the rasters written by the current job, compressed and as they would be uncompressed.
+#[derive(Clone, Copy, Default)]
+pub struct RasterSizes {
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+thread_local! {
+    /// Rasters written on the current thread since the last call to `take_raster_sizes`
+    static RASTER_SIZES: Cell<RasterSizes> = Cell::new(RasterSizes::default());
+}
+
+/// Creation options of the GeoTIFF rasters written by the jobs, uncompressed otherwise.
+pub fn set_geotiff_creation_options(options: CreationOptions) {
+    let _ = GEOTIFF_CREATION_OPTIONS.set(options);
+}
+
+/// Add overviews to the GeoTIFF rasters written by the jobs, for clients displaying them
+/// zoomed out.
+pub fn set_raster_overviews(enabled: bool) {
+    RASTER_OVERVIEWS.store(enabled, Ordering::SeqCst);
+}
+
+/// Parse comma separated creation options like "COMPRESS=DEFLATE,TILED=YES,PREDICTOR=2", empty
+/// for none.
+pub fn parse_creation_options(value: &str) -> Result<CreationOptions, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|option| !option.is_empty())
+        .map(|option| match option.split_once('=') {
+            Some((name, value)) if !name.is_empty() && !value.is_empty() => Ok(option.to_string()),
+            _ => Err(format!(
+                "Invalid creation option \"{}\", expected eg: COMPRESS=DEFLATE",
+                option
+            )),
+        })
+        .collect()
+}
+
+/// `-co` arguments of gdal_translate writing a GeoTIFF.
+pub fn creation_option_args() -> Vec<&'static str> {
+    GEOTIFF_CREATION_OPTIONS
+        .get()
+        .into_iter()
+        .flatten()
+        .flat_map(|option| ["-co", option.as_str()])
+        .collect()
+}
+
+/// Add the overviews of a GeoTIFF just written if enabled, then `check_raster` it. Failing to add
+/// the overviews is only logged, the raster is usable anyway.
+pub fn finish_raster(path: &Path, epsg: u32) -> Result<(), Box<dyn std::error::Error>> {
+    if RASTER_OVERVIEWS.load(Ordering::SeqCst) {
+        let result = run_subprocess_checked(
+            subprocess_command("gdaladdo")
+                .args(["-r", "average"])
+                .args(["--config", "COMPRESS_OVERVIEW", "DEFLATE"])
+                .arg(path)
+                .arg("-q"),
+            "gdaladdo",
+        );
+
+        if let Err(error) = result {
+            warn!("Failed to add the overviews of {}: {}", path.display(), error);
+        }
+    }
+
+    check_raster(path, epsg)
+}
+
+/// Whether a raster or vector dataset has a CRS, according to gdalsrsinfo.
+pub fn has_crs(path: &Path) -> bool {
+    let output = run_subprocess(
+        subprocess_command("gdalsrsinfo").args(["-o", "wkt"]).arg(path),
+        "gdalsrsinfo",
+    );
+
+    match output {
+        Ok(output) => output.status.success() && !String::from_utf8_lossy(&output.stdout).trim().is_empty(),
+        Err(error) => {
+            warn!("Could not read the CRS of {}: {}", path.display(), error);
+            false
+        }
+    }
+}
+
+/// Fail if a raster just written has no CRS or another one than `epsg`, so that the users do not
+/// have to assign it by hand, and count its size in the `RasterSizes` of the job.
+pub fn check_raster(path: &Path, epsg: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let compressed_bytes = metadata(path)?.len();
+
+    let Some(info) = raster_info(path) else {
+        warn!("Could not check the CRS of {}, gdalinfo failed", path.display());
+        add_raster_size(compressed_bytes, compressed_bytes);
+        return Ok(());
+    };
+
+    let has_crs = info
+        .pointer("/coordinateSystem/wkt")
+        .and_then(|wkt| wkt.as_str())
+        .is_some_and(|wkt| !wkt.is_empty());
+
+    if !has_crs {
+        return Err(format!("{} has no CRS, expected EPSG:{}", path.display(), epsg).into());
+    }
+
+    // Only given by GDAL 3.3 and later
+    if let Some(raster_epsg) = info.pointer("/stac/proj:epsg").and_then(|epsg| epsg.as_u64()) {
+        if raster_epsg != epsg as u64 {
+            return Err(format!(
+                "{} is in EPSG:{}, expected EPSG:{}",
+                path.display(),
+                raster_epsg,
+                epsg
+            )
+            .into());
+        }
+    }
+
+    add_raster_size(
+        uncompressed_size(&info).unwrap_or(compressed_bytes),
+        compressed_bytes,
+    );
+
+    Ok(())
+}
+
+fn add_raster_size(uncompressed_bytes: u64, compressed_bytes: u64) {
+    RASTER_SIZES.with(|sizes| {
+        let mut current = sizes.get();
+        current.uncompressed_bytes += uncompressed_bytes;
+        current.compressed_bytes += compressed_bytes;
+        sizes.set(current);
+    });
+}
+
+pub fn raster_sizes() -> RasterSizes {
+    RASTER_SIZES.with(|sizes| sizes.get())
+}
+
+pub fn take_raster_sizes() -> RasterSizes {
+    RASTER_SIZES.with(|sizes| sizes.replace(RasterSizes::default()))
+}
+
+/// Description of a raster given by `gdalinfo -json`.
+pub fn raster_info(path: &Path) -> Option<serde_json::Value> {
+    let output = run_subprocess(subprocess_command("gdalinfo").arg("-json").arg(path), "gdalinfo").ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    serde_json::from_slice(&output.stdout).ok()
+}
+
+/// Size of the pixels of a raster, from its dimensions and band types.
+fn uncompressed_size(info: &serde_json::Value) -> Option<u64> {
+    let size = info.get("size")?.as_array()?;
+    let pixels = size.first()?.as_u64()? * size.get(1)?.as_u64()?;
+
+    let bytes_per_pixel: u64 = info
+        .get("bands")?
+        .as_array()?
+        .iter()
+        .map(
+            |band| match band.get("type").and_then(|band_type| band_type.as_str()) {
+                Some("Byte") | Some("Int8") => 1,
+                Some("UInt16") | Some("Int16") => 2,
+                Some("UInt32") | Some("Int32") | Some("Float32") | Some("CInt16") => 4,
+                Some("CInt32") | Some("CFloat32") => 8,
+                Some("CFloat64") => 16,
+                _ => 8,
+            },
+        )
+        .sum();
+
+    Some(pixels * bytes_per_pixel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_creation_options_trims_the_options() {
+        assert_eq!(
+            parse_creation_options(" COMPRESS=DEFLATE, TILED=YES ,PREDICTOR=2"),
+            Ok(vec![
+                "COMPRESS=DEFLATE".to_string(),
+                "TILED=YES".to_string(),
+                "PREDICTOR=2".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_creation_options_empty_for_none() {
+        assert_eq!(parse_creation_options(""), Ok(vec![]));
+        assert_eq!(parse_creation_options(" , "), Ok(vec![]));
+    }
+
+    #[test]
+    fn parse_creation_options_rejects_options_without_value() {
+        for value in ["COMPRESS", "COMPRESS=", "=DEFLATE", "COMPRESS=DEFLATE,TILED"] {
+            assert!(parse_creation_options(value).is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use reqwest::Url;
+use std::{
+    collections::HashMap,
+    panic::resume_unwind,
+    sync::{Condvar, Mutex, OnceLock},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use crate::state::{current_abort_reason, JobAborted};
+
+/// Host name matching the hosts without limits of their own
+const OTHER_HOSTS: &str = "*";
+
+/// Politeness limits of an external host, shared by all the worker threads. 0 for no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HostLimit {
+    pub requests_per_minute: u32,
+    pub max_connections: usize,
+}
+
+/// Limits by host name.
+pub type HostLimits = HashMap<String, HostLimit>;
+
+static HOST_LIMITS: OnceLock<HostLimits> = OnceLock::new();
+
+/// Minimum delay between two next-job requests of the worker, all threads together
+static POLL_INTERVAL: OnceLock<Duration> = OnceLock::new();
+
+/// Longest interval between two next-job requests `--max-poll-rate` can set
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(3600);
+/// Longest random delay before the first job request of a thread `--startup-jitter` can set
+const MAX_STARTUP_JITTER: Duration = Duration::from_secs(3600);
+/// Time at which the next next-job request is allowed
+static NEXT_POLL_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Parse limits like "geoservices.ign.fr=60/4,*=300/16", in requests per minute / concurrent
+/// connections, `*` for the other hosts.
+pub fn parse_host_limits(value: &str) -> Result<HostLimits, String> {
+    value
+        .split(',')
+        .map(|host_limit| {
+            let invalid = || {
+                format!(
+                    "Invalid host limit \"{}\", expected eg: geoservices.ign.fr=60/4",
+                    host_limit
+                )
+            };
+
+            let (host, limit) = host_limit.trim().split_once('=').ok_or_else(invalid)?;
+            let (requests_per_minute, max_connections) = limit.split_once('/').ok_or_else(invalid)?;
+
+            Ok((
+                host.to_string(),
+                HostLimit {
+                    requests_per_minute: requests_per_minute.parse().map_err(|_| invalid())?,
+                    max_connections: max_connections.parse().map_err(|_| invalid())?,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Limit the requests to external data sources, eg: the LiDAR providers, which throttle or ban
+/// the IPs of the fleets of workers. Requests to the API are not limited.
+pub fn set_host_limits(host_limits: HostLimits) {
+    let _ = HOST_LIMITS.set(host_limits);
+}
+
+/// Parse a number of next-job requests per second, eg: "0.5". Below one request every
+/// `MAX_POLL_INTERVAL` the worker would look stuck.
+pub fn parse_poll_rate(value: &str) -> Result<f64, String> {
+    let requests_per_second: f64 = value
+        .parse()
+        .map_err(|_| format!("Invalid number of requests per second \"{}\"", value))?;
+
+    if !requests_per_second.is_finite() || requests_per_second * MAX_POLL_INTERVAL.as_secs_f64() < 1.0 {
+        return Err(format!(
+            "Expected at least one request every {}s, eg: 0.5",
+            MAX_POLL_INTERVAL.as_secs()
+        ));
+    }
+
+    Ok(requests_per_second)
+}
+
+/// Parse a maximum startup delay in seconds, from 0 to `MAX_STARTUP_JITTER`.
+pub fn parse_startup_jitter(value: &str) -> Result<f64, String> {
+    let seconds: f64 = value
+        .parse()
+        .map_err(|_| format!("Invalid number of seconds \"{}\"", value))?;
+
+    if !(0.0..=MAX_STARTUP_JITTER.as_secs_f64()).contains(&seconds) {
+        return Err(format!(
+            "Expected a number of seconds from 0 to {}",
+            MAX_STARTUP_JITTER.as_secs()
+        ));
+    }
+
+    Ok(seconds)
+}
+
+/// Bound the next-job requests of the worker to `requests_per_second`, whatever the number of
+/// threads.
+pub fn set_max_poll_rate(requests_per_second: f64) {
+    let _ = POLL_INTERVAL.set(Duration::from_secs_f64(1.0 / requests_per_second));
+}
+
+/// Wait for the turn of the current thread to ask the API for a job. The turns are handed out in
+/// order, a thread polling right after a fast job waits for the threads which polled before.
+pub fn wait_for_poll_turn() {
+    let Some(interval) = POLL_INTERVAL.get() else {
+        return;
+    };
+
+    let poll_at = {
+        let mut next_poll_at = NEXT_POLL_AT.lock().unwrap();
+        let poll_at = next_poll_at.map_or(Instant::now(), |next_poll_at| next_poll_at.max(Instant::now()));
+        *next_poll_at = Some(poll_at + *interval);
+
+        poll_at
+    };
+
+    sleep(poll_at.saturating_duration_since(Instant::now()));
+}
+
+/// Random number between 0 and 1, from the random bits of a v4 UUID.
+pub fn random_fraction() -> f64 {
+    (Uuid::new_v4().as_u128() as u64 & ((1 << 53) - 1)) as f64 / (1u64 << 53) as f64
+}
+
+/// `delay` shifted randomly by up to a quarter of it, so that the workers of a fleet restarted at
+/// once do not keep calling the API at the same time.
+pub fn jittered(delay: Duration) -> Duration {
+    delay.mul_f64(0.75 + random_fraction() / 2.0)
+}
+
+fn host_limit(host: &str) -> Option<HostLimit> {
+    let host_limits = HOST_LIMITS.get()?;
+
+    host_limits
+        .get(host)
+        .or_else(|| host_limits.get(OTHER_HOSTS))
+        .copied()
+}
+
+#[derive(Default)]
+struct HostState {
+    connections: usize,
+    last_request_at: Option<Instant>,
+}
+
+fn host_states() -> &'static Mutex<HashMap<String, HostState>> {
+    static HOST_STATES: OnceLock<Mutex<HashMap<String, HostState>>> = OnceLock::new();
+    HOST_STATES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+static CONNECTION_RELEASED: Condvar = Condvar::new();
+
+/// A request to a limited host, counted in its concurrent connections until dropped.
+pub struct HostPermit {
+    host: Option<String>,
+}
+
+impl HostPermit {
+    /// Wait until the host of `url` allows one more request: requests are spread evenly over the
+    /// minute and the number of concurrent connections is capped. Aborts with the job.
+    pub fn acquire(url: &str) -> HostPermit {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+
+        let Some(limit) = host_limit(&host) else {
+            return HostPermit { host: None };
+        };
+
+        let request_interval = match limit.requests_per_minute {
+            0 => Duration::ZERO,
+            requests_per_minute => Duration::from_secs(60) / requests_per_minute,
+        };
+
+        let mut states = host_states().lock().unwrap();
+
+        loop {
+            if let Some(reason) = current_abort_reason() {
+                drop(states);
+                resume_unwind(Box::new(JobAborted(reason)));
+            }
+
+            let state = states.entry(host.clone()).or_default();
+
+            if limit.max_connections > 0 && state.connections >= limit.max_connections {
+                states = CONNECTION_RELEASED
+                    .wait_timeout(states, Duration::from_secs(1))
+                    .unwrap()
+                    .0;
+                continue;
+            }
+
+            let wait = state
+                .last_request_at
+                .map(|last_request_at| request_interval.saturating_sub(last_request_at.elapsed()))
+                .unwrap_or_default();
+
+            if !wait.is_zero() {
+                drop(states);
+                sleep(wait);
+                states = host_states().lock().unwrap();
+                continue;
+            }
+
+            state.connections += 1;
+            state.last_request_at = Some(Instant::now());
+
+            return HostPermit { host: Some(host) };
+        }
+    }
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        let Some(host) = &self.host else {
+            return;
+        };
+
+        if let Some(state) = host_states().lock().unwrap().get_mut(host) {
+            state.connections -= 1;
+        }
+
+        CONNECTION_RELEASED.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_host_limits_by_host() {
+        let host_limits = parse_host_limits("geoservices.ign.fr=60/4, *=0/2").unwrap();
+
+        assert_eq!(host_limits.len(), 2);
+        assert_eq!(host_limits["geoservices.ign.fr"].requests_per_minute, 60);
+        assert_eq!(host_limits["geoservices.ign.fr"].max_connections, 4);
+        assert_eq!(host_limits[OTHER_HOSTS].requests_per_minute, 0);
+        assert_eq!(host_limits[OTHER_HOSTS].max_connections, 2);
+    }
+
+    #[test]
+    fn parse_host_limits_rejects_invalid_limits() {
+        for value in [
+            "geoservices.ign.fr",
+            "geoservices.ign.fr=60",
+            "geoservices.ign.fr=a/4",
+            "=60/-1",
+            "",
+        ] {
+            assert!(parse_host_limits(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_poll_rate_accepts_one_request_per_hour_and_more() {
+        assert_eq!(parse_poll_rate("0.5"), Ok(0.5));
+        assert_eq!(parse_poll_rate("100"), Ok(100.0));
+        assert!(parse_poll_rate(&(1.0 / 3600.0).to_string()).is_ok());
+    }
+
+    #[test]
+    fn parse_poll_rate_rejects_invalid_rates() {
+        for value in ["0", "-1", "0.0001", "NaN", "inf", "fast", ""] {
+            assert!(parse_poll_rate(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_startup_jitter_accepts_up_to_an_hour() {
+        assert_eq!(parse_startup_jitter("0"), Ok(0.0));
+        assert_eq!(parse_startup_jitter("30.5"), Ok(30.5));
+        assert_eq!(parse_startup_jitter("3600"), Ok(3600.0));
+
+        for value in ["-1", "3601", "NaN", "inf", ""] {
+            assert!(parse_startup_jitter(value).is_err(), "{}", value);
+        }
+    }
+}
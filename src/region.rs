@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use std::{fs::read_to_string, path::Path};
+
+/// Description of the grid a mapant-style deployment is built on.
+///
+/// Mapant.fr tiles are 1 km squares in Lambert-93 (EPSG:2154) whose high
+/// quality render is mapped to one zoom 11 web tile. Other countries use
+/// other projections and grids, this struct gathers those assumptions so
+/// that the worker can be pointed to another API without forking.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegionProfile {
+    pub name: String,
+    /// EPSG code of the projected coordinate system used by the tiles.
+    pub epsg: u32,
+    /// Width (and height) of a LiDAR tile, in meters.
+    pub tile_size_meters: i64,
+    /// Web map zoom level matching one LiDAR tile.
+    pub base_zoom_level: i32,
+    /// Width (and height) in pixels of the high quality full map png of a tile.
+    pub high_quality_tile_pixel_size: u32,
+    /// Format of the clipped vector layers of the render steps.
+    #[serde(default)]
+    pub vector_format: VectorFormat,
+}
+
+/// Format of the vector layers, shapefiles by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorFormat {
+    #[default]
+    Shapefile,
+    /// Single file with a spatial index, which web clients can stream
+    FlatGeobuf,
+}
+
+impl VectorFormat {
+    /// Name of the OGR driver writing the format.
+    pub fn driver(&self) -> &'static str {
+        match self {
+            VectorFormat::Shapefile => "ESRI Shapefile",
+            VectorFormat::FlatGeobuf => "FlatGeobuf",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            VectorFormat::Shapefile => "shp",
+            VectorFormat::FlatGeobuf => "fgb",
+        }
+    }
+}
+
+impl RegionProfile {
+    pub fn france() -> Self {
+        RegionProfile {
+            name: "fr".to_string(),
+            epsg: 2154,
+            tile_size_meters: 1000,
+            base_zoom_level: 11,
+            high_quality_tile_pixel_size: 2362,
+            vector_format: VectorFormat::Shapefile,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        match name.trim().to_lowercase().as_str() {
+            "fr" => Ok(RegionProfile::france()),
+            _ => Err(format!(
+                "Unknown region profile '{}'. Expected fr, or a custom profile with --region-file",
+                name
+            )
+            .into()),
+        }
+    }
+
+    /// Load a custom profile from a json file having the same fields as the struct.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = read_to_string(path)?;
+        let profile: RegionProfile = serde_json::from_str(&content)?;
+
+        if profile.tile_size_meters <= 0 || profile.high_quality_tile_pixel_size == 0 {
+            return Err(format!("Invalid region profile in {}", path.display()).into());
+        }
+
+        Ok(profile)
+    }
+
+    /// Tile ids are the coordinates of the bottom left corner of the tile: `{min_x}_{min_y}`
+    pub fn get_extent_from_tile_id(&self, tile_id: &str) -> (i64, i64, i64, i64) {
+        let parts: Vec<i64> = tile_id
+            .trim()
+            .split('_')
+            .map(|s| s.parse::<i64>())
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Problem parsing extent from tile id");
+
+        if parts.len() != 2 {
+            panic!("Problem parsing extent from tile id")
+        }
+
+        (
+            parts[0],
+            parts[1],
+            parts[0] + self.tile_size_meters,
+            parts[1] + self.tile_size_meters,
+        )
+    }
+}
+
+/// Parse a bounding box given as `min_x,min_y,max_x,max_y` in the region's coordinate system.
+pub fn parse_bbox(value: &str) -> Result<(i64, i64, i64, i64), String> {
+    let parts: Vec<i64> = value
+        .split(',')
+        .map(|part| part.trim().parse::<i64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("Invalid bbox '{}', expected min_x,min_y,max_x,max_y", value))?;
+
+    if parts.len() != 4 || parts[0] >= parts[2] || parts[1] >= parts[3] {
+        return Err(format!(
+            "Invalid bbox '{}', expected min_x,min_y,max_x,max_y",
+            value
+        ));
+    }
+
+    Ok((parts[0], parts[1], parts[2], parts[3]))
+}
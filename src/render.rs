@@ -1,126 +1,376 @@
-use cassini::{get_extent_from_lidar_dir_path, process_single_tile_render_step};
-use image::{GenericImage, Rgba, RgbaImage};
-use log::{error, info};
-use reqwest::{
-    blocking::Client,
-    header::{HeaderMap, HeaderValue},
-};
+use cassini::process_single_tile_render_step;
+use image::GenericImage;
+use log::{error, info, warn};
+use reqwest::blocking::Client;
+use serde::Serialize;
 use std::{
-    fs::{self, create_dir_all, remove_dir_all, remove_file, File},
-    io::Write,
+    fs::{self, create_dir_all, read_dir, remove_dir_all, rename},
+    io::ErrorKind,
     path::{Path, PathBuf},
-    process::{Command, ExitStatus},
-    time::Instant,
+    process::{self, ExitStatus},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::utils::{compress_directory, decompress_archive, download_file, upload_files};
+use crate::{
+    artifacts::UploadManifest,
+    auth::ApiAuth,
+    buffer_pool::{release_buffer, transparent_rgba_image},
+    http::http_client,
+    incremental::{
+        acknowledge_unchanged_output, inputs_hash, is_output_unchanged, record_output_inputs,
+        ARCHIVE_HASH_FILE_NAME,
+    },
+    panics::catch_cassini_panic,
+    raster::{creation_option_args, finish_raster, has_crs, raster_sizes},
+    region::{RegionProfile, VectorFormat},
+    render_checkpoint::{RenderCheckpoint, RenderStage},
+    scratch::scratch_dir,
+    state::report_stage,
+    subprocess::{run_subprocess, subprocess_command},
+    tile_encoder::{optimize_png_in_place, quantize_png_in_place},
+    tile_lock::TileLock,
+    tile_metadata::{tile_extent, TILE_METADATA_FILE_NAME},
+    utils::{
+        compress_directory, decompress_archive, download_artifact, partial_path, sha256_of_file,
+        upload_artifacts, write_atomically, ArchiveMismatch, StorageHints,
+    },
+};
 
 const SMALL_BUFFER_FOR_SHAPEFILES_CLIPPING: i64 = 20;
-const HIGH_QUALITY_TILE_PIXEL_SIZE: u32 = 2362;
+/// Downloads of a LiDAR step archive whose unpacked files do not match its manifest
+const UNPACK_ATTEMPTS: u32 = 2;
+/// Delay between two checks of a LiDAR step tile locked by another thread or process
+const TILE_LOCK_RETRY_DELAY: Duration = Duration::from_secs(1);
+const RASTERS_DIR_NAME: &str = "rasters";
+const SHAPEFILES_DIR_NAME: &str = "shapefiles";
+const PNGS_DIR_NAME: &str = "pngs";
+/// Full map resized to a square for the tiles on the edge of the region, the one uploaded
+const SQUARE_FULL_MAP_FILE_NAME: &str = "full-map-square.png";
+/// Read by cassini from the current directory for every render
+const RENDER_CONFIG_PATH: &str = "config.json";
+/// Copy of the config file while a style render replaces it, empty if there was no config file
+const RENDER_CONFIG_BACKUP_PATH: &str = "config.json.default";
+
+/// Held for reading by the renders using the config file of the work directory, and for writing
+/// by the ones replacing it with the config of their style.
+static RENDER_CONFIG_LOCK: RwLock<()> = RwLock::new(());
+
+/// Style of a restyle render: a cassini config (thresholds, DPI, ...) identified by the server.
+pub struct RenderStyle {
+    pub id: String,
+    pub config: serde_json::Value,
+}
+
+/// Lock on the cassini config file for the duration of a render. A style render takes the lock
+/// for writing, every other render waits for it to finish.
+pub enum RenderConfigGuard {
+    /// The config file of the work directory, shared by the renders without a style
+    Default { _lock: RwLockReadGuard<'static, ()> },
+    /// The config of a style replaces the config file, the backup of the previous one is put back
+    /// when dropped, including when the render panics, or by `restore_render_config` at the next
+    /// start if the process is killed meanwhile
+    Style { _lock: RwLockWriteGuard<'static, ()> },
+}
+
+impl RenderConfigGuard {
+    pub fn lock(style: Option<&RenderStyle>) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(style) = style else {
+            let lock = RENDER_CONFIG_LOCK
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            return Ok(RenderConfigGuard::Default { _lock: lock });
+        };
+
+        let lock = RENDER_CONFIG_LOCK
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Not the backup of a killed style render as the config to put back
+        restore_render_config()?;
+        let previous_config = fs::read_to_string(RENDER_CONFIG_PATH).unwrap_or_default();
+        write_file_atomically(Path::new(RENDER_CONFIG_BACKUP_PATH), &previous_config)?;
+        write_file_atomically(
+            Path::new(RENDER_CONFIG_PATH),
+            &serde_json::to_string_pretty(&style.config)?,
+        )?;
 
+        Ok(RenderConfigGuard::Style { _lock: lock })
+    }
+}
+
+impl Drop for RenderConfigGuard {
+    fn drop(&mut self) {
+        if let (RenderConfigGuard::Style { .. }, Err(error)) = (&self, restore_render_config()) {
+            error!("Failed to restore the render config: {}", error);
+        }
+    }
+}
+
+/// Put back the config file replaced by a style render, if any. True if there was one to restore.
+pub fn restore_render_config() -> Result<bool, Box<dyn std::error::Error>> {
+    let previous_config = match fs::read_to_string(RENDER_CONFIG_BACKUP_PATH) {
+        Ok(previous_config) => previous_config,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error.into()),
+    };
+
+    if previous_config.is_empty() {
+        fs::remove_file(RENDER_CONFIG_PATH).or_else(|error| match error.kind() {
+            ErrorKind::NotFound => Ok(()),
+            _ => Err(error),
+        })?;
+    } else {
+        write_file_atomically(Path::new(RENDER_CONFIG_PATH), &previous_config)?;
+    }
+
+    fs::remove_file(RENDER_CONFIG_BACKUP_PATH)?;
+
+    Ok(true)
+}
+
+fn write_file_atomically(path: &Path, contents: &str) -> Result<(), Box<dyn std::error::Error>> {
+    write_atomically(path, |partial_path| {
+        fs::write(partial_path, contents)?;
+        Ok(())
+    })
+}
+
+/// Render a tile from the LiDAR step outputs of the tile and its neighbors, downloaded if not in
+/// the cache. With a style, the render uses the style's config and is uploaded as a variant of
+/// the tile, eg: to iterate on the map style without running the LiDAR step again.
 pub fn render_step(
     tile_id: &str,
     neigbhoring_tiles_ids: &Vec<String>,
-    worker_id: &str,
-    token: &str,
+    auth: &ApiAuth,
     base_api_url: &str,
+    region: &RegionProfile,
+    storage: Option<&StorageHints>,
+    style: Option<&RenderStyle>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let lidar_step_base_dir_path = Path::new("lidar-step");
+    let client = http_client();
+
+    report_stage("downloading inputs");
+    // The locks keep the files from being evicted by another worker process during the render
+    let RenderStepInputs {
+        tile_dir_path: lidar_step_tile_dir_path,
+        neighbor_dir_paths: neighbor_tiles_lidar_step_dir_paths,
+        tile_locks: _tile_locks,
+    } = download_render_step_inputs(
+        &client,
+        tile_id,
+        neigbhoring_tiles_ids,
+        auth,
+        base_api_url,
+        storage,
+    )?;
 
-    if !lidar_step_base_dir_path.exists() {
-        create_dir_all(lidar_step_base_dir_path)?;
-    }
+    let (url, key_prefix) = match style {
+        Some(style) => (
+            format!(
+                "{}/api/map-generation/render-steps/{}/styles/{}",
+                base_api_url, &tile_id, style.id
+            ),
+            format!("styles/{}/render-steps/{}", style.id, tile_id),
+        ),
+        None => (
+            format!("{}/api/map-generation/render-steps/{}", base_api_url, &tile_id),
+            format!("render-steps/{}", tile_id),
+        ),
+    };
 
-    // Downloading lidar step files for the tile if not already on disk
-    let lidar_step_tile_dir_path = lidar_step_base_dir_path.join(tile_id);
+    let render_step_path = scratch_dir().join("render-step");
 
-    let client = Client::new();
+    if !render_step_path.exists() {
+        create_dir_all(&render_step_path)?;
+    }
 
-    download_and_decompress_lidar_step_files_if_not_on_disk(
-        &client,
-        tile_id,
-        worker_id,
-        token,
-        base_api_url,
-        lidar_step_base_dir_path,
+    let output_dir_path = match style {
+        Some(style) => render_step_path.join(format!("{}.style-{}", tile_id, style.id)),
+        None => render_step_path.join(&tile_id),
+    };
+
+    let inputs_hash = render_inputs_hash(
         &lidar_step_tile_dir_path,
+        &neighbor_tiles_lidar_step_dir_paths,
+        region,
+        style,
     )?;
 
-    let mut neighbor_tiles_lidar_step_dir_paths: Vec<PathBuf> = vec![];
+    let mut checkpoint = RenderCheckpoint::load(&output_dir_path, inputs_hash.as_deref());
 
-    // Downloading lidar step files for the neigbhoring tiles if not already on disk
-    for neigbhoring_tile_id in neigbhoring_tiles_ids {
-        let neigbhoring_tile_lidar_step_dir_path = lidar_step_base_dir_path.join(neigbhoring_tile_id);
+    if let Some(inputs_hash) = &inputs_hash {
+        if (is_output_unchanged(&key_prefix, inputs_hash) || checkpoint.is_done(RenderStage::Uploaded))
+            && acknowledge_unchanged_output(&client, auth, &url, base_api_url, inputs_hash)?
+        {
+            info!(
+                "Inputs of the render of tile {} unchanged since it was uploaded, skipping it",
+                &tile_id
+            );
 
-        download_and_decompress_lidar_step_files_if_not_on_disk(
-            &client,
-            neigbhoring_tile_id,
-            worker_id,
-            token,
-            base_api_url,
-            lidar_step_base_dir_path,
-            &neigbhoring_tile_lidar_step_dir_path,
-        )?;
+            return Ok(());
+        }
+    }
 
-        neighbor_tiles_lidar_step_dir_paths.push(neigbhoring_tile_lidar_step_dir_path);
+    // Files of an interrupted render of other inputs, or of a render that can not be resumed
+    if checkpoint.stage().is_none() && output_dir_path.exists() {
+        remove_dir_all(&output_dir_path)?;
     }
 
-    let render_step_path = Path::new("render-step");
+    if !checkpoint.is_done(RenderStage::Rendered) {
+        report_stage("rendering");
+        info!("Processing render step for tile {}", &tile_id);
+        let start = Instant::now();
+
+        if let Some(style) = style {
+            info!("Rendering tile {} with style {}", tile_id, style.id);
+        }
+
+        {
+            let _config_guard = RenderConfigGuard::lock(style)?;
+
+            catch_cassini_panic("render step", || {
+                process_single_tile_render_step(
+                    &lidar_step_tile_dir_path,
+                    &output_dir_path,
+                    neighbor_tiles_lidar_step_dir_paths,
+                    false,
+                    true,
+                )
+            })?;
+        }
+
+        let duration = start.elapsed();
+
+        info!("Render step for tile {} processed in {:.1?}", &tile_id, duration);
+        checkpoint.complete(RenderStage::Rendered);
+    }
 
-    if !render_step_path.exists() {
-        create_dir_all(render_step_path)?;
+    if !checkpoint.is_done(RenderStage::Cropped) {
+        crop_render_outputs(&output_dir_path, &lidar_step_tile_dir_path, tile_id, region)?;
+        checkpoint.complete(RenderStage::Cropped);
     }
 
-    let output_dir_path = render_step_path.join(&tile_id);
+    let rasters_archive_file_name = format!("rasters_{}.tar.xz", &tile_id);
+    let rasters_archive_path = output_dir_path.join(&rasters_archive_file_name);
+    let shapefiles_archive_file_name = format!("shapefiles_{}.tar.xz", &tile_id);
+    let shapefiles_archive_path = output_dir_path.join(&shapefiles_archive_file_name);
+    let pngs_archive_file_name = format!("pngs_{}.tar.xz", &tile_id);
+    let pngs_archive_path = output_dir_path.join(&pngs_archive_file_name);
 
-    info!("Processing render step for tile {}", &tile_id);
-    let start = Instant::now();
+    if !checkpoint.is_done(RenderStage::Archived) {
+        report_stage("compressing");
+        compress_directory(&output_dir_path.join(RASTERS_DIR_NAME), &rasters_archive_path)?;
+        compress_directory(
+            &output_dir_path.join(SHAPEFILES_DIR_NAME),
+            &shapefiles_archive_path,
+        )?;
+        compress_directory(&output_dir_path.join(PNGS_DIR_NAME), &pngs_archive_path)?;
+        checkpoint.complete(RenderStage::Archived);
+    }
 
-    process_single_tile_render_step(
-        &lidar_step_tile_dir_path,
-        &output_dir_path,
-        neighbor_tiles_lidar_step_dir_paths,
-        false,
-        true,
-    );
+    report_stage("uploading");
+    // Upload files
+    upload_artifacts(
+        &client,
+        auth,
+        url,
+        base_api_url,
+        UploadManifest::new()
+            .add(
+                &rasters_archive_file_name,
+                "rasters",
+                &rasters_archive_path,
+                "application/x-bzip2",
+            )?
+            .add(
+                &shapefiles_archive_file_name,
+                "shapefiles",
+                &shapefiles_archive_path,
+                "application/x-bzip2",
+            )?
+            .add(
+                &pngs_archive_file_name,
+                "pngs",
+                &pngs_archive_path,
+                "application/x-bzip2",
+            )?
+            .add(
+                "full-map.png",
+                "full-map",
+                &output_dir_path.join(SQUARE_FULL_MAP_FILE_NAME),
+                "image/png",
+            )?,
+        storage,
+        &key_prefix,
+    )?;
+
+    checkpoint.complete(RenderStage::Uploaded);
+
+    if let Some(inputs_hash) = &inputs_hash {
+        record_output_inputs(&key_prefix, inputs_hash);
+    }
 
-    let duration = start.elapsed();
+    Ok(())
+}
 
-    info!("Render step for tile {} processed in {:.1?}", &tile_id, duration);
+/// Crop the rasters and vectors rendered with a buffer to the tile, and resize the PNGs of the
+/// tiles on the edge of the region to full squares.
+fn crop_render_outputs(
+    output_dir_path: &Path,
+    lidar_step_tile_dir_path: &Path,
+    tile_id: &str,
+    region: &RegionProfile,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Left by an interrupted crop, ogr2ogr does not overwrite
+    for dir_name in [RASTERS_DIR_NAME, SHAPEFILES_DIR_NAME, PNGS_DIR_NAME] {
+        let dir_path = output_dir_path.join(dir_name);
+
+        if dir_path.exists() {
+            remove_dir_all(dir_path)?;
+        }
+    }
 
+    report_stage("cropping");
     // Crop tiff images
-    let rasters_path = output_dir_path.join("rasters");
+    let rasters_path = output_dir_path.join(RASTERS_DIR_NAME);
     create_dir_all(&rasters_path)?;
-    let tile_extent = get_extent_from_lidar_dir_path(&lidar_step_tile_dir_path);
+    let tile_extent = tile_extent(&lidar_step_tile_dir_path.to_path_buf())?;
 
     crop_tiff_image(
         &output_dir_path.join("dem-with-buffer.tif"),
         &rasters_path.join("dem.tif"),
         tile_extent,
+        region.epsg,
     )?;
 
     crop_tiff_image(
         &output_dir_path.join("dem-low-resolution-with-buffer.tif"),
         &rasters_path.join("dem-low-resolution.tif"),
         tile_extent,
+        region.epsg,
     )?;
 
     crop_tiff_image(
         &output_dir_path.join("high-vegetation-with-buffer.tif"),
         &rasters_path.join("high-vegetation.tif"),
         tile_extent,
+        region.epsg,
     )?;
 
     crop_tiff_image(
         &output_dir_path.join("medium-vegetation-with-buffer.tif"),
         &rasters_path.join("medium-vegetation.tif"),
         tile_extent,
+        region.epsg,
     )?;
 
     crop_tiff_image(
         &output_dir_path.join("slopes.tif"),
         &rasters_path.join("slopes.tif"),
         tile_extent,
+        region.epsg,
     )?;
 
     fs::copy(
@@ -133,13 +383,25 @@ pub fn render_step(
         &rasters_path.join("pipeline.json"),
     )?;
 
-    // Compress tiff images
-    let rasters_archive_file_name = format!("rasters_{}.tar.xz", &tile_id);
-    let rasters_archive_path = output_dir_path.join(&rasters_archive_file_name);
-    compress_directory(&rasters_path, &rasters_archive_path)?;
+    // Not there for the LiDAR steps produced by older workers
+    let tile_metadata_path = lidar_step_tile_dir_path.join(TILE_METADATA_FILE_NAME);
+
+    if tile_metadata_path.exists() {
+        fs::copy(&tile_metadata_path, rasters_path.join(TILE_METADATA_FILE_NAME))?;
+    }
+
+    let raster_sizes = raster_sizes();
+    info!(
+        "Rasters of tile {} cropped: {} KB, {} KB uncompressed",
+        &tile_id,
+        raster_sizes.compressed_bytes / 1000,
+        raster_sizes.uncompressed_bytes / 1000
+    );
+
+    write_manifest(&rasters_path, tile_id, region)?;
 
     // Crop shapes
-    let shapefiles_path = output_dir_path.join("shapefiles");
+    let shapefiles_path = output_dir_path.join(SHAPEFILES_DIR_NAME);
     let vectors_path = shapefiles_path.join("vectors");
     let contours_path = shapefiles_path.join("contours");
     let contours_raw_path = shapefiles_path.join("contours-raw");
@@ -151,46 +413,53 @@ pub fn render_step(
 
     clip_shapefiles_with_small_buffer(
         &output_dir_path.join("shapes").join("lines.shp"),
-        &vectors_path.join("lines.shp"),
+        &vectors_path.join(format!("lines.{}", region.vector_format.extension())),
         tile_extent,
+        region.epsg,
+        region.vector_format,
     )?;
 
     clip_shapefiles_with_small_buffer(
         &output_dir_path.join("shapes").join("multipolygons.shp"),
-        &vectors_path.join("multipolygons.shp"),
+        &vectors_path.join(format!("multipolygons.{}", region.vector_format.extension())),
         tile_extent,
+        region.epsg,
+        region.vector_format,
     )?;
 
     clip_shapefiles_with_small_buffer(
         &output_dir_path.join("contours").join("contours.shp"),
-        &contours_path.join("contours.shp"),
+        &contours_path.join(format!("contours.{}", region.vector_format.extension())),
         tile_extent,
+        region.epsg,
+        region.vector_format,
     )?;
 
     clip_shapefiles_with_small_buffer(
         &output_dir_path.join("contours-raw").join("contours-raw.shp"),
-        &contours_raw_path.join("contours-raw.shp"),
+        &contours_raw_path.join(format!("contours-raw.{}", region.vector_format.extension())),
         tile_extent,
+        region.epsg,
+        region.vector_format,
     )?;
 
     clip_shapefiles_with_small_buffer(
         &output_dir_path.join("formlines").join("formlines.shp"),
-        &formlines_path.join("formlines.shp"),
+        &formlines_path.join(format!("formlines.{}", region.vector_format.extension())),
         tile_extent,
+        region.epsg,
+        region.vector_format,
     )?;
 
-    // Compress shapes
-    let shapefiles_archive_file_name = format!("shapefiles_{}.tar.xz", &tile_id);
-    let shapefiles_archive_path = output_dir_path.join(&shapefiles_archive_file_name);
-    compress_directory(&shapefiles_path, &shapefiles_archive_path)?;
+    write_manifest(&shapefiles_path, tile_id, region)?;
 
-    // Resize pngs to 1000 meters square tiles if smaller
-    let (real_min_x, real_min_y, real_max_x, real_max_y) =
-        get_extent_from_lidar_dir_path(&lidar_step_tile_dir_path);
-    let extent = get_extent_from_tile_id(&tile_id);
+    report_stage("resizing");
+    // Resize pngs to full size square tiles if smaller
+    let (real_min_x, real_min_y, real_max_x, real_max_y) = tile_extent;
+    let extent = region.get_extent_from_tile_id(&tile_id);
     let (min_x, min_y, max_x, max_y) = extent;
 
-    let pngs_path = output_dir_path.join("pngs");
+    let pngs_path = output_dir_path.join(PNGS_DIR_NAME);
     create_dir_all(&pngs_path)?;
 
     if real_min_x != min_x || real_min_y != min_y || real_max_x != max_x || real_max_y != max_y {
@@ -200,6 +469,7 @@ pub fn render_step(
             extent,
             real_min_x,
             real_max_y,
+            region.high_quality_tile_pixel_size,
         )?;
 
         resize_png_to_high_quality_square(
@@ -208,6 +478,7 @@ pub fn render_step(
             extent,
             real_min_x,
             real_max_y,
+            region.high_quality_tile_pixel_size,
         )?;
 
         resize_png_to_high_quality_square(
@@ -216,14 +487,16 @@ pub fn render_step(
             extent,
             real_min_x,
             real_max_y,
+            region.high_quality_tile_pixel_size,
         )?;
 
         resize_png_to_high_quality_square(
             &output_dir_path.join("full-map.png"),
-            &output_dir_path.join("full-map.png"),
+            &output_dir_path.join(SQUARE_FULL_MAP_FILE_NAME),
             extent,
             real_min_x,
             real_max_y,
+            region.high_quality_tile_pixel_size,
         )?;
     } else {
         // Copy pngs in the same directory
@@ -239,73 +512,143 @@ pub fn render_step(
             &output_dir_path.join("vegetation.png"),
             &pngs_path.join("vegetation.png"),
         )?;
+
+        write_atomically(&output_dir_path.join(SQUARE_FULL_MAP_FILE_NAME), |partial_path| {
+            Ok(fs::copy(output_dir_path.join("full-map.png"), partial_path)?)
+        })?;
     }
 
-    // Compress pngs
-    let pngs_archive_file_name = format!("pngs_{}.tar.xz", &tile_id);
-    let pngs_archive_path = output_dir_path.join(&pngs_archive_file_name);
-    compress_directory(&pngs_path, &pngs_archive_path)?;
+    for layer_file_name in ["cliffs.png", "contours.png", "vegetation.png"] {
+        quantize_png_in_place(&pngs_path.join(layer_file_name))?;
+    }
 
-    // Upload files
-    let url = format!("{}/api/map-generation/render-steps/{}", base_api_url, &tile_id);
+    report_stage("optimizing");
+    let start = Instant::now();
+    let saved = optimize_png_in_place(&output_dir_path.join(SQUARE_FULL_MAP_FILE_NAME))?;
 
-    upload_files(
-        &client,
-        worker_id,
-        token,
-        url,
-        base_api_url,
-        vec![
-            (
-                rasters_archive_file_name,
-                "rasters".to_string(),
-                rasters_archive_path,
-                "application/x-bzip2".to_string(),
-            ),
-            (
-                shapefiles_archive_file_name,
-                "shapefiles".to_string(),
-                shapefiles_archive_path,
-                "application/x-bzip2".to_string(),
-            ),
-            (
-                pngs_archive_file_name,
-                "pngs".to_string(),
-                pngs_archive_path,
-                "application/x-bzip2".to_string(),
-            ),
-            (
-                "full-map.png".to_string(),
-                "full-map".to_string(),
-                output_dir_path.join("full-map.png"),
-                "image/png".to_string(),
-            ),
-        ],
-    )?;
+    info!(
+        "Full map of tile {} recompressed in {:.1?}, {} KB saved",
+        tile_id,
+        start.elapsed(),
+        saved / 1000
+    );
 
     Ok(())
 }
 
-fn resize_png_to_high_quality_square(
+/// Hash of the LiDAR step archives of the tile and its neighbors, of the config and of the
+/// raster options. None if the archive of a tile is unknown, eg: files left by an older version.
+fn render_inputs_hash(
+    lidar_step_tile_dir_path: &Path,
+    neighbor_tiles_lidar_step_dir_paths: &[PathBuf],
+    region: &RegionProfile,
+    style: Option<&RenderStyle>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut inputs = vec![];
+
+    for lidar_step_dir_path in [lidar_step_tile_dir_path]
+        .into_iter()
+        .chain(neighbor_tiles_lidar_step_dir_paths.iter().map(PathBuf::as_path))
+    {
+        match fs::read_to_string(lidar_step_dir_path.join(ARCHIVE_HASH_FILE_NAME)) {
+            Ok(archive_hash) => inputs.push(archive_hash),
+            Err(_) => return Ok(None),
+        }
+    }
+
+    inputs.push(match style {
+        Some(style) => serde_json::to_string(&style.config)?,
+        None => {
+            // Not the config of a style render replacing the file meanwhile
+            let _config_guard = RenderConfigGuard::lock(None)?;
+            fs::read_to_string(RENDER_CONFIG_PATH).unwrap_or_default()
+        }
+    });
+    inputs.push(region.name.clone());
+    inputs.push(creation_option_args().join(" "));
+
+    Ok(Some(inputs_hash(
+        &inputs.iter().map(String::as_str).collect::<Vec<_>>(),
+    )))
+}
+
+/// LiDAR step files of a tile and of its neighbors, see `download_render_step_inputs`.
+pub struct RenderStepInputs {
+    pub tile_dir_path: PathBuf,
+    pub neighbor_dir_paths: Vec<PathBuf>,
+    /// Shared locks of these tiles, to hold while the files are used
+    pub tile_locks: Vec<TileLock>,
+}
+
+/// Download and decompress the LiDAR step files of a tile and its neighbors if not already on disk.
+pub fn download_render_step_inputs(
+    client: &Client,
+    tile_id: &str,
+    neigbhoring_tiles_ids: &Vec<String>,
+    auth: &ApiAuth,
+    base_api_url: &str,
+    storage: Option<&StorageHints>,
+) -> Result<RenderStepInputs, Box<dyn std::error::Error>> {
+    let lidar_step_base_dir_path = Path::new("lidar-step");
+
+    if !lidar_step_base_dir_path.exists() {
+        create_dir_all(lidar_step_base_dir_path)?;
+    }
+
+    // Downloading lidar step files for the tile if not already on disk
+    let lidar_step_tile_dir_path = lidar_step_base_dir_path.join(tile_id);
+
+    let mut tile_locks = vec![download_and_decompress_lidar_step_files_if_not_on_disk(
+        client,
+        tile_id,
+        auth,
+        base_api_url,
+        lidar_step_base_dir_path,
+        &lidar_step_tile_dir_path,
+        storage,
+    )?];
+
+    let mut neighbor_tiles_lidar_step_dir_paths: Vec<PathBuf> = vec![];
+
+    // Downloading lidar step files for the neigbhoring tiles if not already on disk
+    for neigbhoring_tile_id in neigbhoring_tiles_ids {
+        let neigbhoring_tile_lidar_step_dir_path = lidar_step_base_dir_path.join(neigbhoring_tile_id);
+
+        tile_locks.push(download_and_decompress_lidar_step_files_if_not_on_disk(
+            client,
+            neigbhoring_tile_id,
+            auth,
+            base_api_url,
+            lidar_step_base_dir_path,
+            &neigbhoring_tile_lidar_step_dir_path,
+            storage,
+        )?);
+
+        neighbor_tiles_lidar_step_dir_paths.push(neigbhoring_tile_lidar_step_dir_path);
+    }
+
+    Ok(RenderStepInputs {
+        tile_dir_path: lidar_step_tile_dir_path,
+        neighbor_dir_paths: neighbor_tiles_lidar_step_dir_paths,
+        tile_locks,
+    })
+}
+
+pub fn resize_png_to_high_quality_square(
     image_to_resize_path: &PathBuf,
     output_path: &PathBuf,
     extent: (i64, i64, i64, i64),
     real_min_x: i64,
     real_max_y: i64,
+    tile_pixel_size: u32,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let (min_x, min_y, max_x, max_y) = extent;
 
-    let mut tile_image = RgbaImage::from_pixel(
-        HIGH_QUALITY_TILE_PIXEL_SIZE,
-        HIGH_QUALITY_TILE_PIXEL_SIZE,
-        Rgba([0, 0, 0, 0]),
-    );
+    let mut tile_image = transparent_rgba_image(tile_pixel_size, tile_pixel_size);
 
-    let start_x = HIGH_QUALITY_TILE_PIXEL_SIZE as f64 * (real_min_x as f64 - min_x as f64)
-        / (max_x as f64 - min_x as f64);
+    let start_x = tile_pixel_size as f64 * (real_min_x as f64 - min_x as f64) / (max_x as f64 - min_x as f64);
 
-    let start_y = HIGH_QUALITY_TILE_PIXEL_SIZE as f64 * (max_y as f64 - real_max_y as f64)
-        / (max_y as f64 - min_y as f64);
+    let start_y = tile_pixel_size as f64 * (max_y as f64 - real_max_y as f64) / (max_y as f64 - min_y as f64);
 
     let image_to_resize = image::open(image_to_resize_path)?;
 
@@ -315,55 +658,96 @@ fn resize_png_to_high_quality_square(
         start_y.round() as u32,
     )?;
 
-    tile_image.save(output_path)?;
+    write_atomically(output_path, |partial_path| Ok(tile_image.save(partial_path)?))?;
+    release_buffer(tile_image.into_raw());
 
     Ok(())
 }
 
+/// Make sure the LiDAR step files of a tile are on disk, downloading them if needed. Returns a
+/// shared lock on the tile, to hold while the files are used.
 fn download_and_decompress_lidar_step_files_if_not_on_disk(
     client: &Client,
     tile_id: &str,
-    worker_id: &str,
-    token: &str,
+    auth: &ApiAuth,
     base_api_url: &str,
     lidar_step_base_dir_path: &Path,
     lidar_step_tile_dir_path: &PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // TODO (maybe) implement a real central queue system. Using a naive approach for now
-    let flag_file_path = lidar_step_base_dir_path.join(format!("{}.txt", tile_id));
-
-    if flag_file_path.exists() {
-        info!(
-            "Files from LiDAR step for tile {} already being downloaded and decompressed. Retrying in 0.5s.",
-            &tile_id
-        );
-
-        std::thread::sleep(std::time::Duration::from_millis(500));
-
-        return download_and_decompress_lidar_step_files_if_not_on_disk(
-            &client,
+    storage: Option<&StorageHints>,
+) -> Result<TileLock, Box<dyn std::error::Error>> {
+    let is_published = || lidar_step_tile_dir_path.join("extent.txt").exists();
+    let mut waiting = false;
+
+    // Waiting on an exclusive lock would also wait for the jobs reading the tile once published
+    let exclusive_lock = loop {
+        // Blocks while another thread or process downloads the tile
+        let lock = TileLock::shared(lidar_step_base_dir_path, tile_id)?;
+
+        if is_published() {
+            info!("Files from LiDAR step for tile {} already on disk.", &tile_id);
+
+            return Ok(lock);
+        }
+
+        drop(lock);
+
+        if let Some(exclusive_lock) = TileLock::try_exclusive(lidar_step_base_dir_path, tile_id)? {
+            break exclusive_lock;
+        }
+
+        if !waiting {
+            info!(
+                "Files from LiDAR step for tile {} already being downloaded and decompressed. Waiting.",
+                &tile_id
+            );
+            waiting = true;
+        }
+
+        // The tile may be locked by a reader about to give up on it, eg: evicted meanwhile
+        thread::sleep(TILE_LOCK_RETRY_DELAY);
+    };
+
+    // Another thread or process may have published the tile since the shared lock was released
+    if !is_published() {
+        download_and_publish_lidar_step_files(
+            client,
             tile_id,
-            worker_id,
-            token,
+            auth,
             base_api_url,
             lidar_step_base_dir_path,
             lidar_step_tile_dir_path,
-        );
+            storage,
+        )?;
     }
 
-    if lidar_step_tile_dir_path.join("extent.txt").exists() {
-        info!("Files from LiDAR step for tile {} already on disk.", &tile_id);
+    drop(exclusive_lock);
 
-        return Ok(());
-    }
+    let lock = TileLock::shared(lidar_step_base_dir_path, tile_id)?;
 
-    let mut flag_file = File::create(&flag_file_path).expect("Could not create flag file");
+    if !is_published() {
+        return Err(format!(
+            "Files from LiDAR step for tile {} evicted right after download",
+            tile_id
+        )
+        .into());
+    }
 
-    flag_file
-        .write_all("true".as_bytes())
-        .expect("Could not write to the flag file");
+    Ok(lock)
+}
 
-    flag_file.flush()?;
+/// Download and decompress the LiDAR step files of a tile into a temporary directory, renamed to
+/// the tile directory once complete so that other processes never see partial files. To be
+/// called with the exclusive lock of the tile.
+fn download_and_publish_lidar_step_files(
+    client: &Client,
+    tile_id: &str,
+    auth: &ApiAuth,
+    base_api_url: &str,
+    lidar_step_base_dir_path: &Path,
+    lidar_step_tile_dir_path: &PathBuf,
+    storage: Option<&StorageHints>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    remove_partial_tile_dirs(lidar_step_base_dir_path, tile_id)?;
 
     if lidar_step_tile_dir_path.exists() {
         info!(
@@ -374,80 +758,120 @@ fn download_and_decompress_lidar_step_files_if_not_on_disk(
         remove_dir_all(lidar_step_tile_dir_path)?;
     }
 
-    info!("Downloading files from LiDAR step for tile {}", &tile_id);
-    let start = Instant::now();
-
-    create_dir_all(lidar_step_tile_dir_path)?;
-
     let lidar_step_archive_url = format!("{}/api/map-generation/lidar-steps/{}", base_api_url, tile_id);
 
-    let lidar_step_archive_path = lidar_step_base_dir_path.join(format!("{}.tar.xz", tile_id));
+    // Only needed until decompressed, the archive goes to the scratch directory
+    let archives_path = scratch_dir().join("lidar-step");
+    create_dir_all(&archives_path)?;
+    let lidar_step_archive_path = archives_path.join(format!("{}.tar.xz", tile_id));
+    let partial_dir_path = partial_tile_dir_path(lidar_step_base_dir_path, tile_id);
 
-    let mut headers = HeaderMap::new();
+    for attempt in 1..=UNPACK_ATTEMPTS {
+        info!("Downloading files from LiDAR step for tile {}", &tile_id);
+        let start = Instant::now();
 
-    headers.append(
-        "Authorization",
-        HeaderValue::from_str(&format!("Bearer {}.{}", worker_id, token))?,
-    );
-
-    if let Err(error) = download_file(
-        &client,
-        &lidar_step_archive_url,
-        &lidar_step_archive_path,
-        Some(headers),
-    ) {
-        remove_file(&flag_file_path)?;
-        return Err(error);
-    }
-
-    let duration = start.elapsed();
+        download_artifact(
+            &client,
+            &lidar_step_archive_url,
+            &lidar_step_archive_path,
+            Some(auth),
+            storage,
+            &format!("lidar-steps/{}.tar.xz", tile_id),
+        )?;
 
-    info!(
-        "Files from LiDAR step for tile {} downloaded in {:.1?}",
-        &tile_id, duration
-    );
+        let duration = start.elapsed();
 
-    info!("Decompressing files from LiDAR step for tile {}", &tile_id);
-    let start = Instant::now();
+        info!(
+            "Files from LiDAR step for tile {} downloaded in {:.1?}",
+            &tile_id, duration
+        );
 
-    if let Err(error) = decompress_archive(&lidar_step_archive_path, lidar_step_tile_dir_path) {
-        remove_file(&flag_file_path)?;
-        return Err(error);
+        info!("Decompressing files from LiDAR step for tile {}", &tile_id);
+        let start = Instant::now();
+        create_dir_all(&partial_dir_path)?;
+
+        match decompress_archive(&lidar_step_archive_path, &partial_dir_path) {
+            Ok(()) => {
+                let duration = start.elapsed();
+
+                info!(
+                    "Files from LiDAR step for tile {} decompressed in {:.1?}",
+                    &tile_id, duration
+                );
+
+                break;
+            }
+            // The archive is removed so that it is not reused as an unchanged local copy
+            Err(error) if error.is::<ArchiveMismatch>() && attempt < UNPACK_ATTEMPTS => {
+                warn!("{}. Downloading it again", error);
+                remove_dir_all(&partial_dir_path)?;
+                fs::remove_file(&lidar_step_archive_path)?;
+            }
+            Err(error) => {
+                remove_dir_all(&partial_dir_path)?;
+
+                if error.is::<ArchiveMismatch>() {
+                    fs::remove_file(&lidar_step_archive_path)?;
+                }
+
+                return Err(error);
+            }
+        }
     }
 
-    let duration = start.elapsed();
+    fs::write(
+        partial_dir_path.join(ARCHIVE_HASH_FILE_NAME),
+        sha256_of_file(&lidar_step_archive_path)?,
+    )?;
 
-    info!(
-        "Files from LiDAR step for tile {} decompressed in {:.1?}",
-        &tile_id, duration
-    );
+    if !partial_dir_path.join("extent.txt").exists() {
+        remove_dir_all(&partial_dir_path)?;
+        return Err(format!("LiDAR step archive of tile {} is incomplete", tile_id).into());
+    }
 
-    remove_file(&flag_file_path)?;
+    rename(&partial_dir_path, lidar_step_tile_dir_path)?;
 
     Ok(())
 }
 
-pub fn get_extent_from_tile_id(tile_id: &str) -> (i64, i64, i64, i64) {
-    let parts: Vec<i64> = tile_id
-        .trim()
-        .split('_')
-        .map(|s| s.parse::<i64>())
-        .collect::<Result<Vec<_>, _>>()
-        .expect("Problem parsing extent from tile id");
+/// Temporary directory of the LiDAR step files of a tile being written by this process, renamed to
+/// the tile directory once complete.
+pub fn partial_tile_dir_path(lidar_step_base_dir_path: &Path, tile_id: &str) -> PathBuf {
+    lidar_step_base_dir_path.join(format!("{}.partial-{}", tile_id, process::id()))
+}
 
-    if parts.len() != 2 {
-        panic!("Problem parsing extent from tile id")
+/// Remove the temporary directories of a tile left behind by an interrupted download or LiDAR
+/// step. Nobody else can be writing them under the exclusive lock of the tile.
+pub fn remove_partial_tile_dirs(
+    lidar_step_base_dir_path: &Path,
+    tile_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let partial_dir_prefix = format!("{}.partial-", tile_id);
+
+    for entry in read_dir(lidar_step_base_dir_path)?.filter_map(|entry| entry.ok()) {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&partial_dir_prefix)
+        {
+            remove_dir_all(entry.path())?;
+        }
     }
 
-    return (parts[0], parts[1], parts[0] + 1000, parts[1] + 1000);
+    Ok(())
 }
 
 fn crop_tiff_image(
     input_file_path: &PathBuf,
     output_file_path: &PathBuf,
     (min_x, min_y, max_x, max_y): (i64, i64, i64, i64),
+    epsg: u32,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let gdal_translate_output = Command::new("gdal_translate")
+    // Renamed to `output_file_path` once checked
+    let partial_path = partial_path(output_file_path);
+
+    let mut command = subprocess_command("gdal_translate");
+    command
         .args([
             "-projwin",
             &(min_x).to_string(),
@@ -455,12 +879,26 @@ fn crop_tiff_image(
             &(max_x).to_string(),
             &(min_y).to_string(),
         ])
-        .args(["-of", "GTiff"])
-        .arg(input_file_path.to_str().unwrap())
-        .arg(output_file_path.to_str().unwrap())
-        .arg("--quiet")
-        .output()
-        .expect("failed to execute gdal_translate command");
+        .args(["-of", "GTiff"]);
+
+    // Only when missing, a source in another CRS must fail `check_raster`
+    if !has_crs(input_file_path) {
+        warn!(
+            "{} has no CRS, assigning EPSG:{}",
+            input_file_path.display(),
+            epsg
+        );
+        command.args(["-a_srs", &format!("EPSG:{}", epsg)]);
+    }
+
+    let gdal_translate_output = run_subprocess(
+        command
+            .args(creation_option_args())
+            .arg(input_file_path.to_str().unwrap())
+            .arg(partial_path.to_str().unwrap())
+            .arg("--quiet"),
+        "gdal_translate",
+    )?;
 
     if !ExitStatus::success(&gdal_translate_output.status) {
         error!(
@@ -471,30 +909,50 @@ fn crop_tiff_image(
             max_y,
             String::from_utf8(gdal_translate_output.stderr).unwrap()
         );
+        let _ = fs::remove_file(&partial_path);
+    } else if let Err(error) = finish_raster(&partial_path, epsg) {
+        let _ = fs::remove_file(&partial_path);
+        return Err(error);
+    } else {
+        rename(&partial_path, output_file_path)?;
     }
 
     Ok(())
 }
 
+/// Clip the shapefile of a layer to the tile and write it in `format`.
 fn clip_shapefiles_with_small_buffer(
     input_file_path: &PathBuf,
     output_file_path: &PathBuf,
     (min_x, min_y, max_x, max_y): (i64, i64, i64, i64),
+    epsg: u32,
+    format: VectorFormat,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let ogr2ogr_output = Command::new("ogr2ogr")
-        .arg("-f")
-        .arg("ESRI Shapefile")
-        .arg(output_file_path.to_str().unwrap())
-        .arg(input_file_path.to_str().unwrap())
-        .arg("-clipsrc")
-        .args([
-            &(min_x - SMALL_BUFFER_FOR_SHAPEFILES_CLIPPING).to_string(),
-            &(min_y - SMALL_BUFFER_FOR_SHAPEFILES_CLIPPING).to_string(),
-            &(max_x + SMALL_BUFFER_FOR_SHAPEFILES_CLIPPING).to_string(),
-            &(max_y + SMALL_BUFFER_FOR_SHAPEFILES_CLIPPING).to_string(),
-        ])
-        .output()
-        .expect("failed to execute ogr2ogr command");
+    let mut command = subprocess_command("ogr2ogr");
+    command.arg("-f").arg(format.driver());
+
+    if !has_crs(input_file_path) {
+        warn!(
+            "{} has no CRS, assigning EPSG:{}",
+            input_file_path.display(),
+            epsg
+        );
+        command.args(["-a_srs", &format!("EPSG:{}", epsg)]);
+    }
+
+    let ogr2ogr_output = run_subprocess(
+        command
+            .arg(output_file_path.to_str().unwrap())
+            .arg(input_file_path.to_str().unwrap())
+            .arg("-clipsrc")
+            .args([
+                &(min_x - SMALL_BUFFER_FOR_SHAPEFILES_CLIPPING).to_string(),
+                &(min_y - SMALL_BUFFER_FOR_SHAPEFILES_CLIPPING).to_string(),
+                &(max_x + SMALL_BUFFER_FOR_SHAPEFILES_CLIPPING).to_string(),
+                &(max_y + SMALL_BUFFER_FOR_SHAPEFILES_CLIPPING).to_string(),
+            ]),
+        "ogr2ogr",
+    )?;
 
     if !ExitStatus::success(&ogr2ogr_output.status) {
         error!(
@@ -505,7 +963,60 @@ fn clip_shapefiles_with_small_buffer(
             max_y,
             String::from_utf8(ogr2ogr_output.stderr).unwrap()
         );
+    } else if format == VectorFormat::Shapefile && !output_file_path.with_extension("prj").exists() {
+        write_prj(&output_file_path.with_extension("prj"), epsg)?;
+    }
+
+    Ok(())
+}
+
+/// Write the .prj file of a shapefile, for the GDAL builds which do not.
+fn write_prj(prj_path: &Path, epsg: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let output = run_subprocess(
+        subprocess_command("gdalsrsinfo")
+            .args(["-o", "wkt_esri"])
+            .arg(format!("EPSG:{}", epsg)),
+        "gdalsrsinfo",
+    )?;
+
+    let wkt = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if !output.status.success() || wkt.is_empty() {
+        return Err(format!("Failed to write {}, no WKT for EPSG:{}", prj_path.display(), epsg).into());
     }
 
+    fs::write(prj_path, wkt)?;
+
+    Ok(())
+}
+
+/// Written along the outputs of a tile in its archives, so that they can be used without the API.
+#[derive(Serialize)]
+struct ArtifactManifest<'a> {
+    tile_id: &'a str,
+    region: &'a str,
+    /// CRS of the rasters and vector layers
+    epsg: u32,
+    /// min_x, min_y, max_x, max_y in the CRS
+    extent: (i64, i64, i64, i64),
+}
+
+fn write_manifest(
+    dir_path: &Path,
+    tile_id: &str,
+    region: &RegionProfile,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = ArtifactManifest {
+        tile_id,
+        region: &region.name,
+        epsg: region.epsg,
+        extent: region.get_extent_from_tile_id(tile_id),
+    };
+
+    fs::write(
+        dir_path.join("manifest.json"),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+
     Ok(())
 }
@@ -0,0 +1,101 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_to_string, rename, write},
+    path::{Path, PathBuf},
+};
+
+const CHECKPOINT_FILE_NAME: &str = "checkpoint.json";
+
+/// Sub-stages of a render, in order.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum RenderStage {
+    /// cassini rendered the tile with its buffer
+    Rendered,
+    /// Rasters, vectors and PNGs cropped to the tile
+    Cropped,
+    Archived,
+    Uploaded,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointFile {
+    inputs_hash: String,
+    stage: RenderStage,
+}
+
+/// Last completed sub-stage of the render of a tile, persisted in its output directory so that a
+/// render interrupted by a crash or a restart resumes after it. Only valid for the same inputs,
+/// see `render_inputs_hash`.
+pub struct RenderCheckpoint {
+    path: PathBuf,
+    inputs_hash: Option<String>,
+    stage: Option<RenderStage>,
+}
+
+impl RenderCheckpoint {
+    /// Without an inputs hash, nothing is resumed nor recorded.
+    pub fn load(output_dir_path: &Path, inputs_hash: Option<&str>) -> Self {
+        let path = output_dir_path.join(CHECKPOINT_FILE_NAME);
+
+        let stage = inputs_hash.and_then(|inputs_hash| {
+            let checkpoint: CheckpointFile = serde_json::from_str(&read_to_string(&path).ok()?).ok()?;
+            (checkpoint.inputs_hash == inputs_hash).then_some(checkpoint.stage)
+        });
+
+        if let Some(stage) = stage {
+            info!(
+                "Resuming the render in {} after stage {:?}",
+                output_dir_path.display(),
+                stage
+            );
+        }
+
+        RenderCheckpoint {
+            path,
+            inputs_hash: inputs_hash.map(str::to_string),
+            stage,
+        }
+    }
+
+    /// Last completed stage, None to start from scratch.
+    pub fn stage(&self) -> Option<RenderStage> {
+        self.stage
+    }
+
+    pub fn is_done(&self, stage: RenderStage) -> bool {
+        self.stage.is_some_and(|done| done >= stage)
+    }
+
+    /// Record a completed stage. Failing to is only logged, the stage is done again on resume.
+    pub fn complete(&mut self, stage: RenderStage) {
+        self.stage = Some(stage);
+
+        let Some(inputs_hash) = &self.inputs_hash else {
+            return;
+        };
+
+        let checkpoint = CheckpointFile {
+            inputs_hash: inputs_hash.clone(),
+            stage,
+        };
+
+        if let Err(error) = save_checkpoint(&self.path, &checkpoint) {
+            warn!(
+                "Failed to write the checkpoint {}: {}",
+                self.path.display(),
+                error
+            );
+        }
+    }
+}
+
+/// Written next to the file then renamed, a crash while writing leaves the previous checkpoint.
+fn save_checkpoint(path: &Path, checkpoint: &CheckpointFile) -> Result<(), Box<dyn std::error::Error>> {
+    let partial_path = path.with_extension("partial");
+    write(&partial_path, serde_json::to_string(checkpoint)?)?;
+    rename(&partial_path, path)?;
+
+    Ok(())
+}
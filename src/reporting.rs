@@ -0,0 +1,80 @@
+use log::info;
+use sentry::{protocol::Value, ClientInitGuard, Level};
+use std::time::Duration;
+
+use crate::{state::current_correlation_id, toolchain::Toolchain, worker::PROTOCOL_VERSION};
+
+/// Start sending panics and job failures to a Sentry compatible server. Disabled if no DSN is
+/// given. The guard flushes the pending events when dropped, it must live as long as the worker.
+pub fn init_error_reporting(dsn: Option<&str>) -> Option<ClientInitGuard> {
+    let dsn = dsn?;
+
+    let guard = sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            attach_stacktrace: true,
+            send_default_pii: false,
+            ..Default::default()
+        },
+    ));
+
+    if !guard.is_enabled() {
+        return None;
+    }
+
+    sentry::configure_scope(|scope| {
+        scope.set_tag("protocol_version", PROTOCOL_VERSION);
+        scope.set_tag("os", std::env::consts::OS);
+        scope.set_tag("arch", std::env::consts::ARCH);
+    });
+
+    info!("Error reporting enabled");
+
+    Some(guard)
+}
+
+/// Tag every following event with the worker id, to know which volunteer machine it comes from.
+pub fn set_worker_id(worker_id: &str) {
+    sentry::configure_scope(|scope| scope.set_tag("worker_id", worker_id));
+}
+
+/// Tag every following event with the versions of cassini, GDAL and PROJ.
+pub fn set_toolchain(toolchain: &Toolchain) {
+    sentry::configure_scope(|scope| {
+        scope.set_tag("cassini", toolchain.cassini);
+        scope.set_tag("gdal", toolchain.gdal.as_deref().unwrap_or("unknown"));
+        scope.set_tag("proj", toolchain.proj.as_deref().unwrap_or("unknown"));
+    });
+}
+
+/// Report a failed job with its payload and the stage it failed at. No-op when disabled.
+pub fn report_job_failure(job_type: &str, tile: &str, stage: Option<&str>, job_payload: &str, error: &str) {
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("job_type", job_type);
+            scope.set_tag("tile", tile);
+            scope.set_tag("stage", stage.unwrap_or("unknown"));
+
+            if let Some(correlation_id) = current_correlation_id() {
+                scope.set_tag("correlation_id", correlation_id);
+            }
+
+            let payload = serde_json::from_str(job_payload).unwrap_or(Value::from(job_payload));
+            scope.set_extra("job_payload", payload);
+        },
+        || {
+            sentry::capture_message(
+                &format!("{} job failed for {}: {}", job_type, tile, error),
+                Level::Error,
+            )
+        },
+    );
+}
+
+/// Send the pending events before the process exits without dropping the guard.
+pub fn flush_error_reports() {
+    if let Some(client) = sentry::Hub::current().client() {
+        client.flush(Some(Duration::from_secs(2)));
+    }
+}
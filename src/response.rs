@@ -0,0 +1,44 @@
+use reqwest::blocking::Response;
+use std::io::Read;
+
+/// Bytes of an error response kept for the logs, eg: the HTML error page of a proxy
+const MAX_ERROR_BODY_SIZE: usize = 2048;
+
+/// Status and start of the body of an unsuccessful response, for the logs and errors, eg:
+/// "Status: 404 Not Found. Response: Unknown tile". Only the first bytes of the body are read.
+pub fn describe_error_response(response: Response) -> String {
+    let status = response.status();
+    let body = read_error_body(response);
+
+    if body.is_empty() {
+        format!("Status: {}", status)
+    } else {
+        format!("Status: {}. Response: {}", status, body)
+    }
+}
+
+fn read_error_body(response: Response) -> String {
+    let mut body = Vec::with_capacity(MAX_ERROR_BODY_SIZE);
+
+    if let Err(error) = response
+        .take(MAX_ERROR_BODY_SIZE as u64 + 1)
+        .read_to_end(&mut body)
+    {
+        return format!("<unreadable body: {}>", error);
+    }
+
+    let truncated = body.len() > MAX_ERROR_BODY_SIZE;
+    body.truncate(MAX_ERROR_BODY_SIZE);
+
+    // One line per error in the logs
+    let text = String::from_utf8_lossy(&body)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if truncated {
+        format!("{}… (truncated)", text)
+    } else {
+        text
+    }
+}
@@ -0,0 +1,128 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Temporary credentials handed out by the API to access an S3 compatible bucket (AWS, MinIO...).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct S3Credentials {
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    /// Prepended to every object key
+    #[serde(default)]
+    pub prefix: String,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// Build a path-style presigned url (AWS signature V4, query string authentication) for an object.
+pub fn presign_url(
+    credentials: &S3Credentials,
+    method: &str,
+    key: &str,
+    expires_in_seconds: u64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let endpoint = Url::parse(&credentials.endpoint)?;
+
+    let host = match (endpoint.host_str(), endpoint.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        _ => return Err(format!("Invalid S3 endpoint {}", credentials.endpoint).into()),
+    };
+
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/s3/aws4_request", date, credentials.region);
+
+    let full_key = format!("{}{}", credentials.prefix, key);
+    let canonical_uri = format!(
+        "/{}/{}",
+        uri_encode(&credentials.bucket, true),
+        uri_encode(&full_key, false)
+    );
+
+    let mut query: Vec<(String, String)> = vec![
+        ("X-Amz-Algorithm".to_string(), "AWS4-HMAC-SHA256".to_string()),
+        (
+            "X-Amz-Credential".to_string(),
+            format!("{}/{}", credentials.access_key_id, scope),
+        ),
+        ("X-Amz-Date".to_string(), amz_date.clone()),
+        ("X-Amz-Expires".to_string(), expires_in_seconds.to_string()),
+        ("X-Amz-SignedHeaders".to_string(), "host".to_string()),
+    ];
+
+    if let Some(session_token) = &credentials.session_token {
+        query.push(("X-Amz-Security-Token".to_string(), session_token.clone()));
+    }
+
+    query.sort();
+
+    let canonical_query = query
+        .iter()
+        .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+        .collect::<Vec<String>>()
+        .join("&");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+        method, canonical_uri, canonical_query, host
+    );
+
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let signing_key = [date.as_str(), credentials.region.as_str(), "s3", "aws4_request"]
+        .iter()
+        .try_fold(
+            format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+            |key, data| hmac_sha256(&key, data.as_bytes()),
+        )?;
+
+    let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes())?);
+
+    Ok(format!(
+        "{}://{}{}?{}&X-Amz-Signature={}",
+        endpoint.scheme(),
+        host,
+        canonical_uri,
+        canonical_query,
+        signature
+    ))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut mac = HmacSha256::new_from_slice(key)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Percent encode everything but unreserved characters, as required by AWS signature V4.
+fn uri_encode(input: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(input.len());
+
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
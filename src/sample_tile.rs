@@ -0,0 +1,36 @@
+use log::info;
+use std::{fs, path::Path};
+
+use crate::utils::{sha256_of_file, write_atomically};
+
+/// Synthetic LiDAR tile of 200 m by 200 m in EPSG:2154 (LAS 1.4, point format 6): classified
+/// ground points on smooth hills, and unclassified vegetation points of a forest with a clearing.
+/// Bundled so that the benchmark and the self test run on the same known input without a download.
+const SAMPLE_TILE: &[u8] = include_bytes!("sample_tile.laz");
+/// SHA-256 of `SAMPLE_TILE`, the reference outputs of the self test are computed from this tile
+pub const SAMPLE_TILE_SHA256: &str = "b6717f8b617dfd9822b00e23e3863fada09832d74b406eca1bc82fcbdf56972c";
+
+/// Write the bundled sample tile to `path`, unless it is already there, and check its checksum.
+pub fn write_sample_tile(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if sha256_of_file(path).is_ok_and(|sha256| sha256 == SAMPLE_TILE_SHA256) {
+        return Ok(());
+    }
+
+    info!("Writing the sample tile to {}", path.display());
+
+    write_atomically(path, |partial_path| {
+        fs::write(partial_path, SAMPLE_TILE)?;
+
+        let sha256 = sha256_of_file(partial_path)?;
+
+        if sha256 != SAMPLE_TILE_SHA256 {
+            return Err(format!(
+                "The bundled sample tile is corrupted, SHA-256 {} instead of {}",
+                sha256, SAMPLE_TILE_SHA256
+            )
+            .into());
+        }
+
+        Ok(())
+    })
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use crate::state::WorkerState;
+
+/// Change the number of active worker threads at runtime: SIGUSR1 adds one, SIGUSR2 removes one.
+/// A removed thread finishes its in-flight job before idling. Up to `max_threads` can be active.
+pub fn spawn_thread_scaling(
+    state: Arc<WorkerState>,
+    max_threads: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(unix)]
+    {
+        use log::info;
+        use signal_hook::{
+            consts::{SIGUSR1, SIGUSR2},
+            iterator::Signals,
+        };
+        use std::thread;
+
+        let mut signals = Signals::new([SIGUSR1, SIGUSR2])?;
+
+        thread::Builder::new()
+            .name("thread-scaling".to_string())
+            .spawn(move || {
+                for signal in signals.forever() {
+                    let active_threads = state.active_threads();
+
+                    let new_active_threads = match signal {
+                        SIGUSR1 => (active_threads + 1).min(max_threads),
+                        _ => active_threads.saturating_sub(1).max(1),
+                    };
+
+                    if new_active_threads == active_threads {
+                        info!("Already running {} of {} threads", active_threads, max_threads);
+                        continue;
+                    }
+
+                    info!(
+                        "Scaling from {} to {} active threads",
+                        active_threads, new_active_threads
+                    );
+
+                    state.set_active_threads(new_active_threads);
+                }
+            })?;
+    }
+
+    #[cfg(not(unix))]
+    let _ = (state, max_threads);
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use chrono::{Local, NaiveTime};
+use log::info;
+use std::{sync::Arc, thread, time::Duration};
+
+use crate::{progress::set_bandwidth_limit, state::WorkerState};
+
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Throttling applied during the quiet hours, for volunteers sharing their machine and connection.
+/// The worker runs at full speed the rest of the time.
+pub struct QuietHours {
+    /// Local time windows, the end can be before the start for a window wrapping around midnight
+    pub windows: Vec<(NaiveTime, NaiveTime)>,
+    pub threads: usize,
+    /// Bandwidth in bytes per second shared by all the transfers, 0 for no limit
+    pub bandwidth_limit: u64,
+}
+
+/// Parse time windows like "07:00-22:00" or "08:00-12:00,14:00-19:00".
+pub fn parse_time_windows(value: &str) -> Result<Vec<(NaiveTime, NaiveTime)>, Box<dyn std::error::Error>> {
+    value
+        .split(',')
+        .map(|window| {
+            let (start, end) = window
+                .trim()
+                .split_once('-')
+                .ok_or_else(|| format!("Invalid time window \"{}\", expected eg: 07:00-22:00", window))?;
+
+            Ok((
+                NaiveTime::parse_from_str(start.trim(), "%H:%M")?,
+                NaiveTime::parse_from_str(end.trim(), "%H:%M")?,
+            ))
+        })
+        .collect()
+}
+
+impl QuietHours {
+    fn contains(&self, time: NaiveTime) -> bool {
+        self.windows.iter().any(|&(start, end)| {
+            if start <= end {
+                start <= time && time < end
+            } else {
+                start <= time || time < end
+            }
+        })
+    }
+}
+
+/// Adjust the number of active worker threads and the bandwidth limit according to the time of
+/// day. Jobs already running when the quiet hours start are finished before their thread idles.
+pub fn spawn_scheduler(
+    quiet_hours: QuietHours,
+    state: Arc<WorkerState>,
+    threads: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    thread::Builder::new()
+        .name("scheduler".to_string())
+        .spawn(move || {
+            let mut was_quiet = None;
+
+            loop {
+                let is_quiet = quiet_hours.contains(Local::now().time());
+
+                if was_quiet != Some(is_quiet) {
+                    if is_quiet {
+                        let active_threads = quiet_hours.threads.clamp(1, threads);
+
+                        info!(
+                            "Quiet hours started, running {} of {} threads{}",
+                            active_threads,
+                            threads,
+                            match quiet_hours.bandwidth_limit {
+                                0 => String::new(),
+                                limit => format!(", limited to {:.1} MB/s", limit as f64 / 1_000_000.0),
+                            }
+                        );
+
+                        state.set_active_threads(active_threads);
+                        set_bandwidth_limit(quiet_hours.bandwidth_limit);
+                    } else {
+                        if was_quiet.is_some() {
+                            info!("Quiet hours ended, running at full speed");
+                        }
+
+                        state.set_active_threads(threads);
+                        set_bandwidth_limit(0);
+                    }
+
+                    was_quiet = Some(is_quiet);
+                }
+
+                thread::sleep(SCHEDULE_CHECK_INTERVAL);
+            }
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,517 @@
+use log::debug;
+use serde::Deserialize;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::HashMap,
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{stage_budget::stage_budget, utils::TransferStats};
+
+/// Memory used per LiDAR point while processing a tile, to estimate the memory of a job from its
+/// point count when the server gives no memory estimate
+const BYTES_PER_POINT: u64 = 64;
+/// Share of its duration a job spends downloading and uploading above which it is I/O-bound
+const IO_BOUND_TRANSFER_SHARE: f64 = 0.5;
+/// Weight of the last job in the moving averages of `WorkloadSample`
+const SAMPLE_WEIGHT: f64 = 0.3;
+
+/// Optional size hints of a job, from its top-level `hints` field, eg:
+/// `{"type": "Lidar", "data": {...}, "hints": {"laz_size": 250000000, "point_count": 40000000}}`
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct JobHints {
+    /// Size in bytes of the LAZ file to download
+    #[serde(default)]
+    pub laz_size: Option<u64>,
+    #[serde(default)]
+    pub point_count: Option<u64>,
+    /// Peak memory in bytes of the job
+    #[serde(default)]
+    pub expected_memory: Option<u64>,
+}
+
+impl JobHints {
+    pub fn memory_estimate(&self) -> Option<u64> {
+        self.expected_memory
+            .or(self.point_count.map(|point_count| point_count * BYTES_PER_POINT))
+    }
+}
+
+/// Resources currently available on the machine, unknown if None.
+pub struct AvailableResources {
+    pub memory: Option<u64>,
+    pub disk: Option<u64>,
+    /// Jobs running on the other threads, by type
+    pub running_jobs: HashMap<String, usize>,
+}
+
+impl AvailableResources {
+    /// Whether a job of this type with these hints can start now. Jobs without hints always can,
+    /// unless their type is at its stage budget, see `set_stage_budget`.
+    pub fn admit(&self, job_type: Option<&str>, hints: &JobHints) -> bool {
+        let fits = |needed: Option<u64>, available: Option<u64>| match (needed, available) {
+            (Some(needed), Some(available)) => needed <= available,
+            _ => true,
+        };
+
+        let within_budget = job_type.is_none_or(|job_type| {
+            stage_budget(job_type)
+                .is_none_or(|budget| self.running_jobs.get(job_type).copied().unwrap_or(0) < budget)
+        });
+
+        within_budget && fits(hints.memory_estimate(), self.memory) && fits(hints.laz_size, self.disk)
+    }
+}
+
+/// Whether a job mostly keeps the CPU or the network busy.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Workload {
+    Cpu,
+    Io,
+}
+
+impl Workload {
+    fn name(&self) -> &'static str {
+        match self {
+            Workload::Cpu => "CPU",
+            Workload::Io => "I/O",
+        }
+    }
+}
+
+/// Recent measurements of a job type, as exponential moving averages.
+#[derive(Clone, Copy)]
+struct WorkloadSample {
+    duration_seconds: f64,
+    /// Share of the duration spent downloading and uploading
+    transfer_share: f64,
+}
+
+/// Measurements of the last jobs, to tell the CPU-bound jobs from the I/O-bound ones.
+#[derive(Default)]
+struct WorkloadStats {
+    by_job_type: HashMap<String, WorkloadSample>,
+    download_bytes_per_second: Option<f64>,
+}
+
+impl WorkloadStats {
+    /// Workload of a job from the measurements of its type, the LAZ size hint scaling the share of
+    /// the download. Types not measured yet are classified by what they usually do.
+    fn workload(&self, job_type: &str, hints: &JobHints) -> Workload {
+        let Some(sample) = self.by_job_type.get(job_type) else {
+            return match job_type {
+                "Pyramid" | "Validate" | "Cleanup" => Workload::Io,
+                _ => Workload::Cpu,
+            };
+        };
+
+        let transfer_share = match (hints.laz_size, self.download_bytes_per_second) {
+            (Some(laz_size), Some(rate)) if rate > 0.0 => {
+                let download_seconds = laz_size as f64 / rate;
+                let other_seconds = sample.duration_seconds * (1.0 - sample.transfer_share);
+                download_seconds / (download_seconds + other_seconds).max(f64::EPSILON)
+            }
+            _ => sample.transfer_share,
+        };
+
+        if transfer_share >= IO_BOUND_TRANSFER_SHARE {
+            Workload::Io
+        } else {
+            Workload::Cpu
+        }
+    }
+}
+
+fn moving_average(average: Option<f64>, value: f64) -> f64 {
+    match average {
+        Some(average) => average + SAMPLE_WEIGHT * (value - average),
+        None => value,
+    }
+}
+
+/// Job fetched ahead of time, waiting for a worker thread.
+struct QueuedJob {
+    payload: String,
+    job_type: Option<String>,
+    priority: i64,
+    hints: JobHints,
+    /// Order of arrival, to keep the jobs of the same priority first in, first out
+    sequence: u64,
+}
+
+/// Jobs prefetched by all the worker threads, handed out by priority so that eg: an urgent
+/// re-render does not wait behind background pyramid jobs. Among jobs of the same priority,
+/// CPU-bound and I/O-bound ones are interleaved to keep both the cores and the network busy.
+#[derive(Default)]
+pub struct JobQueue {
+    jobs: Mutex<Vec<QueuedJob>>,
+    workload_stats: Mutex<WorkloadStats>,
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        JobQueue::default()
+    }
+
+    pub fn push(&self, payload: String) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let sequence = jobs.iter().map(|job| job.sequence + 1).max().unwrap_or(0);
+
+        jobs.push(QueuedJob {
+            job_type: job_type(&payload),
+            priority: job_priority(&payload),
+            hints: job_hints(&payload),
+            payload,
+            sequence,
+        });
+    }
+
+    /// The job of highest priority, the oldest one among equals.
+    pub fn pop(&self) -> Option<String> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let index = next_job_index(&jobs.iter().collect::<Vec<_>>())?;
+
+        Some(jobs.remove(index).payload)
+    }
+
+    /// The job of highest priority that fits in the available resources, see `pop`. Among equals,
+    /// the oldest job of the workload least represented in the running jobs.
+    pub fn pop_admissible(&self, resources: &AvailableResources) -> Option<String> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let admissible: Vec<&QueuedJob> = jobs
+            .iter()
+            .filter(|job| resources.admit(job.job_type.as_deref(), &job.hints))
+            .collect();
+        let next = admissible[next_job_index(&admissible)?];
+        let sequence = self.interleave(next, &admissible, resources).sequence;
+        let index = jobs.iter().position(|job| job.sequence == sequence)?;
+
+        Some(jobs.remove(index).payload)
+    }
+
+    /// Record the transfers of a finished job, see `WorkloadStats`.
+    pub fn record_workload(&self, job_type: &str, duration: Duration, transfers: &TransferStats) {
+        if duration.is_zero() {
+            return;
+        }
+
+        let mut stats = self.workload_stats.lock().unwrap();
+        let transfer_share = ((transfers.download_time + transfers.upload_time).as_secs_f64()
+            / duration.as_secs_f64())
+        .min(1.0);
+        let previous = stats.by_job_type.get(job_type).copied();
+
+        stats.by_job_type.insert(
+            job_type.to_string(),
+            WorkloadSample {
+                duration_seconds: moving_average(
+                    previous.map(|sample| sample.duration_seconds),
+                    duration.as_secs_f64(),
+                ),
+                transfer_share: moving_average(previous.map(|sample| sample.transfer_share), transfer_share),
+            },
+        );
+
+        if let Some(rate) = transfers.download_bytes_per_second() {
+            stats.download_bytes_per_second =
+                Some(moving_average(stats.download_bytes_per_second, rate as f64));
+        }
+    }
+
+    /// The oldest job of the priority of `next` whose workload has the fewest running jobs, `next`
+    /// if all the running workloads are balanced or no such job is queued.
+    fn interleave<'a>(
+        &self,
+        next: &'a QueuedJob,
+        admissible: &[&'a QueuedJob],
+        resources: &AvailableResources,
+    ) -> &'a QueuedJob {
+        let stats = self.workload_stats.lock().unwrap();
+        let workload = |job: &QueuedJob| match &job.job_type {
+            Some(job_type) => stats.workload(job_type, &job.hints),
+            None => Workload::Cpu,
+        };
+
+        let (mut running_cpu, mut running_io) = (0, 0);
+
+        for (job_type, count) in &resources.running_jobs {
+            match stats.workload(job_type, &JobHints::default()) {
+                Workload::Cpu => running_cpu += count,
+                Workload::Io => running_io += count,
+            }
+        }
+
+        let wanted = match running_cpu.cmp(&running_io) {
+            Ordering::Greater => Workload::Io,
+            Ordering::Less => Workload::Cpu,
+            Ordering::Equal => workload(next),
+        };
+
+        let chosen = admissible
+            .iter()
+            .filter(|job| job.priority == next.priority && workload(job) == wanted)
+            .min_by_key(|job| job.sequence)
+            .copied()
+            .unwrap_or(next);
+
+        if chosen.sequence != next.sequence {
+            debug!(
+                "{} CPU-bound and {} I/O-bound jobs running, starting the {}-bound {} job before the older {} job",
+                running_cpu,
+                running_io,
+                wanted.name(),
+                chosen.job_type.as_deref().unwrap_or("unknown"),
+                next.job_type.as_deref().unwrap_or("unknown"),
+            );
+        } else {
+            debug!(
+                "{} CPU-bound and {} I/O-bound jobs running, starting the {}-bound {} job",
+                running_cpu,
+                running_io,
+                workload(chosen).name(),
+                chosen.job_type.as_deref().unwrap_or("unknown"),
+            );
+        }
+
+        chosen
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.lock().unwrap().is_empty()
+    }
+
+    /// Remove all the jobs, eg: to hand them back to the API on shutdown.
+    pub fn drain(&self) -> Vec<String> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|job| job.payload)
+            .collect()
+    }
+}
+
+/// Type of a job payload, eg: "Lidar" for `{"type": "Lidar", "data": {...}}`.
+pub fn job_type(payload: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|value| {
+            value
+                .get("type")
+                .and_then(|job_type| job_type.as_str())
+                .map(str::to_string)
+        })
+}
+
+pub fn job_hints(payload: &str) -> JobHints {
+    serde_json::from_str::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|value| value.get("hints").cloned())
+        .and_then(|hints| serde_json::from_value(hints).ok())
+        .unwrap_or_default()
+}
+
+/// Priority of a job payload, from its optional top-level `priority` field, eg:
+/// `{"type": "Render", "data": {...}, "priority": 10}`. Higher goes first, 0 by default.
+pub fn job_priority(payload: &str) -> i64 {
+    serde_json::from_str::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|value| value.get("priority").and_then(|priority| priority.as_i64()))
+        .unwrap_or(0)
+}
+
+fn next_job_index(jobs: &[&QueuedJob]) -> Option<usize> {
+    jobs.iter()
+        .enumerate()
+        .max_by_key(|(_, job)| (job.priority, Reverse(job.sequence)))
+        .map(|(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage_budget::set_stage_budget;
+
+    fn queued_job(priority: i64, sequence: u64) -> QueuedJob {
+        QueuedJob {
+            payload: String::new(),
+            job_type: None,
+            priority,
+            hints: JobHints::default(),
+            sequence,
+        }
+    }
+
+    fn payload(job_type: &str, priority: i64, hints: &str) -> String {
+        format!(
+            r#"{{"type": "{}", "data": {{}}, "priority": {}, "hints": {}}}"#,
+            job_type, priority, hints
+        )
+    }
+
+    fn resources(
+        memory: Option<u64>,
+        disk: Option<u64>,
+        running_jobs: &[(&str, usize)],
+    ) -> AvailableResources {
+        AvailableResources {
+            memory,
+            disk,
+            running_jobs: running_jobs
+                .iter()
+                .map(|(job_type, count)| (job_type.to_string(), *count))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn next_job_index_picks_the_highest_priority() {
+        let jobs = [queued_job(0, 0), queued_job(10, 1), queued_job(5, 2)];
+
+        assert_eq!(next_job_index(&jobs.iter().collect::<Vec<_>>()), Some(1));
+    }
+
+    #[test]
+    fn next_job_index_picks_the_oldest_among_equals() {
+        let jobs = [queued_job(1, 3), queued_job(1, 1), queued_job(1, 2)];
+
+        assert_eq!(next_job_index(&jobs.iter().collect::<Vec<_>>()), Some(1));
+    }
+
+    #[test]
+    fn next_job_index_of_no_jobs() {
+        assert_eq!(next_job_index(&[]), None);
+    }
+
+    #[test]
+    fn admit_jobs_without_hints_or_with_unknown_resources() {
+        let hints = JobHints {
+            laz_size: Some(1_000),
+            point_count: None,
+            expected_memory: Some(1_000),
+        };
+
+        assert!(resources(Some(0), Some(0), &[]).admit(Some("Lidar"), &JobHints::default()));
+        assert!(resources(None, None, &[]).admit(Some("Lidar"), &hints));
+    }
+
+    #[test]
+    fn admit_jobs_fitting_in_the_available_resources() {
+        let hints = JobHints {
+            laz_size: Some(1_000),
+            point_count: None,
+            expected_memory: Some(2_000),
+        };
+
+        assert!(resources(Some(2_000), Some(1_000), &[]).admit(Some("Lidar"), &hints));
+        assert!(!resources(Some(1_999), Some(1_000), &[]).admit(Some("Lidar"), &hints));
+        assert!(!resources(Some(2_000), Some(999), &[]).admit(Some("Lidar"), &hints));
+    }
+
+    #[test]
+    fn admit_estimates_the_memory_from_the_point_count() {
+        let hints = JobHints {
+            laz_size: None,
+            point_count: Some(1_000),
+            expected_memory: None,
+        };
+
+        assert!(resources(Some(1_000 * BYTES_PER_POINT), None, &[]).admit(Some("Lidar"), &hints));
+        assert!(!resources(Some(1_000 * BYTES_PER_POINT - 1), None, &[]).admit(Some("Lidar"), &hints));
+    }
+
+    #[test]
+    fn admit_jobs_within_their_stage_budget() {
+        set_stage_budget("SchedulerTestBudget", 1);
+
+        assert!(resources(None, None, &[]).admit(Some("SchedulerTestBudget"), &JobHints::default()));
+        assert!(!resources(None, None, &[("SchedulerTestBudget", 1)])
+            .admit(Some("SchedulerTestBudget"), &JobHints::default()));
+        assert!(resources(None, None, &[("SchedulerTestBudget", 1)]).admit(None, &JobHints::default()));
+    }
+
+    #[test]
+    fn pop_admissible_skips_the_jobs_that_do_not_fit() {
+        let queue = JobQueue::new();
+        queue.push(payload("Lidar", 10, r#"{"expected_memory": 5000}"#));
+        queue.push(payload("Render", 0, r#"{"expected_memory": 1000}"#));
+
+        let popped = queue.pop_admissible(&resources(Some(2_000), None, &[]));
+
+        assert_eq!(popped.as_deref().and_then(job_type).as_deref(), Some("Render"));
+        assert_eq!(queue.pop_admissible(&resources(Some(2_000), None, &[])), None);
+        assert!(!queue.is_empty());
+    }
+
+    #[test]
+    fn pop_admissible_picks_the_highest_priority() {
+        let queue = JobQueue::new();
+        queue.push(payload("Render", 0, "{}"));
+        queue.push(payload("Render", 5, "{}"));
+
+        assert_eq!(
+            queue.pop_admissible(&resources(None, None, &[])),
+            Some(payload("Render", 5, "{}"))
+        );
+        assert_eq!(
+            queue.pop_admissible(&resources(None, None, &[])),
+            Some(payload("Render", 0, "{}"))
+        );
+        assert_eq!(queue.pop_admissible(&resources(None, None, &[])), None);
+    }
+
+    #[test]
+    fn interleave_starts_the_workload_least_running() {
+        let queue = JobQueue::new();
+        queue.push(payload("Render", 0, "{}"));
+        queue.push(payload("Pyramid", 0, "{}"));
+
+        // A CPU-bound job running, the newer I/O-bound job goes first
+        let popped = queue.pop_admissible(&resources(None, None, &[("Lidar", 1)]));
+
+        assert_eq!(popped.as_deref().and_then(job_type).as_deref(), Some("Pyramid"));
+    }
+
+    #[test]
+    fn interleave_keeps_the_order_when_balanced() {
+        let queue = JobQueue::new();
+        queue.push(payload("Render", 0, "{}"));
+        queue.push(payload("Pyramid", 0, "{}"));
+
+        let popped = queue.pop_admissible(&resources(None, None, &[("Lidar", 1), ("Pyramid", 1)]));
+
+        assert_eq!(popped.as_deref().and_then(job_type).as_deref(), Some("Render"));
+    }
+
+    #[test]
+    fn interleave_does_not_override_the_priority() {
+        let queue = JobQueue::new();
+        queue.push(payload("Render", 10, "{}"));
+        queue.push(payload("Pyramid", 0, "{}"));
+
+        let popped = queue.pop_admissible(&resources(None, None, &[("Lidar", 1)]));
+
+        assert_eq!(popped.as_deref().and_then(job_type).as_deref(), Some("Render"));
+    }
+
+    #[test]
+    fn interleave_uses_the_measured_workloads() {
+        let queue = JobQueue::new();
+        // Render jobs measured as spending most of their time uploading
+        queue.record_workload(
+            "Render",
+            Duration::from_secs(10),
+            &TransferStats {
+                upload_time: Duration::from_secs(8),
+                ..TransferStats::default()
+            },
+        );
+        queue.push(payload("Lidar", 0, "{}"));
+        queue.push(payload("Render", 0, "{}"));
+
+        let popped = queue.pop_admissible(&resources(None, None, &[("Lidar", 1)]));
+
+        assert_eq!(popped.as_deref().and_then(job_type).as_deref(), Some("Render"));
+    }
+}
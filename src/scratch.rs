@@ -0,0 +1,28 @@
+use std::{
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+static SCRATCH_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Put the heavy temporary files (downloaded archives, render intermediates) in `path`, eg: a fast
+/// NVMe disk, while the long-lived cache stays in the work directory.
+pub fn set_scratch_dir(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    create_dir_all(path)?;
+    let _ = SCRATCH_DIR.set(path.to_path_buf());
+
+    Ok(())
+}
+
+/// The scratch directory if one was set with `set_scratch_dir`.
+pub fn configured_scratch_dir() -> Option<&'static Path> {
+    SCRATCH_DIR.get().map(|path| path.as_path())
+}
+
+/// Directory of the temporary files, the work directory if no scratch directory was set.
+pub fn scratch_dir() -> PathBuf {
+    configured_scratch_dir()
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
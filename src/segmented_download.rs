@@ -0,0 +1,278 @@
+use log::{debug, info, warn};
+use reqwest::{
+    blocking::Client,
+    header::{CONTENT_RANGE, RANGE},
+    StatusCode,
+};
+use std::{
+    fs::{remove_file, rename, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    progress::ProgressReader,
+    rate_limit::HostPermit,
+    stage_budget::{StagePermit, DOWNLOAD_STAGE},
+    state::{
+        attach_current_thread, current_abort_reason, current_correlation_id, current_slot,
+        report_transfer_progress, set_correlation_id,
+    },
+    utils::{
+        add_download, download_file, max_download_size, partial_path, store_etag, take_etag,
+        with_if_none_match,
+    },
+};
+
+/// Number of parallel range requests of a segmented download, see `set_download_connections`
+static DOWNLOAD_CONNECTIONS: AtomicUsize = AtomicUsize::new(4);
+/// Files smaller than this are downloaded in a single request
+const MIN_SEGMENTED_DOWNLOAD_SIZE: u64 = 100_000_000;
+const SEGMENT_ATTEMPTS: u32 = 3;
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn set_download_connections(connections: usize) {
+    DOWNLOAD_CONNECTIONS.store(connections.max(1), Ordering::SeqCst);
+}
+
+/// Download a large file with several parallel range requests, each written at its offset of
+/// `file_path`, since a single stream from the LiDAR providers is often much slower than the link.
+/// Falls back to `download_file` for small files and servers not supporting range requests.
+pub fn download_file_segmented(
+    client: &Client,
+    file_url: &str,
+    file_path: &PathBuf,
+    expected_size: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let connections = DOWNLOAD_CONNECTIONS.load(Ordering::SeqCst);
+    let _stage_permit = StagePermit::acquire(DOWNLOAD_STAGE);
+
+    if connections == 1 {
+        return download_file(client, file_url, file_path, None, expected_size);
+    }
+
+    // The first byte, to learn the size of the file and whether the server supports ranges
+    let probe = {
+        let _permit = HostPermit::acquire(file_url);
+
+        with_if_none_match(client.get(file_url), file_path)
+            .header(RANGE, "bytes=0-0")
+            .send()?
+    };
+
+    if probe.status() == StatusCode::NOT_MODIFIED {
+        info!("{} unchanged, reusing the local copy", file_path.display());
+        return Ok(());
+    }
+
+    let total = match probe.status() {
+        StatusCode::PARTIAL_CONTENT => probe
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|content_range| content_range.to_str().ok())
+            .and_then(|content_range| content_range.rsplit('/').next())
+            .and_then(|total| total.parse::<u64>().ok()),
+        _ => None,
+    };
+
+    let total = match total {
+        Some(total) if total >= MIN_SEGMENTED_DOWNLOAD_SIZE => total,
+        _ => {
+            debug!(
+                "No segmented download for {}, downloading it in one request",
+                file_path.display()
+            );
+            drop(probe);
+            return download_file(client, file_url, file_path, None, expected_size);
+        }
+    };
+
+    let max_size = max_download_size();
+
+    if max_size > 0 && total > max_size {
+        return Err(format!(
+            "Refusing to download {} MB for {}, above the maximum download size",
+            total / 1_000_000,
+            file_path.display()
+        )
+        .into());
+    }
+
+    if let Some(expected_size) = expected_size.filter(|expected_size| *expected_size != total) {
+        return Err(format!(
+            "{} is {} bytes on the server, {} bytes expected",
+            file_path.display(),
+            total,
+            expected_size
+        )
+        .into());
+    }
+
+    let etag = take_etag(&probe, file_path);
+    drop(probe);
+
+    info!(
+        "Downloading {} ({} MB) with {} connections",
+        file_path.display(),
+        total / 1_000_000,
+        connections
+    );
+
+    let start = Instant::now();
+    // Renamed to `file_path` once all the segments are written
+    let partial_path = partial_path(file_path);
+    File::create(&partial_path)?.set_len(total)?;
+
+    let segment_size = total.div_ceil(connections as u64);
+    let downloaded = Arc::new(AtomicU64::new(0));
+
+    let result = thread::scope(|scope| {
+        let slot = current_slot();
+        let correlation_id = current_correlation_id();
+
+        let segments = (0..connections as u64)
+            .map(|index| (index * segment_size, ((index + 1) * segment_size).min(total)))
+            .filter(|(segment_start, segment_end)| segment_start < segment_end)
+            .enumerate()
+            .map(|(index, (segment_start, segment_end))| {
+                let slot = slot.clone();
+                let correlation_id = correlation_id.clone();
+                let downloaded = downloaded.clone();
+                let partial_path = &partial_path;
+
+                thread::Builder::new()
+                    .name(format!("segment-{}", index))
+                    .spawn_scoped(scope, move || {
+                        // Report to the job's slot, so that an abort also stops the segments
+                        if let Some((state, thread_index)) = slot {
+                            attach_current_thread(state, thread_index);
+                        }
+
+                        set_correlation_id(correlation_id);
+
+                        download_segment(
+                            client,
+                            file_url,
+                            partial_path,
+                            (segment_start, segment_end),
+                            &downloaded,
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        while !segments.iter().all(|segment| segment.is_finished()) {
+            report_transfer_progress(Some(downloaded.load(Ordering::Relaxed) as f64 / total as f64));
+            thread::sleep(PROGRESS_REPORT_INTERVAL);
+        }
+
+        report_transfer_progress(None);
+
+        segments.into_iter().try_for_each(|segment| {
+            segment
+                .join()
+                .map_err(|_| "Segment download thread panicked".to_string())?
+                .map_err(|error| error.to_string())
+        })?;
+
+        Ok::<(), Box<dyn std::error::Error>>(())
+    });
+
+    if let Err(error) = result {
+        let _ = remove_file(&partial_path);
+        return Err(error);
+    }
+
+    rename(&partial_path, file_path)?;
+
+    add_download(total, start.elapsed());
+    store_etag(file_path, etag);
+
+    Ok(())
+}
+
+/// Download the bytes `start..end` of the file into the same range of `file_path`, resuming from
+/// the last byte written when the connection is cut.
+fn download_segment(
+    client: &Client,
+    file_url: &str,
+    file_path: &PathBuf,
+    (start, end): (u64, u64),
+    downloaded: &AtomicU64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut written = 0;
+    let mut attempt = 0;
+
+    let mut file = OpenOptions::new().write(true).open(file_path)?;
+    let mut buffer = vec![0; 64 * 1024];
+
+    while start + written < end {
+        attempt += 1;
+
+        let result = (|| -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            let _permit = HostPermit::acquire(file_url);
+
+            let response = client
+                .get(file_url)
+                .header(RANGE, format!("bytes={}-{}", start + written, end - 1))
+                .send()?;
+
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                return Err(format!("Range request answered with {}", response.status()).into());
+            }
+
+            let label = format!("Download of {} bytes {}-{}", file_path.display(), start, end);
+            let mut reader = ProgressReader::new(response, label, None).take(end - start - written);
+            file.seek(SeekFrom::Start(start + written))?;
+
+            loop {
+                let read = reader.read(&mut buffer)?;
+
+                if read == 0 {
+                    break;
+                }
+
+                file.write_all(&buffer[..read])?;
+                written += read as u64;
+                downloaded.fetch_add(read as u64, Ordering::Relaxed);
+            }
+
+            Ok(())
+        })();
+
+        match result {
+            Ok(()) if start + written < end => {
+                warn!("Segment {}-{} of {} cut short", start, end, file_path.display())
+            }
+            Ok(()) => {}
+            Err(error) if attempt < SEGMENT_ATTEMPTS && current_abort_reason().is_none() => {
+                warn!(
+                    "Segment {}-{} of {} failed: {}",
+                    start,
+                    end,
+                    file_path.display(),
+                    error
+                )
+            }
+            Err(error) => return Err(error),
+        }
+
+        if start + written < end {
+            if attempt >= SEGMENT_ATTEMPTS {
+                return Err(
+                    format!("Segment {}-{} of {} incomplete", start, end, file_path.display()).into(),
+                );
+            }
+
+            thread::sleep(Duration::from_secs(2u64.pow(attempt)));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use cassini::{process_single_tile_lidar_step, process_single_tile_render_step};
+use log::{error, info};
+use serde::Serialize;
+use std::{
+    fs::{self, create_dir_all, remove_dir_all},
+    path::Path,
+    time::Instant,
+};
+
+use crate::{
+    artifacts::UploadManifest,
+    auth::ApiAuth,
+    http::http_client,
+    panics::catch_cassini_panic,
+    pyramid::generate_base_zoom_levels_tiles,
+    region::RegionProfile,
+    render::RenderConfigGuard,
+    sample_tile::write_sample_tile,
+    scratch::scratch_dir,
+    state::report_stage,
+    toolchain::{toolchain, Toolchain},
+    utils::{download_file, sha256_of_file, upload_files},
+    worker::PROTOCOL_VERSION,
+};
+
+#[derive(Serialize)]
+struct SelfTestStage {
+    name: &'static str,
+    duration_ms: u64,
+}
+
+/// Sent to the API at the end of a self test, successful or not.
+#[derive(Serialize, Default)]
+struct SelfTestReport {
+    protocol_version: u32,
+    worker_version: &'static str,
+    /// First line of `gdalinfo --version` and `ogr2ogr --version`
+    gdal_version: Option<String>,
+    ogr2ogr_version: Option<String>,
+    toolchain: Option<Toolchain>,
+    /// Hex encoded SHA-256 of the LAZ file tested
+    laz_sha256: Option<String>,
+    stages: Vec<SelfTestStage>,
+    /// Hex encoded SHA-256 of the outputs, by file name, to compare with the reference outputs
+    checksums: Vec<(String, String)>,
+    error: Option<String>,
+}
+
+/// Run a small LAZ file, the bundled sample tile unless the job gives another one, through the
+/// LiDAR, render and pyramid steps, and send the timings and checksums of the outputs to the API,
+/// so that the server can check a new worker's environment (GDAL, cassini, uploads) before giving
+/// it real tiles. The test files are not kept.
+pub fn self_test_step(
+    laz_url: Option<&str>,
+    auth: &ApiAuth,
+    base_api_url: &str,
+    region: &RegionProfile,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let self_test_dir_path = scratch_dir().join("self-test");
+
+    if self_test_dir_path.exists() {
+        remove_dir_all(&self_test_dir_path)?;
+    }
+
+    create_dir_all(&self_test_dir_path)?;
+
+    let mut report = SelfTestReport {
+        protocol_version: PROTOCOL_VERSION,
+        worker_version: env!("CARGO_PKG_VERSION"),
+        gdal_version: toolchain().gdal.clone(),
+        ogr2ogr_version: toolchain().ogr2ogr.clone(),
+        toolchain: Some(toolchain().clone()),
+        ..Default::default()
+    };
+
+    let result = run_self_test(&self_test_dir_path, laz_url, region, &mut report);
+
+    if let Err(error) = &result {
+        error!("Self test failed: {}", error);
+        report.error = Some(error.to_string());
+    }
+
+    report_stage("uploading");
+    let report_path = self_test_dir_path.join("report.json");
+    fs::write(&report_path, serde_json::to_string_pretty(&report)?)?;
+
+    let mut manifest = UploadManifest::single("report.json", "report", &report_path, "application/json")?;
+
+    let full_map_path = self_test_dir_path.join("render-step").join("full-map.png");
+
+    if full_map_path.exists() {
+        manifest.add("full-map.png", "full-map", &full_map_path, "image/png")?;
+    }
+
+    let uploaded = upload_files(
+        &http_client(),
+        auth,
+        format!("{}/api/map-generation/self-tests", base_api_url),
+        base_api_url,
+        &manifest,
+    );
+
+    remove_dir_all(&self_test_dir_path)?;
+
+    result.and(uploaded)
+}
+
+fn run_self_test(
+    self_test_dir_path: &Path,
+    laz_url: Option<&str>,
+    region: &RegionProfile,
+    report: &mut SelfTestReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let laz_file_path = self_test_dir_path.join("self-test.laz");
+    let lidar_step_path = self_test_dir_path.join("lidar-step");
+    let render_step_path = self_test_dir_path.join("render-step");
+    let tiles_path = self_test_dir_path.join("tiles");
+
+    match laz_url {
+        Some(laz_url) => run_stage(report, "download", || {
+            download_file(&http_client(), laz_url, &laz_file_path, None, None)
+        })?,
+        None => write_sample_tile(&laz_file_path)?,
+    }
+
+    report.laz_sha256 = Some(sha256_of_file(&laz_file_path)?);
+
+    run_stage(report, "LiDAR step", || {
+        catch_cassini_panic("LiDAR step", || {
+            process_single_tile_lidar_step(&laz_file_path, &lidar_step_path)
+        })?;
+        require_file(&lidar_step_path.join("dem.tif"))
+    })?;
+
+    run_stage(report, "render step", || {
+        let _config_guard = RenderConfigGuard::lock(None)?;
+        catch_cassini_panic("render step", || {
+            process_single_tile_render_step(&lidar_step_path, &render_step_path, vec![], false, true)
+        })?;
+        require_file(&render_step_path.join("full-map.png"))
+    })?;
+
+    let base_tile_path = tiles_path
+        .join(region.base_zoom_level.to_string())
+        .join("0")
+        .join("0.png");
+
+    run_stage(report, "pyramid", || {
+        create_dir_all(base_tile_path.parent().unwrap_or(&tiles_path))?;
+        fs::copy(render_step_path.join("full-map.png"), &base_tile_path)?;
+        generate_base_zoom_levels_tiles(&tiles_path, 0, 0, region.base_zoom_level, None)?;
+        Ok(())
+    })?;
+
+    for output_path in [
+        lidar_step_path.join("dem.tif"),
+        render_step_path.join("full-map.png"),
+        base_tile_path,
+    ] {
+        let file_name = output_path
+            .strip_prefix(self_test_dir_path)
+            .unwrap_or(&output_path)
+            .to_string_lossy()
+            .to_string();
+
+        report.checksums.push((file_name, sha256_of_file(&output_path)?));
+    }
+
+    Ok(())
+}
+
+fn run_stage<F>(
+    report: &mut SelfTestReport,
+    name: &'static str,
+    stage: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce() -> Result<(), Box<dyn std::error::Error>>,
+{
+    report_stage(name);
+    info!("Self test: {}", name);
+    let start = Instant::now();
+    stage().map_err(|error| format!("{} failed: {}", name, error))?;
+
+    report.stages.push(SelfTestStage {
+        name,
+        duration_ms: start.elapsed().as_millis() as u64,
+    });
+
+    Ok(())
+}
+
+fn require_file(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Err(format!("{} was not generated", path.display()).into());
+    }
+
+    Ok(())
+}
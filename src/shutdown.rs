@@ -0,0 +1,82 @@
+use log::{info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::ApiAuth, journal::JobJournal, log_file::flush_log_file, reporting::flush_error_reports,
+    state::WorkerState, tui::restore_terminal,
+};
+
+pub fn register_shutdown_signals() -> Result<Arc<AtomicBool>, Box<dyn std::error::Error>> {
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGTERM, shutdown_requested.clone())?;
+    signal_hook::flag::register(SIGINT, shutdown_requested.clone())?;
+
+    Ok(shutdown_requested)
+}
+
+/// Wait for the worker threads. Once a shutdown signal is received, the threads stop taking new
+/// jobs and in-flight jobs get `drain_timeout` to finish. After that they are reported as
+/// abandoned to the API so that they can be requeued immediately, along with the prefetched jobs,
+/// and the process exits. The jobs the API could not be told about stay in the journal.
+pub fn wait_for_threads(
+    handles: Vec<JoinHandle<()>>,
+    state: &WorkerState,
+    journal: &JobJournal,
+    shutdown_requested: &AtomicBool,
+    drain_timeout: Duration,
+    auth: &ApiAuth,
+    base_url: &str,
+) {
+    let mut drain_started_at: Option<Instant> = None;
+
+    loop {
+        if handles.iter().all(|handle| handle.is_finished()) {
+            info!("All worker threads stopped");
+            log_thread_stats(state);
+            return;
+        }
+
+        if shutdown_requested.load(Ordering::SeqCst) && drain_started_at.is_none() {
+            warn!(
+                "Shutdown requested, waiting up to {:.1?} for in-flight jobs: {}",
+                drain_timeout,
+                state.summary()
+            );
+
+            state.start_draining();
+            drain_started_at = Some(Instant::now());
+        }
+
+        if let Some(drain_started_at) = drain_started_at {
+            if drain_started_at.elapsed() > drain_timeout {
+                journal.abandon_all(auth, base_url);
+                log_thread_stats(state);
+                restore_terminal();
+                flush_error_reports();
+                flush_log_file();
+                std::process::exit(1);
+            }
+        }
+
+        sleep(Duration::from_millis(200));
+    }
+}
+
+fn log_thread_stats(state: &WorkerState) {
+    info!("{}", state.run_summary());
+
+    for stats in state.thread_stats() {
+        info!(
+            "{}: {} jobs done, busy for {:.1?}",
+            stats.name, stats.jobs_done, stats.busy_time
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    panic::resume_unwind,
+    sync::{Condvar, Mutex, OnceLock},
+    time::Duration,
+};
+
+use crate::{
+    state::{current_abort_reason, JobAborted},
+    worker::SUPPORTED_JOB_TYPES,
+};
+
+/// Stage of the transfers of files outside of the API, eg: the LAZ files
+pub const DOWNLOAD_STAGE: &str = "download";
+/// Stage of the uploads of the job results
+pub const UPLOAD_STAGE: &str = "upload";
+
+/// Maximum number of threads in a stage at once, by stage: the job types in lower case, eg:
+/// "lidar", and the `DOWNLOAD_STAGE` and `UPLOAD_STAGE` transfers.
+pub type StageBudgets = HashMap<String, usize>;
+
+static RUNNING: Condvar = Condvar::new();
+
+thread_local! {
+    /// Stages the current thread holds a permit of, a download may start another one
+    static HELD_STAGES: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+fn stage_states() -> &'static Mutex<(StageBudgets, HashMap<&'static str, usize>)> {
+    static STAGE_STATES: OnceLock<Mutex<(StageBudgets, HashMap<&'static str, usize>)>> = OnceLock::new();
+    STAGE_STATES.get_or_init(|| Mutex::new((StageBudgets::new(), HashMap::new())))
+}
+
+/// Parse budgets like "lidar=1,render=2,pyramid=4,download=4,upload=2".
+pub fn parse_stage_budgets(value: &str) -> Result<StageBudgets, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|budget| !budget.is_empty())
+        .map(|budget| {
+            let (stage, threads) = budget
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid stage budget \"{}\", expected eg: lidar=1", budget))?;
+            let stage = stage.trim().to_lowercase();
+
+            let known = [DOWNLOAD_STAGE, UPLOAD_STAGE].contains(&stage.as_str())
+                || SUPPORTED_JOB_TYPES
+                    .iter()
+                    .any(|job_type| job_type.to_lowercase() == stage);
+
+            if !known {
+                return Err(format!("Unknown stage \"{}\"", stage));
+            }
+
+            match threads.trim().parse::<usize>() {
+                Ok(threads) if threads > 0 => Ok((stage, threads)),
+                _ => Err(format!("Invalid number of threads \"{}\" for {}", threads, stage)),
+            }
+        })
+        .collect()
+}
+
+/// Let at most `threads` threads in a stage at once, on top of the total `--threads`.
+pub fn set_stage_budget(stage: &str, threads: usize) {
+    stage_states()
+        .lock()
+        .unwrap()
+        .0
+        .insert(stage.to_lowercase(), threads);
+}
+
+/// Budget of a stage, eg: "Lidar" for the LiDAR jobs. None if not limited.
+pub fn stage_budget(stage: &str) -> Option<usize> {
+    stage_states()
+        .lock()
+        .unwrap()
+        .0
+        .get(&stage.to_lowercase())
+        .copied()
+}
+
+/// A thread in a transfer stage, counted in its budget until dropped.
+pub struct StagePermit {
+    stage: Option<&'static str>,
+}
+
+impl StagePermit {
+    /// Wait until the stage has room for one more thread. Permits are reentrant, the fallback of a
+    /// download to another download method does not wait for itself. Aborts with the job.
+    pub fn acquire(stage: &'static str) -> StagePermit {
+        if HELD_STAGES.with(|held| held.borrow().contains(&stage)) {
+            return StagePermit { stage: None };
+        }
+
+        let mut states = stage_states().lock().unwrap();
+
+        loop {
+            if let Some(reason) = current_abort_reason() {
+                drop(states);
+                resume_unwind(Box::new(JobAborted(reason)));
+            }
+
+            let budget = states.0.get(stage).copied();
+            let running = states.1.entry(stage).or_default();
+
+            if budget.is_some_and(|budget| *running >= budget) {
+                states = RUNNING.wait_timeout(states, Duration::from_secs(1)).unwrap().0;
+                continue;
+            }
+
+            *running += 1;
+            HELD_STAGES.with(|held| held.borrow_mut().push(stage));
+
+            return StagePermit { stage: Some(stage) };
+        }
+    }
+}
+
+impl Drop for StagePermit {
+    fn drop(&mut self) {
+        let Some(stage) = self.stage else {
+            return;
+        };
+
+        if let Some(running) = stage_states().lock().unwrap().1.get_mut(stage) {
+            *running -= 1;
+        }
+
+        HELD_STAGES.with(|held| held.borrow_mut().retain(|held_stage| *held_stage != stage));
+        RUNNING.notify_all();
+    }
+}
@@ -0,0 +1,507 @@
+use crate::{events::record_job_event, stats::RunStats};
+use serde_json::json;
+use std::{
+    cell::RefCell,
+    panic::resume_unwind,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// What a worker thread is currently doing, shared with the monitoring threads.
+pub struct ThreadSlot {
+    /// Stable thread name used in the logs, eg: worker-0
+    pub name: String,
+    pub current_job: Option<String>,
+    /// Job as received from the API, used to report it if it has to be abandoned
+    pub job_payload: Option<String>,
+    pub job_started_at: Option<Instant>,
+    pub jobs_done: u64,
+    /// Time spent processing jobs since the worker started
+    pub busy_time: Duration,
+    /// Step of the current job, eg: "rendering"
+    pub stage: Option<String>,
+    /// Bytes downloaded and uploaded during the current job
+    pub transferred_bytes: u64,
+    /// Ratio (0 to 1) of the ongoing transfer, if its size is known
+    pub transfer_progress: Option<f64>,
+    /// Memory used by the process when the current job started, see `set_process_memory`
+    pub memory_at_start: u64,
+    /// Set by the memory watchdog, the job stops at its next stage or transfer
+    pub abort_reason: Option<String>,
+}
+
+/// Counters of a worker thread since the worker started.
+pub struct ThreadStats {
+    pub name: String,
+    pub jobs_done: u64,
+    pub busy_time: Duration,
+    pub current_job: Option<String>,
+    pub stage: Option<String>,
+    /// Time elapsed since the current job started
+    pub job_elapsed: Option<Duration>,
+    pub transferred_bytes: u64,
+    pub transfer_progress: Option<f64>,
+    /// Growth of the process memory since the current job started, in bytes. Includes the growth
+    /// of the jobs running alongside.
+    pub memory_estimate: Option<u64>,
+    /// Whether the current job was requested to abort and has not stopped yet
+    pub aborting: bool,
+}
+
+/// Abort reason of the jobs cancelled by the server or an operator, see `WorkerState::cancel_job`
+pub const JOB_CANCELLED: &str = "cancelled";
+
+/// Panic payload of a job stopped by `WorkerState::request_abort`, see `report_stage`.
+pub struct JobAborted(pub String);
+
+thread_local! {
+    /// Slot of the worker thread running on this thread, so that the steps can report their
+    /// progress without knowing about the worker state. Not set for the local commands.
+    static CURRENT_SLOT: RefCell<Option<(Arc<WorkerState>, usize)>> = const { RefCell::new(None) };
+
+    /// Id of the job running on this thread, added to its log lines and API requests
+    static CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Set the correlation id of the job starting on the current thread, None once it is over.
+pub fn set_correlation_id(correlation_id: Option<String>) {
+    CORRELATION_ID.with(|current| *current.borrow_mut() = correlation_id);
+}
+
+pub fn current_correlation_id() -> Option<String> {
+    CORRELATION_ID.with(|current| current.borrow().clone())
+}
+
+/// Make `report_stage` and `report_transferred_bytes` update the slot `thread_index` when called
+/// from the current thread.
+pub fn attach_current_thread(state: Arc<WorkerState>, thread_index: usize) {
+    CURRENT_SLOT.with(|current_slot| *current_slot.borrow_mut() = Some((state, thread_index)));
+}
+
+/// Slot the current thread reports to, to attach the helper threads of a job with
+/// `attach_current_thread`.
+pub fn current_slot() -> Option<(Arc<WorkerState>, usize)> {
+    CURRENT_SLOT.with(|current_slot| current_slot.borrow().clone())
+}
+
+fn with_current_slot<F: FnOnce(&mut ThreadSlot)>(update: F) {
+    CURRENT_SLOT.with(|current_slot| {
+        if let Some((state, thread_index)) = current_slot.borrow().as_ref() {
+            if let Some(slot) = state.slots.lock().unwrap().get_mut(*thread_index) {
+                update(slot);
+            }
+        }
+    });
+}
+
+/// Set the step of the job running on the current thread, eg: "rendering". Unwinds with a
+/// `JobAborted` payload if the job was requested to abort.
+pub fn report_stage(stage: &str) {
+    let mut changed_stage_job = None;
+
+    with_current_slot(|slot| {
+        if slot.stage.as_deref() != Some(stage) {
+            changed_stage_job = slot.current_job.clone();
+        }
+
+        slot.stage = Some(stage.to_string());
+    });
+
+    if let Some(job) = changed_stage_job {
+        record_job_event(&job, "stage", json!({ "stage": stage }));
+    }
+
+    if let Some(reason) = current_abort_reason() {
+        resume_unwind(Box::new(JobAborted(reason)));
+    }
+}
+
+/// Why the job running on the current thread should stop, None if it can continue.
+pub fn current_abort_reason() -> Option<String> {
+    let mut abort_reason = None;
+    with_current_slot(|slot| abort_reason = slot.abort_reason.clone());
+
+    abort_reason
+}
+
+/// Add bytes to the transfer volume of the job running on the current thread.
+pub fn report_transferred_bytes(bytes: u64) {
+    with_current_slot(|slot| slot.transferred_bytes += bytes);
+}
+
+/// Set the completion ratio of the transfer running on the current thread, None when it is over.
+pub fn report_transfer_progress(progress: Option<f64>) {
+    with_current_slot(|slot| slot.transfer_progress = progress);
+}
+
+pub fn worker_thread_name(thread_index: usize) -> String {
+    format!("worker-{}", thread_index)
+}
+
+/// Name of the thread prefetching the next job of a worker thread.
+pub fn downloader_thread_name(thread_index: usize) -> String {
+    format!("downloader-{}", thread_index)
+}
+
+pub struct WorkerState {
+    slots: Mutex<Vec<ThreadSlot>>,
+    draining: AtomicBool,
+    /// Set while the free disk space is below the low-water mark
+    disk_paused: AtomicBool,
+    /// Set by an operator through the control socket
+    paused: AtomicBool,
+    /// Set while the monthly bandwidth budget is exhausted
+    quota_paused: AtomicBool,
+    /// Resident memory of the process in bytes, as last sampled by the memory watchdog
+    process_memory: AtomicU64,
+    /// Number of worker threads allowed to fetch new jobs, lowered during the quiet hours and
+    /// changed at runtime with SIGUSR1/SIGUSR2
+    active_threads: AtomicUsize,
+    stats: Mutex<RunStats>,
+    consecutive_failures: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    /// Time of the first failed API call since the last successful one
+    api_unreachable_since: Mutex<Option<Instant>>,
+    /// Time of the last successful API call
+    last_api_contact: Mutex<Option<Instant>>,
+}
+
+impl WorkerState {
+    pub fn new(threads: usize) -> Self {
+        let slots = (0..threads)
+            .map(|thread_index| ThreadSlot {
+                name: worker_thread_name(thread_index),
+                current_job: None,
+                job_payload: None,
+                job_started_at: None,
+                jobs_done: 0,
+                busy_time: Duration::ZERO,
+                stage: None,
+                transferred_bytes: 0,
+                transfer_progress: None,
+                memory_at_start: 0,
+                abort_reason: None,
+            })
+            .collect();
+
+        WorkerState {
+            slots: Mutex::new(slots),
+            draining: AtomicBool::new(false),
+            disk_paused: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            quota_paused: AtomicBool::new(false),
+            process_memory: AtomicU64::new(0),
+            active_threads: AtomicUsize::new(threads),
+            stats: Mutex::new(RunStats::new()),
+            consecutive_failures: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+            api_unreachable_since: Mutex::new(None),
+            last_api_contact: Mutex::new(None),
+        }
+    }
+
+    pub fn start_job(&self, thread_index: usize, description: String, payload: &str) {
+        record_job_event(&description, "accepted", json!({}));
+
+        let mut slots = self.slots.lock().unwrap();
+
+        if let Some(slot) = slots.get_mut(thread_index) {
+            slot.current_job = Some(description);
+            slot.job_payload = Some(payload.to_string());
+            slot.job_started_at = Some(Instant::now());
+            slot.stage = None;
+            slot.transferred_bytes = 0;
+            slot.transfer_progress = None;
+            slot.memory_at_start = self.process_memory();
+            slot.abort_reason = None;
+        }
+    }
+
+    /// Description of the job running on the thread, eg: "Render 1000_6000".
+    pub fn current_job(&self, thread_index: usize) -> Option<String> {
+        let slots = self.slots.lock().unwrap();
+
+        slots.get(thread_index).and_then(|slot| slot.current_job.clone())
+    }
+
+    /// Step of the job running on the thread, see `report_stage`.
+    pub fn current_stage(&self, thread_index: usize) -> Option<String> {
+        let slots = self.slots.lock().unwrap();
+
+        slots.get(thread_index).and_then(|slot| slot.stage.clone())
+    }
+
+    pub fn end_job(&self, thread_index: usize) {
+        let mut slots = self.slots.lock().unwrap();
+
+        if let Some(slot) = slots.get_mut(thread_index) {
+            if let Some(job_started_at) = slot.job_started_at {
+                slot.jobs_done += 1;
+                slot.busy_time += job_started_at.elapsed();
+            }
+
+            slot.current_job = None;
+            slot.job_payload = None;
+            slot.job_started_at = None;
+            slot.stage = None;
+            slot.transfer_progress = None;
+            slot.abort_reason = None;
+        }
+    }
+
+    /// Stop accepting new jobs, in-flight jobs keep running.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn set_disk_paused(&self, paused: bool) {
+        self.disk_paused.store(paused, Ordering::SeqCst);
+    }
+
+    /// Whether new jobs should not be fetched because the disk is almost full.
+    pub fn is_disk_paused(&self) -> bool {
+        self.disk_paused.load(Ordering::SeqCst)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    /// Whether new jobs should not be fetched because an operator paused the worker.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn set_quota_paused(&self, paused: bool) {
+        self.quota_paused.store(paused, Ordering::SeqCst);
+    }
+
+    /// Whether new jobs should not be fetched because the monthly bandwidth budget is exhausted.
+    pub fn is_quota_paused(&self) -> bool {
+        self.quota_paused.load(Ordering::SeqCst)
+    }
+
+    pub fn set_process_memory(&self, bytes: u64) {
+        self.process_memory.store(bytes, Ordering::SeqCst);
+    }
+
+    /// Resident memory of the process in bytes, 0 if the memory watchdog is not running.
+    pub fn process_memory(&self) -> u64 {
+        self.process_memory.load(Ordering::SeqCst)
+    }
+
+    /// Make the job running on the thread stop at its next stage or transfer. Returns false if no
+    /// job is running or it is already aborting.
+    pub fn request_abort(&self, thread_index: usize, reason: String) -> bool {
+        let mut slots = self.slots.lock().unwrap();
+
+        match slots.get_mut(thread_index) {
+            Some(slot) if slot.current_job.is_some() && slot.abort_reason.is_none() => {
+                slot.abort_reason = Some(reason);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Abort the job described as `job` (eg: "Render 1000_6000", as in the heartbeats) on
+    /// whatever thread runs it. Returns false if no such job is running.
+    pub fn cancel_job(&self, job: &str) -> bool {
+        let thread_index = {
+            let slots = self.slots.lock().unwrap();
+            slots
+                .iter()
+                .position(|slot| slot.current_job.as_deref() == Some(job))
+        };
+
+        thread_index.is_some_and(|thread_index| self.request_abort(thread_index, JOB_CANCELLED.to_string()))
+    }
+
+    pub fn set_active_threads(&self, active_threads: usize) {
+        self.active_threads.store(active_threads, Ordering::SeqCst);
+    }
+
+    /// Number of spawned worker threads, active or not.
+    pub fn thread_count(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    pub fn active_threads(&self) -> usize {
+        self.active_threads.load(Ordering::SeqCst)
+    }
+
+    /// Whether the thread may fetch new jobs, the first `active_threads` ones are.
+    pub fn is_thread_active(&self, thread_index: usize) -> bool {
+        thread_index < self.active_threads()
+    }
+
+    /// Sleep for `duration`, waking up early if the worker starts draining.
+    pub fn sleep_unless_draining(&self, duration: Duration) {
+        let start = Instant::now();
+
+        while !self.is_draining() && start.elapsed() < duration {
+            sleep(Duration::from_millis(200).min(duration.saturating_sub(start.elapsed())));
+        }
+    }
+
+    /// Payloads of the jobs currently being processed.
+    pub fn in_flight_jobs(&self) -> Vec<String> {
+        let slots = self.slots.lock().unwrap();
+
+        slots.iter().filter_map(|slot| slot.job_payload.clone()).collect()
+    }
+
+    /// One line summary of the current jobs, eg: "2/3 busy: Render 1000_6000, Lidar 1000_7000",
+    /// prefixed with "paused, " when paused by an operator, with "paused (low disk space), " when the disk is almost full, or with
+    /// "paused (bandwidth quota), " when the monthly bandwidth budget is exhausted
+    pub fn summary(&self) -> String {
+        let slots = self.slots.lock().unwrap();
+
+        let current_jobs: Vec<&str> = slots
+            .iter()
+            .filter_map(|slot| slot.current_job.as_deref())
+            .collect();
+
+        let paused = if self.is_paused() {
+            "paused, "
+        } else if self.is_disk_paused() {
+            "paused (low disk space), "
+        } else if self.is_quota_paused() {
+            "paused (bandwidth quota), "
+        } else {
+            ""
+        };
+
+        if current_jobs.is_empty() {
+            return format!("{}0/{} busy", paused, slots.len());
+        }
+
+        format!(
+            "{}{}/{} busy: {}",
+            paused,
+            current_jobs.len(),
+            slots.len(),
+            current_jobs.join(", ")
+        )
+    }
+
+    pub fn thread_stats(&self) -> Vec<ThreadStats> {
+        let slots = self.slots.lock().unwrap();
+
+        slots
+            .iter()
+            .map(|slot| ThreadStats {
+                name: slot.name.clone(),
+                jobs_done: slot.jobs_done,
+                busy_time: slot.busy_time
+                    + slot
+                        .job_started_at
+                        .map(|job_started_at| job_started_at.elapsed())
+                        .unwrap_or_default(),
+                current_job: slot.current_job.clone(),
+                stage: slot.stage.clone(),
+                job_elapsed: slot.job_started_at.map(|job_started_at| job_started_at.elapsed()),
+                transferred_bytes: slot.transferred_bytes,
+                transfer_progress: slot.transfer_progress,
+                memory_estimate: slot
+                    .job_started_at
+                    .map(|_| self.process_memory().saturating_sub(slot.memory_at_start)),
+                aborting: slot.abort_reason.is_some(),
+            })
+            .collect()
+    }
+
+    pub fn record_job_stats(
+        &self,
+        job_type: &str,
+        duration: Duration,
+        succeeded: bool,
+        bytes_downloaded: u64,
+        bytes_uploaded: u64,
+    ) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.record_job(job_type, duration, succeeded, bytes_downloaded, bytes_uploaded);
+    }
+
+    pub fn record_job_outcome(&self, error: Option<String>) {
+        match error {
+            Some(error) => {
+                self.consecutive_failures.fetch_add(1, Ordering::SeqCst);
+                *self.last_error.lock().unwrap() = Some(error);
+            }
+            None => self.consecutive_failures.store(0, Ordering::SeqCst),
+        }
+    }
+
+    pub fn consecutive_failures(&self) -> u64 {
+        self.consecutive_failures.load(Ordering::SeqCst)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    pub fn record_api_call(&self, reachable: bool) {
+        let mut api_unreachable_since = self.api_unreachable_since.lock().unwrap();
+
+        if reachable {
+            *api_unreachable_since = None;
+            *self.last_api_contact.lock().unwrap() = Some(Instant::now());
+        } else if api_unreachable_since.is_none() {
+            *api_unreachable_since = Some(Instant::now());
+        }
+    }
+
+    /// How long the API has been unreachable, None if the last call succeeded.
+    pub fn api_unreachable_for(&self) -> Option<Duration> {
+        self.api_unreachable_since
+            .lock()
+            .unwrap()
+            .map(|api_unreachable_since| api_unreachable_since.elapsed())
+    }
+
+    /// Time elapsed since the last successful API call, None if there was none.
+    pub fn last_api_contact(&self) -> Option<Duration> {
+        self.last_api_contact
+            .lock()
+            .unwrap()
+            .map(|last_api_contact| last_api_contact.elapsed())
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.stats.lock().unwrap().uptime()
+    }
+
+    /// Counters of the jobs processed since the worker started.
+    pub fn run_stats(&self) -> RunStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Summary of the jobs processed since the worker started, see `RunStats::summary`.
+    pub fn run_summary(&self) -> String {
+        self.stats.lock().unwrap().summary()
+    }
+
+    /// Indexes of the threads running the same job for longer than `max_job_duration`.
+    pub fn stalled_threads(&self, max_job_duration: Duration) -> Vec<usize> {
+        let slots = self.slots.lock().unwrap();
+
+        slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| match slot.job_started_at {
+                Some(started_at) => started_at.elapsed() > max_job_duration,
+                None => false,
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+}
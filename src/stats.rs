@@ -0,0 +1,131 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+#[derive(Default, Clone)]
+pub struct JobTypeStats {
+    pub jobs_done: u64,
+    pub failures: u64,
+    /// Total duration of the successful jobs
+    pub busy_time: Duration,
+}
+
+/// Counters of every job processed since the worker started, aggregated by job type.
+#[derive(Clone)]
+pub struct RunStats {
+    started_at: Instant,
+    by_job_type: BTreeMap<String, JobTypeStats>,
+    bytes_downloaded: u64,
+    bytes_uploaded: u64,
+}
+
+impl RunStats {
+    pub fn new() -> Self {
+        RunStats {
+            started_at: Instant::now(),
+            by_job_type: BTreeMap::new(),
+            bytes_downloaded: 0,
+            bytes_uploaded: 0,
+        }
+    }
+
+    /// Time elapsed since the worker started.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn by_job_type(&self) -> &BTreeMap<String, JobTypeStats> {
+        &self.by_job_type
+    }
+
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.bytes_downloaded
+    }
+
+    pub fn bytes_uploaded(&self) -> u64 {
+        self.bytes_uploaded
+    }
+
+    pub fn record_job(
+        &mut self,
+        job_type: &str,
+        duration: Duration,
+        succeeded: bool,
+        bytes_downloaded: u64,
+        bytes_uploaded: u64,
+    ) {
+        let stats = self.by_job_type.entry(job_type.to_string()).or_default();
+
+        if succeeded {
+            stats.jobs_done += 1;
+            stats.busy_time += duration;
+        } else {
+            stats.failures += 1;
+        }
+
+        self.bytes_downloaded += bytes_downloaded;
+        self.bytes_uploaded += bytes_uploaded;
+    }
+
+    /// Multi-line human readable summary, eg:
+    /// ```text
+    /// Worker running for 2h13m: 41 jobs done, 1 failed, 1234 MB downloaded, 567 MB uploaded
+    ///   Lidar: 30 done (13.5/h), 1 failed, 2m41s on average
+    ///   Render: 11 done (5.0/h), 0 failed, 4m02s on average
+    /// ```
+    pub fn summary(&self) -> String {
+        let elapsed = self.started_at.elapsed();
+        let elapsed_hours = elapsed.as_secs_f64() / 3600.0;
+
+        let jobs_done: u64 = self.by_job_type.values().map(|stats| stats.jobs_done).sum();
+        let failures: u64 = self.by_job_type.values().map(|stats| stats.failures).sum();
+
+        let mut lines = vec![format!(
+            "Worker running for {}: {} jobs done, {} failed, {} MB downloaded, {} MB uploaded",
+            format_duration(elapsed),
+            jobs_done,
+            failures,
+            self.bytes_downloaded / 1_000_000,
+            self.bytes_uploaded / 1_000_000
+        )];
+
+        for (job_type, stats) in &self.by_job_type {
+            let jobs_per_hour = if elapsed_hours > 0.0 {
+                stats.jobs_done as f64 / elapsed_hours
+            } else {
+                0.0
+            };
+
+            let average_duration = if stats.jobs_done > 0 {
+                stats.busy_time / stats.jobs_done as u32
+            } else {
+                Duration::ZERO
+            };
+
+            lines.push(format!(
+                "  {}: {} done ({:.1}/h), {} failed, {} on average",
+                job_type,
+                stats.jobs_done,
+                jobs_per_hour,
+                stats.failures,
+                format_duration(average_duration)
+            ));
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Format a duration as `1h02m`, `3m07s` or `12s`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    if seconds >= 3600 {
+        format!("{}h{:02}m", seconds / 3600, (seconds % 3600) / 60)
+    } else if seconds >= 60 {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
@@ -0,0 +1,105 @@
+use log::{error, info};
+use serde_json::{json, Value};
+use std::{
+    fs::write,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread::{self, sleep},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cache::CacheUsageSnapshot,
+    history::{HistoryQuery, JobHistory},
+    state::WorkerState,
+    utils::write_atomically,
+    web_ui::{cache_json, threads_json, CACHE_USAGE_MAX_AGE},
+};
+
+/// Failed jobs listed in the status file
+const RECENT_FAILURES: u32 = 10;
+
+/// Write a snapshot of the worker to `path` every `interval`, for the monitoring scripts, and to
+/// know what the worker was doing when it crashed.
+pub fn spawn_status_file_writer(
+    state: Arc<WorkerState>,
+    history: Option<Arc<JobHistory>>,
+    path: PathBuf,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(
+        "Writing the status of the worker to {} every {:.0?}",
+        path.display(),
+        interval
+    );
+
+    let mut cache_usage = CacheUsageSnapshot::new(CACHE_USAGE_MAX_AGE);
+
+    thread::Builder::new()
+        .name("status-file".to_string())
+        .spawn(move || loop {
+            let status = status_snapshot(&state, history.as_deref(), &mut cache_usage);
+
+            if let Err(error) = write_status_file(&path, &status) {
+                error!("Failed to write the status file {}: {}", path.display(), error);
+            }
+
+            sleep(interval);
+        })?;
+
+    Ok(())
+}
+
+fn status_snapshot(
+    state: &WorkerState,
+    history: Option<&JobHistory>,
+    cache_usage: &mut CacheUsageSnapshot,
+) -> Value {
+    let recent_failures: Vec<Value> = history
+        .and_then(|history| {
+            history
+                .query(&HistoryQuery {
+                    tile: None,
+                    since_hours: None,
+                    failed_only: true,
+                    limit: RECENT_FAILURES,
+                })
+                .ok()
+        })
+        .unwrap_or_default()
+        .iter()
+        .map(|record| {
+            json!({
+                "job_type": record.job_type,
+                "tile": record.tile,
+                "ended_at": record.ended_at,
+                "error": record.error,
+            })
+        })
+        .collect();
+
+    json!({
+        "written_at": SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "pid": process::id(),
+        "uptime_seconds": state.uptime().as_secs(),
+        "summary": state.summary(),
+        "draining": state.is_draining(),
+        "threads": threads_json(state),
+        "consecutive_failures": state.consecutive_failures(),
+        "last_error": state.last_error(),
+        "recent_failures": recent_failures,
+        "api_unreachable_seconds": state.api_unreachable_for().map(|duration| duration.as_secs()),
+        "last_api_contact_seconds": state.last_api_contact().map(|duration| duration.as_secs()),
+        "cache": cache_json(cache_usage.get()),
+    })
+}
+
+/// Written aside then renamed, the scripts never read a partially written file.
+fn write_status_file(path: &Path, status: &Value) -> Result<(), Box<dyn std::error::Error>> {
+    write_atomically(path, |partial_path| {
+        write(partial_path, serde_json::to_string_pretty(status)?)?;
+        Ok(())
+    })
+}
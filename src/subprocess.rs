@@ -0,0 +1,218 @@
+use log::{debug, info};
+use std::{
+    io::{BufRead, BufReader, Read},
+    panic::resume_unwind,
+    process::{Command, Output, Stdio},
+    sync::atomic::{AtomicU64, Ordering},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    priority::apply_processing_priority,
+    state::{current_abort_reason, current_correlation_id, set_correlation_id, JobAborted},
+};
+
+const SUBPROCESS_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Address space (virtual memory) limit in bytes of the spawned commands, 0 for no limit
+static SUBPROCESS_MEMORY_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_subprocess_memory_limit(bytes: u64) {
+    SUBPROCESS_MEMORY_LIMIT.store(bytes, Ordering::SeqCst);
+}
+
+/// A command (gdal_translate, ogr2ogr, ...) running at the processing priority and under the
+/// memory limit, so that a runaway command fails on its own instead of starving the machine.
+pub fn subprocess_command(program: &str) -> Command {
+    #[allow(unused_mut)]
+    let mut command = Command::new(program);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        let memory_limit = SUBPROCESS_MEMORY_LIMIT.load(Ordering::SeqCst);
+
+        // Safety: the closure only does syscalls, which are allowed between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                apply_processing_priority()?;
+
+                if memory_limit > 0 {
+                    let limit = libc::rlimit {
+                        rlim_cur: memory_limit as libc::rlim_t,
+                        rlim_max: memory_limit as libc::rlim_t,
+                    };
+
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                Ok(())
+            });
+        }
+    }
+
+    command
+}
+
+/// Command line of a command, quoted so that it can be pasted in a shell to reproduce a run.
+pub fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            let is_safe = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+
+            if is_safe {
+                arg.to_string()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Run a command and wait for its output. A command killed by a signal or running out of memory
+/// is an error, other failures are left to the caller. The command is killed if the job is
+/// aborted or cancelled meanwhile. The command line and the output are logged along with the job.
+pub fn run_subprocess(command: &mut Command, name: &str) -> Result<Output, Box<dyn std::error::Error>> {
+    info!("Running {}", command_line(command));
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("Failed to execute {}: {}", name, error))?;
+
+    // Read in the background so that a command filling a pipe does not block
+    let stdout = child
+        .stdout
+        .take()
+        .map(|stdout| read_in_background(stdout, format!("{} stdout", name), false));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|stderr| read_in_background(stderr, format!("{} stderr", name), true));
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if let Some(reason) = current_abort_reason() {
+            let _ = child.kill();
+            let _ = child.wait();
+            resume_unwind(Box::new(JobAborted(reason)));
+        }
+
+        thread::sleep(SUBPROCESS_POLL_INTERVAL);
+    };
+
+    let output = Output {
+        status,
+        stdout: stdout.and_then(|handle| handle.join().ok()).unwrap_or_default(),
+        stderr: stderr.and_then(|handle| handle.join().ok()).unwrap_or_default(),
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = output.status.signal() {
+            if signal == libc::SIGKILL {
+                return Err(format!("{} was killed, most likely by the out-of-memory killer", name).into());
+            }
+
+            return Err(format!(
+                "{} was killed by signal {}, possibly after running out of memory",
+                name, signal
+            )
+            .into());
+        }
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_lowercase();
+
+    if !output.status.success() && (stderr.contains("out of memory") || stderr.contains("cannot allocate")) {
+        return Err(format!(
+            "{} ran out of memory: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(output)
+}
+
+/// Read the output of a command, logging every line with `prefix` on behalf of the job running
+/// on the current thread: stderr at the info level, stdout at the debug level.
+fn read_in_background<R: Read + Send + 'static>(
+    reader: R,
+    prefix: String,
+    is_stderr: bool,
+) -> JoinHandle<Vec<u8>> {
+    let correlation_id = current_correlation_id();
+    let thread_name = thread::current().name().unwrap_or("subprocess").to_string();
+
+    let read = move || {
+        set_correlation_id(correlation_id);
+
+        let mut reader = BufReader::new(reader);
+        let mut buffer = vec![];
+
+        loop {
+            let line_start = buffer.len();
+
+            match reader.read_until(b'\n', &mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+
+            let line = String::from_utf8_lossy(&buffer[line_start..]);
+            let line = line.trim_end();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if is_stderr {
+                info!("[{}] {}", prefix, line);
+            } else {
+                debug!("[{}] {}", prefix, line);
+            }
+        }
+
+        buffer
+    };
+
+    // Named after the job thread, which the log lines are attributed to
+    thread::Builder::new()
+        .name(thread_name)
+        .spawn(read)
+        .expect("Failed to spawn the subprocess output reader")
+}
+
+/// Run a command with `run_subprocess`, any failure being an error.
+pub fn run_subprocess_checked(command: &mut Command, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let output = run_subprocess(command, name)?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use log::{info, warn};
+use std::{
+    sync::Arc,
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+use crate::state::WorkerState;
+
+const STATUS_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Tell systemd the worker is started. No-op when not running as a `Type=notify` service.
+pub fn notify_ready() {
+    #[cfg(unix)]
+    if let Err(error) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        warn!("Failed to notify systemd of readiness: {}", error);
+    }
+}
+
+#[cfg(unix)]
+fn notify_status(status: &str, ping_watchdog: bool) {
+    let mut states = vec![sd_notify::NotifyState::Status(status)];
+
+    if ping_watchdog {
+        states.push(sd_notify::NotifyState::Watchdog);
+    }
+
+    if let Err(error) = sd_notify::notify(false, &states) {
+        warn!("Failed to notify systemd: {}", error);
+    }
+}
+
+/// Periodically report the current jobs as the service STATUS and ping the watchdog when
+/// `WatchdogSec` is set. The watchdog is not pinged anymore if a job runs longer than
+/// `max_job_duration`, so that systemd restarts a hung worker.
+pub fn spawn_notifier(state: Arc<WorkerState>, max_job_duration: Duration) {
+    #[cfg(unix)]
+    {
+        if std::env::var_os("NOTIFY_SOCKET").is_none() {
+            return;
+        }
+
+        let mut watchdog_usec = 0;
+        let watchdog_enabled = sd_notify::watchdog_enabled(false, &mut watchdog_usec);
+
+        let interval = if watchdog_enabled {
+            let half_watchdog = Duration::from_micros(watchdog_usec) / 2;
+            info!("Systemd watchdog enabled, pinging every {:.1?}", half_watchdog);
+            half_watchdog.min(STATUS_REFRESH_INTERVAL)
+        } else {
+            STATUS_REFRESH_INTERVAL
+        };
+
+        spawn(move || loop {
+            let stalled_threads = state.stalled_threads(max_job_duration);
+
+            if !stalled_threads.is_empty() {
+                warn!(
+                    "Threads {:?} running the same job for more than {:.1?}, not pinging the systemd watchdog",
+                    stalled_threads, max_job_duration
+                );
+            }
+
+            notify_status(&state.summary(), watchdog_enabled && stalled_threads.is_empty());
+
+            sleep(interval);
+        });
+    }
+
+    #[cfg(not(unix))]
+    let _ = (state, max_job_duration);
+}
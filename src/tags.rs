@@ -0,0 +1,49 @@
+use log::info;
+use std::{collections::BTreeMap, sync::OnceLock};
+
+/// Labels declared by the operator, eg: region=alps, disk=fast. Sent with the next-job requests so
+/// that the server can target jobs to specific workers.
+pub type WorkerTags = BTreeMap<String, String>;
+
+static WORKER_TAGS: OnceLock<WorkerTags> = OnceLock::new();
+
+pub fn set_worker_tags(tags: WorkerTags) {
+    if !tags.is_empty() {
+        info!("Worker tags: {}", tags_header_value(&tags));
+    }
+
+    let _ = WORKER_TAGS.set(tags);
+}
+
+/// Tags of the worker, empty if none were declared.
+pub fn worker_tags() -> &'static WorkerTags {
+    WORKER_TAGS.get_or_init(WorkerTags::new)
+}
+
+/// Value of the `X-Mapant-Worker-Tags` header, eg: "disk=fast,region=alps".
+pub fn tags_header_value(tags: &WorkerTags) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse tags like "region=alps,disk=fast". Keys and values can not contain commas nor equal
+/// signs, and must be valid in an HTTP header.
+pub fn parse_tags(value: &str) -> Result<WorkerTags, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once('=') {
+            Some((key, value))
+                if !key.trim().is_empty()
+                    && !value.contains('=')
+                    && tag.chars().all(|c| c.is_ascii_graphic() || c == ' ') =>
+            {
+                Ok((key.trim().to_string(), value.trim().to_string()))
+            }
+            _ => Err(format!("Invalid tag \"{}\", expected eg: region=alps", tag)),
+        })
+        .collect()
+}
@@ -0,0 +1,189 @@
+use log::{error, info};
+use std::{
+    fmt::Write,
+    fs::{rename, write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::{self, sleep},
+    time::Duration,
+};
+
+use crate::{
+    cache::{CacheUsage, CacheUsageSnapshot},
+    state::WorkerState,
+    web_ui::CACHE_USAGE_MAX_AGE,
+};
+
+/// Write the metrics of the worker to `path` every `interval`, in the Prometheus text format read
+/// by the textfile collector of node_exporter, for the machines which can not expose a port.
+pub fn spawn_textfile_metrics_writer(
+    state: Arc<WorkerState>,
+    path: PathBuf,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(
+        "Writing the Prometheus metrics to {} every {:.0?}",
+        path.display(),
+        interval
+    );
+
+    let mut cache_usage = CacheUsageSnapshot::new(CACHE_USAGE_MAX_AGE);
+
+    thread::Builder::new()
+        .name("textfile-metrics".to_string())
+        .spawn(move || loop {
+            let metrics = render_metrics(&state, cache_usage.get());
+
+            if let Err(error) = write_metrics_file(&path, &metrics) {
+                error!("Failed to write the metrics to {}: {}", path.display(), error);
+            }
+
+            sleep(interval);
+        })?;
+
+    Ok(())
+}
+
+fn render_metrics(state: &WorkerState, cache_usage: &[CacheUsage]) -> String {
+    let run_stats = state.run_stats();
+    let threads = state.thread_stats();
+    let mut metrics = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+        let _ = writeln!(metrics, "# HELP mapant_worker_{} {}", name, help);
+        let _ = writeln!(metrics, "# TYPE mapant_worker_{} {}", name, kind);
+
+        for (labels, value) in samples {
+            let _ = writeln!(metrics, "mapant_worker_{}{} {}", name, labels, value);
+        }
+    };
+
+    metric(
+        "uptime_seconds",
+        "gauge",
+        "Time since the worker started.",
+        vec![(String::new(), state.uptime().as_secs_f64())],
+    );
+    metric(
+        "threads",
+        "gauge",
+        "Worker threads.",
+        vec![(String::new(), threads.len() as f64)],
+    );
+    metric(
+        "busy_threads",
+        "gauge",
+        "Worker threads running a job.",
+        vec![(
+            String::new(),
+            threads
+                .iter()
+                .filter(|thread| thread.current_job.is_some())
+                .count() as f64,
+        )],
+    );
+    metric(
+        "accepting_jobs",
+        "gauge",
+        "1 unless the worker is draining or paused.",
+        vec![(
+            String::new(),
+            !(state.is_draining() || state.is_paused() || state.is_disk_paused() || state.is_quota_paused())
+                as u8 as f64,
+        )],
+    );
+    metric(
+        "consecutive_failures",
+        "gauge",
+        "Jobs failed in a row.",
+        vec![(String::new(), state.consecutive_failures() as f64)],
+    );
+    metric(
+        "jobs_total",
+        "counter",
+        "Jobs processed since the worker started.",
+        run_stats
+            .by_job_type()
+            .iter()
+            .flat_map(|(job_type, stats)| {
+                [
+                    (
+                        format!("{{job_type=\"{}\",outcome=\"success\"}}", job_type),
+                        stats.jobs_done as f64,
+                    ),
+                    (
+                        format!("{{job_type=\"{}\",outcome=\"failure\"}}", job_type),
+                        stats.failures as f64,
+                    ),
+                ]
+            })
+            .collect(),
+    );
+    metric(
+        "job_seconds_total",
+        "counter",
+        "Time spent on the successful jobs.",
+        run_stats
+            .by_job_type()
+            .iter()
+            .map(|(job_type, stats)| {
+                (
+                    format!("{{job_type=\"{}\"}}", job_type),
+                    stats.busy_time.as_secs_f64(),
+                )
+            })
+            .collect(),
+    );
+    metric(
+        "downloaded_bytes_total",
+        "counter",
+        "Bytes downloaded by the jobs.",
+        vec![(String::new(), run_stats.bytes_downloaded() as f64)],
+    );
+    metric(
+        "uploaded_bytes_total",
+        "counter",
+        "Bytes uploaded by the jobs.",
+        vec![(String::new(), run_stats.bytes_uploaded() as f64)],
+    );
+    metric(
+        "cache_bytes",
+        "gauge",
+        "Size of the cache directories.",
+        cache_usage
+            .iter()
+            .map(|usage| {
+                (
+                    format!("{{cache_dir=\"{}\"}}", usage.cache_dir),
+                    usage.size as f64,
+                )
+            })
+            .collect(),
+    );
+    metric(
+        "cache_entries",
+        "gauge",
+        "Tiles in the cache directories.",
+        cache_usage
+            .iter()
+            .map(|usage| {
+                (
+                    format!("{{cache_dir=\"{}\"}}", usage.cache_dir),
+                    usage.entries as f64,
+                )
+            })
+            .collect(),
+    );
+
+    metrics
+}
+
+/// node_exporter reads every `*.prom` file of its directory: the metrics are written to a `.tmp`
+/// file then renamed, so that it never reads a partially written file.
+fn write_metrics_file(path: &Path, metrics: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let temporary_path = path.with_extension("tmp");
+    write(&temporary_path, metrics)?;
+    rename(&temporary_path, path)?;
+
+    Ok(())
+}
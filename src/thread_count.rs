@@ -0,0 +1,84 @@
+use log::info;
+use std::{path::Path, thread::available_parallelism};
+use sysinfo::System;
+
+use crate::disk::available_space;
+
+/// Rough peak memory of a thread, set by the LiDAR and render steps of a 1 km tile
+const MEMORY_PER_THREAD: u64 = 2_500_000_000;
+/// Rough disk space of the job of a thread: the LAZ file, the LiDAR and render step outputs
+const DISK_PER_THREAD: u64 = 4_000_000_000;
+/// Share of the total RAM the threads are allowed to use
+const USABLE_MEMORY_RATIO: f64 = 0.8;
+/// Above this many times the RAM of the machine, a number of threads is refused without `--force`
+const UNSAFE_MEMORY_RATIO: u64 = 2;
+
+struct Hardware {
+    /// Cores not reserved for the OS, see `--reserved-cores`
+    cores: usize,
+    total_memory: u64,
+    free_disk: Option<u64>,
+}
+
+fn hardware(reserved_cores: usize, work_dir: &Path) -> Hardware {
+    let mut system = System::new();
+    system.refresh_memory();
+
+    let cores = available_parallelism().map(|cores| cores.get()).unwrap_or(1);
+
+    Hardware {
+        cores: cores.saturating_sub(reserved_cores).max(1),
+        total_memory: system.total_memory(),
+        free_disk: available_space(work_dir),
+    }
+}
+
+/// Number of threads fitting in the cores, RAM and free disk space of the machine, logging how it
+/// was chosen.
+pub fn auto_threads(reserved_cores: usize, work_dir: &Path) -> usize {
+    let hardware = hardware(reserved_cores, work_dir);
+
+    let by_memory = match hardware.total_memory {
+        0 => hardware.cores,
+        total_memory => (total_memory as f64 * USABLE_MEMORY_RATIO / MEMORY_PER_THREAD as f64) as usize,
+    };
+    let by_disk = hardware
+        .free_disk
+        .map_or(hardware.cores, |free_disk| (free_disk / DISK_PER_THREAD) as usize);
+
+    let threads = hardware.cores.min(by_memory).min(by_disk).max(1);
+
+    info!(
+        "--threads not given, using {}: {} cores, {} MB of RAM (room for {}), {} of free disk (room for {})",
+        threads,
+        hardware.cores,
+        hardware.total_memory / 1_000_000,
+        by_memory,
+        hardware
+            .free_disk
+            .map_or("unknown".to_string(), |free_disk| format!(
+                "{} MB",
+                free_disk / 1_000_000
+            )),
+        by_disk
+    );
+
+    threads
+}
+
+/// Refuse a number of threads needing much more RAM than the machine has, eg: 32 threads on 8 GB.
+pub fn check_threads(threads: usize, reserved_cores: usize, work_dir: &Path) -> Result<(), String> {
+    let hardware = hardware(reserved_cores, work_dir);
+    let needed_memory = threads as u64 * MEMORY_PER_THREAD;
+
+    if hardware.total_memory > 0 && needed_memory > hardware.total_memory * UNSAFE_MEMORY_RATIO {
+        return Err(format!(
+            "{} threads need about {} GB of RAM, this machine has {} GB. Use --force to start anyway",
+            threads,
+            needed_memory / 1_000_000_000,
+            hardware.total_memory / 1_000_000_000
+        ));
+    }
+
+    Ok(())
+}
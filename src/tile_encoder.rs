@@ -0,0 +1,762 @@
+use clap::ValueEnum;
+use flate2::{write::ZlibEncoder, Compression};
+use image::ColorType;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::HashMap,
+    fs::{metadata, remove_file, rename, File},
+    io::BufWriter,
+    io::Write,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::utils::{partial_path, write_atomically};
+
+/// Interlacing of the PNG web tiles.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PngInterlacing {
+    /// Smallest files
+    #[default]
+    None,
+    /// Adam7, a blurry tile is shown while the rest is downloaded, files about 10-20% larger
+    Adam7,
+}
+
+/// Format of the web tiles uploaded along the PNG tiles.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TileFormat {
+    /// Only the PNG tiles
+    #[default]
+    Png,
+    /// Experimental, an AVIF rendition of every tile is uploaded too. The PNG tiles are still
+    /// uploaded, the lower zoom levels are merged from them.
+    Avif,
+}
+
+/// How the web tiles of a Pyramid job are encoded, as preferred by the server.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct TileEncoding {
+    #[serde(default)]
+    pub interlacing: PngInterlacing,
+    #[serde(default)]
+    pub format: TileFormat,
+    /// AVIF quality, from 1 to 100
+    #[serde(default)]
+    pub avif_quality: Option<u8>,
+}
+
+/// AVIF quality when the server does not send one, the maps stay sharp at this level
+#[cfg(feature = "avif")]
+const AVIF_QUALITY: u8 = 70;
+/// Speed of the AV1 encoder, from 1 (smallest files) to 10 (fastest)
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 6;
+
+static DEFAULT_PNG_INTERLACING: OnceLock<PngInterlacing> = OnceLock::new();
+static PALETTE_QUANTIZATION: OnceLock<bool> = OnceLock::new();
+
+/// Above this number of colors an image is not line-art, eg: a hillshade, and is kept in true
+/// colors. Below, the few map colors and their antialiased edges fit in a 256 colors palette.
+const MAX_LINE_ART_COLORS: usize = 4096;
+/// Palette colors kept exact when an image has more than 256 colors
+const EXACT_PALETTE_COLORS: usize = 128;
+/// k-means iterations refining the median cut colors
+const PALETTE_REFINEMENTS: usize = 3;
+
+/// Interlacing of the tiles of the jobs without a preference of the server.
+pub fn set_default_png_interlacing(interlacing: PngInterlacing) {
+    let _ = DEFAULT_PNG_INTERLACING.set(interlacing);
+}
+
+/// Tile formats the worker can produce, sent when fetching jobs so that the server only asks for
+/// AVIF tiles to the workers built with the `avif` feature.
+pub fn supported_tile_formats() -> &'static [&'static str] {
+    if cfg!(feature = "avif") {
+        &["png", "avif"]
+    } else {
+        &["png"]
+    }
+}
+
+/// Write the line-art tiles and layers as 8 bits palette PNGs, enabled by `--palette-quantization`.
+/// Lossy, their antialiased edges can lose colors.
+pub fn set_palette_quantization(enabled: bool) {
+    let _ = PALETTE_QUANTIZATION.set(enabled);
+}
+
+fn palette_quantization_enabled() -> bool {
+    PALETTE_QUANTIZATION.get().copied().unwrap_or(false)
+}
+
+/// Adam7 passes: first column, first row, column step, row step
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Write a web tile to `path` as a PNG with only the critical chunks, interlaced as preferred by
+/// the server in `encoding`, or by `--png-interlacing` if None. Line-art tiles are written as
+/// palette PNGs with `--palette-quantization`.
+pub fn save_tile(
+    path: &Path,
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    encoding: Option<TileEncoding>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let interlacing = encoding
+        .map(|encoding| encoding.interlacing)
+        .unwrap_or_else(|| DEFAULT_PNG_INTERLACING.get().copied().unwrap_or_default());
+
+    let palette = match color {
+        ColorType::Rgb8 | ColorType::Rgba8 if palette_quantization_enabled() => {
+            quantize(pixels, color.bytes_per_pixel() as usize, MAX_LINE_ART_COLORS)
+        }
+        _ => None,
+    };
+
+    save_png(
+        path,
+        pixels,
+        width,
+        height,
+        color,
+        &PngOptions {
+            interlacing,
+            compression: Compression::default(),
+            palette: palette.as_ref(),
+        },
+    )
+}
+
+/// Rewrite the PNG at `path` as a palette PNG if it is line-art, eg: the cliffs, contours and
+/// vegetation layers of the render steps. Left as is otherwise.
+pub fn quantize_png_in_place(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !palette_quantization_enabled() {
+        return Ok(());
+    }
+
+    let image = image::open(path)?.to_rgba8();
+
+    let Some(palette) = quantize(image.as_raw(), 4, MAX_LINE_ART_COLORS) else {
+        return Ok(());
+    };
+
+    write_atomically(path, |partial_path| {
+        save_png(
+            partial_path,
+            image.as_raw(),
+            image.width(),
+            image.height(),
+            ColorType::Rgba8,
+            &PngOptions {
+                interlacing: PngInterlacing::None,
+                compression: Compression::default(),
+                palette: Some(&palette),
+            },
+        )
+    })
+}
+
+/// Recompress the PNG at `path` without loss, with the best zlib level and a filter search, and as
+/// a palette PNG if it has at most 256 colors, eg: the full maps uploaded by the render steps. The
+/// RGB of the fully transparent pixels aside, the pixels are the same. Kept as is if the
+/// recompressed file is not smaller. Returns the bytes saved.
+pub fn optimize_png_in_place(path: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+    let image = image::open(path)?;
+    let color = image.color();
+
+    let palette = match color {
+        ColorType::Rgb8 | ColorType::Rgba8 => {
+            quantize(image.as_bytes(), color.bytes_per_pixel() as usize, 256)
+        }
+        _ => None,
+    };
+
+    let optimized_path = partial_path(path);
+
+    let result = save_png(
+        &optimized_path,
+        image.as_bytes(),
+        image.width(),
+        image.height(),
+        color,
+        &PngOptions {
+            interlacing: PngInterlacing::None,
+            compression: Compression::best(),
+            palette: palette.as_ref(),
+        },
+    )
+    .and_then(|_| {
+        let original_size = metadata(path)?.len();
+        let optimized_size = metadata(&optimized_path)?.len();
+
+        if optimized_size >= original_size {
+            remove_file(&optimized_path)?;
+            return Ok(0);
+        }
+
+        rename(&optimized_path, path)?;
+        Ok(original_size - optimized_size)
+    });
+
+    if result.is_err() {
+        let _ = remove_file(&optimized_path);
+    }
+
+    result
+}
+
+/// How `save_png` writes a PNG.
+struct PngOptions<'a> {
+    interlacing: PngInterlacing,
+    compression: Compression,
+    /// Written as a palette PNG if given
+    palette: Option<&'a Palette>,
+}
+
+/// Write a PNG with only the critical chunks.
+fn save_png(
+    path: &Path,
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    options: &PngOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let png_color = match color {
+        ColorType::L8 => png::ColorType::Grayscale,
+        ColorType::La8 => png::ColorType::GrayscaleAlpha,
+        ColorType::Rgb8 => png::ColorType::Rgb,
+        ColorType::Rgba8 => png::ColorType::Rgba,
+        // 16 bits tiles are not produced by cassini, the image crate handles them as before
+        _ => return Ok(image::save_buffer(path, pixels, width, height, color)?),
+    };
+
+    let mut info = png::Info::with_size(width, height);
+    info.bit_depth = png::BitDepth::Eight;
+    info.interlaced = options.interlacing == PngInterlacing::Adam7;
+
+    // The PNG specification recommends no filter for the palette images
+    let (pixels, bytes_per_pixel, filters) = match options.palette {
+        Some(palette) => {
+            info.color_type = png::ColorType::Indexed;
+            info.palette = Some(Cow::Owned(
+                palette
+                    .colors
+                    .iter()
+                    .flat_map(|color| &color[..3])
+                    .copied()
+                    .collect(),
+            ));
+
+            if palette.colors.iter().any(|color| color[3] < 255) {
+                info.trns = Some(Cow::Owned(palette.colors.iter().map(|color| color[3]).collect()));
+            }
+
+            (palette.indices.as_slice(), 1, 0..=0)
+        }
+        None => {
+            info.color_type = png_color;
+            (pixels, color.bytes_per_pixel() as usize, 0..=4)
+        }
+    };
+
+    let mut writer = png::Encoder::with_info(BufWriter::new(File::create(path)?), info)?.write_header()?;
+
+    let (width, height) = (width as usize, height as usize);
+
+    let passes: &[(usize, usize, usize, usize)] = match options.interlacing {
+        PngInterlacing::None => &[(0, 0, 1, 1)],
+        PngInterlacing::Adam7 => &ADAM7_PASSES,
+    };
+
+    let mut filtered = Vec::with_capacity(pixels.len() + height * passes.len());
+
+    for &(first_column, first_row, column_step, row_step) in passes {
+        let pass_width = width.saturating_sub(first_column).div_ceil(column_step);
+
+        if pass_width == 0 {
+            continue;
+        }
+
+        let mut previous_row = vec![0; pass_width * bytes_per_pixel];
+
+        for y in (first_row..height).step_by(row_step) {
+            let row: Vec<u8> = (first_column..width)
+                .step_by(column_step)
+                .flat_map(|x| {
+                    let offset = (y * width + x) * bytes_per_pixel;
+                    &pixels[offset..offset + bytes_per_pixel]
+                })
+                .copied()
+                .collect();
+
+            filter_row(
+                &row,
+                &previous_row,
+                bytes_per_pixel,
+                filters.clone(),
+                &mut filtered,
+            );
+            previous_row = row;
+        }
+    }
+
+    let mut compressor = ZlibEncoder::new(Vec::new(), options.compression);
+    compressor.write_all(&filtered)?;
+
+    writer.write_chunk(png::chunk::IDAT, &compressor.finish()?)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Palette of a line-art image, with the palette index of every pixel.
+struct Palette {
+    /// RGBA colors, at most 256
+    colors: Vec<[u8; 4]>,
+    indices: Vec<u8>,
+}
+
+/// Palette of the RGB or RGBA `pixels`, exact up to 256 colors. Up to `max_colors`, the most
+/// frequent colors, ie: the map colors, are kept exact and a median cut of the other colors, like
+/// pngquant, gives the rest of the palette for the antialiased edges. None above.
+fn quantize(pixels: &[u8], bytes_per_pixel: usize, max_colors: usize) -> Option<Palette> {
+    // All the fully transparent pixels, outside of the map, are the same color
+    let rgba = |pixel: &[u8]| match pixel.get(3).copied().unwrap_or(255) {
+        0 => [0; 4],
+        alpha => [pixel[0], pixel[1], pixel[2], alpha],
+    };
+
+    let mut counts: HashMap<[u8; 4], usize> = HashMap::new();
+
+    for pixel in pixels.chunks_exact(bytes_per_pixel) {
+        *counts.entry(rgba(pixel)).or_default() += 1;
+
+        if counts.len() > max_colors {
+            return None;
+        }
+    }
+
+    // Most frequent first, the order of the equally frequent ones is deterministic
+    let mut distinct_colors: Vec<([u8; 4], usize)> = counts.into_iter().collect();
+    distinct_colors.sort_unstable_by_key(|(color, count)| (Reverse(*count), *color));
+
+    let mut colors: Vec<[u8; 4]> = distinct_colors
+        .iter()
+        .take(if distinct_colors.len() <= 256 {
+            256
+        } else {
+            EXACT_PALETTE_COLORS
+        })
+        .map(|(color, _)| *color)
+        .collect();
+
+    if distinct_colors.len() > 256 {
+        let exact_colors = colors.len();
+        let other_colors = &distinct_colors[exact_colors..];
+        colors.extend(median_cut(other_colors.to_vec(), 256 - exact_colors));
+
+        // Move the median cut colors to the average of the colors closest to them, as k-means
+        for _ in 0..PALETTE_REFINEMENTS {
+            let mut sums = vec![([0; 4], 0); colors.len()];
+
+            for (color, count) in other_colors {
+                let (sum, total) = &mut sums[nearest_color_index(&colors, color) as usize];
+
+                for channel in 0..4 {
+                    sum[channel] += color[channel] as usize * count;
+                }
+
+                *total += count;
+            }
+
+            for (color, (sum, total)) in colors.iter_mut().zip(sums).skip(exact_colors) {
+                if total > 0 {
+                    *color = sum.map(|sum| ((sum + total / 2) / total) as u8);
+                }
+            }
+        }
+    }
+
+    let index_of: HashMap<[u8; 4], u8> = distinct_colors
+        .iter()
+        .map(|(color, _)| (*color, nearest_color_index(&colors, color)))
+        .collect();
+
+    let indices = pixels
+        .chunks_exact(bytes_per_pixel)
+        .map(|pixel| index_of[&rgba(pixel)])
+        .collect();
+
+    Some(Palette { colors, indices })
+}
+
+/// Reduce the `colors`, with their pixel counts, to `palette_size` colors: the group of colors with
+/// the widest channel range is split at its median until there are enough groups, and each group
+/// gives the average of its colors weighted by their counts.
+fn median_cut(colors: Vec<([u8; 4], usize)>, palette_size: usize) -> Vec<[u8; 4]> {
+    let mut groups = vec![colors];
+
+    while groups.len() < palette_size {
+        let widest = groups
+            .iter()
+            .enumerate()
+            .filter(|(_, group)| group.len() > 1)
+            .map(|(index, group)| {
+                let (channel, range) = (0..4)
+                    .map(|channel| {
+                        let values = group.iter().map(|(color, _)| color[channel]);
+                        let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                        // Like in the nearest color search, the alpha differences weigh more
+                        (channel, range as u32 * if channel == 3 { 2 } else { 1 })
+                    })
+                    .max_by_key(|(_, range)| *range)
+                    .unwrap_or((0, 0));
+
+                (index, channel, range)
+            })
+            .max_by_key(|(_, _, range)| *range);
+
+        let Some((index, channel, _)) = widest else {
+            break;
+        };
+
+        let mut group = groups.swap_remove(index);
+        group.sort_unstable_by_key(|(color, _)| color[channel]);
+        let upper_half = group.split_off(group.len() / 2);
+        groups.push(group);
+        groups.push(upper_half);
+    }
+
+    groups
+        .iter()
+        .map(|group| {
+            let total: usize = group.iter().map(|(_, count)| count).sum();
+            let mut average = [0; 4];
+
+            for (channel, value) in average.iter_mut().enumerate() {
+                let sum: usize = group
+                    .iter()
+                    .map(|(color, count)| color[channel] as usize * count)
+                    .sum();
+                *value = ((sum + total / 2) / total.max(1)) as u8;
+            }
+
+            average
+        })
+        .collect()
+}
+
+/// Index of the palette color closest to `color`. The alpha differences weigh more, a half
+/// transparent edge mapped to an opaque color is more visible than a slightly different hue.
+fn nearest_color_index(palette: &[[u8; 4]], color: &[u8; 4]) -> u8 {
+    let distance = |other: &[u8; 4]| {
+        (0..4)
+            .map(|channel| {
+                let difference = color[channel] as i32 - other[channel] as i32;
+                difference * difference * if channel == 3 { 4 } else { 1 }
+            })
+            .sum::<i32>()
+    };
+
+    (0..palette.len())
+        .min_by_key(|index| distance(&palette[*index]))
+        .unwrap_or_default() as u8
+}
+
+/// Append the filter type and the filtered bytes of a scanline, with the filter of `filters` giving
+/// the smallest sum of absolute values, the heuristic recommended by the PNG specification.
+fn filter_row(
+    row: &[u8],
+    previous_row: &[u8],
+    bytes_per_pixel: usize,
+    filters: RangeInclusive<u8>,
+    output: &mut Vec<u8>,
+) {
+    let mut best: Option<(u64, u8, Vec<u8>)> = None;
+
+    for filter in filters {
+        let filtered: Vec<u8> = (0..row.len())
+            .map(|i| {
+                let left = if i >= bytes_per_pixel {
+                    row[i - bytes_per_pixel]
+                } else {
+                    0
+                };
+                let up = previous_row[i];
+                let up_left = if i >= bytes_per_pixel {
+                    previous_row[i - bytes_per_pixel]
+                } else {
+                    0
+                };
+
+                let prediction = match filter {
+                    0 => 0,
+                    1 => left,
+                    2 => up,
+                    3 => ((left as u16 + up as u16) / 2) as u8,
+                    _ => paeth(left, up, up_left),
+                };
+
+                row[i].wrapping_sub(prediction)
+            })
+            .collect();
+
+        let cost = filtered
+            .iter()
+            .map(|byte| (*byte as i8).unsigned_abs() as u64)
+            .sum();
+
+        if best.as_ref().is_none_or(|(best_cost, _, _)| cost < *best_cost) {
+            best = Some((cost, filter, filtered));
+        }
+    }
+
+    if let Some((_, filter, filtered)) = best {
+        output.push(filter);
+        output.extend(filtered);
+    }
+}
+
+/// Encode the PNG tile at `tile_path` to an AVIF file next to it if the server asked for the AVIF
+/// format in `encoding`, and return its path.
+pub fn write_avif_rendition(
+    tile_path: &Path,
+    encoding: Option<TileEncoding>,
+) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let Some(encoding) = encoding.filter(|encoding| encoding.format == TileFormat::Avif) else {
+        return Ok(None);
+    };
+
+    let avif_path = tile_path.with_extension("avif");
+    write_atomically(&avif_path, |partial_path| {
+        encode_avif(tile_path, partial_path, encoding)
+    })?;
+
+    Ok(Some(avif_path))
+}
+
+#[cfg(feature = "avif")]
+fn encode_avif(
+    tile_path: &Path,
+    output_path: &Path,
+    encoding: TileEncoding,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tile = image::open(tile_path)?.to_rgba8();
+    let quality = encoding.avif_quality.unwrap_or(AVIF_QUALITY).clamp(1, 100);
+    let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+        BufWriter::new(File::create(output_path)?),
+        AVIF_SPEED,
+        quality,
+    );
+    tile.write_with_encoder(encoder)?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "avif"))]
+fn encode_avif(
+    _tile_path: &Path,
+    _output_path: &Path,
+    _encoding: TileEncoding,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("AVIF tiles were requested, but the worker was built without the avif feature".into())
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let left_distance = (estimate - left as i16).abs();
+    let up_distance = (estimate - up as i16).abs();
+    let up_left_distance = (estimate - up_left as i16).abs();
+
+    if left_distance <= up_distance && left_distance <= up_left_distance {
+        left
+    } else if up_distance <= up_left_distance {
+        up
+    } else {
+        up_left
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [(u32, u32); 3] = [(1, 1), (3, 5), (9, 9)];
+
+    /// Pixels with every channel value varying, fully transparent ones included.
+    fn noise(width: u32, height: u32, bytes_per_pixel: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..width as usize * height as usize * bytes_per_pixel)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    /// Write the pixels with `save_png`, and decode them back with the png and image crates.
+    fn round_trip(
+        name: &str,
+        pixels: &[u8],
+        (width, height): (u32, u32),
+        color: ColorType,
+        interlacing: PngInterlacing,
+        palette: Option<&Palette>,
+    ) -> image::RgbaImage {
+        let path = std::env::temp_dir().join(format!("tile-encoder-{}-{}.png", name, std::process::id()));
+        let options = PngOptions {
+            interlacing,
+            compression: Compression::default(),
+            palette,
+        };
+        save_png(&path, pixels, width, height, color, &options).unwrap();
+
+        let reader = png::Decoder::new(File::open(&path).unwrap()).read_info().unwrap();
+        assert_eq!(reader.info().interlaced, interlacing == PngInterlacing::Adam7);
+        assert_eq!(
+            reader.info().color_type == png::ColorType::Indexed,
+            palette.is_some()
+        );
+
+        let image = image::open(&path).unwrap().to_rgba8();
+        remove_file(&path).unwrap();
+
+        assert_eq!(image.dimensions(), (width, height));
+        image
+    }
+
+    fn to_rgba(pixels: &[u8], color: ColorType) -> Vec<u8> {
+        let bytes_per_pixel = color.bytes_per_pixel() as usize;
+
+        pixels
+            .chunks_exact(bytes_per_pixel)
+            .flat_map(|pixel| match bytes_per_pixel {
+                1 => [pixel[0], pixel[0], pixel[0], 255],
+                2 => [pixel[0], pixel[0], pixel[0], pixel[1]],
+                3 => [pixel[0], pixel[1], pixel[2], 255],
+                _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn true_color_round_trip() {
+        for color in [ColorType::L8, ColorType::La8, ColorType::Rgb8, ColorType::Rgba8] {
+            for interlacing in [PngInterlacing::None, PngInterlacing::Adam7] {
+                for size in SIZES {
+                    let pixels = noise(
+                        size.0,
+                        size.1,
+                        color.bytes_per_pixel() as usize,
+                        size.0 * 7 + size.1,
+                    );
+                    let name = format!("true-color-{:?}-{:?}-{}x{}", color, interlacing, size.0, size.1);
+
+                    let image = round_trip(&name, &pixels, size, color, interlacing, None);
+
+                    assert_eq!(image.as_raw(), &to_rgba(&pixels, color), "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn palette_round_trip_is_exact_up_to_256_colors() {
+        for color in [ColorType::Rgb8, ColorType::Rgba8] {
+            for interlacing in [PngInterlacing::None, PngInterlacing::Adam7] {
+                for size in SIZES {
+                    let bytes_per_pixel = color.bytes_per_pixel() as usize;
+                    let mut pixels = noise(size.0, size.1, bytes_per_pixel, size.0 + size.1);
+
+                    // Half transparent and fully transparent pixels for the tRNS chunk
+                    if color == ColorType::Rgba8 {
+                        let last_alpha = pixels.len() - 1;
+                        pixels[3] = 0;
+                        pixels[last_alpha] = 128;
+                    }
+
+                    let palette = quantize(&pixels, bytes_per_pixel, 256).unwrap();
+                    let name = format!("palette-{:?}-{:?}-{}x{}", color, interlacing, size.0, size.1);
+
+                    let image = round_trip(&name, &pixels, size, color, interlacing, Some(&palette));
+
+                    // The RGB of the fully transparent pixels is not kept
+                    let mut expected = to_rgba(&pixels, color);
+                    for pixel in expected.chunks_exact_mut(4).filter(|pixel| pixel[3] == 0) {
+                        pixel.copy_from_slice(&[0; 4]);
+                    }
+
+                    assert_eq!(image.as_raw(), &expected, "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn palette_round_trip_above_256_colors() {
+        let size = (40, 40);
+        let mut pixels = noise(size.0, size.1, 4, 1);
+
+        // A map color covering most of the image, the rest is antialiased edges
+        for pixel in pixels.chunks_exact_mut(4).take(1_000) {
+            pixel.copy_from_slice(&[10, 120, 30, 255]);
+        }
+
+        let palette = quantize(&pixels, 4, MAX_LINE_ART_COLORS).unwrap();
+        assert!(palette.colors.len() <= 256);
+
+        for interlacing in [PngInterlacing::None, PngInterlacing::Adam7] {
+            let name = format!("palette-above-256-{:?}", interlacing);
+            let image = round_trip(
+                &name,
+                &pixels,
+                size,
+                ColorType::Rgba8,
+                interlacing,
+                Some(&palette),
+            );
+
+            for (decoded, pixel) in image.pixels().zip(pixels.chunks_exact(4)) {
+                let expected = palette.colors[nearest_color_index(
+                    &palette.colors,
+                    &[pixel[0], pixel[1], pixel[2], pixel[3]],
+                ) as usize];
+
+                assert!(pixel[3] == 0 || decoded.0 == expected, "{}", name);
+            }
+
+            assert_eq!(image.get_pixel(0, 0).0, [10, 120, 30, 255]);
+        }
+    }
+
+    #[test]
+    fn quantize_refuses_images_above_max_colors() {
+        let pixels = noise(64, 64, 3, 2);
+
+        assert!(quantize(&pixels, 3, 256).is_none());
+    }
+
+    #[test]
+    fn paeth_picks_the_closest_neighbor() {
+        assert_eq!(paeth(10, 20, 10), 20);
+        assert_eq!(paeth(20, 10, 10), 20);
+        assert_eq!(paeth(10, 10, 20), 10);
+        assert_eq!(paeth(0, 0, 0), 0);
+    }
+}
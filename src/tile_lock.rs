@@ -0,0 +1,52 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::Path,
+};
+
+/// Advisory lock on a tile of a cache directory, so that several worker processes can share it.
+/// Writers take it exclusively to download and publish the tile, readers take it shared while
+/// they use the files so that the tile is not evicted under them. The lock is released when
+/// dropped, or when the process dies, so it never goes stale like a flag file would.
+///
+/// Lock files are never removed: a process could be waiting on the file being removed while
+/// another one locks a new file at the same path.
+pub struct TileLock {
+    _file: File,
+}
+
+fn open_lock_file(cache_dir: &Path, tile_id: &str) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(cache_dir.join(format!("{}.lock", tile_id)))
+}
+
+impl TileLock {
+    /// Wait until no other thread or process uses the tile, and lock it.
+    pub fn exclusive(cache_dir: &Path, tile_id: &str) -> std::io::Result<Self> {
+        let file = open_lock_file(cache_dir, tile_id)?;
+        file.lock()?;
+
+        Ok(TileLock { _file: file })
+    }
+
+    /// Wait until no other thread or process writes the tile, and lock it for reading.
+    pub fn shared(cache_dir: &Path, tile_id: &str) -> std::io::Result<Self> {
+        let file = open_lock_file(cache_dir, tile_id)?;
+        file.lock_shared()?;
+
+        Ok(TileLock { _file: file })
+    }
+
+    /// Lock the tile if no other thread or process uses it, None otherwise.
+    pub fn try_exclusive(cache_dir: &Path, tile_id: &str) -> std::io::Result<Option<Self>> {
+        let file = open_lock_file(cache_dir, tile_id)?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Some(TileLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(error)) => Err(error),
+        }
+    }
+}
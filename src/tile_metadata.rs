@@ -0,0 +1,102 @@
+use cassini::get_extent_from_lidar_dir_path;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_to_string, write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{raster::raster_info, region::RegionProfile, toolchain::toolchain};
+
+/// Name of the metadata file in the LiDAR step directory of a tile.
+pub const TILE_METADATA_FILE_NAME: &str = "tile.json";
+
+/// What the steps after the LiDAR step need to know about a tile. Written along the legacy
+/// `extent.txt` and `pipeline.json`, which cassini still reads.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TileMetadata {
+    pub tile_id: String,
+    /// min_x, min_y, max_x, max_y of the LiDAR points, smaller than the tile on the borders
+    pub extent: (i64, i64, i64, i64),
+    pub epsg: u32,
+    /// Size of a pixel of the DEM, in meters. None if it could not be read.
+    pub resolution: Option<f64>,
+    pub producer: Producer,
+    /// Unix timestamp of the end of the LiDAR step, in seconds
+    pub created_at: u64,
+}
+
+/// Versions of the software which produced a tile.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Producer {
+    pub worker: String,
+    pub cassini: String,
+    pub gdal: Option<String>,
+}
+
+/// Write the metadata of a tile at the end of its LiDAR step, from the outputs of cassini.
+pub fn write_tile_metadata(
+    lidar_step_tile_dir_path: &Path,
+    tile_id: &str,
+    region: &RegionProfile,
+) -> Result<TileMetadata, Box<dyn std::error::Error>> {
+    let resolution = raster_info(&lidar_step_tile_dir_path.join("dem.tif"))
+        .and_then(|info| info.pointer("/geoTransform/1").and_then(|size| size.as_f64()));
+
+    let metadata = TileMetadata {
+        tile_id: tile_id.to_string(),
+        extent: get_extent_from_lidar_dir_path(&lidar_step_tile_dir_path.to_path_buf()),
+        epsg: region.epsg,
+        resolution,
+        producer: Producer {
+            worker: env!("CARGO_PKG_VERSION").to_string(),
+            cassini: toolchain().cassini.to_string(),
+            gdal: toolchain().gdal.clone(),
+        },
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+
+    write(
+        lidar_step_tile_dir_path.join(TILE_METADATA_FILE_NAME),
+        serde_json::to_string_pretty(&metadata)?,
+    )?;
+
+    Ok(metadata)
+}
+
+/// Metadata of a tile from its LiDAR step directory. None for the LiDAR steps produced before
+/// `tile.json` existed.
+pub fn read_tile_metadata(
+    lidar_step_tile_dir_path: &Path,
+) -> Result<Option<TileMetadata>, Box<dyn std::error::Error>> {
+    let path = lidar_step_tile_dir_path.join(TILE_METADATA_FILE_NAME);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let metadata = serde_json::from_str(&read_to_string(&path)?)
+        .map_err(|error| format!("Invalid {}: {}", path.display(), error))?;
+
+    Ok(Some(metadata))
+}
+
+/// Extent of the LiDAR points of a tile, from `tile.json` or the legacy `extent.txt`.
+pub fn tile_extent(
+    lidar_step_tile_dir_path: &PathBuf,
+) -> Result<(i64, i64, i64, i64), Box<dyn std::error::Error>> {
+    if let Some(metadata) = read_tile_metadata(lidar_step_tile_dir_path)? {
+        return Ok(metadata.extent);
+    }
+
+    if !lidar_step_tile_dir_path.join("extent.txt").exists() {
+        return Err(format!(
+            "No {} nor extent.txt in {}",
+            TILE_METADATA_FILE_NAME,
+            lidar_step_tile_dir_path.display()
+        )
+        .into());
+    }
+
+    Ok(get_extent_from_lidar_dir_path(lidar_step_tile_dir_path))
+}
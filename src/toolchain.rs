@@ -0,0 +1,91 @@
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::OnceLock;
+
+use crate::subprocess::{run_subprocess, subprocess_command};
+
+/// Version of the cassini dependency, keep in sync with Cargo.toml
+pub const CASSINI_VERSION: &str = "0.12.5";
+
+/// Versions of the libraries and tools the outputs depend on, eg: clips differ between GDAL 3.4
+/// and 3.8. The GDAL and PROJ ones are the first lines the tools print, None if not installed.
+#[derive(Serialize, Clone, Debug)]
+pub struct Toolchain {
+    pub cassini: &'static str,
+    /// eg: "GDAL 3.8.4, released 2024/02/08"
+    pub gdal: Option<String>,
+    pub ogr2ogr: Option<String>,
+    /// eg: "Rel. 9.4.0, March 1st, 2024"
+    pub proj: Option<String>,
+}
+
+static TOOLCHAIN: OnceLock<Toolchain> = OnceLock::new();
+
+/// Versions of the toolchain, detected on the first call.
+pub fn toolchain() -> &'static Toolchain {
+    TOOLCHAIN.get_or_init(|| Toolchain {
+        cassini: CASSINI_VERSION,
+        gdal: tool_version("gdalinfo", &["--version"]),
+        ogr2ogr: tool_version("ogr2ogr", &["--version"]),
+        // Without arguments, proj prints its release then its usage
+        proj: tool_version("proj", &[]),
+    })
+}
+
+/// Detect and log the versions of the toolchain, warning about the missing tools.
+pub fn log_toolchain() {
+    let toolchain = toolchain();
+
+    info!("Toolchain: {}", toolchain.summary());
+
+    for (program, version) in [
+        ("gdalinfo", &toolchain.gdal),
+        ("ogr2ogr", &toolchain.ogr2ogr),
+        ("proj", &toolchain.proj),
+    ] {
+        if version.is_none() {
+            warn!("Could not get the version of {}, is it installed?", program);
+        }
+    }
+}
+
+impl Toolchain {
+    /// eg: "cassini/0.12.5 gdal/3.8.4 ogr2ogr/3.8.4 proj/9.4.0", sent in the
+    /// `X-Mapant-Toolchain` header of the job results.
+    pub fn summary(&self) -> String {
+        let mut summary = format!("cassini/{}", self.cassini);
+
+        for (name, version) in [
+            ("gdal", &self.gdal),
+            ("ogr2ogr", &self.ogr2ogr),
+            ("proj", &self.proj),
+        ] {
+            let version = version.as_deref().and_then(version_number).unwrap_or("unknown");
+            summary.push_str(&format!(" {}/{}", name, version));
+        }
+
+        summary
+    }
+}
+
+/// First word starting with a digit, eg: "3.8.4" for "GDAL 3.8.4, released 2024/02/08".
+fn version_number(version: &str) -> Option<&str> {
+    version
+        .split_whitespace()
+        .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))
+        .map(|word| word.trim_end_matches(','))
+}
+
+fn tool_version(program: &str, args: &[&str]) -> Option<String> {
+    let output = run_subprocess(subprocess_command(program).args(args), program).ok()?;
+
+    [output.stdout, output.stderr]
+        .iter()
+        .filter_map(|output| {
+            String::from_utf8_lossy(output)
+                .lines()
+                .next()
+                .map(|line| line.trim().to_string())
+        })
+        .find(|line| !line.is_empty())
+}
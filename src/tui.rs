@@ -0,0 +1,214 @@
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    widgets::{Block, Paragraph, Row, Table},
+    Frame,
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::state::{ThreadStats, WorkerState};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+/// Number of log lines kept for the logs panel
+pub const LOG_LINES_CAPACITY: usize = 200;
+
+static TUI_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Last log lines, written by the logger and displayed by the dashboard.
+pub type LogLines = Arc<Mutex<VecDeque<String>>>;
+
+pub fn push_log_line(log_lines: &LogLines, line: String) {
+    let mut log_lines = log_lines.lock().unwrap();
+
+    if log_lines.len() >= LOG_LINES_CAPACITY {
+        log_lines.pop_front();
+    }
+
+    log_lines.push_back(line);
+}
+
+/// Show a live dashboard of the worker threads in the terminal. Pressing `q` or `Ctrl+C` requests
+/// a graceful shutdown, as SIGINT would.
+pub fn spawn_tui(
+    state: Arc<WorkerState>,
+    log_lines: LogLines,
+    shutdown_requested: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut terminal = ratatui::init();
+    TUI_ACTIVE.store(true, Ordering::SeqCst);
+
+    thread::Builder::new().name("tui".to_string()).spawn(move || {
+        let mut dashboard = Dashboard::new();
+
+        while TUI_ACTIVE.load(Ordering::SeqCst) {
+            dashboard.refresh(&state);
+
+            if terminal
+                .draw(|frame| dashboard.draw(frame, &state, &log_lines))
+                .is_err()
+            {
+                break;
+            }
+
+            if quit_requested() {
+                shutdown_requested.store(true, Ordering::SeqCst);
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Give the terminal back to the shell. No-op when the dashboard is not shown.
+pub fn restore_terminal() {
+    if TUI_ACTIVE.swap(false, Ordering::SeqCst) {
+        ratatui::restore();
+    }
+}
+
+/// Wait for a key press until the next refresh, return true for `q` or `Ctrl+C`.
+fn quit_requested() -> bool {
+    let deadline = Instant::now() + REFRESH_INTERVAL;
+
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        match event::poll(timeout) {
+            Ok(true) => {}
+            _ => return false,
+        }
+
+        if let Ok(Event::Key(key)) = event::read() {
+            let is_ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+
+            if key.kind == KeyEventKind::Press && (key.code == KeyCode::Char('q') || is_ctrl_c) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+struct Dashboard {
+    threads: Vec<ThreadStats>,
+    /// Transfer speed of every thread in bytes per second since the previous refresh
+    speeds: Vec<f64>,
+    refreshed_at: Instant,
+}
+
+impl Dashboard {
+    fn new() -> Self {
+        Dashboard {
+            threads: vec![],
+            speeds: vec![],
+            refreshed_at: Instant::now(),
+        }
+    }
+
+    fn refresh(&mut self, state: &WorkerState) {
+        let threads = state.thread_stats();
+        let elapsed = self.refreshed_at.elapsed().as_secs_f64();
+
+        self.speeds = threads
+            .iter()
+            .enumerate()
+            .map(|(index, thread)| {
+                let previous_bytes = self
+                    .threads
+                    .get(index)
+                    .map(|previous| previous.transferred_bytes)
+                    .unwrap_or(0);
+
+                // The counter is reset when a new job starts
+                let bytes = if thread.transferred_bytes >= previous_bytes {
+                    thread.transferred_bytes - previous_bytes
+                } else {
+                    thread.transferred_bytes
+                };
+
+                if elapsed > 0.0 {
+                    bytes as f64 / elapsed
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        self.threads = threads;
+        self.refreshed_at = Instant::now();
+    }
+
+    fn draw(&self, frame: &mut Frame, state: &WorkerState, log_lines: &LogLines) {
+        let summary = state.run_summary();
+        let summary_height = summary.lines().count() as u16 + 2;
+
+        let [threads_area, summary_area, logs_area] = Layout::vertical([
+            Constraint::Length(self.threads.len() as u16 + 3),
+            Constraint::Length(summary_height),
+            Constraint::Min(3),
+        ])
+        .areas(frame.area());
+
+        let rows = self.threads.iter().zip(&self.speeds).map(|(thread, speed)| {
+            Row::new(vec![
+                thread.name.clone(),
+                thread.current_job.clone().unwrap_or_else(|| "idle".to_string()),
+                thread.stage.clone().unwrap_or_default(),
+                thread
+                    .job_elapsed
+                    .map(|elapsed| format!("{:.0?}", elapsed))
+                    .unwrap_or_default(),
+                match thread.transfer_progress {
+                    Some(progress) => format!("{:.1} MB/s {:.0}%", speed / 1_000_000.0, progress * 100.0),
+                    None => format!("{:.1} MB/s", speed / 1_000_000.0),
+                },
+                thread.jobs_done.to_string(),
+            ])
+        });
+
+        let threads_table = Table::new(
+            rows,
+            [
+                Constraint::Length(14),
+                Constraint::Min(20),
+                Constraint::Length(28),
+                Constraint::Length(10),
+                Constraint::Length(16),
+                Constraint::Length(10),
+            ],
+        )
+        .header(
+            Row::new(vec!["Thread", "Job", "Stage", "Elapsed", "Transfer", "Jobs done"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::bordered().title(format!(" Worker threads - {} - q to quit ", state.summary())));
+
+        frame.render_widget(threads_table, threads_area);
+        frame.render_widget(
+            Paragraph::new(summary).block(Block::bordered().title(" Summary ")),
+            summary_area,
+        );
+
+        let visible_lines = logs_area.height.saturating_sub(2) as usize;
+        let log_lines = log_lines.lock().unwrap();
+
+        let logs: Vec<&str> = log_lines
+            .iter()
+            .skip(log_lines.len().saturating_sub(visible_lines))
+            .map(|line| line.as_str())
+            .collect();
+
+        frame.render_widget(
+            Paragraph::new(logs.join("\n")).block(Block::bordered().title(" Logs ")),
+            logs_area,
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use reqwest::blocking::{multipart, Response};
+use std::{
+    fmt,
+    fs::{metadata, File},
+    io::{Read, Seek, SeekFrom},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{artifacts::Artifact, progress::ProgressReader, utils::sha256_of_file};
+
+/// Room left in every request for the multipart boundaries and part headers
+const FORM_OVERHEAD: u64 = 64 * 1024;
+
+/// Smallest request body size tried when the API rejects requests as too large without
+/// advertising its maximum
+const MIN_BODY_SIZE: u64 = 1024 * 1024;
+
+/// Maximum request body size of the API, 0 if it did not advertise one
+static MAX_BODY_SIZE: AtomicU64 = AtomicU64::new(0);
+
+/// The API rejected a request of the given body size with a 413 status.
+#[derive(Debug)]
+pub struct BodyTooLarge(pub u64);
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request body of {} bytes too large for the API", self.0)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// Remember the maximum request body size advertised by the API in the `X-Mapant-Max-Body-Size`
+/// header of its responses, eg: when a reverse proxy in front of it caps the request sizes. Kept
+/// until the API advertises another one.
+pub fn record_max_body_size(response: &Response) {
+    let max_body_size = response
+        .headers()
+        .get("X-Mapant-Max-Body-Size")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+
+    if let Some(max_body_size) = max_body_size {
+        MAX_BODY_SIZE.store(max_body_size, Ordering::SeqCst);
+    }
+}
+
+pub fn max_body_size() -> Option<u64> {
+    Some(MAX_BODY_SIZE.load(Ordering::SeqCst)).filter(|size| *size > 0)
+}
+
+/// Maximum body size to plan the upload with again after the API rejected a request of
+/// `rejected_size` bytes as too large, half of it. None below 1 MB, the upload can't be split
+/// further.
+pub fn smaller_body_size(rejected_size: u64) -> Option<u64> {
+    Some(rejected_size / 2).filter(|size| *size >= MIN_BODY_SIZE)
+}
+
+/// A whole artifact, or the bytes `offset..offset + length` of an artifact too large for a request.
+pub struct UploadPiece<'a> {
+    pub artifact: &'a Artifact,
+    pub offset: u64,
+    pub length: u64,
+    /// Size of the whole artifact
+    pub size: u64,
+}
+
+impl UploadPiece<'_> {
+    pub fn is_whole(&self) -> bool {
+        self.length == self.size
+    }
+
+    /// Line of the piece in the content hash of a request signed with HMAC, see `CONTENT_HASH_HEADER`.
+    pub fn content_hash_line(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let sha256 = match &self.artifact.sha256 {
+            Some(sha256) => sha256.clone(),
+            None => sha256_of_file(&self.artifact.path)?,
+        };
+
+        Ok(format!(
+            "{} {} {} {} {}\n",
+            self.artifact.role, self.artifact.name, sha256, self.offset, self.length
+        ))
+    }
+
+    /// Multipart part streaming the bytes of the piece, with its length.
+    pub fn part(&self) -> Result<(multipart::Part, u64), Box<dyn std::error::Error>> {
+        let mut file = File::open(&self.artifact.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+
+        let label = if self.is_whole() {
+            format!("Upload of {}", self.artifact.name)
+        } else {
+            format!("Upload of {} from byte {}", self.artifact.name, self.offset)
+        };
+
+        let reader = ProgressReader::new(file.take(self.length), label, Some(self.length));
+
+        Ok((
+            multipart::Part::reader_with_length(reader, self.length),
+            self.length,
+        ))
+    }
+}
+
+/// Group the artifacts into the requests of an upload to the API, each below the maximum body
+/// size. A single request with all the artifacts if they fit or if there is no maximum. The
+/// artifacts larger than a request are split into parts, each in its own request.
+pub fn plan_upload_requests(
+    artifacts: &[Artifact],
+    max_body_size: Option<u64>,
+) -> Result<Vec<Vec<UploadPiece<'_>>>, Box<dyn std::error::Error>> {
+    let mut sizes = vec![];
+
+    for artifact in artifacts {
+        sizes.push(metadata(&artifact.path)?.len());
+    }
+
+    let whole_pieces = || {
+        artifacts.iter().zip(&sizes).map(|(artifact, size)| UploadPiece {
+            artifact,
+            offset: 0,
+            length: *size,
+            size: *size,
+        })
+    };
+
+    let Some(max_body_size) = max_body_size else {
+        return Ok(vec![whole_pieces().collect()]);
+    };
+
+    let budget = max_body_size.saturating_sub(FORM_OVERHEAD);
+
+    if budget == 0 {
+        return Err(format!(
+            "A maximum body size of {} bytes is too small for uploads",
+            max_body_size
+        )
+        .into());
+    }
+
+    if sizes.iter().sum::<u64>() <= budget {
+        return Ok(vec![whole_pieces().collect()]);
+    }
+
+    let mut requests: Vec<Vec<UploadPiece>> = vec![];
+    let mut batch: Vec<UploadPiece> = vec![];
+    let mut batch_size = 0;
+
+    for piece in whole_pieces() {
+        if piece.size > budget {
+            for offset in (0..piece.size).step_by(budget as usize) {
+                requests.push(vec![UploadPiece {
+                    offset,
+                    length: budget.min(piece.size - offset),
+                    ..piece
+                }]);
+            }
+
+            continue;
+        }
+
+        if batch_size + piece.size > budget {
+            requests.push(std::mem::take(&mut batch));
+            batch_size = 0;
+        }
+
+        batch_size += piece.size;
+        batch.push(piece);
+    }
+
+    if !batch.is_empty() {
+        requests.push(batch);
+    }
+
+    Ok(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    /// Artifact of `size` bytes in the temporary directory, removed when dropped.
+    struct TestArtifact(Artifact);
+
+    impl TestArtifact {
+        fn new(name: &str, size: usize) -> Self {
+            let path = std::env::temp_dir().join(format!("upload-parts-{}-{}", name, std::process::id()));
+            write(&path, vec![0; size]).unwrap();
+
+            TestArtifact(Artifact {
+                name: name.to_string(),
+                role: "file".to_string(),
+                path,
+                mime: "application/octet-stream".to_string(),
+                sha256: None,
+            })
+        }
+    }
+
+    impl Drop for TestArtifact {
+        fn drop(&mut self) {
+            let _ = remove_file(&self.0.path);
+        }
+    }
+
+    fn artifacts(test_artifacts: &[TestArtifact]) -> Vec<Artifact> {
+        test_artifacts.iter().map(|artifact| artifact.0.clone()).collect()
+    }
+
+    /// Name, offset and length of the pieces of every request.
+    fn plan(artifacts: &[Artifact], max_body_size: Option<u64>) -> Vec<Vec<(String, u64, u64)>> {
+        plan_upload_requests(artifacts, max_body_size)
+            .unwrap()
+            .iter()
+            .map(|pieces| {
+                pieces
+                    .iter()
+                    .map(|piece| (piece.artifact.name.clone(), piece.offset, piece.length))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn piece(name: &str, offset: u64, length: u64) -> (String, u64, u64) {
+        (name.to_string(), offset, length)
+    }
+
+    #[test]
+    fn single_request_without_maximum() {
+        let test_artifacts = [
+            TestArtifact::new("no-max-a", 3_000),
+            TestArtifact::new("no-max-b", 5_000),
+        ];
+
+        assert_eq!(
+            plan(&artifacts(&test_artifacts), None),
+            vec![vec![piece("no-max-a", 0, 3_000), piece("no-max-b", 0, 5_000)]]
+        );
+    }
+
+    #[test]
+    fn single_request_when_everything_fits() {
+        let test_artifacts = [
+            TestArtifact::new("fits-a", 3_000),
+            TestArtifact::new("fits-b", 5_000),
+        ];
+
+        assert_eq!(
+            plan(&artifacts(&test_artifacts), Some(FORM_OVERHEAD + 8_000)),
+            vec![vec![piece("fits-a", 0, 3_000), piece("fits-b", 0, 5_000)]]
+        );
+    }
+
+    #[test]
+    fn artifacts_batched_below_the_budget() {
+        let test_artifacts = [
+            TestArtifact::new("batch-a", 3_000),
+            TestArtifact::new("batch-b", 5_000),
+            TestArtifact::new("batch-c", 2_000),
+            TestArtifact::new("batch-d", 4_000),
+        ];
+
+        assert_eq!(
+            plan(&artifacts(&test_artifacts), Some(FORM_OVERHEAD + 8_000)),
+            vec![
+                vec![piece("batch-a", 0, 3_000), piece("batch-b", 0, 5_000)],
+                vec![piece("batch-c", 0, 2_000), piece("batch-d", 0, 4_000)],
+            ]
+        );
+    }
+
+    #[test]
+    fn artifact_larger_than_the_budget_split_on_budget_boundaries() {
+        let test_artifacts = [
+            TestArtifact::new("split-a", 1_000),
+            TestArtifact::new("split-b", 10_000),
+        ];
+
+        assert_eq!(
+            plan(&artifacts(&test_artifacts), Some(FORM_OVERHEAD + 4_000)),
+            vec![
+                vec![piece("split-b", 0, 4_000)],
+                vec![piece("split-b", 4_000, 4_000)],
+                vec![piece("split-b", 8_000, 2_000)],
+                vec![piece("split-a", 0, 1_000)],
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_artifact_uploaded_whole() {
+        let test_artifacts = [
+            TestArtifact::new("empty-a", 0),
+            TestArtifact::new("empty-b", 10_000),
+        ];
+
+        assert_eq!(
+            plan(&artifacts(&test_artifacts), Some(FORM_OVERHEAD + 5_000)),
+            vec![
+                vec![piece("empty-b", 0, 5_000)],
+                vec![piece("empty-b", 5_000, 5_000)],
+                vec![piece("empty-a", 0, 0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn maximum_below_the_form_overhead_refused() {
+        let test_artifacts = [TestArtifact::new("overhead", 1_000)];
+
+        assert!(plan_upload_requests(&artifacts(&test_artifacts), Some(FORM_OVERHEAD)).is_err());
+        assert!(plan_upload_requests(&artifacts(&test_artifacts), Some(FORM_OVERHEAD / 2)).is_err());
+    }
+
+    #[test]
+    fn smaller_body_size_halves_down_to_the_minimum() {
+        assert_eq!(smaller_body_size(8 * MIN_BODY_SIZE), Some(4 * MIN_BODY_SIZE));
+        assert_eq!(smaller_body_size(2 * MIN_BODY_SIZE), Some(MIN_BODY_SIZE));
+        assert_eq!(smaller_body_size(2 * MIN_BODY_SIZE - 1), None);
+        assert_eq!(smaller_body_size(0), None);
+    }
+}
@@ -1,156 +1,987 @@
-use log::{error, info};
-use reqwest::blocking::{multipart, Client};
-use reqwest::header::HeaderMap;
-use std::fs::{read, File};
-use std::time::Instant;
-use std::{io::copy, path::PathBuf};
+use log::{debug, error, info, warn};
+use reqwest::blocking::{multipart, Body, Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{metadata, read_dir, read_to_string, remove_file, rename, write, File};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use std::{
+    fmt,
+    io::{copy, sink, ErrorKind, Read},
+    path::{Component, Path, PathBuf},
+    process, thread,
+};
 use tar::Archive;
 use tar::Builder;
+use uuid::Uuid;
 use xz2::read::XzDecoder;
 use xz2::write::XzEncoder;
 
+use crate::{
+    artifacts::{Artifact, UploadManifest},
+    auth::{ApiAuth, CONTENT_HASH_HEADER},
+    circuit::CircuitOpen,
+    compression::multipart_body,
+    outbox::{is_gateway_error, keep_json_if_unreachable, keep_upload_if_unreachable, ApiUnavailable},
+    partial_download::PartialDownload,
+    progress::ProgressReader,
+    raster::raster_sizes,
+    rate_limit::HostPermit,
+    response::describe_error_response,
+    s3::{presign_url, S3Credentials},
+    stage_budget::{StagePermit, DOWNLOAD_STAGE, UPLOAD_STAGE},
+    state::current_abort_reason,
+    toolchain::toolchain,
+    upload_parts::{max_body_size, plan_upload_requests, smaller_body_size, BodyTooLarge, UploadPiece},
+};
+
+const PRESIGNED_URL_EXPIRATION_SECONDS: u64 = 3600;
+
+/// Bytes transferred and time spent transferring them, to compute the throughputs of a job.
+#[derive(Clone, Copy, Default)]
+pub struct TransferStats {
+    pub bytes_downloaded: u64,
+    pub download_time: Duration,
+    pub bytes_uploaded: u64,
+    pub upload_time: Duration,
+}
+
+impl TransferStats {
+    pub fn download_bytes_per_second(&self) -> Option<u64> {
+        bytes_per_second(self.bytes_downloaded, self.download_time)
+    }
+
+    pub fn upload_bytes_per_second(&self) -> Option<u64> {
+        bytes_per_second(self.bytes_uploaded, self.upload_time)
+    }
+}
+
+fn bytes_per_second(bytes: u64, duration: Duration) -> Option<u64> {
+    if bytes == 0 || duration.is_zero() {
+        return None;
+    }
+
+    Some((bytes as f64 / duration.as_secs_f64()) as u64)
+}
+
+thread_local! {
+    /// Transfers of the current thread since the last call to `take_transfer_stats`, used to
+    /// record the transfer volumes and throughputs of a job.
+    static TRANSFER_STATS: Cell<TransferStats> = Cell::new(TransferStats::default());
+}
+
+/// File listing the files of an archive with their size and SHA-256, added by
+/// `compress_directory` and checked by `decompress_archive`
+pub const ARCHIVE_MANIFEST_FILE_NAME: &str = "archive-manifest.json";
+
+/// Manifest embedded in an archive, by path relative to the archived directory.
+type ArchiveManifest = BTreeMap<String, ArchivedFile>;
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedFile {
+    size: u64,
+    sha256: String,
+}
+
+/// Error of the unpacked archives not matching their manifest, eg: a truncated download.
+#[derive(Debug)]
+pub struct ArchiveMismatch(String);
+
+impl fmt::Display for ArchiveMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ArchiveMismatch {}
+
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+const UPLOAD_ATTEMPTS: u32 = 3;
+/// Maximum size of a download in bytes, 0 for no limit. See `set_max_download_size`.
+static MAX_DOWNLOAD_SIZE: AtomicU64 = AtomicU64::new(0);
+/// Makes the `partial_path` of the files written at the same time by a process unique
+static PARTIAL_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// xz preset of the archives, from 0 to 9. Compressing needs about 94 MB at 6 and 10 MB at 1.
+static ARCHIVE_COMPRESSION_LEVEL: AtomicU32 = AtomicU32::new(6);
+
+/// Refuse downloads above `max_size` bytes, so that a bogus response cannot fill the disk.
+pub fn set_max_download_size(max_size: u64) {
+    MAX_DOWNLOAD_SIZE.store(max_size, Ordering::Relaxed);
+}
+
+/// Compress the archives with another xz preset, lower ones need less memory.
+pub fn set_archive_compression_level(level: u32) {
+    ARCHIVE_COMPRESSION_LEVEL.store(level.min(9), Ordering::Relaxed);
+}
+
+pub fn max_download_size() -> u64 {
+    MAX_DOWNLOAD_SIZE.load(Ordering::Relaxed)
+}
+
+/// Upload throughput of the worker in bytes per second, averaged over the recent uploads. 0 until
+/// a large enough upload is done.
+static UPLOAD_SPEED_ESTIMATE: AtomicU64 = AtomicU64::new(0);
+/// Uploads smaller than this are dominated by latency, they do not update the estimate
+const MIN_UPLOAD_SIZE_FOR_ESTIMATE: u64 = 1_000_000;
+
+/// Transfers of the current thread since the previous call, then reset them.
+pub fn take_transfer_stats() -> TransferStats {
+    TRANSFER_STATS.with(|stats| stats.replace(TransferStats::default()))
+}
+
+pub fn add_download(bytes: u64, duration: Duration) {
+    TRANSFER_STATS.with(|stats| {
+        let mut current = stats.get();
+        current.bytes_downloaded += bytes;
+        current.download_time += duration;
+        stats.set(current);
+    });
+}
+
+pub fn add_upload(bytes: u64, duration: Duration) {
+    TRANSFER_STATS.with(|stats| {
+        let mut current = stats.get();
+        current.bytes_uploaded += bytes;
+        current.upload_time += duration;
+        stats.set(current);
+    });
+
+    if bytes < MIN_UPLOAD_SIZE_FOR_ESTIMATE {
+        return;
+    }
+
+    if let Some(speed) = bytes_per_second(bytes, duration) {
+        let previous = UPLOAD_SPEED_ESTIMATE.load(Ordering::SeqCst);
+
+        let estimate = if previous == 0 {
+            speed
+        } else {
+            (previous * 3 + speed) / 4
+        };
+
+        UPLOAD_SPEED_ESTIMATE.store(estimate, Ordering::SeqCst);
+    }
+}
+
+/// Add the throughputs measured during the current job to a request sending its results, so that
+/// the scheduler can dispatch the jobs according to the links of the workers:
+/// - `X-Mapant-Download-Bytes-Per-Second`: effective download throughput of the job so far
+/// - `X-Mapant-Upload-Bytes-Per-Second`: recent upload throughput of the worker
+/// - `X-Mapant-Upload-Eta-Seconds`: estimated duration of the upload of `upload_size` bytes
+/// - `X-Mapant-Raster-Bytes` and `X-Mapant-Raster-Uncompressed-Bytes`: size of the rasters written
+///   by the job, and what they would weigh uncompressed
+///
+/// along with the `X-Mapant-Toolchain` the results were produced with.
+fn with_transfer_report(request: RequestBuilder, upload_size: u64) -> RequestBuilder {
+    let mut request = request.header("X-Mapant-Toolchain", toolchain().summary());
+    let job_transfers = TRANSFER_STATS.with(|stats| stats.get());
+
+    if let Some(download_speed) = job_transfers.download_bytes_per_second() {
+        request = request.header("X-Mapant-Download-Bytes-Per-Second", download_speed.to_string());
+    }
+
+    let upload_speed = UPLOAD_SPEED_ESTIMATE.load(Ordering::SeqCst);
+
+    if upload_speed > 0 {
+        request = request
+            .header("X-Mapant-Upload-Bytes-Per-Second", upload_speed.to_string())
+            .header(
+                "X-Mapant-Upload-Eta-Seconds",
+                (upload_size / upload_speed).to_string(),
+            );
+    }
+
+    let raster_sizes = raster_sizes();
+
+    if raster_sizes.compressed_bytes > 0 {
+        request = request
+            .header("X-Mapant-Raster-Bytes", raster_sizes.compressed_bytes.to_string())
+            .header(
+                "X-Mapant-Raster-Uncompressed-Bytes",
+                raster_sizes.uncompressed_bytes.to_string(),
+            );
+    }
+
+    request
+}
+
+/// Where a job's artifacts should be stored, when not going through the API server.
+/// Artifacts are identified by a key, eg: `lidar-steps/1000_6000.tar.xz`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "data")]
+pub enum StorageHints {
+    /// Presigned urls by artifact key. Artifacts missing from the maps go through the API.
+    Presigned {
+        #[serde(default)]
+        uploads: HashMap<String, String>,
+        #[serde(default)]
+        downloads: HashMap<String, String>,
+    },
+    S3(S3Credentials),
+}
+
+impl StorageHints {
+    fn upload_url(&self, key: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        match self {
+            StorageHints::Presigned { uploads, .. } => Ok(uploads.get(key).cloned()),
+            StorageHints::S3(credentials) => Ok(Some(presign_url(
+                credentials,
+                "PUT",
+                key,
+                PRESIGNED_URL_EXPIRATION_SECONDS,
+            )?)),
+        }
+    }
+
+    fn download_url(&self, key: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        match self {
+            StorageHints::Presigned { downloads, .. } => Ok(downloads.get(key).cloned()),
+            StorageHints::S3(credentials) => Ok(Some(presign_url(
+                credentials,
+                "GET",
+                key,
+                PRESIGNED_URL_EXPIRATION_SECONDS,
+            )?)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StoredArtifact {
+    form_part_name: String,
+    key: String,
+    size: u64,
+    sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+struct StoredArtifacts {
+    stored_artifacts: Vec<StoredArtifact>,
+}
+
+/// Download `file_url` into `file_path`. The number of bytes received is checked against the
+/// Content-Length header and `expected_size`, the download being retried on mismatch.
 pub fn download_file(
     client: &Client,
     file_url: &str,
     file_path: &PathBuf,
-    headers: Option<HeaderMap>,
+    auth: Option<&ApiAuth>,
+    expected_size: Option<u64>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let request = match headers {
-        Some(h) => client.get(file_url).headers(h),
-        None => client.get(file_url),
+    for attempt in 1..=DOWNLOAD_ATTEMPTS {
+        match try_download_file(client, file_url, file_path, auth, expected_size) {
+            Ok(()) => return Ok(()),
+            Err(DownloadError::Incomplete(reason)) if attempt < DOWNLOAD_ATTEMPTS => {
+                warn!("Incomplete download, attempt {}: {}", attempt, reason);
+                thread::sleep(Duration::from_secs(2u64.pow(attempt)));
+            }
+            Err(DownloadError::Incomplete(reason)) => {
+                let _ = remove_file(file_path);
+                return Err(reason.into());
+            }
+            Err(DownloadError::Failed(error)) => {
+                let _ = remove_file(file_path);
+                return Err(error);
+            }
+        }
+    }
+
+    unreachable!()
+}
+
+enum DownloadError {
+    /// Fewer or more bytes than announced were received, worth retrying
+    Incomplete(String),
+    Failed(Box<dyn std::error::Error>),
+}
+
+impl<E: Into<Box<dyn std::error::Error>>> From<E> for DownloadError {
+    fn from(error: E) -> Self {
+        DownloadError::Failed(error.into())
+    }
+}
+
+fn try_download_file(
+    client: &Client,
+    file_url: &str,
+    file_path: &Path,
+    auth: Option<&ApiAuth>,
+    expected_size: Option<u64>,
+) -> Result<(), DownloadError> {
+    let start = Instant::now();
+
+    let mut partial_download = PartialDownload::open(file_path, file_url);
+    let request = if partial_download.offset() > 0 {
+        partial_download.resume_request(client.get(file_url))
+    } else {
+        with_if_none_match(client.get(file_url), file_path)
     };
+    // Held until the file is downloaded, the API is not limited
+    let _stage_permit = auth.is_none().then(|| StagePermit::acquire(DOWNLOAD_STAGE));
+    let _permit = auth.is_none().then(|| HostPermit::acquire(file_url));
 
-    let mut response = request.send()?;
+    let response = match auth {
+        Some(auth) => auth.send(request)?,
+        None => {
+            // Presigned urls carry their credentials in the query string, not logged
+            debug!("GET {}", file_url.split('?').next().unwrap_or(file_url));
+            request.send()?
+        }
+    };
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        info!("{} unchanged, reusing the local copy", file_path.display());
+        return Ok(());
+    }
+
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        partial_download.discard();
+        return Err(DownloadError::Incomplete(format!(
+            "Could not resume the download of {}",
+            file_path.display()
+        )));
+    }
 
     if !response.status().is_success() {
+        partial_download.discard();
         error!(
-            "Failed to download file with url {}. Status: {}. Response: {:?}",
-            file_url,
-            response.status(),
-            response.text()
+            "Failed to download file with url {}. {}",
+            file_url.split('?').next().unwrap_or(file_url),
+            describe_error_response(response)
         );
 
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Failed to download file.",
-        )));
+        return Err(std::io::Error::other("Failed to download file.").into());
+    }
+
+    let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+    let resumed_bytes = if resumed { partial_download.offset() } else { 0 };
+    let total = response.content_length().map(|length| length + resumed_bytes);
+    let max_size = max_download_size();
+
+    if let Some(size) = total
+        .or(expected_size)
+        .filter(|size| max_size > 0 && *size > max_size)
+    {
+        partial_download.discard();
+        return Err(format!(
+            "Refusing to download {} MB from {}, above the maximum download size",
+            size / 1_000_000,
+            file_url.split('?').next().unwrap_or(file_url)
+        )
+        .into());
+    }
+
+    let file_name = file_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let etag = take_etag(&response, file_path);
+    let remaining = response.content_length();
+    let reader = ProgressReader::new(response, format!("Download of {}", file_name), remaining);
+
+    let mut file = partial_download.file(resumed, etag.clone())?;
+
+    let result = copy(&mut reader.take(read_limit(max_size, resumed_bytes)), &mut file);
+
+    let size = file.metadata()?.len();
+    add_download(size - resumed_bytes, start.elapsed());
+
+    // Connection cut before the end of the body, or the job aborted, eg: on shutdown. The received
+    // bytes are kept for the next attempt
+    if let Err(error) = result {
+        partial_download.keep(&file);
+
+        return match current_abort_reason() {
+            None => Err(DownloadError::Incomplete(format!("{}: {}", file_name, error))),
+            Some(_) => Err(error.into()),
+        };
+    }
+
+    if max_size > 0 && size > max_size {
+        partial_download.discard();
+        return Err(format!(
+            "Download of {} went beyond the maximum download size of {} MB",
+            file_name,
+            max_size / 1_000_000
+        )
+        .into());
+    }
+
+    for (expected, source) in [(total, "Content-Length"), (expected_size, "expected size")] {
+        if let Some(expected) = expected.filter(|expected| *expected != size) {
+            if size < expected {
+                partial_download.keep(&file);
+            } else {
+                partial_download.discard();
+            }
+
+            return Err(DownloadError::Incomplete(format!(
+                "{} bytes received for {}, {} of {} bytes",
+                size, file_name, source, expected
+            )));
+        }
+    }
+
+    drop(file);
+    partial_download.finish(file_path)?;
+    store_etag(file_path, etag);
+
+    Ok(())
+}
+
+/// Bytes to read from a response body resuming after `resumed_bytes`: one byte more than the rest of
+/// the maximum download size, to detect a response going beyond it, or no limit for a maximum of 0.
+fn read_limit(max_size: u64, resumed_bytes: u64) -> u64 {
+    if max_size == 0 {
+        u64::MAX
+    } else {
+        max_size.saturating_sub(resumed_bytes).saturating_add(1)
     }
+}
 
-    let mut file = File::create(file_path)?;
-    copy(&mut response, &mut file)?;
+fn etag_path(file_path: &Path) -> PathBuf {
+    let mut etag_path = file_path.as_os_str().to_owned();
+    etag_path.push(".etag");
 
-    return Ok(());
+    PathBuf::from(etag_path)
 }
 
-pub fn upload_file(
+/// Send the ETag stored with the local copy of `file_path`, if any, so that the server can answer
+/// 304 Not Modified instead of sending the same file again.
+pub fn with_if_none_match(request: RequestBuilder, file_path: &Path) -> RequestBuilder {
+    if !file_path.exists() {
+        return request;
+    }
+
+    match read_to_string(etag_path(file_path)) {
+        Ok(etag) => request.header(IF_NONE_MATCH, etag.trim()),
+        Err(_) => request,
+    }
+}
+
+/// ETag of a successful response about to overwrite `file_path`. The ETag of the previous copy is
+/// removed, it must only be stored back with `store_etag` once the new copy is complete.
+pub fn take_etag(response: &Response, file_path: &Path) -> Option<String> {
+    let _ = remove_file(etag_path(file_path));
+
+    if !response.status().is_success() {
+        return None;
+    }
+
+    response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(|etag| etag.to_string())
+}
+
+pub fn store_etag(file_path: &Path, etag: Option<String>) {
+    if let Some(etag) = etag {
+        if let Err(error) = write(etag_path(file_path), etag) {
+            warn!("Failed to store the ETag of {}: {}", file_path.display(), error);
+        }
+    }
+}
+
+/// Upload the artifacts of a manifest to the API in a multipart form, split if it exceeds the
+/// maximum body size of the API. Kept in the outbox if the API is unreachable.
+pub fn upload_files(
     client: &Client,
-    worker_id: &str,
-    token: &str,
+    auth: &ApiAuth,
     url: String,
     origin: &str,
-    file_name: String,
-    file_path: std::path::PathBuf,
-    mime_str: &str,
+    manifest: &UploadManifest,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Uploading file {}", &file_name);
-    let start = Instant::now();
+    upload_artifacts(client, auth, url, origin, manifest, None, "")
+}
 
-    let file = read(&file_path)?;
+/// Upload the artifacts to the API in a multipart form, or in several ones when they exceed the
+/// maximum body size it advertised. The requests of a split upload share an `X-Mapant-Upload-Id`
+/// and are numbered in `X-Mapant-Upload-Request`, eg: `2/5`, the parts of a split artifact give
+/// their position in `X-Mapant-Part-Offset` and the artifact size in `X-Mapant-Artifact-Size`.
+/// When the API rejects a request as too large without advertising its maximum, the upload is
+/// planned again with requests half that size.
+fn send_files(
+    client: &Client,
+    auth: &ApiAuth,
+    url: String,
+    origin: &str,
+    artifacts: &[Artifact],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut body_size_limit = max_body_size();
 
-    let part = multipart::Part::bytes(file)
-        .file_name(file_name.clone())
-        .mime_str(mime_str)?;
+    loop {
+        let error = match send_requests(client, auth, &url, origin, artifacts, body_size_limit) {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
 
-    let form = multipart::Form::new().part("file", part);
+        let Some(BodyTooLarge(rejected_size)) = error.downcast_ref::<BodyTooLarge>() else {
+            return Err(error);
+        };
 
-    let response = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}.{}", worker_id, token))
-        .header("Origin", origin)
-        .multipart(form)
-        .send()?;
+        if max_body_size().is_some_and(|size| size < *rejected_size) {
+            // The API advertised a smaller maximum with the rejection
+            body_size_limit = max_body_size();
+        } else if let Some(size) = smaller_body_size(*rejected_size) {
+            body_size_limit = Some(size);
+        } else {
+            return Err(error);
+        }
 
-    if response.status().is_success() {
-        let duration = start.elapsed();
+        warn!(
+            "{}, uploading again in requests of at most {} bytes",
+            error,
+            body_size_limit.unwrap_or_default()
+        );
+    }
+}
 
-        info!("File {} uploaded in {:.1?}", &file_name, duration);
-    } else {
-        error!(
-            "Failed to upload file {}: {} {}",
-            &file_name,
-            response.status(),
-            response.text()?
+fn send_requests(
+    client: &Client,
+    auth: &ApiAuth,
+    url: &str,
+    origin: &str,
+    artifacts: &[Artifact],
+    body_size_limit: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let requests = plan_upload_requests(artifacts, body_size_limit)?;
+
+    if let [pieces] = requests.as_slice() {
+        return send_form(client, auth, url, origin, pieces, HeaderMap::new());
+    }
+
+    let upload_id = Uuid::new_v4().to_string();
+
+    info!(
+        "Uploading files {} in {} requests of at most {} bytes",
+        artifacts
+            .iter()
+            .map(|artifact| artifact.name.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        requests.len(),
+        body_size_limit.unwrap_or_default()
+    );
+
+    for (index, pieces) in requests.iter().enumerate() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Mapant-Upload-Id", upload_id.parse()?);
+        headers.insert(
+            "X-Mapant-Upload-Request",
+            format!("{}/{}", index + 1, requests.len()).parse()?,
         );
+
+        if let Err(error) = send_form(client, auth, url, origin, pieces, headers) {
+            error!("Request {}/{} of the upload failed", index + 1, requests.len());
+            return Err(error);
+        }
     }
 
     Ok(())
 }
 
-pub fn upload_files(
+/// Send the pieces in a multipart form. Fails with `BodyTooLarge` when the API rejects it with a
+/// 413 status.
+fn send_form(
     client: &Client,
-    worker_id: &str,
-    token: &str,
-    url: String,
+    auth: &ApiAuth,
+    url: &str,
     origin: &str,
-    files: Vec<(String, String, PathBuf, String)>,
+    pieces: &[UploadPiece],
+    headers: HeaderMap,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let file_names = files
+    let file_names = pieces
         .iter()
-        .map(|file| file.0.clone())
-        .collect::<Vec<String>>()
+        .map(|piece| piece.artifact.name.as_str())
+        .collect::<Vec<_>>()
         .join(" ");
 
     info!("Uploading files {}", &file_names);
     let start = Instant::now();
 
     let mut form = multipart::Form::new();
+    let mut size: u64 = 0;
+    let mut content_hash = Sha256::new();
+
+    for piece in pieces {
+        let artifact = piece.artifact;
+        let (part, part_size) = piece.part()?;
+        size += part_size;
+        content_hash.update(piece.content_hash_line()?);
 
-    for (file_name, file_formpart_name, file_path, mime_str) in files {
-        let file = read(&file_path)?;
+        let mut headers = HeaderMap::new();
 
-        let part = multipart::Part::bytes(file)
-            .file_name(file_name.clone())
-            .mime_str(&mime_str)?;
+        if let Some(sha256) = &artifact.sha256 {
+            headers.insert("X-Mapant-Sha256", sha256.parse()?);
+        }
 
-        form = form.part(file_formpart_name, part);
+        if !piece.is_whole() {
+            headers.insert("X-Mapant-Part-Offset", piece.offset.to_string().parse()?);
+            headers.insert("X-Mapant-Artifact-Size", piece.size.to_string().parse()?);
+        }
+
+        form = form.part(
+            artifact.role.clone(),
+            part.file_name(artifact.name.clone())
+                .mime_str(&artifact.mime)?
+                .headers(headers),
+        );
     }
 
-    let response = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}.{}", worker_id, token))
-        .header("Origin", origin)
-        .multipart(form)
-        .send()?;
+    let (request, wire_size) = multipart_body(
+        client
+            .post(url)
+            .header("Origin", origin)
+            .header(CONTENT_HASH_HEADER, hex::encode(content_hash.finalize()))
+            .headers(headers),
+        form,
+        size,
+    )?;
+    let response = auth.send(with_transfer_report(request, wire_size))?;
 
     if response.status().is_success() {
         let duration = start.elapsed();
+        add_upload(wire_size, duration);
 
         info!("Files {} uploaded in {:.1?}", &file_names, duration);
+    } else if is_gateway_error(response.status()) {
+        return Err(ApiUnavailable(response.status()).into());
+    } else if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return Err(BodyTooLarge(size).into());
     } else {
-        error!(
-            "Failed to upload files {}: {} {}",
+        return Err(format!(
+            "Failed to upload files {}. {}",
             &file_names,
-            response.status(),
-            response.text()?
-        );
+            describe_error_response(response)
+        )
+        .into());
     }
 
     Ok(())
 }
 
-pub fn compress_directory(
-    input_dir: &PathBuf,
-    output_file: &PathBuf,
+/// Post a JSON body to the API, eg: the results of a job. Kept in the outbox if the API is
+/// unreachable.
+pub fn post_json<T: Serialize>(
+    client: &Client,
+    auth: &ApiAuth,
+    url: &str,
+    origin: &str,
+    body: &T,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let tar_xz_file = File::create(output_file)?;
-    let xz_encoder = XzEncoder::new(tar_xz_file, 6);
-    let mut tar_builder = Builder::new(xz_encoder);
-    tar_builder.append_dir_all(".", input_dir)?;
-    tar_builder.finish()?;
+    let body = serde_json::to_value(body)?;
+    let result = send_json(client, auth, url, origin, &body);
+    keep_json_if_unreachable(result, url, origin, &body)
+}
+
+pub fn send_json(
+    client: &Client,
+    auth: &ApiAuth,
+    url: &str,
+    origin: &str,
+    body: &serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = auth.send(
+        client
+            .post(url)
+            .header("Origin", origin)
+            .header("X-Mapant-Toolchain", toolchain().summary())
+            .json(body),
+    )?;
+
+    if is_gateway_error(response.status()) {
+        return Err(ApiUnavailable(response.status()).into());
+    }
+
+    if !response.status().is_success() {
+        return Err(format!("Failed to post to {}. {}", url, describe_error_response(response)).into());
+    }
+
+    Ok(())
+}
+
+/// Tell the API that a job will not be completed by this worker, so that it can be requeued
+/// without waiting for its lease to expire.
+pub fn notify_job_abandoned(
+    client: &Client,
+    auth: &ApiAuth,
+    base_url: &str,
+    job_payload: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!("{}/api/map-generation/abandoned-jobs", base_url);
+
+    let response = auth.send(
+        client
+            .post(url)
+            .header("Origin", base_url)
+            .header("Content-Type", "application/json")
+            .body(job_payload.to_string()),
+    )?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "Failed to notify abandoned job. {}",
+            describe_error_response(response)
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Tell the API that a job failed on every attempt, with the error of each attempt, so that it is
+/// not handed back to this worker as if its lease had expired. Kept in the outbox if the API is
+/// unreachable.
+pub fn notify_job_failed(
+    client: &Client,
+    auth: &ApiAuth,
+    base_url: &str,
+    job_payload: &str,
+    errors: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!("{}/api/map-generation/failed-jobs", base_url);
+    let job = serde_json::from_str(job_payload).unwrap_or(serde_json::Value::from(job_payload));
+
+    post_json(
+        client,
+        auth,
+        &url,
+        base_url,
+        &serde_json::json!({ "job": job, "attempts": errors.len(), "errors": errors }),
+    )
+}
+
+/// Acknowledge the cancellation of a job by the server, once the worker stopped working on it.
+pub fn notify_job_cancelled(
+    client: &Client,
+    auth: &ApiAuth,
+    base_url: &str,
+    job_payload: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!("{}/api/map-generation/cancelled-jobs", base_url);
+
+    let response = auth.send(
+        client
+            .post(url)
+            .header("Origin", base_url)
+            .header("Content-Type", "application/json")
+            .body(job_payload.to_string()),
+    )?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "Failed to acknowledge cancelled job. {}",
+            describe_error_response(response)
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Temporary path next to `path`, unique to the process and the call, eg:
+/// `dem.partial-1234-5.tif`. The extension is kept for the writers guessing the format from it.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let suffix = format!(
+        "partial-{}-{}",
+        process::id(),
+        PARTIAL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+
+    match path.extension() {
+        Some(extension) => path.with_extension(format!("{}.{}", suffix, extension.to_string_lossy())),
+        None => path.with_extension(suffix),
+    }
+}
+
+/// Let `write` write the file to a `partial_path`, renamed to `path` once written and checked, so
+/// that a crash or another process never sees a partially written file under its final name.
+pub fn write_atomically<T>(
+    path: &Path,
+    write: impl FnOnce(&Path) -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let partial_path = partial_path(path);
+
+    let result = write(&partial_path).and_then(|value| {
+        rename(&partial_path, path)?;
+        Ok(value)
+    });
+
+    if result.is_err() {
+        let _ = remove_file(&partial_path);
+    }
+
+    result
+}
+
+/// Compress `input_dir` into a `.tar.xz` archive, then check that the archive can be read back,
+/// so that a truncated archive never gets uploaded.
+pub fn compress_directory(input_dir: &PathBuf, output_file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    write_atomically(output_file, |partial_path| {
+        let tar_xz_file = File::create(partial_path)?;
+        let xz_encoder = XzEncoder::new(tar_xz_file, ARCHIVE_COMPRESSION_LEVEL.load(Ordering::Relaxed));
+        let mut tar_builder = Builder::new(xz_encoder);
+        tar_builder.append_dir_all(".", input_dir)?;
+
+        let manifest = serde_json::to_vec_pretty(&archive_manifest(input_dir)?)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar_builder.append_data(&mut header, ARCHIVE_MANIFEST_FILE_NAME, manifest.as_slice())?;
+
+        tar_builder.into_inner()?.finish()?.sync_all()?;
+
+        verify_archive(partial_path, input_dir)
+    })
+}
+
+/// Size and SHA-256 of the files of a directory, by path relative to it.
+fn archive_manifest(dir: &Path) -> Result<ArchiveManifest, Box<dyn std::error::Error>> {
+    let mut files: BTreeMap<PathBuf, u64> = BTreeMap::new();
+    list_files(dir, Path::new(""), &mut files)?;
+    files.remove(Path::new(ARCHIVE_MANIFEST_FILE_NAME));
+
+    files
+        .into_iter()
+        .map(|(path, size)| {
+            let sha256 = sha256_of_file(&dir.join(&path))?;
+            Ok((path.to_string_lossy().to_string(), ArchivedFile { size, sha256 }))
+        })
+        .collect()
+}
+
+/// Check the files unpacked in `dir` against the manifest embedded in the archive. The archives
+/// made by older workers have none and are not checked.
+fn verify_unpacked_archive(archive_file: &Path, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest: ArchiveManifest = match read_to_string(dir.join(ARCHIVE_MANIFEST_FILE_NAME)) {
+        Ok(manifest) => serde_json::from_str(&manifest)?,
+        Err(_) => {
+            debug!("Archive {} has no manifest, not verified", archive_file.display());
+            return Ok(());
+        }
+    };
+
+    let mismatching_files = manifest
+        .iter()
+        .filter(|(path, expected)| {
+            let path = dir.join(path);
+            metadata(&path).map(|metadata| metadata.len()).ok() != Some(expected.size)
+                || sha256_of_file(&path).ok().as_ref() != Some(&expected.sha256)
+        })
+        .map(|(path, _)| path.as_str())
+        .collect::<Vec<_>>();
+
+    if !mismatching_files.is_empty() {
+        return Err(ArchiveMismatch(format!(
+            "Files unpacked from {} do not match its manifest, missing or corrupted: {}",
+            archive_file.display(),
+            mismatching_files.join(", ")
+        ))
+        .into());
+    }
+
+    debug!(
+        "Files unpacked from {} verified, {} files",
+        archive_file.display(),
+        manifest.len()
+    );
+
+    Ok(())
+}
+
+/// Decompress the whole archive and compare its files and their sizes to the ones of `source_dir`.
+fn verify_archive(archive_file: &Path, source_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut archive = Archive::new(XzDecoder::new(File::open(archive_file)?));
+    let mut archived_files: BTreeMap<PathBuf, u64> = BTreeMap::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path: PathBuf = entry
+            .path()?
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect();
+
+        let size = copy(&mut entry, &mut sink())?;
+        archived_files.insert(path, size);
+    }
+
+    let mut source_files: BTreeMap<PathBuf, u64> = BTreeMap::new();
+    list_files(source_dir, Path::new(""), &mut source_files)?;
+
+    for files in [&mut archived_files, &mut source_files] {
+        files.remove(Path::new(ARCHIVE_MANIFEST_FILE_NAME));
+    }
+
+    if archived_files != source_files {
+        let missing_files = source_files
+            .iter()
+            .filter(|(path, size)| archived_files.get(*path) != Some(*size))
+            .map(|(path, _)| path.display().to_string())
+            .collect::<Vec<_>>();
+
+        return Err(format!(
+            "Archive {} does not match {}, missing or truncated files: {}",
+            archive_file.display(),
+            source_dir.display(),
+            missing_files.join(", ")
+        )
+        .into());
+    }
+
+    debug!(
+        "Archive {} verified, {} files",
+        archive_file.display(),
+        archived_files.len()
+    );
+
+    Ok(())
+}
+
+/// Sizes of the files of `dir`, recursively, by path relative to the root directory.
+fn list_files(
+    dir: &Path,
+    relative_dir: &Path,
+    files: &mut BTreeMap<PathBuf, u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let relative_path = relative_dir.join(entry.file_name());
+        // Symlinks are followed, as when building the archive
+        let metadata = metadata(entry.path())?;
+
+        if metadata.is_dir() {
+            list_files(&entry.path(), &relative_path, files)?;
+        } else {
+            files.insert(relative_path, metadata.len());
+        }
+    }
 
     Ok(())
 }
 
+/// Hex encoded SHA-256 of a file.
+pub fn sha256_of_file(file_path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    copy(&mut File::open(file_path)?, &mut hasher)?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Unpack a `.tar.xz` archive and verify its files against its manifest. Fails with
+/// `ArchiveMismatch` when the archive is corrupted or truncated, so that it is downloaded again.
 pub fn decompress_archive(
     input_file: &PathBuf,
     output_dir: &PathBuf,
@@ -158,7 +989,248 @@ pub fn decompress_archive(
     let tar_xz_file = File::open(input_file)?;
     let bz_decoder = XzDecoder::new(tar_xz_file);
     let mut archive = Archive::new(bz_decoder);
-    archive.unpack(output_dir)?;
+
+    if let Err(error) = archive.unpack(output_dir) {
+        // Not the archive's fault
+        if matches!(error.kind(), ErrorKind::StorageFull | ErrorKind::PermissionDenied) {
+            return Err(error.into());
+        }
+
+        return Err(ArchiveMismatch(format!("Failed to unpack {}: {}", input_file.display(), error)).into());
+    }
+
+    verify_unpacked_archive(input_file, output_dir)
+}
+
+/// Download an artifact from the storage when the job provides storage hints for its key,
+/// from the API otherwise.
+pub fn download_artifact(
+    client: &Client,
+    api_url: &str,
+    file_path: &PathBuf,
+    api_auth: Option<&ApiAuth>,
+    storage: Option<&StorageHints>,
+    key: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(storage) = storage {
+        if let Some(url) = storage.download_url(key)? {
+            info!("Downloading artifact {} from storage", key);
+            return download_file(client, &url, file_path, None, None);
+        }
+
+        debug!("No storage url for artifact {}, downloading it from the API", key);
+    }
+
+    download_file(client, api_url, file_path, api_auth, None)
+}
+
+/// Upload the artifacts of a manifest directly to the storage described by the job's storage
+/// hints, then tell the API where they are. Falls back to a multipart upload to the API when there
+/// is no storage hints. Artifact keys are `{key_prefix}/{name}`. Failed uploads are retried, then
+/// kept in the outbox if the API is unreachable.
+pub fn upload_artifacts(
+    client: &Client,
+    auth: &ApiAuth,
+    url: String,
+    origin: &str,
+    manifest: &UploadManifest,
+    storage: Option<&StorageHints>,
+    key_prefix: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _permit = StagePermit::acquire(UPLOAD_STAGE);
+    let start = Instant::now();
+    let mut attempt = 1;
+
+    let result = loop {
+        let result = send_artifacts(
+            client,
+            auth,
+            url.clone(),
+            origin,
+            &manifest.artifacts,
+            storage,
+            key_prefix,
+        );
+
+        match result {
+            // An open circuit will not close before the retries, the outbox takes over
+            Err(error) if attempt < UPLOAD_ATTEMPTS && !error.is::<CircuitOpen>() => {
+                let delay = Duration::from_secs(2u64.pow(attempt));
+                warn!(
+                    "Failed to upload {} (attempt {}/{}): {}. Retrying in {:.1?}",
+                    manifest.names(),
+                    attempt,
+                    UPLOAD_ATTEMPTS,
+                    error,
+                    delay
+                );
+
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => break result,
+        }
+    };
+
+    match &result {
+        Ok(()) => info!(
+            "{} artifacts uploaded in {:.1?}: {}",
+            manifest.artifacts.len(),
+            start.elapsed(),
+            manifest.names()
+        ),
+        Err(error) => error!(
+            "Failed to upload {} after {} attempts: {}",
+            manifest.names(),
+            attempt,
+            error
+        ),
+    }
+
+    keep_upload_if_unreachable(result, &url, origin, &manifest.artifacts, storage, key_prefix)
+}
+
+pub fn send_artifacts(
+    client: &Client,
+    auth: &ApiAuth,
+    url: String,
+    origin: &str,
+    artifacts: &[Artifact],
+    storage: Option<&StorageHints>,
+    key_prefix: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let storage = match storage {
+        Some(storage) => storage,
+        None => return send_files(client, auth, url, origin, artifacts),
+    };
+
+    let mut api_artifacts: Vec<Artifact> = vec![];
+    let mut stored_artifacts: Vec<StoredArtifact> = vec![];
+
+    for artifact in artifacts {
+        let file_name = &artifact.name;
+        let key = format!("{}/{}", key_prefix, file_name);
+
+        let storage_url = match storage.upload_url(&key)? {
+            Some(storage_url) => storage_url,
+            None => {
+                debug!(
+                    "No storage url for artifact {}, uploading it through the API",
+                    &key
+                );
+                api_artifacts.push(artifact.clone());
+                continue;
+            }
+        };
+
+        info!("Uploading file {} to storage", &file_name);
+        debug!("PUT {}", storage_url.split('?').next().unwrap_or(&storage_url));
+        let start = Instant::now();
+
+        let size = metadata(&artifact.path)?.len();
+        let reader = ProgressReader::new(
+            File::open(&artifact.path)?,
+            format!("Upload of {}", &file_name),
+            Some(size),
+        );
+
+        let response = client
+            .put(storage_url)
+            .header("Content-Type", &artifact.mime)
+            .body(Body::sized(reader, size))
+            .send()?;
+
+        if !response.status().is_success() {
+            error!(
+                "Failed to upload file {} to storage. {}",
+                &file_name,
+                describe_error_response(response)
+            );
+
+            return Err(format!("Failed to upload file {} to storage", &file_name).into());
+        }
+
+        let duration = start.elapsed();
+        add_upload(size, duration);
+        info!("File {} uploaded to storage in {:.1?}", &file_name, duration);
+
+        stored_artifacts.push(StoredArtifact {
+            form_part_name: artifact.role.clone(),
+            key,
+            size,
+            sha256: artifact.sha256.clone(),
+        });
+    }
+
+    if !api_artifacts.is_empty() {
+        send_files(client, auth, url.clone(), origin, &api_artifacts)?;
+    }
+
+    // Every artifact was uploaded through the API, none has a storage url
+    if stored_artifacts.is_empty() {
+        return Ok(());
+    }
+
+    let response = auth.send(with_transfer_report(
+        client
+            .post(url)
+            .header("Origin", origin)
+            .json(&StoredArtifacts { stored_artifacts }),
+        0,
+    ))?;
+
+    if is_gateway_error(response.status()) {
+        return Err(ApiUnavailable(response.status()).into());
+    }
+
+    if !response.status().is_success() {
+        error!(
+            "Failed to register stored artifacts. {}",
+            describe_error_response(response)
+        );
+
+        return Err("Failed to register stored artifacts".into());
+    }
 
     Ok(())
 }
+
+/// Total size in bytes of the files in a directory, recursively. Missing directories weigh 0.
+pub fn directory_size(path: &Path) -> u64 {
+    let entries = match read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_limit_is_unlimited_without_maximum() {
+        assert_eq!(read_limit(0, 0), u64::MAX);
+        assert_eq!(read_limit(0, 1_000), u64::MAX);
+    }
+
+    #[test]
+    fn read_limit_is_one_byte_above_the_maximum() {
+        assert_eq!(read_limit(1_000, 0), 1_001);
+        assert_eq!(read_limit(u64::MAX, 0), u64::MAX);
+    }
+
+    #[test]
+    fn read_limit_counts_the_resumed_bytes() {
+        assert_eq!(read_limit(1_000, 400), 601);
+        assert_eq!(read_limit(1_000, 1_000), 1);
+    }
+}
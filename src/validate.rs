@@ -0,0 +1,218 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{create_dir_all, read_dir, remove_dir_all},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    auth::ApiAuth,
+    http::http_client,
+    scratch::scratch_dir,
+    state::report_stage,
+    subprocess::{run_subprocess, run_subprocess_checked, subprocess_command},
+    utils::{decompress_archive, download_artifact, post_json, sha256_of_file, StorageHints},
+};
+
+/// Previously uploaded artifact to check, eg: the rasters archive of a render step.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArtifactToValidate {
+    /// Storage key of the artifact, eg: `render-steps/1000_6000/rasters_1000_6000.tar.xz`
+    pub key: String,
+    /// API url of the artifact, used when the storage hints have no url for the key
+    pub url: String,
+    /// Hex encoded SHA-256 of the artifact, when recorded by the server
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ValidationProblem {
+    artifact: String,
+    problem: String,
+}
+
+#[derive(Serialize)]
+struct ValidationVerdict {
+    valid: bool,
+    problems: Vec<ValidationProblem>,
+}
+
+/// Download the artifacts of a tile and check that they are usable: checksums, archives
+/// decompressing, pngs decoding, tiffs georeferenced and shapefiles opening. The verdict is sent
+/// to the API, the job only fails when the verdict cannot be reached or sent.
+pub fn validate_step(
+    tile_id: &str,
+    artifacts: &[ArtifactToValidate],
+    auth: &ApiAuth,
+    base_api_url: &str,
+    storage: Option<&StorageHints>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = http_client();
+    let validate_dir_path = scratch_dir().join("validate").join(tile_id);
+
+    // Left behind by an interrupted job
+    if validate_dir_path.exists() {
+        remove_dir_all(&validate_dir_path)?;
+    }
+
+    create_dir_all(&validate_dir_path)?;
+
+    report_stage("validating");
+    info!("Validating {} artifacts of tile {}", artifacts.len(), tile_id);
+    let start = Instant::now();
+
+    let mut problems: Vec<ValidationProblem> = vec![];
+
+    for (index, artifact) in artifacts.iter().enumerate() {
+        let artifact_dir_path = validate_dir_path.join(index.to_string());
+        create_dir_all(&artifact_dir_path)?;
+
+        let file_name = artifact.key.rsplit('/').next().unwrap_or(&artifact.key);
+        let file_path = artifact_dir_path.join(file_name);
+
+        let artifact_problems = match download_artifact(
+            &client,
+            &artifact.url,
+            &file_path,
+            Some(auth),
+            storage,
+            &artifact.key,
+        ) {
+            Ok(()) => validate_artifact(artifact, &file_path, &artifact_dir_path.join("content")),
+            Err(error) => vec![format!("download failed: {}", error)],
+        };
+
+        for problem in artifact_problems {
+            warn!(
+                "Artifact {} of tile {} invalid: {}",
+                artifact.key, tile_id, problem
+            );
+
+            problems.push(ValidationProblem {
+                artifact: artifact.key.clone(),
+                problem,
+            });
+        }
+    }
+
+    remove_dir_all(&validate_dir_path)?;
+
+    info!(
+        "Artifacts of tile {} validated in {:.1?}, {} problems",
+        tile_id,
+        start.elapsed(),
+        problems.len()
+    );
+
+    report_stage("reporting verdict");
+    let verdict = ValidationVerdict {
+        valid: problems.is_empty(),
+        problems,
+    };
+
+    let url = format!("{}/api/map-generation/validations/{}", base_api_url, tile_id);
+    post_json(&client, auth, &url, base_api_url, &verdict)
+}
+
+/// Problems found in a downloaded artifact, empty if it is valid.
+fn validate_artifact(
+    artifact: &ArtifactToValidate,
+    file_path: &Path,
+    content_dir_path: &Path,
+) -> Vec<String> {
+    let mut problems = vec![];
+
+    if let Some(expected) = &artifact.sha256 {
+        match sha256_of_file(file_path) {
+            Ok(actual) if actual.eq_ignore_ascii_case(expected) => {}
+            Ok(actual) => problems.push(format!("checksum {} instead of {}", actual, expected)),
+            Err(error) => problems.push(format!("checksum failed: {}", error)),
+        }
+    }
+
+    let file_name = file_path.to_string_lossy();
+
+    if file_name.ends_with(".tar.xz") {
+        let decompressed = create_dir_all(content_dir_path)
+            .map_err(|error| error.into())
+            .and_then(|()| decompress_archive(&file_path.to_path_buf(), &content_dir_path.to_path_buf()));
+
+        match decompressed {
+            Ok(()) => {
+                let mut files = vec![];
+                list_files(content_dir_path, &mut files);
+
+                if files.is_empty() {
+                    problems.push("empty archive".to_string());
+                }
+
+                for file in files {
+                    if let Err(error) = validate_file(&file) {
+                        let relative_path = file.strip_prefix(content_dir_path).unwrap_or(&file);
+                        problems.push(format!("{}: {}", relative_path.display(), error));
+                    }
+                }
+            }
+            Err(error) => problems.push(format!("decompression failed: {}", error)),
+        }
+    } else if let Err(error) = validate_file(file_path) {
+        problems.push(error.to_string());
+    }
+
+    problems
+}
+
+/// Structural checks of a file depending on its extension, other files are not checked.
+fn validate_file(file_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    match file_path.extension().and_then(|extension| extension.to_str()) {
+        Some("png") => {
+            image::open(file_path).map_err(|error| format!("png does not decode: {}", error))?;
+        }
+        Some("tif") => {
+            let output = run_subprocess(
+                subprocess_command("gdalinfo").arg("-json").arg(file_path),
+                "gdalinfo",
+            )?;
+
+            if !output.status.success() {
+                return Err("tiff does not open".into());
+            }
+
+            let info: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+
+            if info.get("geoTransform").is_none() || info.get("coordinateSystem").is_none() {
+                return Err("tiff is not georeferenced".into());
+            }
+        }
+        Some("shp") | Some("fgb") => {
+            run_subprocess_checked(
+                subprocess_command("ogrinfo")
+                    .args(["-ro", "-so", "-al", "-q"])
+                    .arg(file_path),
+                "ogrinfo",
+            )
+            .map_err(|error| format!("shapefile does not open: {}", error))?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+
+        if path.is_dir() {
+            list_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
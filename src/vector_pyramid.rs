@@ -0,0 +1,210 @@
+use log::info;
+use serde_json::json;
+use std::{
+    fs::{create_dir_all, remove_dir_all},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    artifacts::UploadManifest,
+    auth::ApiAuth,
+    http::http_client,
+    region::{RegionProfile, VectorFormat},
+    scratch::scratch_dir,
+    state::report_stage,
+    subprocess::{run_subprocess_checked, subprocess_command},
+    utils::{decompress_archive, download_artifact, upload_artifacts, StorageHints},
+};
+
+/// Layers of the vector tiles: name, shapefile in the render step shapefiles archive, and zoom
+/// level from which it is included. Detailed layers only show up when zoomed in.
+const VECTOR_LAYERS: [(&str, &str, u8); 4] = [
+    ("contours", "contours/contours", 12),
+    ("lines", "vectors/lines", 13),
+    ("multipolygons", "vectors/multipolygons", 13),
+    ("formlines", "formlines/formlines", 14),
+];
+
+/// Simplification tolerance, in tile pixels, of the geometries on the zoom levels below the
+/// maximum one
+const SIMPLIFICATION: f64 = 1.0;
+
+/// Merge the clipped shapefiles of `tiles_ids` and cut them into a vector tile set for the zoom
+/// levels `min_zoom..=max_zoom`, uploaded as a single PMTiles archive.
+pub fn vector_pyramid_step(
+    area_id: &str,
+    tiles_ids: &Vec<String>,
+    (min_zoom, max_zoom): (u8, u8),
+    auth: &ApiAuth,
+    base_api_url: &str,
+    region: &RegionProfile,
+    storage: Option<&StorageHints>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let vector_pyramid_dir_path = scratch_dir().join("vector-pyramid").join(area_id);
+
+    // Left behind by an interrupted job
+    if vector_pyramid_dir_path.exists() {
+        remove_dir_all(&vector_pyramid_dir_path)?;
+    }
+
+    create_dir_all(&vector_pyramid_dir_path)?;
+
+    let result = (|| {
+        let merged_path = download_and_merge_shapefiles(
+            &vector_pyramid_dir_path,
+            tiles_ids,
+            auth,
+            base_api_url,
+            region,
+            storage,
+        )?;
+
+        report_stage("generating vector tiles");
+        info!(
+            "Generating vector tiles for zoom {} to {} of area {}",
+            min_zoom, max_zoom, area_id
+        );
+        let start = Instant::now();
+
+        // Per layer zoom bands, never below the minimum zoom of the tile set
+        let layers_config = VECTOR_LAYERS
+            .iter()
+            .map(|(layer, _, layer_min_zoom)| {
+                (
+                    layer.to_string(),
+                    json!({ "minzoom": (*layer_min_zoom).max(min_zoom), "maxzoom": max_zoom }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+
+        let pmtiles_file_name = "vector-tiles.pmtiles".to_string();
+        let pmtiles_path = vector_pyramid_dir_path.join(&pmtiles_file_name);
+
+        run_subprocess_checked(
+            subprocess_command("ogr2ogr")
+                .args(["-f", "PMTiles"])
+                .arg(&pmtiles_path)
+                .arg(&merged_path)
+                .args(["-dsco", &format!("MINZOOM={}", min_zoom)])
+                .args(["-dsco", &format!("MAXZOOM={}", max_zoom)])
+                .args(["-dsco", &format!("SIMPLIFICATION={}", SIMPLIFICATION)])
+                .args(["-dsco", "SIMPLIFICATION_MAX_ZOOM=0"])
+                .args([
+                    "-dsco",
+                    &format!("CONF={}", serde_json::Value::Object(layers_config)),
+                ]),
+            "ogr2ogr",
+        )?;
+
+        info!(
+            "Vector tiles of area {} generated in {:.1?}",
+            area_id,
+            start.elapsed()
+        );
+
+        report_stage("uploading");
+        let url = format!("{}/api/map-generation/vector-pyramids/{}", base_api_url, area_id);
+
+        upload_artifacts(
+            &http_client(),
+            auth,
+            url,
+            base_api_url,
+            &UploadManifest::single(
+                &pmtiles_file_name,
+                "vector-tiles",
+                &pmtiles_path,
+                "application/vnd.pmtiles",
+            )?,
+            storage,
+            &format!("vector-pyramids/{}", area_id),
+        )
+    })();
+
+    // Vector tile sets are only built once, nothing worth keeping in the cache
+    remove_dir_all(&vector_pyramid_dir_path)?;
+
+    result
+}
+
+/// Download the shapefiles archives of the render steps, and merge every layer of all the tiles
+/// into a single GeoPackage. Returns the path of the GeoPackage.
+fn download_and_merge_shapefiles(
+    vector_pyramid_dir_path: &Path,
+    tiles_ids: &Vec<String>,
+    auth: &ApiAuth,
+    base_api_url: &str,
+    region: &RegionProfile,
+    storage: Option<&StorageHints>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let client = http_client();
+
+    report_stage("downloading shapefiles");
+    info!("Downloading the shapefiles of {} tiles", tiles_ids.len());
+    let start = Instant::now();
+
+    let merged_path = vector_pyramid_dir_path.join("merged.gpkg");
+
+    for tile_id in tiles_ids {
+        let tile_dir_path = vector_pyramid_dir_path.join(tile_id);
+        create_dir_all(&tile_dir_path)?;
+
+        let archive_path = tile_dir_path.join(format!("shapefiles_{}.tar.xz", tile_id));
+
+        download_artifact(
+            &client,
+            &format!(
+                "{}/api/map-generation/render-steps/{}/shapefiles",
+                base_api_url, tile_id
+            ),
+            &archive_path,
+            Some(auth),
+            storage,
+            &format!("render-steps/{}/shapefiles_{}.tar.xz", tile_id, tile_id),
+        )?;
+
+        decompress_archive(&archive_path, &tile_dir_path)?;
+
+        for (layer, layer_path, _) in VECTOR_LAYERS {
+            // Shapefiles or FlatGeobuf files depending on the region profile of the render worker
+            let shapefile_path = [VectorFormat::Shapefile, VectorFormat::FlatGeobuf]
+                .iter()
+                .map(|format| tile_dir_path.join(format!("{}.{}", layer_path, format.extension())))
+                .find(|path| path.exists());
+
+            // Tiles without any feature of a layer, eg: no formlines on flat ground
+            let Some(shapefile_path) = shapefile_path else {
+                continue;
+            };
+
+            let mut command = subprocess_command("ogr2ogr");
+
+            if merged_path.exists() {
+                command.args(["-update", "-append"]);
+            }
+
+            run_subprocess_checked(
+                command
+                    .args(["-f", "GPKG"])
+                    .args(["-a_srs", &format!("EPSG:{}", region.epsg)])
+                    .args(["-nln", layer])
+                    .arg(&merged_path)
+                    .arg(&shapefile_path),
+                "ogr2ogr",
+            )?;
+        }
+    }
+
+    if !merged_path.exists() {
+        return Err("No shapefiles to generate vector tiles from".into());
+    }
+
+    info!(
+        "Shapefiles of {} tiles downloaded and merged in {:.1?}",
+        tiles_ids.len(),
+        start.elapsed()
+    );
+
+    Ok(merged_path)
+}
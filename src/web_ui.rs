@@ -0,0 +1,205 @@
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cache::{CacheUsage, CacheUsageSnapshot},
+    history::{HistoryQuery, JobHistory, JobRecord},
+    state::WorkerState,
+};
+
+/// Page served on `/`, refreshed from `/status.json`.
+pub const WEB_UI_PAGE: &str = include_str!("web_ui.html");
+
+/// Jobs listed on the page
+const RECENT_JOBS: usize = 20;
+/// Hours covered by the throughput charts
+const THROUGHPUT_HOURS: u64 = 24;
+/// Walking the cache directories is slow, their usage is refreshed at most this often
+pub const CACHE_USAGE_MAX_AGE: Duration = Duration::from_secs(60);
+/// How often the status is computed while the page is open
+const STATUS_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+/// The status is no longer computed when nobody requested it for this long
+const STATUS_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// State of the worker shown by the web UI, computed on each refresh except the cache usage.
+pub struct WebUiStatus {
+    cache_usage: CacheUsageSnapshot,
+}
+
+/// Last status computed for `/status.json`. The history queries and the walk of the cache
+/// directories run on a thread of their own, so that they never hold up the probes.
+pub struct CachedStatus {
+    /// None until the first status is computed
+    json: Mutex<Option<String>>,
+    last_requested: Mutex<Instant>,
+}
+
+impl CachedStatus {
+    pub fn get(&self) -> Option<String> {
+        *self.last_requested.lock().unwrap() = Instant::now();
+        self.json.lock().unwrap().clone()
+    }
+}
+
+/// Compute the status every `STATUS_REFRESH_INTERVAL`, as long as it was requested recently.
+pub fn spawn_status_refresher(
+    state: Arc<WorkerState>,
+    history: Option<Arc<JobHistory>>,
+) -> Result<Arc<CachedStatus>, Box<dyn std::error::Error>> {
+    let cached_status = Arc::new(CachedStatus {
+        json: Mutex::new(None),
+        last_requested: Mutex::new(Instant::now()),
+    });
+
+    let refreshed_status = cached_status.clone();
+
+    thread::Builder::new()
+        .name("web-ui-status".to_string())
+        .spawn(move || {
+            let mut web_ui_status = WebUiStatus::new();
+
+            loop {
+                let is_requested =
+                    refreshed_status.last_requested.lock().unwrap().elapsed() < STATUS_IDLE_TIMEOUT;
+
+                if is_requested {
+                    let json = web_ui_status.status_json(&state, history.as_deref()).to_string();
+                    *refreshed_status.json.lock().unwrap() = Some(json);
+                }
+
+                thread::sleep(STATUS_REFRESH_INTERVAL);
+            }
+        })?;
+
+    Ok(cached_status)
+}
+
+/// Current job, stage and counters of every worker thread.
+pub fn threads_json(state: &WorkerState) -> Vec<Value> {
+    state
+        .thread_stats()
+        .iter()
+        .map(|stats| {
+            json!({
+                "name": stats.name,
+                "jobs_done": stats.jobs_done,
+                "current_job": stats.current_job,
+                "stage": stats.stage,
+                "job_elapsed_seconds": stats.job_elapsed.map(|elapsed| elapsed.as_secs()),
+                "transferred_bytes": stats.transferred_bytes,
+                "transfer_progress": stats.transfer_progress,
+            })
+        })
+        .collect()
+}
+
+pub fn cache_json(cache_usage: &[CacheUsage]) -> Vec<Value> {
+    cache_usage
+        .iter()
+        .map(|usage| {
+            json!({
+                "cache_dir": usage.cache_dir,
+                "entries": usage.entries,
+                "size": usage.size,
+                "oldest_seconds": usage.oldest.map(|oldest| oldest.as_secs()),
+            })
+        })
+        .collect()
+}
+
+impl WebUiStatus {
+    pub fn new() -> Self {
+        WebUiStatus {
+            cache_usage: CacheUsageSnapshot::new(CACHE_USAGE_MAX_AGE),
+        }
+    }
+
+    pub fn status_json(&mut self, state: &WorkerState, history: Option<&JobHistory>) -> Value {
+        // The history is only recorded when enabled, the page shows no job nor chart otherwise
+        let records = history
+            .and_then(|history| {
+                history
+                    .query(&HistoryQuery {
+                        tile: None,
+                        since_hours: Some(THROUGHPUT_HOURS),
+                        failed_only: false,
+                        limit: 100_000,
+                    })
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        let recent_jobs: Vec<Value> = records
+            .iter()
+            .take(RECENT_JOBS)
+            .map(|record| {
+                json!({
+                    "job_type": record.job_type,
+                    "tile": record.tile,
+                    "thread": record.thread,
+                    "ended_at": record.ended_at,
+                    "duration_ms": record.duration_ms,
+                    "error": record.error,
+                    "bytes_downloaded": record.bytes_downloaded,
+                    "bytes_uploaded": record.bytes_uploaded,
+                })
+            })
+            .collect();
+
+        json!({
+            "summary": state.summary(),
+            "run_summary": state.run_summary(),
+            "uptime_seconds": state.uptime().as_secs(),
+            "history_enabled": history.is_some(),
+            "threads": threads_json(state),
+            "recent_jobs": recent_jobs,
+            "cache": cache_json(self.cache_usage.get()),
+            "throughput": hourly_throughput(&records),
+        })
+    }
+}
+
+/// Jobs and bytes transferred by hour over the last `THROUGHPUT_HOURS`, oldest first, by the
+/// hour the jobs ended.
+fn hourly_throughput(records: &[JobRecord]) -> Vec<Value> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let current_hour = now - now % 3600;
+
+    let mut hours: BTreeMap<u64, (u64, u64, u64, u64)> = (0..THROUGHPUT_HOURS)
+        .map(|hours_ago| (current_hour - hours_ago * 3600, (0, 0, 0, 0)))
+        .collect();
+
+    for record in records {
+        let Some((jobs, failures, bytes_downloaded, bytes_uploaded)) =
+            hours.get_mut(&(record.ended_at - record.ended_at % 3600))
+        else {
+            continue;
+        };
+
+        *jobs += 1;
+        *failures += record.error.is_some() as u64;
+        *bytes_downloaded += record.bytes_downloaded;
+        *bytes_uploaded += record.bytes_uploaded;
+    }
+
+    hours
+        .into_iter()
+        .map(|(hour, (jobs, failures, bytes_downloaded, bytes_uploaded))| {
+            json!({
+                "hour": hour,
+                "jobs": jobs,
+                "failures": failures,
+                "bytes_downloaded": bytes_downloaded,
+                "bytes_uploaded": bytes_uploaded,
+            })
+        })
+        .collect()
+}
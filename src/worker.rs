@@ -0,0 +1,1171 @@
+use log::{debug, error, info, warn};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+    sync::Arc,
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use sysinfo::System;
+use uuid::Uuid;
+
+use crate::{
+    affinity::apply_worker_thread_affinity,
+    auth::ApiAuth,
+    bbox::{bbox_header_value, tiles_outside_bbox},
+    cache::invalidate_cache_entries,
+    capabilities::missing_requirements,
+    circuit::is_circuit_open,
+    disk::available_space,
+    events::record_job_event,
+    history::{JobHistory, JobRecord},
+    http::http_client,
+    in_flight::InFlightJobs,
+    journal::{abandon_jobs, JobJournal, LeaseGuard},
+    lidar::lidar_step,
+    metrics_push::{JobMetric, MetricsQueue},
+    mosaic::{mosaic_step, MosaicLayer},
+    panics::panic_message,
+    poison::PoisonedJobs,
+    priority::lower_current_thread_priority,
+    pyramid::pyramid_step,
+    quota::BandwidthQuota,
+    raster::take_raster_sizes,
+    rate_limit::{jittered, wait_for_poll_turn},
+    region::RegionProfile,
+    render::{download_render_step_inputs, render_step, RenderStyle},
+    reporting::report_job_failure,
+    response::describe_error_response,
+    scheduler::{job_type, AvailableResources, JobQueue},
+    self_test::self_test_step,
+    state::{
+        attach_current_thread, current_abort_reason, current_correlation_id, downloader_thread_name,
+        set_correlation_id, worker_thread_name, JobAborted, WorkerState, JOB_CANCELLED,
+    },
+    tags::{tags_header_value, worker_tags},
+    tile_encoder::{supported_tile_formats, TileEncoding},
+    utils::{
+        directory_size, notify_job_abandoned, notify_job_cancelled, notify_job_failed, take_transfer_stats,
+        StorageHints,
+    },
+    validate::{validate_step, ArtifactToValidate},
+    vector_pyramid::vector_pyramid_step,
+};
+
+/// Version of the worker <-> API protocol, sent with the next-job requests so that the server only
+/// hands out jobs this worker understands. Bump it when adding or changing job types.
+pub const PROTOCOL_VERSION: u32 = 7;
+pub const SUPPORTED_JOB_TYPES: [&str; 10] = [
+    "Lidar",
+    "Render",
+    "Pyramid",
+    "Mosaic",
+    "VectorPyramid",
+    "Validate",
+    "Cleanup",
+    "RestyleRender",
+    "SelfTest",
+    "NoJobLeft",
+];
+/// Delay before restarting a crashed worker thread, not to spin on a persistent failure
+const THREAD_RESTART_DELAY: Duration = Duration::from_secs(5);
+/// Delay before the second attempt at a failed job, doubled for each next attempt
+const JOB_RETRY_DELAY: Duration = Duration::from_secs(10);
+/// Longest delay between two attempts at a failed job, eg: with a high `--job-attempts`
+const MAX_JOB_RETRY_DELAY: Duration = Duration::from_secs(600);
+/// Delay before fetching a new job after declining one, the API may hand out the same job again
+const DECLINED_JOB_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum Job {
+    Lidar {
+        tile_id: String,
+        tile_url: String,
+        /// Other URLs of the same LAZ file, eg: community mirrors, tried when `tile_url` fails
+        #[serde(default)]
+        mirror_urls: Vec<String>,
+        /// Size in bytes of the LAZ file, when known by the server
+        #[serde(default)]
+        tile_size: Option<u64>,
+        #[serde(default)]
+        storage: Option<StorageHints>,
+    },
+    Render {
+        tile_id: String,
+        neigbhoring_tiles_ids: Vec<String>,
+        #[serde(default)]
+        storage: Option<StorageHints>,
+    },
+    Pyramid {
+        x: i32,
+        y: i32,
+        z: i32,
+        base_zoom_level_tile_id: Option<String>,
+        area_id: String,
+        #[serde(default)]
+        tile_encoding: Option<TileEncoding>,
+    },
+    /// Assemble the render steps of an area into a downloadable GeoTIFF
+    Mosaic {
+        area_id: String,
+        tiles_ids: Vec<String>,
+        layer: MosaicLayer,
+        #[serde(default)]
+        storage: Option<StorageHints>,
+    },
+    /// Cut the shapefiles of an area into vector tiles
+    VectorPyramid {
+        area_id: String,
+        tiles_ids: Vec<String>,
+        min_zoom: u8,
+        max_zoom: u8,
+        #[serde(default)]
+        storage: Option<StorageHints>,
+    },
+    /// Re-check the uploaded artifacts of a tile and report a verdict
+    Validate {
+        tile_id: String,
+        artifacts: Vec<ArtifactToValidate>,
+        #[serde(default)]
+        storage: Option<StorageHints>,
+    },
+    /// Remove tiles and areas from the local caches, eg: after they were reprocessed
+    Cleanup {
+        #[serde(default)]
+        tiles_ids: Vec<String>,
+        #[serde(default)]
+        areas_ids: Vec<String>,
+    },
+    /// Render a tile again with another style, from the cached or downloaded LiDAR step outputs
+    RestyleRender {
+        tile_id: String,
+        neigbhoring_tiles_ids: Vec<String>,
+        style_id: String,
+        /// cassini config of the style, see its `config.json`
+        style: serde_json::Value,
+        #[serde(default)]
+        storage: Option<StorageHints>,
+    },
+    /// Check the environment of a new worker on a small LAZ file, see `self_test_step`
+    SelfTest {
+        /// The bundled sample tile if None
+        #[serde(default)]
+        laz_url: Option<String>,
+    },
+    NoJobLeft,
+}
+
+impl Job {
+    /// Tiles the job is about, to check them against `--bbox`. Neighbors of the rendered tiles
+    /// are only read, they are not listed.
+    fn tiles_ids(&self) -> Vec<&str> {
+        match self {
+            Job::Lidar { tile_id, .. }
+            | Job::Render { tile_id, .. }
+            | Job::RestyleRender { tile_id, .. }
+            | Job::Validate { tile_id, .. } => vec![tile_id],
+            Job::Mosaic { tiles_ids, .. } | Job::VectorPyramid { tiles_ids, .. } => {
+                tiles_ids.iter().map(String::as_str).collect()
+            }
+            Job::Pyramid {
+                base_zoom_level_tile_id,
+                ..
+            } => base_zoom_level_tile_id.iter().map(String::as_str).collect(),
+            Job::Cleanup { .. } | Job::SelfTest { .. } | Job::NoJobLeft => vec![],
+        }
+    }
+
+    /// Identifies the same job across leases, eg: "Pyramid area-1 12/2048/1420", to detect the
+    /// jobs failing again and again. None for the jobs which can not be poisoned.
+    fn label(&self) -> Option<String> {
+        match self {
+            Job::Lidar { tile_id, .. } => Some(format!("Lidar {}", tile_id)),
+            Job::Render { tile_id, .. } => Some(format!("Render {}", tile_id)),
+            Job::RestyleRender {
+                tile_id, style_id, ..
+            } => Some(format!("RestyleRender {} {}", tile_id, style_id)),
+            Job::Validate { tile_id, .. } => Some(format!("Validate {}", tile_id)),
+            Job::Pyramid { x, y, z, area_id, .. } => Some(format!("Pyramid {} {}/{}/{}", area_id, z, x, y)),
+            Job::Mosaic { area_id, layer, .. } => Some(format!("Mosaic {} {:?}", area_id, layer)),
+            Job::VectorPyramid { area_id, .. } => Some(format!("VectorPyramid {}", area_id)),
+            Job::Cleanup { .. } | Job::SelfTest { .. } | Job::NoJobLeft => None,
+        }
+    }
+}
+
+/// Everything a worker thread needs, shared by all the threads.
+#[derive(Clone)]
+pub struct WorkerContext {
+    pub auth: ApiAuth,
+    pub base_url: String,
+    pub region: RegionProfile,
+    pub state: Arc<WorkerState>,
+    /// Size in bytes of the lidar-step directory above which the next job is not prefetched.
+    /// Prefetching is disabled if None.
+    pub prefetch_disk_budget: Option<u64>,
+    /// Local database recording every processed job. Disabled if None.
+    pub history: Option<Arc<JobHistory>>,
+    /// Metrics of the processed jobs waiting to be pushed to the API. Disabled if None.
+    pub metrics: Option<Arc<MetricsQueue>>,
+    /// Monthly bandwidth budget, no limit if None.
+    pub quota: Option<Arc<BandwidthQuota>>,
+    /// Jobs refused after failing repeatedly. Disabled if None.
+    pub poisoned_jobs: Option<Arc<PoisonedJobs>>,
+    /// Attempts at a failing job before reporting it as failed, at least 1
+    pub job_attempts: u32,
+    /// Prefetched jobs of all the threads, by priority
+    pub queue: Arc<JobQueue>,
+    /// Jobs running on the threads, to decline the duplicates
+    pub in_flight: Arc<InFlightJobs>,
+    /// Jobs leased from the API and not finished yet
+    pub journal: Arc<JobJournal>,
+    /// Return after a single job instead of waiting for the next one, see `run_single_job`
+    pub once: bool,
+    /// Drain the worker, then exit, when the API has no job left, see `--exit-when-idle`
+    pub exit_when_idle: bool,
+}
+
+/// Run `run_worker_thread`, restarting it if a panic escapes the jobs, eg: from the bookkeeping
+/// around them, so that the worker does not silently lose parallelism.
+pub fn supervise_worker_thread(context: WorkerContext, thread_index: usize) {
+    loop {
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            run_worker_thread(context.clone(), thread_index)
+        }));
+
+        let Err(payload) = result else {
+            return;
+        };
+
+        set_correlation_id(None);
+        context.state.end_job(thread_index);
+
+        if context.state.is_draining() {
+            error!(
+                "Worker thread crashed while draining: {}",
+                panic_message(payload.as_ref())
+            );
+            return;
+        }
+
+        error!(
+            "Worker thread crashed: {}. Restarting it in {:.1?}",
+            panic_message(payload.as_ref()),
+            THREAD_RESTART_DELAY
+        );
+        sleep(THREAD_RESTART_DELAY);
+    }
+}
+
+/// Fetch and process a single job on the current thread, for `--once`. Fails if the job failed.
+pub fn run_single_job(context: WorkerContext) -> Result<(), Box<dyn std::error::Error>> {
+    attach_current_thread(context.state.clone(), 0);
+    lower_current_thread_priority();
+
+    // Prefetching is disabled, no job is left in the queue afterwards
+    let mut prefetched_job = None;
+    let result = catch_job_panic(|| get_and_handle_next_job(&context, 0, &mut prefetched_job));
+    set_correlation_id(None);
+
+    context.state.end_job(0);
+
+    result
+}
+
+/// Poll and process jobs until the worker starts draining.
+fn run_worker_thread(context: WorkerContext, thread_index: usize) {
+    let mut prefetched_job: Option<JoinHandle<Option<String>>> = None;
+    attach_current_thread(context.state.clone(), thread_index);
+    lower_current_thread_priority();
+    apply_worker_thread_affinity(thread_index);
+
+    while !context.state.is_draining() {
+        // Already prefetched jobs are leased to this worker, they are processed anyway
+        let has_leased_jobs = prefetched_job.is_some() || !context.queue.is_empty();
+
+        if context.state.is_paused() && !has_leased_jobs {
+            debug!("Worker paused, not fetching a new job, checking again in 5s");
+            context.state.sleep_unless_draining(Duration::from_secs(5));
+            continue;
+        }
+
+        if context.state.is_disk_paused() && !has_leased_jobs {
+            debug!("Disk space low, not fetching a new job, checking again in 5s");
+            context.state.sleep_unless_draining(Duration::from_secs(5));
+            continue;
+        }
+
+        if let Some(quota) = &context.quota {
+            let exhausted = quota.is_exhausted();
+            context.state.set_quota_paused(exhausted);
+
+            if exhausted && !has_leased_jobs {
+                debug!("Monthly bandwidth budget reached, not fetching a new job, checking again in 60s");
+                context.state.sleep_unless_draining(Duration::from_secs(60));
+                continue;
+            }
+        }
+
+        if is_circuit_open() && !has_leased_jobs {
+            debug!("API calls paused, not fetching a new job, checking again in 5s");
+            context.state.sleep_unless_draining(Duration::from_secs(5));
+            continue;
+        }
+
+        if !context.state.is_thread_active(thread_index) && !has_leased_jobs {
+            debug!("Thread idle during the quiet hours, checking again in 5s");
+            context.state.sleep_unless_draining(Duration::from_secs(5));
+            continue;
+        }
+
+        // A panic must not kill the thread, which would reduce the parallelism for good
+        let result = catch_job_panic(|| get_and_handle_next_job(&context, thread_index, &mut prefetched_job));
+        set_correlation_id(None);
+
+        context.state.end_job(thread_index);
+
+        match result {
+            // The polling rate is bounded by `fetch_next_job`
+            Ok(_) => {}
+            Err(error) => {
+                let delay = jittered(Duration::from_secs(1));
+                error!("Error: {}. Restarting the thread...", error);
+                debug!("Fetching a new job in {:.1?}", delay);
+                sleep(delay);
+            }
+        }
+    }
+
+    if let Some(job_payload) = prefetched_job.and_then(|handle| handle.join().ok().flatten()) {
+        context.queue.push(job_payload);
+    }
+
+    // The prefetched jobs will never be started, handing them back to the API
+    abandon_jobs(
+        &context.journal,
+        context.queue.drain(),
+        &context.auth,
+        &context.base_url,
+    );
+}
+
+fn available_resources(state: &WorkerState) -> AvailableResources {
+    let mut system = System::new();
+    system.refresh_memory();
+
+    AvailableResources {
+        memory: Some(system.available_memory()).filter(|memory| *memory > 0),
+        disk: available_space(Path::new(".")),
+        running_jobs: state
+            .in_flight_jobs()
+            .iter()
+            .filter_map(|payload| job_type(payload))
+            .fold(HashMap::new(), |mut running_jobs, job_type| {
+                *running_jobs.entry(job_type).or_default() += 1;
+                running_jobs
+            }),
+    }
+}
+
+/// Run `step`, turning a panic or an abort requested by the memory watchdog into an error.
+fn catch_job_panic<F: FnOnce() -> Result<(), Box<dyn std::error::Error>>>(
+    step: F,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payload = match catch_unwind(AssertUnwindSafe(step)) {
+        Ok(result) => return result,
+        Err(payload) => payload,
+    };
+
+    if let Some(JobAborted(reason)) = payload.downcast_ref::<JobAborted>() {
+        return Err(format!("Job aborted: {}", reason).into());
+    }
+
+    Err(format!("Panicked: {}", panic_message(payload.as_ref())).into())
+}
+
+/// Run `step` until it succeeds, at most `max_attempts` times, with a growing delay between the
+/// attempts. The aborted jobs, eg: cancelled or over their memory budget, are not retried, nor
+/// the jobs of a draining worker. The error of each failed attempt is appended to `errors`.
+fn run_job_attempts<F: FnMut() -> Result<(), Box<dyn std::error::Error>>>(
+    state: &WorkerState,
+    max_attempts: u32,
+    errors: &mut Vec<String>,
+    mut step: F,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let error = match catch_job_panic(&mut step) {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+
+        errors.push(error.to_string());
+
+        if errors.len() >= max_attempts as usize || current_abort_reason().is_some() || state.is_draining() {
+            return Err(error);
+        }
+
+        let delay = JOB_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(errors.len() as u32 - 1))
+            .min(MAX_JOB_RETRY_DELAY);
+        warn!(
+            "Attempt {}/{} failed: {}. Retrying in {:.0?}",
+            errors.len(),
+            max_attempts,
+            error,
+            delay
+        );
+        state.sleep_unless_draining(delay);
+
+        if state.is_draining() {
+            return Err(error);
+        }
+    }
+}
+
+/// Wait before fetching a new job after declining one, not to poll in a loop while the API keeps
+/// handing it out.
+fn back_off_after_declined_job(state: &WorkerState) {
+    let delay = jittered(DECLINED_JOB_DELAY);
+    debug!("Fetching a new job in {:.1?}", delay);
+    state.sleep_unless_draining(delay);
+}
+
+fn fetch_next_job(
+    client: &Client,
+    auth: &ApiAuth,
+    base_url: &str,
+    state: &WorkerState,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let url = format!("{}/api/map-generation/next-job", base_url);
+    wait_for_poll_turn();
+
+    let mut request = client
+        .post(&url)
+        .header("X-Mapant-Protocol-Version", PROTOCOL_VERSION.to_string())
+        .header("X-Mapant-Supported-Jobs", supported_job_types().join(","))
+        .header("X-Mapant-Worker-Tags", tags_header_value(worker_tags()))
+        .header("X-Mapant-Tile-Formats", supported_tile_formats().join(","));
+
+    if let Some(bbox) = bbox_header_value() {
+        request = request.header("X-Mapant-Bbox", bbox);
+    }
+
+    let res = auth.send(request);
+
+    let res = match res {
+        Ok(res) => res,
+        Err(error) => {
+            state.record_api_call(false);
+            return Err(error);
+        }
+    };
+
+    state.record_api_call(!res.status().is_server_error());
+
+    if !res.status().is_success() {
+        error!(
+            "Failed to call mapant generation 'next-job' endpoint. {}",
+            describe_error_response(res)
+        );
+
+        return Err("Failed to call endpoint".into());
+    }
+
+    Ok(res.text()?)
+}
+
+fn get_and_handle_next_job(
+    context: &WorkerContext,
+    thread_index: usize,
+    prefetched_job: &mut Option<JoinHandle<Option<String>>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = http_client();
+    let auth = &context.auth;
+    let base_url = context.base_url.as_str();
+    let region = &context.region;
+    let state = context.state.as_ref();
+
+    if let Some(text) = prefetched_job
+        .take()
+        .and_then(|handle| handle.join().ok().flatten())
+    {
+        context.queue.push(text);
+    }
+
+    if context.queue.is_empty() {
+        let text = fetch_next_job(&client, auth, base_url, state)?;
+        context.journal.lease(&text);
+        context.queue.push(text);
+    }
+
+    // The job of this thread, or a more urgent one prefetched by another thread, that fits in the
+    // memory and disk left by the running jobs. The only running job takes it anyway.
+    let text = match context.queue.pop_admissible(&available_resources(state)) {
+        Some(text) => text,
+        None if state.in_flight_jobs().is_empty() => match context.queue.pop() {
+            Some(text) => text,
+            None => return Ok(()),
+        },
+        None => {
+            debug!("Not enough memory or disk space for the queued jobs, checking again in 5s");
+            state.sleep_unless_draining(Duration::from_secs(5));
+            return Ok(());
+        }
+    };
+
+    // Finished, failed or panicked, the job is not leased anymore once handled
+    let _lease = LeaseGuard::new(context.journal.clone(), &text);
+
+    let job = match parse_job(&text, region) {
+        Ok(job) => job,
+        Err(reason) => {
+            warn!("Unsupported job received: {}. Job: {}", reason, &text);
+
+            if let Err(error) = report_unsupported_job(&client, auth, base_url, &text, &reason) {
+                error!("Failed to report unsupported job: {}", error);
+            }
+
+            back_off_after_declined_job(state);
+            return Ok(());
+        }
+    };
+
+    let label = job.label();
+
+    if let (Some(poisoned_jobs), Some(label)) = (&context.poisoned_jobs, &label) {
+        if let Some(reason) = poisoned_jobs.refusal_reason(label) {
+            warn!("{}: {}", label, reason);
+
+            if let Err(error) = report_unsupported_job(&client, auth, base_url, &text, &reason) {
+                error!("Failed to report the poisoned job: {}", error);
+            }
+
+            back_off_after_declined_job(state);
+            return Ok(());
+        }
+    }
+
+    // Another thread got the same job, eg: from a lease race, both would write to the same
+    // directories. Released once the job is handled.
+    let _in_flight = match &label {
+        Some(label) => match context.in_flight.claim(label) {
+            Some(guard) => Some(guard),
+            None => {
+                warn!(
+                    "{} is already running on another thread, handing the duplicate back to the API",
+                    label
+                );
+
+                if let Err(error) = notify_job_abandoned(&client, auth, base_url, &text) {
+                    error!("Failed to hand the duplicate job back to the API: {}", error);
+                }
+
+                back_off_after_declined_job(state);
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    if !matches!(job, Job::NoJobLeft) {
+        *prefetched_job = spawn_prefetch(context, thread_index);
+    }
+
+    let started_at = SystemTime::now();
+    take_transfer_stats();
+    take_raster_sizes();
+
+    if !matches!(job, Job::NoJobLeft) {
+        set_correlation_id(Some(Uuid::new_v4().to_string()));
+    }
+
+    // Errors of the failed attempts at the job, see `run_job_attempts`
+    let mut errors = Vec::new();
+
+    let (job_type, tile, result) = match job {
+        Job::Lidar {
+            tile_id,
+            tile_url,
+            mirror_urls,
+            tile_size,
+            storage,
+        } => {
+            info!("Handle Lidar job for tile {}", tile_id);
+            state.start_job(thread_index, format!("Lidar {}", tile_id), &text);
+            let start = Instant::now();
+
+            let laz_file_urls = [vec![tile_url], mirror_urls].concat();
+
+            let result = run_job_attempts(state, context.job_attempts, &mut errors, || {
+                lidar_step(
+                    &tile_id,
+                    &laz_file_urls,
+                    tile_size,
+                    auth,
+                    base_url,
+                    region,
+                    storage.as_ref(),
+                )
+            });
+
+            if result.is_ok() {
+                let duration = start.elapsed();
+                info!("Lidar job for tile {} done in {:.1?}", &tile_id, duration);
+            }
+
+            ("Lidar", tile_id, result)
+        }
+        Job::Render {
+            tile_id,
+            neigbhoring_tiles_ids,
+            storage,
+        } => {
+            info!("Handle Render job for tile {}", tile_id);
+            state.start_job(thread_index, format!("Render {}", tile_id), &text);
+            let start = Instant::now();
+
+            let result = run_job_attempts(state, context.job_attempts, &mut errors, || {
+                render_step(
+                    &tile_id,
+                    &neigbhoring_tiles_ids,
+                    auth,
+                    base_url,
+                    region,
+                    storage.as_ref(),
+                    None,
+                )
+            });
+
+            if result.is_ok() {
+                let duration = start.elapsed();
+                info!("Render job for tile {} done in {:.1?}", &tile_id, duration);
+            }
+
+            ("Render", tile_id, result)
+        }
+        Job::RestyleRender {
+            tile_id,
+            neigbhoring_tiles_ids,
+            style_id,
+            style,
+            storage,
+        } => {
+            info!(
+                "Handle RestyleRender job for tile {} with style {}",
+                tile_id, style_id
+            );
+            state.start_job(thread_index, format!("RestyleRender {}", tile_id), &text);
+            let start = Instant::now();
+
+            let style = RenderStyle {
+                id: style_id,
+                config: style,
+            };
+
+            let result = run_job_attempts(state, context.job_attempts, &mut errors, || {
+                render_step(
+                    &tile_id,
+                    &neigbhoring_tiles_ids,
+                    auth,
+                    base_url,
+                    region,
+                    storage.as_ref(),
+                    Some(&style),
+                )
+            });
+
+            if result.is_ok() {
+                let duration = start.elapsed();
+                info!("RestyleRender job for tile {} done in {:.1?}", &tile_id, duration);
+            }
+
+            ("RestyleRender", tile_id, result)
+        }
+        Job::SelfTest { laz_url } => {
+            info!("Handle SelfTest job");
+            state.start_job(thread_index, "SelfTest".to_string(), &text);
+            let start = Instant::now();
+
+            let result = run_job_attempts(state, context.job_attempts, &mut errors, || {
+                self_test_step(laz_url.as_deref(), auth, base_url, region)
+            });
+
+            if result.is_ok() {
+                let duration = start.elapsed();
+                info!("SelfTest job done in {:.1?}", duration);
+            }
+
+            ("SelfTest", String::new(), result)
+        }
+        Job::Pyramid {
+            x,
+            y,
+            z,
+            base_zoom_level_tile_id,
+            area_id,
+            tile_encoding,
+        } => {
+            info!("Handle Pyramid job x={}, y={}, z={}", x, y, z);
+            state.start_job(thread_index, format!("Pyramid {}/{}/{}", z, x, y), &text);
+            let start = Instant::now();
+
+            let result = run_job_attempts(state, context.job_attempts, &mut errors, || {
+                pyramid_step(
+                    x,
+                    y,
+                    z,
+                    base_zoom_level_tile_id.clone(),
+                    area_id.clone(),
+                    tile_encoding,
+                    auth,
+                    base_url,
+                    region,
+                )
+            });
+
+            if result.is_ok() {
+                let duration = start.elapsed();
+                info!("Pyramid job x={}, y={}, z={} done in {:.1?}", x, y, z, duration);
+            }
+
+            ("Pyramid", format!("{}/{}/{}", z, x, y), result)
+        }
+        Job::Mosaic {
+            area_id,
+            tiles_ids,
+            layer,
+            storage,
+        } => {
+            info!("Handle Mosaic job for area {} ({:?})", area_id, layer);
+            state.start_job(thread_index, format!("Mosaic {}", area_id), &text);
+            let start = Instant::now();
+
+            let result = run_job_attempts(state, context.job_attempts, &mut errors, || {
+                mosaic_step(
+                    &area_id,
+                    &tiles_ids,
+                    layer,
+                    auth,
+                    base_url,
+                    region,
+                    storage.as_ref(),
+                )
+            });
+
+            if result.is_ok() {
+                let duration = start.elapsed();
+                info!("Mosaic job for area {} done in {:.1?}", area_id, duration);
+            }
+
+            ("Mosaic", area_id, result)
+        }
+        Job::VectorPyramid {
+            area_id,
+            tiles_ids,
+            min_zoom,
+            max_zoom,
+            storage,
+        } => {
+            info!("Handle VectorPyramid job for area {}", area_id);
+            state.start_job(thread_index, format!("VectorPyramid {}", area_id), &text);
+            let start = Instant::now();
+
+            let result = run_job_attempts(state, context.job_attempts, &mut errors, || {
+                vector_pyramid_step(
+                    &area_id,
+                    &tiles_ids,
+                    (min_zoom, max_zoom),
+                    auth,
+                    base_url,
+                    region,
+                    storage.as_ref(),
+                )
+            });
+
+            if result.is_ok() {
+                let duration = start.elapsed();
+                info!("VectorPyramid job for area {} done in {:.1?}", area_id, duration);
+            }
+
+            ("VectorPyramid", area_id, result)
+        }
+        Job::Validate {
+            tile_id,
+            artifacts,
+            storage,
+        } => {
+            info!("Handle Validate job for tile {}", tile_id);
+            state.start_job(thread_index, format!("Validate {}", tile_id), &text);
+            let start = Instant::now();
+
+            let result = run_job_attempts(state, context.job_attempts, &mut errors, || {
+                validate_step(&tile_id, &artifacts, auth, base_url, storage.as_ref())
+            });
+
+            if result.is_ok() {
+                let duration = start.elapsed();
+                info!("Validate job for tile {} done in {:.1?}", &tile_id, duration);
+            }
+
+            ("Validate", tile_id, result)
+        }
+        Job::Cleanup { tiles_ids, areas_ids } => {
+            info!(
+                "Handle Cleanup job for {} tiles and {} areas",
+                tiles_ids.len(),
+                areas_ids.len()
+            );
+            state.start_job(thread_index, "Cleanup".to_string(), &text);
+
+            // The payload of this job lists the tiles too
+            let in_flight_jobs: Vec<String> = state
+                .in_flight_jobs()
+                .into_iter()
+                .filter(|job_payload| *job_payload != text)
+                .collect();
+
+            let result = run_job_attempts(state, context.job_attempts, &mut errors, || {
+                let freed = invalidate_cache_entries(Path::new(""), &tiles_ids, &areas_ids, &in_flight_jobs)?;
+                info!("Cleanup job done, {} MB freed", freed / 1_000_000);
+
+                Ok(())
+            });
+
+            ("Cleanup", [tiles_ids, areas_ids].concat().join(","), result)
+        }
+        Job::NoJobLeft if context.once => {
+            info!("No job left");
+            return Ok(());
+        }
+        Job::NoJobLeft if context.exit_when_idle => {
+            info!("No job left, exiting once the in-flight jobs are done");
+            state.start_draining();
+            return Ok(());
+        }
+        Job::NoJobLeft => {
+            let delay = jittered(Duration::from_secs(30));
+            warn!("No job left, retrying in {:.0?}", delay);
+            state.sleep_unless_draining(delay);
+            return Ok(());
+        }
+    };
+
+    let cancelled = current_abort_reason().as_deref() == Some(JOB_CANCELLED);
+
+    if cancelled {
+        info!("{} job for {} cancelled", job_type, tile);
+
+        if let Err(error) = notify_job_cancelled(&client, auth, base_url, &text) {
+            error!("Failed to acknowledge the cancelled job: {}", error);
+        }
+    }
+
+    let transfers = take_transfer_stats();
+    let (bytes_downloaded, bytes_uploaded) = (transfers.bytes_downloaded, transfers.bytes_uploaded);
+    let ended_at = SystemTime::now();
+    let duration = ended_at.duration_since(started_at).unwrap_or_default();
+
+    if result.is_ok() {
+        context.queue.record_workload(job_type, duration, &transfers);
+    }
+
+    state.record_job_stats(
+        job_type,
+        duration,
+        result.is_ok(),
+        bytes_downloaded,
+        bytes_uploaded,
+    );
+
+    if let Some(quota) = &context.quota {
+        quota.record_transfers();
+    }
+
+    if let Some(description) = state.current_job(thread_index) {
+        let event = match &result {
+            _ if cancelled => "cancelled",
+            Ok(()) => "completed",
+            Err(_) => "failed",
+        };
+
+        record_job_event(
+            &description,
+            event,
+            serde_json::json!({
+                "job_type": job_type,
+                "tile": tile,
+                "duration_ms": duration.as_millis() as u64,
+                "attempts": errors.len() + result.is_ok() as usize,
+                "bytes_downloaded": bytes_downloaded,
+                "bytes_uploaded": bytes_uploaded,
+                "error": result.as_ref().err().map(|error| error.to_string()),
+            }),
+        );
+    }
+
+    // A cancellation is not a failure of the worker
+    if !cancelled {
+        state.record_job_outcome(result.as_ref().err().map(|error| error.to_string()));
+
+        if let (Some(poisoned_jobs), Some(label)) = (&context.poisoned_jobs, &label) {
+            poisoned_jobs.record(
+                label,
+                result.as_ref().err().map(|error| error.to_string()).as_deref(),
+            );
+        }
+
+        if let Err(error) = &result {
+            report_job_failure(
+                job_type,
+                &tile,
+                state.current_stage(thread_index).as_deref(),
+                &text,
+                &error.to_string(),
+            );
+        }
+    }
+
+    // Failed on every attempt, the failure is final instead of left to the thread restart loop
+    let failed_for_good = !cancelled && result.is_err() && errors.len() >= context.job_attempts as usize;
+
+    if failed_for_good {
+        error!(
+            "{} job for {} failed {} times, reporting it as failed",
+            job_type,
+            tile,
+            errors.len()
+        );
+
+        if let Err(error) = notify_job_failed(&client, auth, base_url, &text, &errors) {
+            error!("Failed to report the failed job: {}", error);
+        }
+    }
+
+    // Stopped before its last attempt, eg: the worker draining or the job aborted by the memory
+    // watchdog. Handed back, the lease is released from the journal once the job is handled
+    if result.is_err() && !cancelled && !failed_for_good {
+        warn!(
+            "{} job for {} stopped before its last attempt, handing it back to the API",
+            job_type, tile
+        );
+
+        if let Err(error) = notify_job_abandoned(&client, auth, base_url, &text) {
+            error!("Failed to hand the stopped job back to the API: {}", error);
+        }
+    }
+
+    if let Some(metrics) = &context.metrics {
+        metrics.push(JobMetric {
+            job_type: job_type.to_string(),
+            tile: tile.clone(),
+            correlation_id: current_correlation_id(),
+            ended_at: ended_at.duration_since(UNIX_EPOCH)?.as_secs(),
+            duration_ms: duration.as_millis() as u64,
+            succeeded: result.is_ok(),
+            bytes_downloaded,
+            bytes_uploaded,
+            download_bytes_per_second: transfers.download_bytes_per_second(),
+            upload_bytes_per_second: transfers.upload_bytes_per_second(),
+        });
+    }
+
+    if let Some(history) = &context.history {
+        let record = JobRecord {
+            job_type: job_type.to_string(),
+            tile,
+            thread: worker_thread_name(thread_index),
+            started_at: started_at.duration_since(UNIX_EPOCH)?.as_secs(),
+            ended_at: ended_at.duration_since(UNIX_EPOCH)?.as_secs(),
+            duration_ms: duration.as_millis() as u64,
+            error: result.as_ref().err().map(|error| error.to_string()),
+            bytes_downloaded,
+            bytes_uploaded,
+        };
+
+        if let Err(error) = history.record(&record) {
+            error!("Failed to record job in the history: {}", error);
+        }
+    }
+
+    // `--once` exits with an error anyway
+    if failed_for_good && !context.once {
+        return Ok(());
+    }
+
+    result
+}
+
+/// Fetch the next job in the background while the current one is processed, and download the
+/// LiDAR step files it needs into the cache. Downloads go through the same tile locks as the
+/// render step, so a prefetch and a running job never download the same archive twice.
+fn spawn_prefetch(context: &WorkerContext, thread_index: usize) -> Option<JoinHandle<Option<String>>> {
+    let disk_budget = context.prefetch_disk_budget?;
+
+    if context.state.is_paused() || context.state.is_disk_paused() {
+        return None;
+    }
+
+    let cache_size = directory_size(Path::new("lidar-step"));
+
+    debug!(
+        "LiDAR step cache size: {} MB, prefetch disk budget: {} MB",
+        cache_size / 1_000_000,
+        disk_budget / 1_000_000
+    );
+
+    if cache_size > disk_budget {
+        info!(
+            "LiDAR step cache size ({} MB) above the prefetch disk budget, not prefetching",
+            cache_size / 1_000_000
+        );
+
+        return None;
+    }
+
+    let context = context.clone();
+
+    let spawned_thread = thread::Builder::new()
+        .name(downloader_thread_name(thread_index))
+        .spawn(move || {
+            let client = http_client();
+
+            let text = match fetch_next_job(&client, &context.auth, &context.base_url, &context.state) {
+                Ok(text) => text,
+                Err(error) => {
+                    warn!("Failed to prefetch next job: {}", error);
+                    return None;
+                }
+            };
+
+            context.journal.lease(&text);
+
+            match parse_job(&text, &context.region) {
+                Ok(Job::NoJobLeft) => return None,
+                Ok(Job::Render {
+                    tile_id,
+                    neigbhoring_tiles_ids,
+                    storage,
+                })
+                | Ok(Job::RestyleRender {
+                    tile_id,
+                    neigbhoring_tiles_ids,
+                    storage,
+                    ..
+                }) => {
+                    info!("Prefetching LiDAR step files for render job of tile {}", &tile_id);
+
+                    if let Err(error) = download_render_step_inputs(
+                        &client,
+                        &tile_id,
+                        &neigbhoring_tiles_ids,
+                        &context.auth,
+                        &context.base_url,
+                        storage.as_ref(),
+                    ) {
+                        warn!("Failed to prefetch files for tile {}: {}", &tile_id, error);
+                    }
+                }
+                _ => {}
+            }
+
+            Some(text)
+        });
+
+    match spawned_thread {
+        Ok(handle) => Some(handle),
+        Err(error) => {
+            warn!("Failed to spawn the prefetch thread: {}", error);
+            None
+        }
+    }
+}
+
+/// Job types this worker accepts: the known ones whose GDAL tools and drivers are installed.
+pub fn supported_job_types() -> Vec<&'static str> {
+    SUPPORTED_JOB_TYPES
+        .into_iter()
+        .filter(|job_type| missing_requirements(job_type).is_empty())
+        .collect()
+}
+
+/// Parse a job sent by the API. Unknown fields are ignored (and logged) so that the server can
+/// add fields without breaking older workers. An error is returned for unknown job types or
+/// invalid payloads, and for the jobs of tiles outside of `--bbox`.
+fn parse_job(text: &str, region: &RegionProfile) -> Result<Job, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(text).map_err(|error| format!("invalid json: {}", error))?;
+
+    let job_type = value
+        .get("type")
+        .and_then(|job_type| job_type.as_str())
+        .ok_or("missing job type")?;
+
+    if !SUPPORTED_JOB_TYPES.contains(&job_type) {
+        return Err(format!("unknown job type '{}'", job_type));
+    }
+
+    let missing = missing_requirements(job_type);
+
+    if !missing.is_empty() {
+        return Err(format!(
+            "{} jobs need {}, missing on this worker",
+            job_type,
+            missing.join(", ")
+        ));
+    }
+
+    let job: Job = serde_json::from_value(value.clone())
+        .map_err(|error| format!("invalid {} job: {}", job_type, error))?;
+
+    if let (Some(received), Ok(parsed)) = (value.get("data"), serde_json::to_value(&job)) {
+        if let (Some(received), Some(parsed)) = (
+            received.as_object(),
+            parsed.get("data").and_then(|data| data.as_object()),
+        ) {
+            let unknown_fields: Vec<&String> =
+                received.keys().filter(|key| !parsed.contains_key(*key)).collect();
+
+            if !unknown_fields.is_empty() {
+                warn!("Ignoring unknown fields {:?} of {} job", unknown_fields, job_type);
+            }
+        }
+    }
+
+    let outside = tiles_outside_bbox(&job.tiles_ids(), region);
+
+    if !outside.is_empty() {
+        return Err(format!(
+            "tiles {} outside of the bbox of this worker",
+            outside.join(", ")
+        ));
+    }
+
+    Ok(job)
+}
+
+fn report_unsupported_job(
+    client: &Client,
+    auth: &ApiAuth,
+    base_url: &str,
+    job_payload: &str,
+    reason: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!("{}/api/map-generation/unsupported-jobs", base_url);
+
+    let response = auth.send(
+        client
+            .post(url)
+            .header("X-Mapant-Protocol-Version", PROTOCOL_VERSION.to_string())
+            .json(&serde_json::json!({ "job": job_payload, "reason": reason })),
+    )?;
+
+    if !response.status().is_success() {
+        return Err(describe_error_response(response).into());
+    }
+
+    Ok(())
+}